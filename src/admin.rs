@@ -0,0 +1,397 @@
+use futures::StreamExt;
+use lapin::{
+    options::{
+        BasicAckOptions, BasicConsumeOptions, BasicPublishOptions, ExchangeDeclareOptions,
+        QueueBindOptions, QueueDeclareOptions,
+    },
+    types::FieldTable,
+    BasicProperties, Channel as RabbitChannel, Error as LapinError,
+};
+use serde::{Deserialize, Serialize};
+use tokio::time::{sleep, Duration};
+use warp::http::StatusCode;
+
+use crate::{
+    auth_helpers::AdminClaims,
+    channel_acl::validate_message,
+    config::CONFIG,
+    outbound::{OutboundMessage, Priority},
+    requests::BroadcastRequest,
+    responses::{
+        AdminActionResponse, AdminChannelsResponse, ChannelSummary, ConnectionSummary, ControlFrame,
+    },
+    shared::{decode_path_segment, open_rabbitmq_channel, Channels, RABBIT_POOL, REPLICA_ID},
+};
+
+const DISCONNECT_REASON: &str = "Disconnected by an administrator";
+const CLOSE_REASON: &str = "Channel closed by an administrator";
+
+// Actions on live sockets. Any replica may hold the sockets concerned, so commands are sent to
+// every replica over `admin.exchange`.
+#[derive(Deserialize, Serialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum AdminCommand {
+    DisconnectUser { user_id: String },
+    DisconnectConnection { connection_id: String },
+    CloseChannel { channel: String },
+    Broadcast { message: String },
+}
+
+#[derive(Deserialize, Serialize)]
+struct AdminEvent {
+    replica: String,
+    command: AdminCommand,
+}
+
+pub async fn declare_admin_exchange(rabbit_channel: &RabbitChannel) -> Result<(), LapinError> {
+    rabbit_channel
+        .exchange_declare(
+            &CONFIG.admin.exchange,
+            lapin::ExchangeKind::Fanout,
+            ExchangeDeclareOptions {
+                durable: true,
+                ..Default::default()
+            },
+            FieldTable::default(),
+        )
+        .await
+}
+
+async fn publish_event(event: &AdminEvent) -> Result<(), LapinError> {
+    let rabbit_channel = RABBIT_POOL.channel().await?;
+
+    rabbit_channel
+        .basic_publish(
+            &CONFIG.admin.exchange,
+            "",
+            BasicPublishOptions::default(),
+            &serde_json::to_vec(event).unwrap(),
+            BasicProperties::default(),
+        )
+        .await?;
+    Ok(())
+}
+
+// Apply a command to the local sockets, returning how many it reached
+async fn apply_command(channels: &Channels, command: &AdminCommand) -> usize {
+    let mut registry = channels.lock().await;
+
+    match command {
+        AdminCommand::DisconnectUser { user_id } => registry
+            .disconnect_where(DISCONNECT_REASON, |_, _, connection| {
+                connection.user_id.as_ref() == Some(user_id)
+            }),
+        AdminCommand::DisconnectConnection { connection_id } => {
+            registry.disconnect_where(DISCONNECT_REASON, |_, id, _| id == connection_id.as_str())
+        }
+        AdminCommand::CloseChannel { channel } => {
+            registry.disconnect_where(CLOSE_REASON, |name, _, _| name == channel.as_str())
+        }
+        AdminCommand::Broadcast { message } => {
+            let outbound = OutboundMessage {
+                priority: Priority::Critical,
+                expires_at: None,
+                collapse_key: None,
+                payload: ControlFrame::System { message }.encode(),
+                plain: None,
+                id: None,
+                publisher: None,
+            };
+            registry
+                .values()
+                .flat_map(|channel| channel.connections.values())
+                .filter(|connection| connection.queue.push(outbound.clone()))
+                .count()
+        }
+    }
+}
+
+// Applied here right away and by the other replicas as the command reaches them
+async fn run_command(channels: &Channels, command: AdminCommand) -> usize {
+    let event = AdminEvent {
+        replica: REPLICA_ID.clone(),
+        command,
+    };
+    if let Err(e) = publish_event(&event).await {
+        eprintln!("Failed to send admin command to other replicas: {:?}", e);
+    }
+    apply_command(channels, &event.command).await
+}
+
+pub async fn consume_admin_commands(channels: Channels) {
+    loop {
+        match open_rabbitmq_channel().await {
+            Ok(rabbit_channel) => {
+                if let Err(e) = process_admin_commands(rabbit_channel, &channels).await {
+                    eprintln!("Error processing admin commands: {:?}", e);
+                }
+            }
+            Err(e) => {
+                eprintln!("Error connecting to RabbitMQ: {:?}", e);
+            }
+        }
+
+        eprintln!("Reconnecting to admin commands in 5 seconds...");
+        sleep(Duration::from_secs(5)).await;
+    }
+}
+
+async fn process_admin_commands(
+    rabbit_channel: RabbitChannel,
+    channels: &Channels,
+) -> Result<(), LapinError> {
+    declare_admin_exchange(&rabbit_channel).await?;
+
+    let queue = rabbit_channel
+        .queue_declare(
+            "",
+            QueueDeclareOptions {
+                exclusive: true,
+                auto_delete: true,
+                ..Default::default()
+            },
+            FieldTable::default(),
+        )
+        .await?;
+
+    rabbit_channel
+        .queue_bind(
+            queue.name().as_str(),
+            &CONFIG.admin.exchange,
+            "",
+            QueueBindOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    let mut consumer = rabbit_channel
+        .basic_consume(
+            queue.name().as_str(),
+            "admin_consumer",
+            BasicConsumeOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    while let Some(delivery) = consumer.next().await {
+        let delivery = delivery?;
+
+        match serde_json::from_slice::<AdminEvent>(&delivery.data) {
+            // Our own commands were applied when they were sent; a broadcast mustn't go out twice
+            Ok(event) if event.replica == *REPLICA_ID => {}
+            Ok(event) => {
+                let reached = apply_command(channels, &event.command).await;
+                println!("Applied admin command to {} local connection(s)", reached);
+            }
+            Err(e) => println!("Failed to deserialize admin command: {:?}", e),
+        }
+
+        delivery.ack(BasicAckOptions::default()).await?;
+    }
+
+    Ok(())
+}
+
+fn accepted(local_connections: usize) -> Box<dyn warp::Reply> {
+    Box::new(warp::reply::with_status(
+        warp::reply::json(&AdminActionResponse { local_connections }),
+        StatusCode::ACCEPTED,
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/notification/admin/channels",
+    responses(
+        (status = 200, description = "Channels with subscribers on the replica answering", body = AdminChannelsResponse),
+        (status = 403, description = "Caller lacks the notification-admin role")
+    ),
+    security(("bearerAuth" = [])),
+    tag = "default"
+)]
+pub async fn list_channels(
+    _claims: AdminClaims,
+    channels: Channels,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let registry = channels.lock().await;
+    let mut summaries = registry
+        .values()
+        .map(|channel| ChannelSummary {
+            name: channel.name.clone(),
+            subscribers: channel.connections.len(),
+            buffered: channel
+                .connections
+                .values()
+                .map(|connection| connection.queue.queued())
+                .sum(),
+            buffer_capacity: channel
+                .connections
+                .values()
+                .map(|connection| connection.queue.capacity())
+                .sum(),
+        })
+        .collect::<Vec<_>>();
+    summaries.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(warp::reply::json(&AdminChannelsResponse {
+        replica_id: REPLICA_ID.clone(),
+        channels: summaries,
+    }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/notification/admin/channels/{channel}/connections",
+    params(
+        ("channel" = String, Path, description = "The channel name or pattern sockets subscribed with, percent-encoded")
+    ),
+    responses(
+        (status = 200, description = "The channel's connections on the replica answering", body = [ConnectionSummary]),
+        (status = 403, description = "Caller lacks the notification-admin role"),
+        (status = 404, description = "No connections to the channel on this replica")
+    ),
+    security(("bearerAuth" = [])),
+    tag = "default"
+)]
+pub async fn list_connections(
+    channel: String,
+    _claims: AdminClaims,
+    channels: Channels,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    let channel = decode_path_segment(&channel);
+    let registry = channels.lock().await;
+
+    let Some(channel) = registry.get(&channel) else {
+        return Ok(Box::new(warp::reply::with_status(
+            warp::reply::json(&"No connections to this channel on this replica"),
+            StatusCode::NOT_FOUND,
+        )));
+    };
+
+    let mut connections = channel
+        .connections
+        .iter()
+        .map(|(connection_id, connection)| ConnectionSummary {
+            connection_id: connection_id.clone(),
+            user_id: connection.user_id.clone(),
+            connected_at: connection.connected_at,
+            remote_addr: connection.remote_addr.clone(),
+            buffered: connection.queue.queued(),
+        })
+        .collect::<Vec<_>>();
+    connections.sort_by_key(|connection| connection.connected_at);
+
+    Ok(Box::new(warp::reply::json(&connections)))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/notification/admin/connections/{connection_id}",
+    params(
+        ("connection_id" = String, Path, description = "The connection to close")
+    ),
+    responses(
+        (status = 202, description = "Connection closed wherever it is connected", body = AdminActionResponse),
+        (status = 403, description = "Caller lacks the notification-admin role")
+    ),
+    security(("bearerAuth" = [])),
+    tag = "default"
+)]
+pub async fn disconnect_connection(
+    connection_id: String,
+    claims: AdminClaims,
+    channels: Channels,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    println!(
+        "{} disconnected connection {}",
+        claims.user_id, connection_id
+    );
+    let reached = run_command(
+        &channels,
+        AdminCommand::DisconnectConnection { connection_id },
+    )
+    .await;
+    Ok(accepted(reached))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/notification/admin/users/{user_id}/connections",
+    params(
+        ("user_id" = String, Path, description = "The user whose sockets to close")
+    ),
+    responses(
+        (status = 202, description = "The user's sockets closed on every replica", body = AdminActionResponse),
+        (status = 403, description = "Caller lacks the notification-admin role")
+    ),
+    security(("bearerAuth" = [])),
+    tag = "default"
+)]
+pub async fn disconnect_user(
+    user_id: String,
+    claims: AdminClaims,
+    channels: Channels,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    println!("{} disconnected user {}", claims.user_id, user_id);
+    let reached = run_command(&channels, AdminCommand::DisconnectUser { user_id }).await;
+    Ok(accepted(reached))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/notification/admin/channels/{channel}",
+    params(
+        ("channel" = String, Path, description = "The channel name or pattern sockets subscribed with, percent-encoded")
+    ),
+    responses(
+        (status = 202, description = "Every socket subscribed with the name closed on every replica", body = AdminActionResponse),
+        (status = 403, description = "Caller lacks the notification-admin role")
+    ),
+    security(("bearerAuth" = [])),
+    tag = "default"
+)]
+pub async fn close_channel(
+    channel: String,
+    claims: AdminClaims,
+    channels: Channels,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    let channel = decode_path_segment(&channel);
+    println!("{} closed channel {}", claims.user_id, channel);
+    let reached = run_command(&channels, AdminCommand::CloseChannel { channel }).await;
+    Ok(accepted(reached))
+}
+
+#[utoipa::path(
+    post,
+    path = "/notification/admin/broadcast",
+    request_body = BroadcastRequest,
+    responses(
+        (status = 202, description = "Announcement sent to every connection on every replica", body = AdminActionResponse),
+        (status = 400, description = "Message too large"),
+        (status = 403, description = "Caller lacks the notification-admin role")
+    ),
+    security(("bearerAuth" = [])),
+    tag = "default"
+)]
+pub async fn broadcast(
+    request: BroadcastRequest,
+    claims: AdminClaims,
+    channels: Channels,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    if let Err(e) = validate_message(&request.message) {
+        return Ok(Box::new(warp::reply::with_status(
+            warp::reply::json(&e),
+            StatusCode::BAD_REQUEST,
+        )));
+    }
+
+    println!("{} broadcast a system message", claims.user_id);
+    let reached = run_command(
+        &channels,
+        AdminCommand::Broadcast {
+            message: request.message,
+        },
+    )
+    .await;
+    Ok(accepted(reached))
+}
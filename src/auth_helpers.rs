@@ -1,12 +1,21 @@
 // Handle a new WebSocket connection
 use crate::{
-    responses::JWTError,
-    shared::{Channel, Channels},
+    channel_acl::{authorize_api_subscribe, authorize_user_subscribe, validate_channel_pattern},
+    config::CONFIG,
+    outbound::{OutboundMessage, OutboundQueue, Priority},
+    receipts::{publish_receipt, DeliveredMessages, ReceiptEvent, ReceiptStatus},
+    requests::ClientFrame,
+    responses::{DeliveryEnvelope, JWTError},
+    responses::{InvalidChannelError, ShuttingDownError},
+    shared::{decode_path_segment, Channels, ConnectionHandle},
+    shutdown::SHUTDOWN,
 };
+use chrono::Utc;
 use futures::StreamExt;
 use ginger_shared_rs::rocket_utils::Claims;
 use jsonwebtoken::{decode, DecodingKey, Validation};
-use tokio::sync::{broadcast, Mutex};
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
 use warp::{
     reject::Rejection,
     ws::{Message, WebSocket},
@@ -14,54 +23,186 @@ use warp::{
 
 use futures::sink::SinkExt;
 
-pub async fn user_connected(ws: WebSocket, channel_name: String, channels: Channels) {
+pub async fn user_connected(
+    ws: WebSocket,
+    channel_name: String,
+    channels: Channels,
+    user_id: Option<String>,
+    remote_addr: Option<String>,
+    envelope: bool,
+) {
     let (mut tx, mut rx) = ws.split();
 
-    let (channel_tx, mut channel_rx) = {
-        let mut channels_lock = channels.lock().await;
-        let channel = channels_lock
-            .entry(channel_name.clone())
-            .or_insert_with(|| {
-                let (tx, _) = broadcast::channel(100);
-                Channel {
-                    name: channel_name.clone(),
-                    tx,
-                }
-            });
+    let connection_id = Uuid::new_v4().to_string();
+    let queue = OutboundQueue::new(CONFIG.delivery.outbound_queue_capacity);
 
-        (channel.tx.clone(), channel.tx.subscribe())
-    };
+    channels.lock().await.subscribe(
+        &channel_name,
+        connection_id.clone(),
+        ConnectionHandle {
+            user_id: user_id.clone(),
+            queue: queue.clone(),
+            connected_at: Utc::now(),
+            remote_addr,
+        },
+    );
+
+    // Filled in as messages are written, checked as receipts come back
+    let delivered = Arc::new(Mutex::new(DeliveredMessages::new(
+        CONFIG.receipts.tracked_per_connection,
+    )));
+    let writer_delivered = delivered.clone();
 
+    let reader_queue = queue.clone();
     tokio::spawn(async move {
         while let Some(result) = rx.next().await {
             if let Ok(msg) = result {
                 if let Ok(text) = msg.to_str() {
-                    let _ = channel_tx.send(text.to_string());
+                    // Receipts for delivered messages; any other text is relayed to the channel
+                    if let Ok(frame) = serde_json::from_str::<ClientFrame>(text) {
+                        if let Some(user_id) = &user_id {
+                            send_receipt(frame, &channel_name, user_id, &delivered).await;
+                        }
+                        continue;
+                    }
+
+                    let id = Uuid::new_v4().to_string();
+                    let envelope = DeliveryEnvelope {
+                        id: &id,
+                        channel: &channel_name,
+                        message: text,
+                        expires_at: None,
+                        collapse_key: None,
+                    }
+                    .encode();
+                    if let Some(channel) = channels.lock().await.get(&channel_name) {
+                        channel.deliver(&OutboundMessage {
+                            priority: Priority::Normal,
+                            expires_at: None,
+                            collapse_key: None,
+                            payload: envelope,
+                            plain: Some(text.to_string()),
+                            id: Some(id),
+                            publisher: user_id.as_ref().map(|user_id| format!("user:{}", user_id)),
+                        });
+                    }
                 }
             }
         }
+
+        // Socket closed, stop routing messages to it
+        channels
+            .lock()
+            .await
+            .unsubscribe(&channel_name, &connection_id);
+        reader_queue.close();
     });
 
     tokio::spawn(async move {
-        while let Ok(message) = channel_rx.recv().await {
-            if tx.send(Message::text(message)).await.is_err() {
-                break;
+        while let Some(message) = queue.pop().await {
+            if let Some(id) = message.id.clone() {
+                writer_delivered
+                    .lock()
+                    .unwrap()
+                    .record(id, message.publisher.clone());
+            }
+
+            let text = if envelope {
+                message.payload
+            } else if let Some(plain) = message.plain {
+                plain
+            } else {
+                continue;
+            };
+            if tx.send(Message::text(text)).await.is_err() {
+                return;
             }
         }
+
+        // Closed on our side, e.g. by an administrator; fails harmlessly if the client left
+        let _ = tx.send(Message::close()).await;
     });
 }
 
+async fn send_receipt(
+    frame: ClientFrame,
+    channel_name: &str,
+    user_id: &str,
+    delivered: &Mutex<DeliveredMessages>,
+) {
+    let (message_id, status) = match frame {
+        ClientFrame::Ack { id } => (id, ReceiptStatus::Delivered),
+        ClientFrame::Read { id } => (id, ReceiptStatus::Read),
+    };
+
+    let Some(publisher) = delivered.lock().unwrap().publisher(&message_id) else {
+        println!(
+            "Ignoring receipt from user {} for {}, it wasn't delivered on this connection",
+            user_id, message_id
+        );
+        return;
+    };
+
+    let event = ReceiptEvent {
+        message_id,
+        channel: channel_name.to_string(),
+        user_id: user_id.to_string(),
+        status,
+        at: Utc::now(),
+        publisher,
+    };
+
+    if let Err(e) = publish_receipt(&event).await {
+        println!(
+            "Failed to publish receipt for {}: {:?}",
+            event.message_id, e
+        );
+    }
+}
+
+type AuthenticatedUpgrade = (
+    warp::ws::Ws,
+    String,
+    Channels,
+    Option<String>, // User id
+    Option<String>, // Remote address
+    bool,           // Whether the client asked for envelopes
+);
+
 pub async fn handle_ws_upgrade(
-    (ws, channel_name, channels): (warp::ws::Ws, String, Channels),
+    (ws, channel_name, channels, user_id, remote_addr, envelope): AuthenticatedUpgrade,
 ) -> Result<impl warp::Reply, Rejection> {
-    Ok(ws.on_upgrade(move |socket| user_connected(socket, channel_name, channels)))
+    Ok(ws.on_upgrade(move |socket| {
+        user_connected(
+            socket,
+            channel_name,
+            channels,
+            user_id,
+            remote_addr,
+            envelope,
+        )
+    }))
 }
 pub async fn user_authenticated(
     channel_name: String,
     ws: warp::ws::Ws,
     channels: Channels,
     token: Option<String>, // Extract token from query parameters
-) -> Result<(warp::ws::Ws, String, Channels), Rejection> {
+    remote_addr: Option<String>,
+    envelope: bool,
+) -> Result<AuthenticatedUpgrade, Rejection> {
+    // Clients asked to reconnect elsewhere mustn't land here again
+    if SHUTDOWN.is_draining() {
+        return Err(warp::reject::custom(ShuttingDownError));
+    }
+
+    // Patterns such as org.42.%23 arrive percent-encoded
+    let channel_name = decode_path_segment(&channel_name);
+    if let Err(e) = validate_channel_pattern(&channel_name) {
+        println!("Rejected subscription: {}", e);
+        return Err(warp::reject::custom(InvalidChannelError));
+    }
+
     if let Some(token) = token {
         // No need to trim "Bearer " since the token is expected to be plain
         let secret = "1234";
@@ -72,13 +213,28 @@ pub async fn user_authenticated(
         // Try decoding as `Claims`
         if let Ok(token_data) = decode::<Claims>(&token, &decoding_key, &validation) {
             println!("Authenticated user: {:?}", token_data.claims.user_id);
-            return Ok((ws, channel_name, channels));
+            if let Err(e) = authorize_user_subscribe(&token_data.claims.user_id, &channel_name) {
+                println!("{}", e);
+                return Err(warp::reject::custom(ForbiddenError));
+            }
+            return Ok((
+                ws,
+                channel_name,
+                channels,
+                Some(token_data.claims.user_id),
+                remote_addr,
+                envelope,
+            ));
         }
 
         // Try decoding as `APIClaims`
         if let Ok(token_data) = decode::<APIClaims>(&token, &decoding_key, &validation) {
             println!("Authenticated API user: {:?}", token_data.claims.sub);
-            return Ok((ws, channel_name, channels));
+            if let Err(e) = authorize_api_subscribe(&token_data.claims.sub, &channel_name) {
+                println!("{}", e);
+                return Err(warp::reject::custom(ForbiddenError));
+            }
+            return Ok((ws, channel_name, channels, None, remote_addr, envelope));
         }
 
         println!("Unauthorized access attempt");
@@ -90,9 +246,20 @@ pub async fn user_authenticated(
 }
 
 use ginger_shared_rs::{rocket_utils::APIClaims, ISCClaims};
+use serde::Deserialize;
 use warp::Filter;
 
-use crate::responses::InvalidTokenError;
+use crate::responses::{ForbiddenError, InvalidTokenError};
+
+pub const ADMIN_ROLE: &str = "notification-admin";
+
+// User claims carrying the roles granted to the user, used to gate admin endpoints
+#[derive(Debug, Deserialize, Clone)]
+pub struct AdminClaims {
+    pub user_id: String,
+    #[serde(default)]
+    pub roles: Vec<String>,
+}
 
 pub async fn authenticate_token(token: Option<String>) -> Result<Claims, warp::Rejection> {
     if let Some(token) = token {
@@ -123,6 +290,66 @@ pub fn with_auth() -> impl Filter<Extract = (Claims,), Error = warp::Rejection>
     )
 }
 
+// Identity of the caller for resources owned by either users or API clients, namespaced so
+// a user id can never collide with an API client's `sub`
+pub fn with_caller_identity() -> impl Filter<Extract = (String,), Error = warp::Rejection> + Clone {
+    with_auth()
+        .map(|claims: Claims| format!("user:{}", claims.user_id))
+        .or(with_api_auth().map(|claims: APIClaims| format!("api:{}", claims.sub)))
+        .unify()
+}
+
+// Users may only read and change resources of their own, such as preferences and inbox
+pub fn forbidden_unless_self(claims: &Claims, user_id: &str) -> Option<Box<dyn warp::Reply>> {
+    if claims.user_id == user_id {
+        return None;
+    }
+    Some(Box::new(warp::reply::with_status(
+        warp::reply::json(&format!(
+            "User {} may not access resources of user {}",
+            claims.user_id, user_id
+        )),
+        warp::http::StatusCode::FORBIDDEN,
+    )))
+}
+
+pub async fn authenticate_admin_token(token: String) -> Result<AdminClaims, warp::Rejection> {
+    let secret = "1234"; // Use environment variable in production
+
+    let decoding_key = DecodingKey::from_secret(secret.as_ref());
+    let validation = Validation::new(jsonwebtoken::Algorithm::HS256);
+
+    match decode::<AdminClaims>(&token, &decoding_key, &validation) {
+        Ok(token_data)
+            if token_data
+                .claims
+                .roles
+                .iter()
+                .any(|role| role == ADMIN_ROLE) =>
+        {
+            Ok(token_data.claims)
+        }
+        Ok(token_data) => {
+            println!("User {} is not an admin", token_data.claims.user_id);
+            Err(warp::reject::custom(ForbiddenError))
+        }
+        Err(_) => Err(warp::reject::custom(JWTError)),
+    }
+}
+
+pub fn with_admin_auth() -> impl Filter<Extract = (AdminClaims,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("Authorization").and_then(
+        |auth_header: Option<String>| async move {
+            if let Some(token) = auth_header {
+                let token = token.trim_start_matches("Bearer ").to_string();
+                authenticate_admin_token(token).await
+            } else {
+                Err(warp::reject::custom(JWTError))
+            }
+        },
+    )
+}
+
 pub async fn authenticate_isc_api_token(
     token: Option<String>,
 ) -> Result<ISCClaims, warp::Rejection> {
@@ -0,0 +1,263 @@
+use chrono::Utc;
+
+use crate::{
+    channel_trie::{is_wildcard, MULTI_WILDCARD, SEGMENT_SEPARATOR, SINGLE_WILDCARD},
+    config::CONFIG,
+    requests::PublishRequest,
+    shared::{GROUP_CHANNEL_PREFIX, USER_CHANNEL_PREFIX},
+};
+
+// Channel names double as AMQP routing keys, which are limited to 255 bytes
+const MAX_CHANNEL_NAME_LENGTH: usize = 255;
+
+// Messages are published to concrete channels; only subscriptions may use wildcards
+pub fn validate_channel_name(channel: &str) -> Result<(), String> {
+    validate_channel_pattern(channel)?;
+    if channel.split(SEGMENT_SEPARATOR).any(is_wildcard) {
+        return Err(format!(
+            "Channel name {} contains a wildcard, publish to a concrete channel",
+            channel
+        ));
+    }
+    Ok(())
+}
+
+// `*` and `#` are only allowed as whole `.` separated segments
+pub fn validate_channel_pattern(channel: &str) -> Result<(), String> {
+    if channel.is_empty() {
+        return Err("Channel name is empty".to_string());
+    }
+    if channel.len() > MAX_CHANNEL_NAME_LENGTH {
+        return Err(format!("Channel name {} is too long", channel));
+    }
+    if channel.chars().any(char::is_whitespace) {
+        return Err(format!("Channel name {} contains whitespace", channel));
+    }
+    if channel.starts_with(GROUP_CHANNEL_PREFIX) {
+        return Err(format!(
+            "Channel {} is reserved, publish to groups through the group endpoint",
+            channel
+        ));
+    }
+    if channel.starts_with(USER_CHANNEL_PREFIX) {
+        return Err(format!(
+            "Channel {} is reserved, notify users through the notify endpoint",
+            channel
+        ));
+    }
+
+    let misplaced_wildcard = channel.split(SEGMENT_SEPARATOR).any(|segment| {
+        !is_wildcard(segment)
+            && (segment.contains(SINGLE_WILDCARD) || segment.contains(MULTI_WILDCARD))
+    });
+    if misplaced_wildcard {
+        return Err(format!(
+            "Channel pattern {} may only use * and # as whole segments",
+            channel
+        ));
+    }
+    Ok(())
+}
+
+pub fn validate_message(message: &str) -> Result<(), String> {
+    if message.len() > CONFIG.publish.max_message_bytes {
+        return Err(format!(
+            "Message is larger than {} bytes",
+            CONFIG.publish.max_message_bytes
+        ));
+    }
+    Ok(())
+}
+
+pub fn validate_expiry(publish_request: &PublishRequest) -> Result<(), String> {
+    match (publish_request.expires_at, publish_request.ttl_secs) {
+        (Some(_), Some(_)) => Err("Set either expires_at or ttl_secs, not both".to_string()),
+        (Some(expires_at), None) if expires_at <= Utc::now() => {
+            Err("expires_at is in the past".to_string())
+        }
+        (None, Some(0)) => Err("ttl_secs must be at least 1".to_string()),
+        (None, Some(_)) if publish_request.expiry(Utc::now()).is_none() => {
+            Err("ttl_secs is too large".to_string())
+        }
+        _ => Ok(()),
+    }
+}
+
+// Whether every channel matched by `requested` is also matched by `allowed`
+fn pattern_covers(allowed: &[&str], requested: &[&str]) -> bool {
+    match (allowed.split_first(), requested.split_first()) {
+        (None, None) => true,
+        (Some((&MULTI_WILDCARD, allowed_rest)), _) => {
+            pattern_covers(allowed_rest, requested)
+                || (!requested.is_empty() && pattern_covers(allowed, &requested[1..]))
+        }
+        (Some((&SINGLE_WILDCARD, allowed_rest)), Some((segment, requested_rest)))
+            if *segment != MULTI_WILDCARD =>
+        {
+            pattern_covers(allowed_rest, requested_rest)
+        }
+        (Some((allowed_segment, allowed_rest)), Some((segment, requested_rest)))
+            if allowed_segment == segment =>
+        {
+            pattern_covers(allowed_rest, requested_rest)
+        }
+        _ => false,
+    }
+}
+
+fn subscription_allowed(allowed: &[String], requested: &str, user_id: Option<&str>) -> bool {
+    let requested = requested.split(SEGMENT_SEPARATOR).collect::<Vec<_>>();
+
+    allowed.iter().any(|pattern| {
+        let pattern = match user_id {
+            Some(user_id) => pattern.replace("{user_id}", user_id),
+            None => pattern.clone(),
+        };
+        let pattern = pattern.split(SEGMENT_SEPARATOR).collect::<Vec<_>>();
+        pattern_covers(&pattern, &requested)
+    })
+}
+
+// Whether the channel, or every channel a pattern could match, is covered by `patterns`
+pub fn patterns_cover(patterns: &[String], channel: &str) -> bool {
+    subscription_allowed(patterns, channel, None)
+}
+
+fn is_pattern(channel: &str) -> bool {
+    channel.split(SEGMENT_SEPARATOR).any(is_wildcard)
+}
+
+// Check a user's subscription, pattern or not, against `subscribe.users`. Without rules any
+// concrete channel is allowed, but a pattern such as `#` is only allowed when a rule covers it.
+pub fn authorize_user_subscribe(user_id: &str, channel: &str) -> Result<(), String> {
+    let allowed = &CONFIG.subscribe.users;
+    let unrestricted = allowed.is_empty() && !is_pattern(channel);
+    if unrestricted || subscription_allowed(allowed, channel, Some(user_id)) {
+        Ok(())
+    } else {
+        Err(format!("User {} may not subscribe to {}", user_id, channel))
+    }
+}
+
+// Check an API client's subscription, pattern or not, against `subscribe.acl`, which likewise
+// has to allow patterns explicitly
+pub fn authorize_api_subscribe(client: &str, channel: &str) -> Result<(), String> {
+    if CONFIG.subscribe.acl.is_empty() && !is_pattern(channel) {
+        return Ok(());
+    }
+
+    let allowed = CONFIG
+        .subscribe
+        .acl
+        .get(client)
+        .is_some_and(|patterns| subscription_allowed(patterns, channel, None));
+
+    if allowed {
+        Ok(())
+    } else {
+        Err(format!("{} may not subscribe to {}", client, channel))
+    }
+}
+
+// Check `channel` against the publish ACL configured for the API client
+pub fn authorize_publish(client: &str, channel: &str) -> Result<(), String> {
+    if CONFIG.publish.acl.is_empty() {
+        return Ok(());
+    }
+
+    let allowed = CONFIG
+        .publish
+        .acl
+        .get(client)
+        .is_some_and(|patterns| patterns_cover(patterns, channel));
+
+    if allowed {
+        Ok(())
+    } else {
+        Err(format!("{} may not publish to {}", client, channel))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration as ChronoDuration;
+
+    #[test]
+    fn denies_patterns_no_rule_allows() {
+        // The default config has no subscribe rules
+        assert!(authorize_user_subscribe("17", "docs.42").is_ok());
+        assert!(authorize_user_subscribe("17", "#").is_err());
+        assert!(authorize_user_subscribe("17", "docs.*").is_err());
+        assert!(authorize_api_subscribe("build-service", "org.42").is_ok());
+        assert!(authorize_api_subscribe("build-service", "org.#").is_err());
+    }
+
+    #[test]
+    fn patterns_are_allowed_only_within_a_rule() {
+        let allowed = ["user.{user_id}.#".to_string(), "public.*".to_string()];
+
+        assert!(subscription_allowed(&allowed, "user.17.#", Some("17")));
+        assert!(subscription_allowed(
+            &allowed,
+            "user.17.alerts.*",
+            Some("17")
+        ));
+        assert!(!subscription_allowed(&allowed, "user.23.#", Some("17")));
+        assert!(!subscription_allowed(&allowed, "user.#", Some("17")));
+        assert!(subscription_allowed(&allowed, "public.news", Some("17")));
+        assert!(!subscription_allowed(&allowed, "public.#", Some("17")));
+        assert!(!subscription_allowed(&allowed, "#", Some("17")));
+    }
+
+    #[test]
+    fn publish_patterns_match_whole_segments() {
+        let allowed = ["invoice.#".to_string(), "billing.*.paid".to_string()];
+
+        assert!(patterns_cover(&allowed, "invoice.42"));
+        assert!(patterns_cover(&allowed, "invoice.42.paid"));
+        assert!(patterns_cover(&allowed, "billing.42.paid"));
+        assert!(!patterns_cover(&allowed, "invoices.42"));
+        assert!(!patterns_cover(&allowed, "invoice-archive"));
+        assert!(!patterns_cover(&allowed, "billing.42.refunded"));
+    }
+
+    fn with_ttl(ttl_secs: u64) -> PublishRequest {
+        PublishRequest {
+            ttl_secs: Some(ttl_secs),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn accepts_a_ttl() {
+        assert!(validate_expiry(&with_ttl(60)).is_ok());
+        assert!(validate_expiry(&PublishRequest::default()).is_ok());
+    }
+
+    #[test]
+    fn rejects_a_zero_ttl() {
+        assert!(validate_expiry(&with_ttl(0)).is_err());
+    }
+
+    #[test]
+    fn rejects_ttls_too_large_to_represent() {
+        assert!(validate_expiry(&with_ttl(i64::MAX as u64)).is_err());
+        assert!(validate_expiry(&with_ttl(u64::MAX)).is_err());
+    }
+
+    #[test]
+    fn rejects_past_or_conflicting_expiry() {
+        let past = PublishRequest {
+            expires_at: Some(Utc::now() - ChronoDuration::seconds(1)),
+            ..Default::default()
+        };
+        assert!(validate_expiry(&past).is_err());
+
+        let both = PublishRequest {
+            expires_at: Some(Utc::now() + ChronoDuration::seconds(60)),
+            ..with_ttl(60)
+        };
+        assert!(validate_expiry(&both).is_err());
+    }
+}
@@ -0,0 +1,150 @@
+use std::collections::{HashMap, HashSet};
+
+pub const SEGMENT_SEPARATOR: char = '.';
+pub const SINGLE_WILDCARD: &str = "*";
+pub const MULTI_WILDCARD: &str = "#";
+
+pub fn is_wildcard(segment: &str) -> bool {
+    segment == SINGLE_WILDCARD || segment == MULTI_WILDCARD
+}
+
+// Subscription patterns indexed by segment, so a channel name is matched against every
+// pattern without scanning them. `*` matches exactly one segment and `#` zero or more.
+#[derive(Default, Debug)]
+pub struct ChannelTrie {
+    root: TrieNode,
+}
+
+#[derive(Default, Debug)]
+struct TrieNode {
+    children: HashMap<String, TrieNode>,
+    pattern: Option<String>,
+}
+
+impl ChannelTrie {
+    pub fn insert(&mut self, pattern: &str) {
+        let mut node = &mut self.root;
+        for segment in pattern.split(SEGMENT_SEPARATOR) {
+            node = node.children.entry(segment.to_string()).or_default();
+        }
+        node.pattern = Some(pattern.to_string());
+    }
+
+    pub fn remove(&mut self, pattern: &str) {
+        let segments = pattern.split(SEGMENT_SEPARATOR).collect::<Vec<_>>();
+        remove_from(&mut self.root, &segments);
+    }
+
+    // Every inserted pattern matching the concrete channel name
+    pub fn matches(&self, channel: &str) -> HashSet<&str> {
+        let segments = channel.split(SEGMENT_SEPARATOR).collect::<Vec<_>>();
+        let mut matched = HashSet::new();
+        collect(&self.root, &segments, &mut matched);
+        matched
+    }
+}
+
+// Returns true once `node` holds nothing and can be pruned
+fn remove_from(node: &mut TrieNode, segments: &[&str]) -> bool {
+    match segments.split_first() {
+        None => node.pattern = None,
+        Some((segment, rest)) => {
+            if let Some(child) = node.children.get_mut(*segment) {
+                if remove_from(child, rest) {
+                    node.children.remove(*segment);
+                }
+            }
+        }
+    }
+    node.pattern.is_none() && node.children.is_empty()
+}
+
+fn collect<'a>(node: &'a TrieNode, segments: &[&str], matched: &mut HashSet<&'a str>) {
+    if let Some(child) = node.children.get(MULTI_WILDCARD) {
+        for skip in 0..=segments.len() {
+            collect(child, &segments[skip..], matched);
+        }
+    }
+
+    match segments.split_first() {
+        None => {
+            if let Some(pattern) = &node.pattern {
+                matched.insert(pattern);
+            }
+        }
+        Some((segment, rest)) => {
+            if let Some(child) = node.children.get(*segment) {
+                collect(child, rest, matched);
+            }
+            if let Some(child) = node.children.get(SINGLE_WILDCARD) {
+                collect(child, rest, matched);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trie(patterns: &[&str]) -> ChannelTrie {
+        let mut trie = ChannelTrie::default();
+        for pattern in patterns {
+            trie.insert(pattern);
+        }
+        trie
+    }
+
+    fn sorted(matched: HashSet<&str>) -> Vec<&str> {
+        let mut matched = matched.into_iter().collect::<Vec<_>>();
+        matched.sort();
+        matched
+    }
+
+    #[test]
+    fn matches_exact_names() {
+        let trie = trie(&["orders", "orders.eu"]);
+
+        assert_eq!(sorted(trie.matches("orders")), ["orders"]);
+        assert_eq!(sorted(trie.matches("orders.eu")), ["orders.eu"]);
+        assert!(trie.matches("orders.us").is_empty());
+    }
+
+    #[test]
+    fn single_wildcard_matches_one_segment() {
+        let trie = trie(&["orders.*", "*.eu.created"]);
+
+        assert_eq!(sorted(trie.matches("orders.eu")), ["orders.*"]);
+        assert_eq!(sorted(trie.matches("orders.eu.created")), ["*.eu.created"]);
+        assert!(trie.matches("orders").is_empty());
+    }
+
+    #[test]
+    fn multi_wildcard_matches_zero_or_more_segments() {
+        let trie = trie(&["#", "orders.#", "orders.#.created"]);
+
+        assert_eq!(sorted(trie.matches("orders")), ["#", "orders.#"]);
+        assert_eq!(
+            sorted(trie.matches("orders.created")),
+            ["#", "orders.#", "orders.#.created"]
+        );
+        assert_eq!(
+            sorted(trie.matches("orders.eu.de.created")),
+            ["#", "orders.#", "orders.#.created"]
+        );
+        assert_eq!(sorted(trie.matches("invoices.eu")), ["#"]);
+    }
+
+    #[test]
+    fn removed_patterns_stop_matching() {
+        let mut trie = trie(&["orders.*", "orders.eu"]);
+        trie.remove("orders.*");
+
+        assert_eq!(sorted(trie.matches("orders.eu")), ["orders.eu"]);
+        assert!(trie.matches("orders.us").is_empty());
+
+        trie.remove("orders.eu");
+        trie.remove("never.inserted");
+        assert!(trie.root.children.is_empty());
+    }
+}
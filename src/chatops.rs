@@ -0,0 +1,410 @@
+use chrono::{DateTime, Utc};
+use lapin::{
+    options::{BasicAckOptions, BasicConsumeOptions, BasicQosOptions, QueueDeclareOptions},
+    types::FieldTable,
+    Channel as RabbitChannel, Error as LapinError,
+};
+use reqwest::{header::RETRY_AFTER, StatusCode as HttpStatus};
+use serde_json::{json, Value};
+use tokio::time::{sleep, Duration};
+
+use crate::{
+    channel_acl::{patterns_cover, validate_channel_pattern},
+    config::{ChatOpsFormat, ChatOpsMapping, CONFIG},
+    message_queue_helpers::decode_delivery,
+    outbound::Priority,
+    prom_helpers::CHATOPS_MESSAGES_COUNTER,
+    requests::RabbitMessage,
+    shared::{bind_channel, connect_rabbitmq, HTTP_CLIENT},
+    shutdown::SHUTDOWN,
+};
+
+// Slack rejects header blocks over 150 characters and section text over 3000
+const MAX_TITLE_CHARS: usize = 150;
+const MAX_TEXT_CHARS: usize = 3000;
+
+// Backoff after a failure without a Retry-After, doubled per attempt up to a minute
+const INITIAL_BACKOFF_SECS: u64 = 1;
+const MAX_BACKOFF_SECS: u64 = 60;
+
+enum ChatOpsError {
+    // The target asked us to wait, with 429 or 503 and a Retry-After
+    RateLimited(Duration),
+    // Any other 4xx; sending the same body again won't help
+    Rejected(String),
+    Failed(String),
+}
+
+fn truncate(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        return text.to_string();
+    }
+    let mut truncated = text.chars().take(max_chars - 1).collect::<String>();
+    // Never leave half of an escaped character behind
+    if let Some(amp) = truncated.rfind('&') {
+        if !truncated[amp..].contains(';') {
+            truncated.truncate(amp);
+        }
+    }
+    truncated.push('…');
+    truncated
+}
+
+// Slack treats &, < and > as control characters in text, e.g. <!channel> pings everyone, so
+// they must be sent as entities
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn color(priority: Priority) -> &'static str {
+    match priority {
+        Priority::Bulk => "#9e9e9e",
+        Priority::Normal => "#439fe0",
+        Priority::Critical => "#d00000",
+    }
+}
+
+// JSON messages are shown pretty-printed in a code block, anything else as is
+fn message_text(message: &str) -> String {
+    match serde_json::from_str::<Value>(message) {
+        Ok(value @ (Value::Object(_) | Value::Array(_))) => format!(
+            "```{}```",
+            escape(&serde_json::to_string_pretty(&value).unwrap_or_default())
+        ),
+        _ => escape(message),
+    }
+}
+
+// Slack incoming-webhook JSON for a message; Mattermost understands the attachments format
+pub fn format_message(mapping: &ChatOpsMapping, message: &RabbitMessage) -> Value {
+    let title = truncate(
+        &escape(&match &message.event_type {
+            Some(event_type) => format!("{} on {}", event_type, message.channel_id),
+            None => message.channel_id.clone(),
+        }),
+        MAX_TITLE_CHARS,
+    );
+    let text = truncate(&message_text(&message.message), MAX_TEXT_CHARS);
+    let fallback = format!("{}: {}", title, escape(&message.message));
+    let footer = escape(&format!(
+        "{} priority · message {}",
+        message.priority.as_str(),
+        message.message_id
+    ));
+
+    let mut body = match mapping.format {
+        ChatOpsFormat::Blocks => json!({
+            "text": fallback,
+            "blocks": [
+                {"type": "header", "text": {"type": "plain_text", "text": title}},
+                {"type": "section", "text": {"type": "mrkdwn", "text": text}},
+                {"type": "context", "elements": [{"type": "mrkdwn", "text": footer}]},
+            ],
+        }),
+        ChatOpsFormat::Attachments => json!({
+            "attachments": [{
+                "fallback": fallback,
+                "color": color(message.priority),
+                "title": title,
+                "text": text,
+                "footer": footer,
+                "ts": Utc::now().timestamp(),
+            }],
+        }),
+    };
+
+    if let Some(username) = &mapping.username {
+        body["username"] = json!(username);
+    }
+    if let Some(icon_emoji) = &mapping.icon_emoji {
+        body["icon_emoji"] = json!(icon_emoji);
+    }
+    body
+}
+
+// Retry-After is either a number of seconds or an HTTP date
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    if let Ok(secs) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = DateTime::parse_from_rfc2822(value.trim()).ok()?;
+    let wait = at.with_timezone(&Utc) - Utc::now();
+    Some(wait.to_std().unwrap_or(Duration::ZERO))
+}
+
+async fn post_message(mapping: &ChatOpsMapping, body: &Value) -> Result<(), ChatOpsError> {
+    let response = HTTP_CLIENT
+        .post(&mapping.webhook_url)
+        .json(body)
+        .send()
+        .await
+        .map_err(|e| ChatOpsError::Failed(format!("Request failed: {}", e)))?;
+
+    let status = response.status();
+    if status.is_success() {
+        return Ok(());
+    }
+
+    let retry_after = response
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_retry_after);
+    if let (HttpStatus::TOO_MANY_REQUESTS | HttpStatus::SERVICE_UNAVAILABLE, Some(wait)) =
+        (status, retry_after)
+    {
+        return Err(ChatOpsError::RateLimited(wait));
+    }
+
+    let reason = response.text().await.unwrap_or_default();
+    let error = format!(
+        "Target responded with {}: {}",
+        status,
+        truncate(&reason, 200)
+    );
+    if status.is_client_error() && status != HttpStatus::TOO_MANY_REQUESTS {
+        Err(ChatOpsError::Rejected(error))
+    } else {
+        Err(ChatOpsError::Failed(error))
+    }
+}
+
+// Messages are posted one at a time, so waiting out a Retry-After holds back everything queued
+// behind it rather than hammering the target
+async fn deliver(mapping: &ChatOpsMapping, message: &RabbitMessage) {
+    let body = format_message(mapping, message);
+    let max_attempts = CONFIG.chatops.max_attempts.max(1);
+
+    for attempt in 1..=max_attempts {
+        // Retries and Retry-After waits can outlast the message
+        if message
+            .expires_at
+            .is_some_and(|expires_at| expires_at <= Utc::now())
+        {
+            println!(
+                "Dropping message {} for chat, it expired before it was posted",
+                message.message_id
+            );
+            CHATOPS_MESSAGES_COUNTER
+                .with_label_values(&["expired"])
+                .inc();
+            return;
+        }
+
+        let wait = match post_message(mapping, &body).await {
+            Ok(()) => {
+                println!(
+                    "Posted message {} from {} to chat",
+                    message.message_id, message.channel_id
+                );
+                CHATOPS_MESSAGES_COUNTER.with_label_values(&["sent"]).inc();
+                return;
+            }
+            Err(ChatOpsError::RateLimited(wait)) => {
+                CHATOPS_MESSAGES_COUNTER
+                    .with_label_values(&["rate_limited"])
+                    .inc();
+                let wait = wait.min(Duration::from_secs(CONFIG.chatops.max_retry_after_secs));
+                println!(
+                    "Chat target for {} is rate limited, retrying in {:?}",
+                    mapping.channel, wait
+                );
+                wait
+            }
+            Err(ChatOpsError::Rejected(error)) => {
+                eprintln!(
+                    "Chat target rejected message {}, not retrying: {}",
+                    message.message_id, error
+                );
+                CHATOPS_MESSAGES_COUNTER
+                    .with_label_values(&["failed"])
+                    .inc();
+                return;
+            }
+            Err(ChatOpsError::Failed(error)) => {
+                let backoff = INITIAL_BACKOFF_SECS
+                    .saturating_mul(1u64 << (attempt - 1).min(31))
+                    .min(MAX_BACKOFF_SECS);
+                eprintln!(
+                    "Failed to post message {} to chat (attempt {}): {}",
+                    message.message_id, attempt, error
+                );
+                Duration::from_secs(backoff)
+            }
+        };
+
+        if attempt < max_attempts {
+            sleep(wait).await;
+        }
+    }
+
+    eprintln!(
+        "Giving up on posting message {} to chat after {} attempt(s)",
+        message.message_id, max_attempts
+    );
+    CHATOPS_MESSAGES_COUNTER
+        .with_label_values(&["failed"])
+        .inc();
+}
+
+// Whether the mapping mirrors the message. Group and user messages are addressed to people,
+// so they are never mirrored, even by a `#` mapping.
+fn mirrors(mapping: &ChatOpsMapping, message: &RabbitMessage) -> bool {
+    if validate_channel_pattern(&message.channel_id).is_err() {
+        return false;
+    }
+
+    let type_matches = mapping.event_types.is_empty()
+        || message
+            .event_type
+            .as_ref()
+            .is_some_and(|event_type| mapping.event_types.contains(event_type));
+    type_matches && patterns_cover(std::slice::from_ref(&mapping.channel), &message.channel_id)
+}
+
+// Mirrors the configured channels from a queue shared by every replica, so each message is
+// posted once
+pub async fn consume_chatops() {
+    for mapping in &CONFIG.chatops.mappings {
+        if let Err(e) = validate_channel_pattern(&mapping.channel) {
+            eprintln!("Ignoring chat-ops mapping: {}", e);
+        }
+    }
+
+    loop {
+        match connect_rabbitmq().await {
+            Ok(rabbit_channel) => {
+                if let Err(e) = process_chatops_messages(rabbit_channel).await {
+                    eprintln!("Error mirroring messages to chat: {:?}", e);
+                }
+            }
+            Err(e) => {
+                eprintln!("Error connecting to RabbitMQ: {:?}", e);
+            }
+        }
+
+        if SHUTDOWN.is_draining() {
+            return;
+        }
+
+        eprintln!("Reconnecting chat-ops connector to RabbitMQ in 5 seconds...");
+        tokio::select! {
+            _ = sleep(Duration::from_secs(5)) => {}
+            _ = SHUTDOWN.started() => return,
+        }
+    }
+}
+
+async fn process_chatops_messages(rabbit_channel: RabbitChannel) -> Result<(), LapinError> {
+    rabbit_channel
+        .queue_declare(
+            &CONFIG.chatops.queue,
+            QueueDeclareOptions {
+                durable: true,
+                ..Default::default()
+            },
+            FieldTable::default(),
+        )
+        .await?;
+
+    for mapping in &CONFIG.chatops.mappings {
+        if validate_channel_pattern(&mapping.channel).is_ok() {
+            bind_channel(&rabbit_channel, &CONFIG.chatops.queue, &mapping.channel).await?;
+        }
+    }
+
+    rabbit_channel
+        .basic_qos(1, BasicQosOptions::default())
+        .await?;
+
+    let mut consumer = rabbit_channel
+        .basic_consume(
+            &CONFIG.chatops.queue,
+            "chatops_connector",
+            BasicConsumeOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    while let Some(delivery) = SHUTDOWN.next_delivery(&mut consumer).await {
+        let delivery = delivery?;
+
+        if let Some(rabbit_message) = decode_delivery(&delivery.data) {
+            for mapping in &CONFIG.chatops.mappings {
+                if mirrors(mapping, &rabbit_message) {
+                    deliver(mapping, &rabbit_message).await;
+                }
+            }
+        }
+
+        delivery.ack(BasicAckOptions::default()).await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping(format: ChatOpsFormat) -> ChatOpsMapping {
+        ChatOpsMapping {
+            channel: "alerts.*".to_string(),
+            webhook_url: "https://hooks.slack.com/services/T000/B000/XXXX".to_string(),
+            format,
+            username: None,
+            icon_emoji: None,
+            event_types: vec![],
+        }
+    }
+
+    fn message(text: &str) -> RabbitMessage {
+        serde_json::from_value(json!({
+            "channel_id": "alerts.db",
+            "message": text,
+            "message_id": "m1",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn parses_retry_after_seconds_and_dates() {
+        assert_eq!(parse_retry_after("30"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_retry_after(" 0 "), Some(Duration::ZERO));
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::ZERO)
+        );
+
+        let later = (Utc::now() + chrono::Duration::seconds(120)).to_rfc2822();
+        let wait = parse_retry_after(&later).unwrap();
+        assert!(wait > Duration::from_secs(110) && wait <= Duration::from_secs(120));
+
+        assert_eq!(parse_retry_after("soon"), None);
+        assert_eq!(parse_retry_after("-5"), None);
+    }
+
+    #[test]
+    fn escapes_control_characters() {
+        let body = format_message(
+            &mapping(ChatOpsFormat::Blocks),
+            &message("<!channel> db & cache > 90%"),
+        );
+
+        let text = "&lt;!channel&gt; db &amp; cache &gt; 90%";
+        assert_eq!(body["blocks"][1]["text"]["text"], text);
+        assert_eq!(body["text"], format!("alerts.db: {}", text));
+
+        let body = format_message(&mapping(ChatOpsFormat::Attachments), &message("<@U1>"));
+        assert_eq!(body["attachments"][0]["text"], "&lt;@U1&gt;");
+    }
+
+    #[test]
+    fn truncates_without_splitting_entities() {
+        assert_eq!(truncate("abc", 3), "abc");
+        assert_eq!(truncate("abcdef", 4), "abc…");
+        assert_eq!(truncate(&escape("ab<cd"), 5), "ab…");
+    }
+}
@@ -0,0 +1,631 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+
+// Service configuration, read from the TOML file pointed to by NOTIFICATION_CONFIG
+// (defaults to ./notification.toml). Every section is optional.
+#[derive(Debug, Deserialize, Default)]
+#[serde(default)]
+pub struct NotificationConfig {
+    pub email: EmailConfig,
+    pub senders: Vec<SenderIdentity>,
+    pub ses_events: SesEventsConfig,
+    pub suppressions: SuppressionsConfig,
+    pub email_limits: EmailLimitsConfig,
+    pub digest: DigestConfig,
+    pub groups: GroupsConfig,
+    pub publish: PublishConfig,
+    pub idempotency: IdempotencyConfig,
+    pub schedule: ScheduleConfig,
+    pub delivery: DeliveryConfig,
+    pub subscribe: SubscribeConfig,
+    pub presence: PresenceConfig,
+    pub receipts: ReceiptsConfig,
+    pub preferences: PreferencesConfig,
+    pub notify: NotifyConfig,
+    pub inbox: InboxConfig,
+    pub templates: HashMap<String, NotificationTemplate>,
+    pub web_push: WebPushConfig,
+    pub webhooks: WebhooksConfig,
+    pub chatops: ChatOpsConfig,
+    pub sms: SmsConfig,
+    pub admin: AdminConfig,
+    pub shutdown: ShutdownConfig,
+    pub sync: SyncConfig,
+}
+
+// Retries of queued emails, plus the fanout exchange replicating their status and how long
+// it is kept
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct EmailConfig {
+    pub max_attempts: u32,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    pub exchange: String,
+    pub retention_days: u32,
+}
+
+impl Default for EmailConfig {
+    fn default() -> Self {
+        EmailConfig {
+            max_attempts: 5,
+            initial_backoff_ms: 2_000,
+            max_backoff_ms: 300_000,
+            exchange: "notification-emails".to_string(),
+            retention_days: 30,
+        }
+    }
+}
+
+// From-addresses an ISC service (matched on the `sub` of its claims) may send email as.
+// The first address is used when a request doesn't pick one.
+#[derive(Debug, Deserialize, Clone)]
+pub struct SenderIdentity {
+    pub service: String,
+    pub addresses: Vec<SenderAddress>,
+    pub default_reply_to: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct SenderAddress {
+    pub address: String,
+    pub display_name: Option<String>,
+    pub reply_to: Option<String>,
+}
+
+// Shared secret SNS must pass as `?token=` when posting SES events. Events are refused with 503
+// until it is set.
+#[derive(Debug, Deserialize, Default)]
+#[serde(default)]
+pub struct SesEventsConfig {
+    pub token: Option<String>,
+}
+
+// Fanout exchange replicating the suppression list, and how long lifted suppressions are kept
+// so a replica that missed the removal doesn't bring them back
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct SuppressionsConfig {
+    pub exchange: String,
+    pub tombstone_days: u32,
+}
+
+impl Default for SuppressionsConfig {
+    fn default() -> Self {
+        SuppressionsConfig {
+            exchange: "notification-suppressions".to_string(),
+            tombstone_days: 30,
+        }
+    }
+}
+
+// Limits applied to send-email. `per_service` / `per_domain` apply to every caller / recipient
+// domain without an entry in `services` / `domains`. Anything left unset is unlimited.
+#[derive(Debug, Deserialize, Default)]
+#[serde(default)]
+pub struct EmailLimitsConfig {
+    pub per_service: RateLimit,
+    pub services: HashMap<String, RateLimit>,
+    pub per_domain: RateLimit,
+    pub domains: HashMap<String, RateLimit>,
+}
+
+impl EmailLimitsConfig {
+    pub fn for_service(&self, service: &str) -> &RateLimit {
+        self.services.get(service).unwrap_or(&self.per_service)
+    }
+
+    pub fn for_domain(&self, domain: &str) -> &RateLimit {
+        self.domains.get(domain).unwrap_or(&self.per_domain)
+    }
+}
+
+// Token bucket refilled at `per_minute` holding at most `burst` (defaults to `per_minute`)
+// tokens, plus an optional cap per UTC day
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct RateLimit {
+    pub per_minute: Option<f64>,
+    pub burst: Option<f64>,
+    pub daily_quota: Option<u64>,
+}
+
+// `{count}` is replaced in the subject; `{subject}`, `{message}` and `{time}` in each item.
+// Pending digests are replicated over `exchange`.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct DigestConfig {
+    pub daily_hour_utc: u32,
+    pub subject_template: String,
+    pub item_template: String,
+    pub exchange: String,
+}
+
+impl Default for DigestConfig {
+    fn default() -> Self {
+        DigestConfig {
+            daily_hour_utc: 8,
+            subject_template: "You have {count} new notifications".to_string(),
+            item_template: "{subject} ({time})\n{message}\n".to_string(),
+            exchange: "notification-digests".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct GroupsConfig {
+    pub membership_ttl_secs: u64,
+    pub iam_events_exchange: String,
+}
+
+impl Default for GroupsConfig {
+    fn default() -> Self {
+        GroupsConfig {
+            membership_ttl_secs: 300,
+            iam_events_exchange: "iam-events".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct PublishConfig {
+    pub max_batch_items: usize,
+    // Channels across every item of a batch, and how many of them are published at once
+    pub max_batch_channels: usize,
+    pub batch_concurrency: usize,
+    pub max_message_bytes: usize,
+    // API client (`sub` of its token) -> channels it may publish to, as `*` / `#` patterns like
+    // subscriptions. When empty, every client may publish to every channel.
+    pub acl: HashMap<String, Vec<String>>,
+}
+
+impl Default for PublishConfig {
+    fn default() -> Self {
+        PublishConfig {
+            max_batch_items: 500,
+            max_batch_channels: 1000,
+            batch_concurrency: 50,
+            max_message_bytes: 64 * 1024,
+            acl: HashMap::new(),
+        }
+    }
+}
+
+// How long responses are remembered for replay to requests with the same Idempotency-Key, and
+// how long a key stays reserved for a request that never finished. Keys are replicated over
+// `exchange`.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct IdempotencyConfig {
+    pub window_secs: u64,
+    pub reservation_secs: u64,
+    pub exchange: String,
+}
+
+impl Default for IdempotencyConfig {
+    fn default() -> Self {
+        IdempotencyConfig {
+            window_secs: 24 * 60 * 60,
+            reservation_secs: 60,
+            exchange: "notification-idempotency".to_string(),
+        }
+    }
+}
+
+// How often due scheduled notifications are published, how far ahead they may be scheduled
+// and where changes to them are replicated
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct ScheduleConfig {
+    pub poll_interval_secs: u64,
+    pub max_horizon_days: u32,
+    pub exchange: String,
+}
+
+impl Default for ScheduleConfig {
+    fn default() -> Self {
+        ScheduleConfig {
+            poll_interval_secs: 1,
+            max_horizon_days: 90,
+            exchange: "notification-schedules".to_string(),
+        }
+    }
+}
+
+// Messages buffered per WebSocket before lower priority ones are dropped for a slow client
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct DeliveryConfig {
+    pub outbound_queue_capacity: usize,
+}
+
+impl Default for DeliveryConfig {
+    fn default() -> Self {
+        DeliveryConfig {
+            outbound_queue_capacity: 256,
+        }
+    }
+}
+
+// Channels and patterns sockets may subscribe to, using `*` for one segment and `#` for any
+// number. A subscription pattern must be covered by an allowed one. `users` applies to every
+// user token, with `{user_id}` replaced by the caller's id; `acl` maps API clients (`sub`)
+// to their patterns. Either left empty allows those callers any concrete channel, but no pattern.
+#[derive(Debug, Deserialize, Default)]
+#[serde(default)]
+pub struct SubscribeConfig {
+    pub users: Vec<String>,
+    pub acl: HashMap<String, Vec<String>>,
+}
+
+// Replicas share who is connected over `exchange`, sending a full snapshot every
+// `heartbeat_secs`. Channels matching `announce` (same syntax as subscriptions) push
+// join / leave events to their subscribers.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct PresenceConfig {
+    pub exchange: String,
+    pub heartbeat_secs: u64,
+    pub announce: Vec<String>,
+}
+
+impl Default for PresenceConfig {
+    fn default() -> Self {
+        PresenceConfig {
+            exchange: "notification-presence".to_string(),
+            heartbeat_secs: 15,
+            announce: vec![],
+        }
+    }
+}
+
+// Fanout exchange receipts are published to, and how long they are kept. Clients may only
+// send receipts for the last `tracked_per_connection` messages written to their socket.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct ReceiptsConfig {
+    pub exchange: String,
+    pub retention_days: u32,
+    pub tracked_per_connection: usize,
+}
+
+impl Default for ReceiptsConfig {
+    fn default() -> Self {
+        ReceiptsConfig {
+            exchange: "notification-receipts".to_string(),
+            retention_days: 30,
+            tracked_per_connection: 1000,
+        }
+    }
+}
+
+// Fanout exchange replicating preference changes to every replica, and how emails routed by
+// preference are sent. Routed emails are only sent when `email_service` names a [[senders]] entry.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct PreferencesConfig {
+    pub exchange: String,
+    pub routing_queue: String,
+    pub email_service: Option<String>,
+    pub email_subject: String, // {type} is replaced with the event type
+}
+
+impl Default for PreferencesConfig {
+    fn default() -> Self {
+        PreferencesConfig {
+            exchange: "notification-preferences".to_string(),
+            routing_queue: "notification-preference-routing".to_string(),
+            email_service: None,
+            email_subject: "New {type} notification".to_string(),
+        }
+    }
+}
+
+// Users who don't come online within `email_fallback_secs` of a notify are emailed instead
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct NotifyConfig {
+    pub email_fallback_secs: u64,
+    pub fallback_poll_interval_secs: u64,
+}
+
+impl Default for NotifyConfig {
+    fn default() -> Self {
+        NotifyConfig {
+            email_fallback_secs: 300,
+            fallback_poll_interval_secs: 5,
+        }
+    }
+}
+
+// Fanout exchange replicating inbox changes, and how much of each inbox is kept
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct InboxConfig {
+    pub exchange: String,
+    pub retention_days: u32,
+    pub max_items: usize,
+}
+
+impl Default for InboxConfig {
+    fn default() -> Self {
+        InboxConfig {
+            exchange: "notification-inbox".to_string(),
+            retention_days: 30,
+            max_items: 100,
+        }
+    }
+}
+
+// Referenced by name from notify. `{field}` is replaced with the payload's top-level fields.
+#[derive(Debug, Deserialize, Clone)]
+pub struct NotificationTemplate {
+    pub subject: String,
+    pub message: String,
+}
+
+// VAPID keys identifying this service to push services. `vapid_private_key_path` points to a
+// PKCS#8 PEM P-256 key and `vapid_public_key` is its uncompressed point, base64url encoded, as
+// handed to browsers. Pushes are off until both are set. Subscription endpoints must be https
+// urls on one of `allowed_hosts`, where `*.` allows any subdomain. `push_service_url` replaces
+// the origin of every subscription endpoint instead, e.g. to point at a local stand-in push service.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct WebPushConfig {
+    pub vapid_private_key_path: Option<String>,
+    pub vapid_public_key: Option<String>,
+    pub subject: String,
+    pub ttl_secs: u64,
+    pub exchange: String,
+    pub max_subscriptions_per_user: usize,
+    pub allowed_hosts: Vec<String>,
+    pub push_service_url: Option<String>,
+}
+
+impl Default for WebPushConfig {
+    fn default() -> Self {
+        WebPushConfig {
+            vapid_private_key_path: None,
+            vapid_public_key: None,
+            subject: "mailto:notifications@example.com".to_string(),
+            ttl_secs: 24 * 60 * 60,
+            exchange: "notification-push-subscriptions".to_string(),
+            max_subscriptions_per_user: 10,
+            // Chrome, Firefox, Safari and Edge
+            allowed_hosts: vec![
+                "fcm.googleapis.com".to_string(),
+                "*.push.services.mozilla.com".to_string(),
+                "*.push.apple.com".to_string(),
+                "*.notify.windows.com".to_string(),
+            ],
+            push_service_url: None,
+        }
+    }
+}
+
+// Webhooks and their delivery log are replicated over `exchange`. Failed deliveries are retried
+// with exponential backoff and dead-lettered after `max_attempts`. Up to `concurrency` deliveries
+// are in flight per replica. Plain `http` urls, and ones resolving to loopback or private
+// addresses, are only accepted with `allow_http` and `allow_internal`, e.g. for a local stand-in.
+// Redirects are never followed.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct WebhooksConfig {
+    pub exchange: String,
+    pub max_attempts: u32,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    pub concurrency: u16,
+    pub max_per_client: usize,
+    pub log_retention_days: u32,
+    pub allow_http: bool,
+    pub allow_internal: bool,
+}
+
+impl Default for WebhooksConfig {
+    fn default() -> Self {
+        WebhooksConfig {
+            exchange: "notification-webhooks".to_string(),
+            max_attempts: 8,
+            initial_backoff_ms: 5_000,
+            max_backoff_ms: 60 * 60 * 1000,
+            concurrency: 10,
+            max_per_client: 50,
+            log_retention_days: 7,
+            allow_http: false,
+            allow_internal: false,
+        }
+    }
+}
+
+// Channels mirrored into chat rooms through Slack / Mattermost compatible incoming webhooks.
+// `queue` is shared by every replica, so each message is posted once. Retry-After waits are
+// capped at `max_retry_after_secs`.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct ChatOpsConfig {
+    pub queue: String,
+    pub max_attempts: u32,
+    pub max_retry_after_secs: u64,
+    pub mappings: Vec<ChatOpsMapping>,
+}
+
+impl Default for ChatOpsConfig {
+    fn default() -> Self {
+        ChatOpsConfig {
+            queue: "notification-chatops".to_string(),
+            max_attempts: 5,
+            max_retry_after_secs: 300,
+            mappings: vec![],
+        }
+    }
+}
+
+// `channel` may be a pattern, with the same syntax as subscriptions. `event_types` limits the
+// mapping to typed messages of those types.
+#[derive(Debug, Deserialize, Clone)]
+pub struct ChatOpsMapping {
+    pub channel: String,
+    pub webhook_url: String,
+    #[serde(default)]
+    pub format: ChatOpsFormat,
+    pub username: Option<String>,
+    pub icon_emoji: Option<String>,
+    #[serde(default)]
+    pub event_types: Vec<String>,
+}
+
+// Slack renders both; Mattermost only renders attachments
+#[derive(Debug, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatOpsFormat {
+    #[default]
+    Blocks,
+    Attachments,
+}
+
+// How send-sms delivers messages, and its limits. Without a transport send-sms answers 503. The
+// `log` transport appends messages to `log_path` instead of sending them; messages carry codes,
+// so they are never printed.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct SmsConfig {
+    pub transport: Option<SmsTransportKind>,
+    pub log_path: Option<String>,
+    pub http: SmsHttpConfig,
+    pub sns: SmsSnsConfig,
+    pub max_message_chars: usize,
+    pub per_service: RateLimit,
+    pub per_recipient: RateLimit,
+}
+
+impl Default for SmsConfig {
+    fn default() -> Self {
+        SmsConfig {
+            transport: None,
+            log_path: None,
+            http: SmsHttpConfig::default(),
+            sns: SmsSnsConfig::default(),
+            max_message_chars: 1600,
+            per_service: RateLimit::default(),
+            per_recipient: RateLimit {
+                per_minute: Some(5.0),
+                burst: Some(5.0),
+                daily_quota: Some(50),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum SmsTransportKind {
+    Log,
+    Http,
+    Sns,
+}
+
+// A generic gateway taking `{"to", "from", "message"}` as JSON, with an optional bearer token
+#[derive(Debug, Deserialize, Default)]
+#[serde(default)]
+pub struct SmsHttpConfig {
+    pub url: Option<String>,
+    pub auth_token: Option<String>,
+    pub from: Option<String>,
+}
+
+// `sms_type` is Transactional or Promotional; `sender_id` is only honoured in some countries
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct SmsSnsConfig {
+    pub sms_type: String,
+    pub sender_id: Option<String>,
+}
+
+impl Default for SmsSnsConfig {
+    fn default() -> Self {
+        SmsSnsConfig {
+            sms_type: "Transactional".to_string(),
+            sender_id: None,
+        }
+    }
+}
+
+// Fanout exchange carrying admin actions on live sockets to every replica. Connections are
+// listed with the address in `X-Forwarded-For` only with `trust_forwarded_for`, set when a proxy
+// in front of the service overwrites the header, as clients can send anything in it.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct AdminConfig {
+    pub exchange: String,
+    pub trust_forwarded_for: bool,
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        AdminConfig {
+            exchange: "notification-admin".to_string(),
+            trust_forwarded_for: false,
+        }
+    }
+}
+
+// On SIGTERM sockets are asked to reconnect after a random delay of up to
+// `reconnect_jitter_ms`; the process exits after `deadline_secs` at the latest, which should be
+// under the pod's termination grace period
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct ShutdownConfig {
+    pub deadline_secs: u64,
+    pub reconnect_jitter_ms: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig {
+            deadline_secs: 25,
+            reconnect_jitter_ms: 5_000,
+        }
+    }
+}
+
+// Fanout exchange a starting replica asks the others for copies of the replicated stores on,
+// and how long it waits for their answers
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct SyncConfig {
+    pub exchange: String,
+    pub bootstrap_secs: u64,
+}
+
+impl Default for SyncConfig {
+    fn default() -> Self {
+        SyncConfig {
+            exchange: "notification-store-sync".to_string(),
+            bootstrap_secs: 3,
+        }
+    }
+}
+
+impl NotificationConfig {
+    pub fn load() -> Self {
+        let path = std::env::var("NOTIFICATION_CONFIG")
+            .unwrap_or_else(|_| "notification.toml".to_string());
+
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => toml::from_str(&contents).unwrap_or_else(|e| {
+                panic!("Invalid notification config {}: {}", path, e);
+            }),
+            Err(_) => {
+                println!("No config found at {}, using defaults", path);
+                NotificationConfig::default()
+            }
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    pub static ref CONFIG: NotificationConfig = NotificationConfig::load();
+}
@@ -0,0 +1,431 @@
+use chrono::{DateTime, Duration as ChronoDuration, Timelike, Utc};
+use futures::StreamExt;
+use lapin::{
+    options::{
+        BasicAckOptions, BasicConsumeOptions, BasicPublishOptions, ExchangeDeclareOptions,
+        QueueBindOptions, QueueDeclareOptions,
+    },
+    types::FieldTable,
+    BasicProperties, Channel as RabbitChannel, Error as LapinError,
+};
+use serde::{Deserialize, Serialize};
+use tokio::time::{sleep, Duration};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    config::CONFIG,
+    email_queue::{queue_email, EmailStore},
+    leader::run_on_one_replica,
+    requests::EmailRequest,
+    shared::{open_rabbitmq_channel, RABBIT_POOL},
+    store::Store,
+};
+
+// Ticks for flushing digests, handled by one replica at a time
+const DIGEST_FLUSH_QUEUE: &str = "digest-flush-ticks";
+
+#[derive(Deserialize, Serialize, ToSchema, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DigestFrequency {
+    Hourly,
+    Daily,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct DigestItem {
+    #[serde(default)]
+    pub id: String,
+    pub subject: String,
+    pub message: String,
+    pub created_at: DateTime<Utc>,
+}
+
+// Notifications accumulated for one recipient until `flush_at`
+#[derive(Deserialize, Serialize, Clone)]
+pub struct DigestBatch {
+    pub id: String,
+    pub service: String,
+    pub to: String,
+    pub from: Option<String>,
+    pub reply_to: Option<String>,
+    pub source: String,
+    pub frequency: DigestFrequency,
+    pub items: Vec<DigestItem>,
+    pub flush_at: DateTime<Utc>,
+}
+
+// Keyed by frequency, calling service, sender and recipient, so each digest has one sender
+pub type DigestStore = Store<DigestBatch>;
+
+// Of two copies of a batch, keep the one holding more items
+pub fn prefer_fuller_digest(local: &DigestBatch, other: &DigestBatch) -> bool {
+    local.items.len() < other.items.len()
+}
+
+// Pending digests are replicated over `digest.exchange`, so the replica flushing them has every
+// item whichever replica took it
+#[derive(Deserialize, Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+enum DigestEvent {
+    Added {
+        batch: Box<DigestBatch>,
+        item: DigestItem,
+    },
+    Flushed {
+        id: String,
+        item_ids: Vec<String>,
+    },
+}
+
+pub async fn declare_digest_exchange(rabbit_channel: &RabbitChannel) -> Result<(), LapinError> {
+    rabbit_channel
+        .exchange_declare(
+            &CONFIG.digest.exchange,
+            lapin::ExchangeKind::Fanout,
+            ExchangeDeclareOptions {
+                durable: true,
+                ..Default::default()
+            },
+            FieldTable::default(),
+        )
+        .await
+}
+
+async fn publish_event(event: &DigestEvent) -> Result<(), LapinError> {
+    let rabbit_channel = RABBIT_POOL.channel().await?;
+
+    rabbit_channel
+        .basic_publish(
+            &CONFIG.digest.exchange,
+            "",
+            BasicPublishOptions::default(),
+            &serde_json::to_vec(event).unwrap(),
+            BasicProperties::default(),
+        )
+        .await?;
+    Ok(())
+}
+
+// Applying an event twice is harmless, as the replica that published it applies it right away.
+// Only the flushed items leave a batch, so ones added while it was being sent go out next time.
+async fn apply_event(digests: &DigestStore, event: DigestEvent) -> Option<DigestBatch> {
+    match event {
+        DigestEvent::Added { batch, item } => {
+            let id = batch.id.clone();
+            Some(
+                digests
+                    .upsert(
+                        &id,
+                        || *batch,
+                        |batch| {
+                            if !batch.items.iter().any(|existing| existing.id == item.id) {
+                                batch.items.push(item);
+                            }
+                        },
+                    )
+                    .await,
+            )
+        }
+        DigestEvent::Flushed { id, item_ids } => {
+            let remaining = digests
+                .update(&id, |batch| {
+                    batch.items.retain(|item| !item_ids.contains(&item.id));
+                    batch.flush_at = next_flush(&batch.frequency, Utc::now());
+                })
+                .await;
+            if remaining.is_some_and(|batch| batch.items.is_empty()) {
+                digests.remove(&id).await;
+            }
+            None
+        }
+    }
+}
+
+async fn replicate(digests: &DigestStore, event: DigestEvent) -> Option<DigestBatch> {
+    if let Err(e) = publish_event(&event).await {
+        eprintln!("Failed to replicate digest change: {:?}", e);
+    }
+    apply_event(digests, event).await
+}
+
+pub async fn consume_digest_events(digests: DigestStore) {
+    loop {
+        match open_rabbitmq_channel().await {
+            Ok(rabbit_channel) => {
+                if let Err(e) = process_digest_events(rabbit_channel, &digests).await {
+                    eprintln!("Error processing digest events: {:?}", e);
+                }
+            }
+            Err(e) => {
+                eprintln!("Error connecting to RabbitMQ: {:?}", e);
+            }
+        }
+
+        eprintln!("Reconnecting to digest events in 5 seconds...");
+        sleep(Duration::from_secs(5)).await;
+    }
+}
+
+async fn process_digest_events(
+    rabbit_channel: RabbitChannel,
+    digests: &DigestStore,
+) -> Result<(), LapinError> {
+    declare_digest_exchange(&rabbit_channel).await?;
+
+    let queue = rabbit_channel
+        .queue_declare(
+            "",
+            QueueDeclareOptions {
+                exclusive: true,
+                auto_delete: true,
+                ..Default::default()
+            },
+            FieldTable::default(),
+        )
+        .await?;
+
+    rabbit_channel
+        .queue_bind(
+            queue.name().as_str(),
+            &CONFIG.digest.exchange,
+            "",
+            QueueBindOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    let mut consumer = rabbit_channel
+        .basic_consume(
+            queue.name().as_str(),
+            "digest_consumer",
+            BasicConsumeOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    while let Some(delivery) = consumer.next().await {
+        let delivery = delivery?;
+
+        match serde_json::from_slice::<DigestEvent>(&delivery.data) {
+            Ok(event) => {
+                apply_event(digests, event).await;
+            }
+            Err(e) => println!("Failed to deserialize digest event: {:?}", e),
+        }
+
+        delivery.ack(BasicAckOptions::default()).await?;
+    }
+
+    Ok(())
+}
+
+fn next_flush(frequency: &DigestFrequency, now: DateTime<Utc>) -> DateTime<Utc> {
+    let start_of_hour = now
+        .with_minute(0)
+        .and_then(|t| t.with_second(0))
+        .and_then(|t| t.with_nanosecond(0))
+        .unwrap_or(now);
+
+    match frequency {
+        DigestFrequency::Hourly => start_of_hour + ChronoDuration::hours(1),
+        DigestFrequency::Daily => {
+            let today = start_of_hour
+                .with_hour(CONFIG.digest.daily_hour_utc.min(23))
+                .unwrap_or(start_of_hour);
+            if today > now {
+                today
+            } else {
+                today + ChronoDuration::days(1)
+            }
+        }
+    }
+}
+
+pub async fn add_to_digest(
+    digests: &DigestStore,
+    service: &str,
+    email_request: EmailRequest,
+    source: String,
+    frequency: DigestFrequency,
+) -> DigestBatch {
+    // Emails from different senders of the same service are digested separately, as a digest
+    // is sent as one of them
+    let key = format!(
+        "{:?}|{}|{}|{}|{}",
+        frequency,
+        service,
+        source,
+        email_request.reply_to.as_deref().unwrap_or_default(),
+        email_request.to.trim().to_lowercase()
+    );
+    let now = Utc::now();
+
+    let item = DigestItem {
+        id: Uuid::new_v4().to_string(),
+        subject: email_request.subject.clone(),
+        message: email_request.message.clone(),
+        created_at: now,
+    };
+    let batch = DigestBatch {
+        id: key.clone(),
+        service: service.to_string(),
+        to: email_request.to.clone(),
+        from: email_request.from.clone(),
+        reply_to: email_request.reply_to.clone(),
+        source,
+        frequency: frequency.clone(),
+        items: vec![],
+        flush_at: next_flush(&frequency, now),
+    };
+
+    let event = DigestEvent::Added {
+        batch: Box::new(batch),
+        item,
+    };
+    replicate(digests, event)
+        .await
+        .expect("adding an item returns its batch")
+}
+
+fn render_digest(batch: &DigestBatch) -> EmailRequest {
+    let subject = CONFIG
+        .digest
+        .subject_template
+        .replace("{count}", &batch.items.len().to_string());
+
+    let message = batch
+        .items
+        .iter()
+        .map(|item| {
+            CONFIG
+                .digest
+                .item_template
+                .replace("{subject}", &item.subject)
+                .replace("{message}", &item.message)
+                .replace(
+                    "{time}",
+                    &item.created_at.format("%Y-%m-%d %H:%M UTC").to_string(),
+                )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    EmailRequest {
+        message,
+        to: batch.to.clone(),
+        reply_to: batch.reply_to.clone(),
+        subject,
+        from: batch.from.clone(),
+        digest: None,
+    }
+}
+
+// Send every batch whose flush time has passed as a single email
+pub async fn flush_due_digests(digests: &DigestStore, emails: &EmailStore) {
+    let now = Utc::now();
+
+    for batch in digests.values().await {
+        if batch.flush_at > now || batch.items.is_empty() {
+            continue;
+        }
+
+        match queue_email(
+            emails,
+            &batch.service,
+            render_digest(&batch),
+            batch.source.clone(),
+        )
+        .await
+        {
+            Ok(email_id) => {
+                println!(
+                    "Flushed digest {} with {} item(s) as email {}",
+                    batch.id,
+                    batch.items.len(),
+                    email_id
+                );
+                let event = DigestEvent::Flushed {
+                    id: batch.id,
+                    item_ids: batch.items.into_iter().map(|item| item.id).collect(),
+                };
+                replicate(digests, event).await;
+            }
+            // The batch is left as it is and tried again on the next tick
+            Err(e) => eprintln!("Failed to queue digest {}, retrying: {:?}", batch.id, e),
+        }
+    }
+}
+
+// Every replica holds every digest, so only one of them flushes
+pub async fn run_digest_scheduler(digests: DigestStore, emails: EmailStore) {
+    run_on_one_replica(DIGEST_FLUSH_QUEUE, Duration::from_secs(60), || {
+        flush_due_digests(&digests, &emails)
+    })
+    .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn added(batch: &DigestBatch, subject: &str) -> (DigestEvent, String) {
+        let item = DigestItem {
+            id: Uuid::new_v4().to_string(),
+            subject: subject.to_string(),
+            message: "Hello".to_string(),
+            created_at: Utc::now(),
+        };
+        let id = item.id.clone();
+        let event = DigestEvent::Added {
+            batch: Box::new(batch.clone()),
+            item,
+        };
+        (event, id)
+    }
+
+    #[tokio::test]
+    async fn keeps_items_added_while_a_digest_was_sent() {
+        let file_name = format!("digests-test-{}.json", Uuid::new_v4());
+        let digests: DigestStore = crate::store::JsonStore::open(&file_name);
+
+        let batch = DigestBatch {
+            id: "daily".to_string(),
+            service: "iam-service".to_string(),
+            to: "someone@example.com".to_string(),
+            from: None,
+            reply_to: None,
+            source: "no-reply@gingersociety.org".to_string(),
+            frequency: DigestFrequency::Daily,
+            items: vec![],
+            flush_at: Utc::now(),
+        };
+
+        let (first, first_id) = added(&batch, "First");
+        let (second, second_id) = added(&batch, "Second");
+        let first_copy = serde_json::from_slice(&serde_json::to_vec(&first).unwrap()).unwrap();
+        apply_event(&digests, first).await;
+        // The replica's own event coming back over the exchange
+        apply_event(&digests, first_copy).await;
+        assert_eq!(digests.get("daily").await.unwrap().items.len(), 1);
+
+        apply_event(&digests, second).await;
+        let event = DigestEvent::Flushed {
+            id: "daily".to_string(),
+            item_ids: vec![first_id],
+        };
+        apply_event(&digests, event).await;
+        let pending = digests.get("daily").await.unwrap();
+        assert_eq!(pending.items.len(), 1);
+        assert_eq!(pending.items[0].id, second_id);
+
+        let event = DigestEvent::Flushed {
+            id: "daily".to_string(),
+            item_ids: vec![second_id],
+        };
+        apply_event(&digests, event).await;
+        assert!(digests.get("daily").await.is_none());
+
+        let _ = std::fs::remove_file(crate::store::data_path(&file_name));
+    }
+}
@@ -0,0 +1,554 @@
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use futures::StreamExt;
+use lapin::{
+    options::{
+        BasicAckOptions, BasicConsumeOptions, BasicPublishOptions, BasicQosOptions,
+        ExchangeDeclareOptions, QueueBindOptions, QueueDeclareOptions,
+    },
+    types::{AMQPValue, FieldTable},
+    BasicProperties, Channel as RabbitChannel, Error as LapinError,
+};
+use serde::{Deserialize, Serialize};
+use tokio::time::{interval, sleep, Duration};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    config::CONFIG,
+    mailer::deliver_email,
+    requests::{EmailJob, EmailRequest},
+    senders::DEFAULT_SENDER,
+    shared::{open_rabbitmq_channel, RABBIT_POOL},
+    shutdown::SHUTDOWN,
+    store::Store,
+    suppression::{find_suppression, SuppressionStore},
+};
+
+pub const EMAIL_QUEUE: &str = "email-queue";
+pub const EMAIL_RETRY_QUEUE: &str = "email-retry-queue";
+pub const EMAIL_DEAD_LETTER_QUEUE: &str = "email-dead-letter-queue";
+
+#[derive(Deserialize, Serialize, ToSchema, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum EmailStatus {
+    Queued,
+    Sent,
+    Failed,
+    Bounced,
+}
+
+#[derive(Deserialize, Serialize, ToSchema, Clone)]
+pub struct EmailRecord {
+    pub id: String,
+    // The `sub` of the service that queued it, the only one allowed to look it up
+    #[serde(default)]
+    pub service: String,
+    pub to: String,
+    #[serde(default)]
+    pub from: String,
+    pub subject: String,
+    pub status: EmailStatus,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub ses_message_id: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+// Keyed by email id
+pub type EmailStore = Store<EmailRecord>;
+// Email ids keyed by the SES message id they were sent with, so bounces find their email
+pub type SesMessageStore = Store<String>;
+
+pub fn prefer_newer_email(local: &EmailRecord, other: &EmailRecord) -> bool {
+    local.updated_at < other.updated_at
+}
+
+// Email records are replicated over `email.exchange`, so any replica can answer for any email
+// whichever replica's worker sent it
+#[derive(Deserialize, Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+enum EmailEvent {
+    Updated { record: EmailRecord },
+    Removed { id: String },
+}
+
+// Declared on the pooled channel, so handlers can queue emails on it right away
+pub async fn declare_email_queues(channel: &RabbitChannel) -> Result<(), LapinError> {
+    channel
+        .exchange_declare(
+            &CONFIG.email.exchange,
+            lapin::ExchangeKind::Fanout,
+            ExchangeDeclareOptions {
+                durable: true,
+                ..Default::default()
+            },
+            FieldTable::default(),
+        )
+        .await?;
+
+    let durable = QueueDeclareOptions {
+        durable: true,
+        ..Default::default()
+    };
+
+    channel
+        .queue_declare(EMAIL_QUEUE, durable, FieldTable::default())
+        .await?;
+
+    // Jobs waiting out their backoff sit in the retry queue until their expiration
+    // elapses, at which point RabbitMQ dead-letters them back onto the email queue
+    let mut retry_args = FieldTable::default();
+    retry_args.insert(
+        "x-dead-letter-exchange".into(),
+        AMQPValue::LongString("".into()),
+    );
+    retry_args.insert(
+        "x-dead-letter-routing-key".into(),
+        AMQPValue::LongString(EMAIL_QUEUE.into()),
+    );
+    channel
+        .queue_declare(EMAIL_RETRY_QUEUE, durable, retry_args)
+        .await?;
+
+    channel
+        .queue_declare(EMAIL_DEAD_LETTER_QUEUE, durable, FieldTable::default())
+        .await?;
+
+    Ok(())
+}
+
+async fn publish_event(event: &EmailEvent) -> Result<(), LapinError> {
+    let rabbit_channel = RABBIT_POOL.channel().await?;
+
+    rabbit_channel
+        .basic_publish(
+            &CONFIG.email.exchange,
+            "",
+            BasicPublishOptions::default(),
+            &serde_json::to_vec(event).unwrap(),
+            BasicProperties::default(),
+        )
+        .await?;
+    Ok(())
+}
+
+// Applying an event twice is harmless, as the replica that published it applies it right away.
+// Status updates may arrive out of order, so an older one never replaces a newer one.
+async fn apply_event(store: &EmailStore, event: EmailEvent) {
+    match event {
+        EmailEvent::Updated { record } => {
+            let id = record.id.clone();
+            let initial = record.clone();
+            store
+                .upsert(
+                    &id,
+                    || initial,
+                    |existing| {
+                        if existing.updated_at <= record.updated_at {
+                            *existing = record;
+                        }
+                    },
+                )
+                .await;
+        }
+        EmailEvent::Removed { id } => {
+            store.remove(&id).await;
+        }
+    }
+}
+
+async fn replicate(store: &EmailStore, event: EmailEvent) {
+    if let Err(e) = publish_event(&event).await {
+        eprintln!("Failed to replicate email status: {:?}", e);
+    }
+    apply_event(store, event).await;
+}
+
+// Change an email's record on this replica and replicate the result
+pub async fn update_email(store: &EmailStore, id: &str, change: impl FnOnce(&mut EmailRecord)) {
+    if let Some(record) = store.update(id, change).await {
+        replicate(store, EmailEvent::Updated { record }).await;
+    }
+}
+
+pub async fn enqueue_email(job: &EmailJob) -> Result<(), LapinError> {
+    let rabbit_channel = RABBIT_POOL.channel().await?;
+    publish_job(&rabbit_channel, EMAIL_QUEUE, job, None).await
+}
+
+// Record a new email as queued on behalf of `service` and hand it to the email worker,
+// returning its id
+pub async fn queue_email(
+    store: &EmailStore,
+    service: &str,
+    request: EmailRequest,
+    source: String,
+) -> Result<String, LapinError> {
+    let now = Utc::now();
+    let job = EmailJob {
+        id: Uuid::new_v4().to_string(),
+        request,
+        source,
+        attempts: 0,
+    };
+
+    let record = EmailRecord {
+        id: job.id.clone(),
+        service: service.to_string(),
+        to: job.request.to.clone(),
+        from: job.request.from.clone().unwrap_or_default(),
+        subject: job.request.subject.clone(),
+        status: EmailStatus::Queued,
+        attempts: 0,
+        last_error: None,
+        ses_message_id: None,
+        created_at: now,
+        updated_at: now,
+    };
+    replicate(store, EmailEvent::Updated { record }).await;
+
+    if let Err(e) = enqueue_email(&job).await {
+        replicate(store, EmailEvent::Removed { id: job.id.clone() }).await;
+        return Err(e);
+    }
+
+    Ok(job.id)
+}
+
+async fn publish_job(
+    rabbit_channel: &RabbitChannel,
+    queue: &str,
+    job: &EmailJob,
+    expiration_ms: Option<u64>,
+) -> Result<(), LapinError> {
+    let mut properties = BasicProperties::default()
+        .with_delivery_mode(2) // Persistent
+        .with_message_id(job.id.clone().into());
+
+    if let Some(expiration_ms) = expiration_ms {
+        properties = properties.with_expiration(expiration_ms.to_string().into());
+    }
+
+    rabbit_channel
+        .basic_publish(
+            "", // Default exchange, routed by queue name
+            queue,
+            BasicPublishOptions::default(),
+            &serde_json::to_vec(job).unwrap(),
+            properties,
+        )
+        .await?;
+
+    Ok(())
+}
+
+// Exponential backoff for the given (1-based) attempt, capped at `max_backoff_ms`
+fn retry_backoff_ms(attempt: u32) -> u64 {
+    let exponent = attempt.saturating_sub(1).min(31);
+    CONFIG
+        .email
+        .initial_backoff_ms
+        .saturating_mul(1u64 << exponent)
+        .min(CONFIG.email.max_backoff_ms)
+}
+
+pub async fn consume_email_events(store: EmailStore, ses_messages: SesMessageStore) {
+    loop {
+        match open_rabbitmq_channel().await {
+            Ok(rabbit_channel) => {
+                if let Err(e) = process_email_events(rabbit_channel, &store, &ses_messages).await {
+                    eprintln!("Error processing email events: {:?}", e);
+                }
+            }
+            Err(e) => {
+                eprintln!("Error connecting to RabbitMQ: {:?}", e);
+            }
+        }
+
+        eprintln!("Reconnecting to email events in 5 seconds...");
+        sleep(Duration::from_secs(5)).await;
+    }
+}
+
+async fn process_email_events(
+    rabbit_channel: RabbitChannel,
+    store: &EmailStore,
+    ses_messages: &SesMessageStore,
+) -> Result<(), LapinError> {
+    declare_email_queues(&rabbit_channel).await?;
+
+    let queue = rabbit_channel
+        .queue_declare(
+            "",
+            QueueDeclareOptions {
+                exclusive: true,
+                auto_delete: true,
+                ..Default::default()
+            },
+            FieldTable::default(),
+        )
+        .await?;
+
+    rabbit_channel
+        .queue_bind(
+            queue.name().as_str(),
+            &CONFIG.email.exchange,
+            "",
+            QueueBindOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    let mut consumer = rabbit_channel
+        .basic_consume(
+            queue.name().as_str(),
+            "email_event_consumer",
+            BasicConsumeOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    while let Some(delivery) = consumer.next().await {
+        let delivery = delivery?;
+
+        match serde_json::from_slice::<EmailEvent>(&delivery.data) {
+            Ok(event) => {
+                // Every replica, the one that sent the email included, indexes it here
+                if let EmailEvent::Updated { record } = &event {
+                    if let Some(ses_message_id) = &record.ses_message_id {
+                        ses_messages
+                            .insert(ses_message_id.clone(), record.id.clone())
+                            .await;
+                    }
+                }
+                apply_event(store, event).await;
+            }
+            Err(e) => println!("Failed to deserialize email event: {:?}", e),
+        }
+
+        delivery.ack(BasicAckOptions::default()).await?;
+    }
+
+    Ok(())
+}
+
+// Every replica prunes its own copy, so removals aren't replicated
+pub async fn prune_emails(store: EmailStore, ses_messages: SesMessageStore) {
+    let mut ticker = interval(Duration::from_secs(3600));
+
+    loop {
+        ticker.tick().await;
+        let cutoff = Utc::now() - ChronoDuration::days(CONFIG.email.retention_days as i64);
+        let removed = store.retain(|record| record.updated_at > cutoff).await;
+        if removed > 0 {
+            println!("Pruned {} email records", removed);
+        }
+
+        let remaining = store.entries().await;
+        ses_messages
+            .retain(|email_id| remaining.contains_key(email_id))
+            .await;
+    }
+}
+
+pub async fn consume_emails(store: EmailStore, suppressions: SuppressionStore) {
+    loop {
+        match open_rabbitmq_channel().await {
+            Ok(rabbit_channel) => {
+                if let Err(e) =
+                    process_email_jobs(rabbit_channel, store.clone(), suppressions.clone()).await
+                {
+                    eprintln!("Error processing email jobs: {:?}", e);
+                }
+            }
+            Err(e) => {
+                eprintln!("Error connecting to RabbitMQ: {:?}", e);
+            }
+        }
+
+        // Wait before retrying
+        if SHUTDOWN.is_draining() {
+            return;
+        }
+
+        eprintln!("Reconnecting email worker to RabbitMQ in 5 seconds...");
+        tokio::select! {
+            _ = sleep(Duration::from_secs(5)) => {}
+            _ = SHUTDOWN.started() => return,
+        }
+    }
+}
+
+async fn process_email_jobs(
+    rabbit_channel: RabbitChannel,
+    store: EmailStore,
+    suppressions: SuppressionStore,
+) -> Result<(), LapinError> {
+    declare_email_queues(&rabbit_channel).await?;
+
+    // Send one email at a time so a slow SES call doesn't pile up unacked deliveries
+    rabbit_channel
+        .basic_qos(1, BasicQosOptions::default())
+        .await?;
+
+    let mut consumer = rabbit_channel
+        .basic_consume(
+            EMAIL_QUEUE,
+            "email_worker",
+            BasicConsumeOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    while let Some(delivery) = SHUTDOWN.next_delivery(&mut consumer).await {
+        let delivery = delivery?;
+
+        match serde_json::from_slice::<EmailJob>(&delivery.data) {
+            Ok(job) => handle_email_job(&rabbit_channel, &store, &suppressions, job).await?,
+            Err(e) => println!("Failed to deserialize email job: {:?}", e),
+        }
+
+        delivery.ack(BasicAckOptions::default()).await?;
+    }
+
+    Ok(())
+}
+
+async fn handle_email_job(
+    rabbit_channel: &RabbitChannel,
+    store: &EmailStore,
+    suppressions: &SuppressionStore,
+    mut job: EmailJob,
+) -> Result<(), LapinError> {
+    if let Some(record) = store.get(&job.id).await {
+        if record.status != EmailStatus::Queued {
+            println!("Skipping email {} already {:?}", job.id, record.status);
+            return Ok(());
+        }
+    }
+
+    // The recipient may have bounced or complained while this job was waiting
+    if let Some(suppression) = find_suppression(suppressions, &job.request.to).await {
+        println!(
+            "Dropping email {} to suppressed {}",
+            job.id, suppression.email
+        );
+        update_email(store, &job.id, |record| {
+            record.status = EmailStatus::Failed;
+            record.last_error = Some(format!("Recipient suppressed ({:?})", suppression.reason));
+            record.updated_at = Utc::now();
+        })
+        .await;
+        return Ok(());
+    }
+
+    job.attempts += 1;
+    let attempts = job.attempts;
+
+    let source = match job.source.as_str() {
+        "" => DEFAULT_SENDER,
+        source => source,
+    };
+
+    match deliver_email(&job.request, source).await {
+        Ok(ses_message_id) => {
+            println!("Email {} sent after {} attempt(s)", job.id, attempts);
+            update_email(store, &job.id, |record| {
+                record.status = EmailStatus::Sent;
+                record.attempts = attempts;
+                record.last_error = None;
+                record.ses_message_id = Some(ses_message_id);
+                record.updated_at = Utc::now();
+            })
+            .await;
+        }
+        Err(error) => {
+            let status = if attempts >= CONFIG.email.max_attempts {
+                eprintln!("Email {} failed permanently: {}", job.id, error);
+                publish_job(rabbit_channel, EMAIL_DEAD_LETTER_QUEUE, &job, None).await?;
+                EmailStatus::Failed
+            } else {
+                let backoff_ms = retry_backoff_ms(attempts);
+                eprintln!(
+                    "Email {} failed (attempt {}), retrying in {}ms: {}",
+                    job.id, attempts, backoff_ms, error
+                );
+                publish_job(rabbit_channel, EMAIL_RETRY_QUEUE, &job, Some(backoff_ms)).await?;
+                EmailStatus::Queued
+            };
+
+            update_email(store, &job.id, |record| {
+                record.status = status;
+                record.attempts = attempts;
+                record.last_error = Some(error);
+                record.updated_at = Utc::now();
+            })
+            .await;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backs_off_exponentially_up_to_the_cap() {
+        let initial = CONFIG.email.initial_backoff_ms;
+        let cap = CONFIG.email.max_backoff_ms;
+
+        assert_eq!(retry_backoff_ms(0), initial.min(cap));
+        assert_eq!(retry_backoff_ms(1), initial.min(cap));
+        assert_eq!(retry_backoff_ms(2), (initial * 2).min(cap));
+        assert_eq!(retry_backoff_ms(3), (initial * 4).min(cap));
+        assert_eq!(retry_backoff_ms(u32::MAX), cap);
+    }
+
+    #[tokio::test]
+    async fn keeps_the_newer_status_whatever_order_updates_arrive_in() {
+        let file_name = format!("emails-test-{}.json", Uuid::new_v4());
+        let store: EmailStore = crate::store::JsonStore::open(&file_name);
+
+        let now = Utc::now();
+        let queued = EmailRecord {
+            id: "email-1".to_string(),
+            service: "iam-service".to_string(),
+            to: "someone@example.com".to_string(),
+            from: "no-reply@gingersociety.org".to_string(),
+            subject: "Hi".to_string(),
+            status: EmailStatus::Queued,
+            attempts: 0,
+            last_error: None,
+            ses_message_id: None,
+            created_at: now,
+            updated_at: now,
+        };
+        let sent = EmailRecord {
+            status: EmailStatus::Sent,
+            attempts: 1,
+            updated_at: now + ChronoDuration::seconds(1),
+            ..queued.clone()
+        };
+
+        apply_event(&store, EmailEvent::Updated { record: sent }).await;
+        apply_event(&store, EmailEvent::Updated { record: queued }).await;
+        assert_eq!(
+            store.get("email-1").await.map(|record| record.status),
+            Some(EmailStatus::Sent)
+        );
+
+        apply_event(
+            &store,
+            EmailEvent::Removed {
+                id: "email-1".to_string(),
+            },
+        )
+        .await;
+        assert!(store.get("email-1").await.is_none());
+
+        let _ = std::fs::remove_file(crate::store::data_path(&file_name));
+    }
+}
@@ -0,0 +1,197 @@
+use std::{collections::HashMap, convert::Infallible, sync::Arc};
+
+use futures::StreamExt;
+use lapin::{
+    options::{
+        BasicAckOptions, BasicConsumeOptions, ExchangeDeclareOptions, QueueBindOptions,
+        QueueDeclareOptions,
+    },
+    types::FieldTable,
+    Channel as RabbitChannel, Error as LapinError,
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokio::{
+    sync::Mutex,
+    time::{sleep, Duration, Instant},
+};
+use warp::Filter;
+use IAMService::{
+    apis::default_api::{identity_get_group_members_ids, IdentityGetGroupMembersIdsParams},
+    get_configuration,
+};
+
+use crate::{
+    config::CONFIG,
+    prom_helpers::{GROUP_CACHE_HITS, GROUP_CACHE_MISSES},
+    shared::open_rabbitmq_channel,
+};
+
+// Group member ids fetched from IAM, kept for `groups.membership_ttl_secs`. Entries are keyed by
+// the caller as well as the group, as IAM decides per caller whether the members may be listed.
+#[derive(Default)]
+pub struct GroupMembershipCache {
+    entries: Mutex<HashMap<(String, String), CachedMembers>>,
+}
+
+// When the members were fetched, and who they are
+type CachedMembers = (Instant, Arc<Vec<String>>);
+
+// Tokens aren't kept around as keys, only their digest
+fn caller_key(auth_header: &str) -> String {
+    hex::encode(Sha256::digest(auth_header.as_bytes()))
+}
+
+pub type SharedGroupCache = Arc<GroupMembershipCache>;
+
+impl GroupMembershipCache {
+    pub async fn get_members(
+        &self,
+        group_id: &str,
+        auth_header: String,
+    ) -> Result<Arc<Vec<String>>, String> {
+        let ttl = Duration::from_secs(CONFIG.groups.membership_ttl_secs);
+        let key = (caller_key(&auth_header), group_id.to_string());
+
+        if let Some((fetched_at, members)) = self.entries.lock().await.get(&key) {
+            if fetched_at.elapsed() < ttl {
+                GROUP_CACHE_HITS.inc();
+                return Ok(members.clone());
+            }
+        }
+        GROUP_CACHE_MISSES.inc();
+
+        let iam_config = get_configuration(Some(auth_header));
+        let ids = identity_get_group_members_ids(
+            &iam_config,
+            IdentityGetGroupMembersIdsParams {
+                group_identifier: group_id.to_string(),
+            },
+        )
+        .await
+        .map_err(|e| format!("{:?}", e))?;
+
+        let members = Arc::new(ids.into_iter().map(|id| id.to_string()).collect::<Vec<_>>());
+        let mut entries = self.entries.lock().await;
+        // Callers come and go as tokens expire, so drop whatever has gone stale
+        entries.retain(|_, (fetched_at, _)| fetched_at.elapsed() < ttl);
+        entries.insert(key, (Instant::now(), members.clone()));
+
+        Ok(members)
+    }
+
+    pub async fn invalidate(&self, group_id: Option<&str>) {
+        let mut entries = self.entries.lock().await;
+        match group_id {
+            Some(group_id) => entries.retain(|(_, cached_group), _| cached_group != group_id),
+            None => entries.clear(),
+        }
+    }
+}
+
+// Token this service uses to look up memberships when delivering group messages consumed
+// from RabbitMQ, where there is no caller token to forward
+pub fn service_auth_header() -> Option<String> {
+    std::env::var("IAM_API_TOKEN").ok()
+}
+
+// Filter to inject the group membership cache into the route handlers
+pub fn with_group_cache(
+    cache: SharedGroupCache,
+) -> impl Filter<Extract = (SharedGroupCache,), Error = Infallible> + Clone {
+    warp::any().map(move || cache.clone())
+}
+
+// Change event published by IAM. Events without a group id invalidate every group.
+#[derive(Deserialize, Debug)]
+pub struct IamChangeEvent {
+    pub group_id: Option<String>,
+}
+
+pub async fn consume_iam_events(cache: SharedGroupCache) {
+    loop {
+        match open_rabbitmq_channel().await {
+            Ok(rabbit_channel) => {
+                if let Err(e) = process_iam_events(rabbit_channel, cache.clone()).await {
+                    eprintln!("Error processing IAM events: {:?}", e);
+                }
+            }
+            Err(e) => {
+                eprintln!("Error connecting to RabbitMQ: {:?}", e);
+            }
+        }
+
+        // Anything may have changed while we weren't listening
+        cache.invalidate(None).await;
+
+        eprintln!("Reconnecting to IAM events in 5 seconds...");
+        sleep(Duration::from_secs(5)).await;
+    }
+}
+
+async fn process_iam_events(
+    rabbit_channel: RabbitChannel,
+    cache: SharedGroupCache,
+) -> Result<(), LapinError> {
+    let exchange = &CONFIG.groups.iam_events_exchange;
+
+    rabbit_channel
+        .exchange_declare(
+            exchange,
+            lapin::ExchangeKind::Fanout,
+            ExchangeDeclareOptions {
+                durable: true,
+                ..Default::default()
+            },
+            FieldTable::default(),
+        )
+        .await?;
+
+    // Every replica keeps its own cache, so each gets a private queue
+    let queue = rabbit_channel
+        .queue_declare(
+            "",
+            QueueDeclareOptions {
+                exclusive: true,
+                auto_delete: true,
+                ..Default::default()
+            },
+            FieldTable::default(),
+        )
+        .await?;
+
+    rabbit_channel
+        .queue_bind(
+            queue.name().as_str(),
+            exchange,
+            "",
+            QueueBindOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    let mut consumer = rabbit_channel
+        .basic_consume(
+            queue.name().as_str(),
+            "iam_events_consumer",
+            BasicConsumeOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    while let Some(delivery) = consumer.next().await {
+        let delivery = delivery?;
+
+        match serde_json::from_slice::<IamChangeEvent>(&delivery.data) {
+            Ok(event) => {
+                println!("Invalidating group membership cache for {:?}", event);
+                cache.invalidate(event.group_id.as_deref()).await;
+            }
+            Err(e) => println!("Failed to deserialize IAM event: {:?}", e),
+        }
+
+        delivery.ack(BasicAckOptions::default()).await?;
+    }
+
+    Ok(())
+}
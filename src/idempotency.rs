@@ -0,0 +1,462 @@
+use std::future::Future;
+
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use lapin::{
+    options::{
+        BasicAckOptions, BasicConsumeOptions, BasicPublishOptions, ExchangeDeclareOptions,
+        QueueBindOptions, QueueDeclareOptions,
+    },
+    types::FieldTable,
+    BasicProperties, Channel as RabbitChannel, Error as LapinError,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::time::{interval, sleep, Duration};
+use uuid::Uuid;
+use warp::{
+    http::{header::CONTENT_TYPE, HeaderValue, Response, StatusCode},
+    hyper::body::{to_bytes, Body},
+    Reply,
+};
+
+use crate::{
+    config::CONFIG,
+    shared::{open_rabbitmq_channel, RABBIT_POOL, REPLICA_ID},
+    store::Store,
+};
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+// A successful response remembered for replay to retries carrying the same key
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct SavedResponse {
+    pub status: u16,
+    pub content_type: Option<String>,
+    pub body: String,
+}
+
+// Written before the handler runs, so a retry arriving while the first request is still being
+// handled is turned away instead of running it again. `response` is set once it succeeds.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct IdempotencyRecord {
+    // Digest of the request, so a key can't be reused for a different one
+    pub fingerprint: String,
+    pub response: Option<SavedResponse>,
+    pub created_at: DateTime<Utc>,
+}
+
+// Keyed by the caller's scope (route, identity, target) and the idempotency key
+pub type IdempotencyStore = Store<IdempotencyRecord>;
+
+// Responses are kept for `window_secs`. Reservations only for `reservation_secs`, in case the
+// replica handling the request died before finishing it.
+fn is_expired(record: &IdempotencyRecord, now: DateTime<Utc>) -> bool {
+    let lifetime = match record.response {
+        Some(_) => CONFIG.idempotency.window_secs,
+        None => CONFIG.idempotency.reservation_secs,
+    };
+    u64::try_from((now - record.created_at).num_seconds()).is_ok_and(|age| age > lifetime)
+}
+
+// Digest of a request body, compared when its idempotency key is seen again
+pub fn fingerprint<T: Serialize>(request: &T) -> String {
+    hex::encode(Sha256::digest(
+        serde_json::to_vec(request).unwrap_or_default(),
+    ))
+}
+
+// Stable message id for a publish, so WS clients can drop duplicates of a retried request
+pub fn message_id_for(scope: &str, idempotency_key: Option<&str>) -> String {
+    match idempotency_key {
+        Some(key) => Uuid::new_v5(
+            &Uuid::NAMESPACE_OID,
+            format!("{}|{}", scope, key).as_bytes(),
+        )
+        .to_string(),
+        None => Uuid::new_v4().to_string(),
+    }
+}
+
+// What a request reusing a key that is already recorded gets instead of running
+fn reused(existing: IdempotencyRecord, fingerprint: &str) -> warp::reply::Response {
+    if existing.fingerprint != fingerprint {
+        return warp::reply::with_status(
+            warp::reply::json(&"Idempotency-Key was already used for a different request"),
+            StatusCode::UNPROCESSABLE_ENTITY,
+        )
+        .into_response();
+    }
+
+    match existing.response {
+        Some(saved) => replay(saved),
+        None => warp::reply::with_header(
+            warp::reply::with_status(
+                warp::reply::json(&"A request with this Idempotency-Key is still in progress"),
+                StatusCode::CONFLICT,
+            ),
+            "Retry-After",
+            "1",
+        )
+        .into_response(),
+    }
+}
+
+// Run `handler` unless the same scope and key are already recorded. A saved response is
+// replayed, a request still in progress gets 409 and a different request under the key 422.
+pub async fn idempotent<Fut, R>(
+    store: IdempotencyStore,
+    scope: String,
+    idempotency_key: Option<String>,
+    fingerprint: String,
+    handler: Fut,
+) -> Result<warp::reply::Response, warp::Rejection>
+where
+    Fut: Future<Output = Result<R, warp::Rejection>>,
+    R: Reply,
+{
+    let Some(idempotency_key) = idempotency_key else {
+        return handler.await.map(Reply::into_response);
+    };
+    let store_key = format!("{}|{}", scope, idempotency_key);
+
+    let now = Utc::now();
+    let reservation = IdempotencyRecord {
+        fingerprint: fingerprint.clone(),
+        response: None,
+        created_at: now,
+    };
+    if let Err(existing) = store
+        .try_insert(store_key.clone(), reservation.clone(), |existing| {
+            !is_expired(existing, now)
+        })
+        .await
+    {
+        println!("Idempotency key {} was already seen", idempotency_key);
+        return Ok(reused(existing, &fingerprint));
+    }
+    replicate(IdempotencyEvent::Reserved {
+        key: store_key.clone(),
+        record: reservation,
+    })
+    .await;
+
+    let (parts, body) = match handler.await {
+        Ok(reply) => reply.into_response().into_parts(),
+        Err(rejection) => {
+            release(&store, store_key).await;
+            return Err(rejection);
+        }
+    };
+    let bytes = to_bytes(body).await.unwrap_or_default();
+
+    // Only completed requests are remembered; failures may be retried for real
+    if parts.status.is_success() {
+        let record = IdempotencyRecord {
+            fingerprint,
+            response: Some(SavedResponse {
+                status: parts.status.as_u16(),
+                content_type: parts
+                    .headers
+                    .get(CONTENT_TYPE)
+                    .and_then(|value| value.to_str().ok())
+                    .map(str::to_string),
+                body: String::from_utf8_lossy(&bytes).to_string(),
+            }),
+            created_at: Utc::now(),
+        };
+        store.insert(store_key.clone(), record.clone()).await;
+        replicate(IdempotencyEvent::Completed {
+            key: store_key,
+            record,
+        })
+        .await;
+    } else {
+        release(&store, store_key).await;
+    }
+
+    Ok(Response::from_parts(parts, Body::from(bytes)))
+}
+
+async fn release(store: &IdempotencyStore, store_key: String) {
+    store.remove(&store_key).await;
+    replicate(IdempotencyEvent::Released { key: store_key }).await;
+}
+
+fn replay(saved: SavedResponse) -> warp::reply::Response {
+    let mut response = Response::new(Body::from(saved.body));
+    *response.status_mut() = StatusCode::from_u16(saved.status).unwrap_or(StatusCode::OK);
+
+    let headers = response.headers_mut();
+    if let Some(content_type) = saved
+        .content_type
+        .and_then(|value| HeaderValue::from_str(&value).ok())
+    {
+        headers.insert(CONTENT_TYPE, content_type);
+    }
+    headers.insert("Idempotent-Replayed", HeaderValue::from_static("true"));
+
+    response
+}
+
+// Retries may land on any replica, so reservations and saved responses are copied to all of
+// them. Two replicas reserving the same key before either hears of the other both run it.
+#[derive(Deserialize, Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+enum IdempotencyEvent {
+    Reserved {
+        key: String,
+        record: IdempotencyRecord,
+    },
+    Completed {
+        key: String,
+        record: IdempotencyRecord,
+    },
+    Released {
+        key: String,
+    },
+}
+
+#[derive(Deserialize, Serialize)]
+struct ReplicatedEvent {
+    replica: String,
+    #[serde(flatten)]
+    event: IdempotencyEvent,
+}
+
+pub async fn declare_idempotency_exchange(
+    rabbit_channel: &RabbitChannel,
+) -> Result<(), LapinError> {
+    rabbit_channel
+        .exchange_declare(
+            &CONFIG.idempotency.exchange,
+            lapin::ExchangeKind::Fanout,
+            ExchangeDeclareOptions {
+                durable: true,
+                ..Default::default()
+            },
+            FieldTable::default(),
+        )
+        .await
+}
+
+async fn replicate(event: IdempotencyEvent) {
+    let event = ReplicatedEvent {
+        replica: REPLICA_ID.clone(),
+        event,
+    };
+    if let Err(e) = publish_event(&event).await {
+        eprintln!("Failed to replicate idempotency key: {:?}", e);
+    }
+}
+
+async fn publish_event(event: &ReplicatedEvent) -> Result<(), LapinError> {
+    let rabbit_channel = RABBIT_POOL.channel().await?;
+
+    rabbit_channel
+        .basic_publish(
+            &CONFIG.idempotency.exchange,
+            "",
+            BasicPublishOptions::default(),
+            &serde_json::to_vec(event).unwrap(),
+            BasicProperties::default(),
+        )
+        .await?;
+    Ok(())
+}
+
+async fn apply_event(store: &IdempotencyStore, event: IdempotencyEvent) {
+    match event {
+        IdempotencyEvent::Reserved { key, record } => {
+            let now = Utc::now();
+            let _ = store
+                .try_insert(key, record, |existing| !is_expired(existing, now))
+                .await;
+        }
+        IdempotencyEvent::Completed { key, record } => store.insert(key, record).await,
+        // A saved response stays, whatever happened to a concurrent run elsewhere
+        IdempotencyEvent::Released { key } => {
+            if store
+                .get(&key)
+                .await
+                .is_some_and(|record| record.response.is_none())
+            {
+                store.remove(&key).await;
+            }
+        }
+    }
+}
+
+pub async fn consume_idempotency_events(store: IdempotencyStore) {
+    loop {
+        match open_rabbitmq_channel().await {
+            Ok(rabbit_channel) => {
+                if let Err(e) = process_idempotency_events(rabbit_channel, &store).await {
+                    eprintln!("Error processing idempotency events: {:?}", e);
+                }
+            }
+            Err(e) => {
+                eprintln!("Error connecting to RabbitMQ: {:?}", e);
+            }
+        }
+
+        eprintln!("Reconnecting to idempotency events in 5 seconds...");
+        sleep(Duration::from_secs(5)).await;
+    }
+}
+
+async fn process_idempotency_events(
+    rabbit_channel: RabbitChannel,
+    store: &IdempotencyStore,
+) -> Result<(), LapinError> {
+    declare_idempotency_exchange(&rabbit_channel).await?;
+
+    let queue = rabbit_channel
+        .queue_declare(
+            "",
+            QueueDeclareOptions {
+                exclusive: true,
+                auto_delete: true,
+                ..Default::default()
+            },
+            FieldTable::default(),
+        )
+        .await?;
+
+    rabbit_channel
+        .queue_bind(
+            queue.name().as_str(),
+            &CONFIG.idempotency.exchange,
+            "",
+            QueueBindOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    let mut consumer = rabbit_channel
+        .basic_consume(
+            queue.name().as_str(),
+            "idempotency_consumer",
+            BasicConsumeOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    while let Some(delivery) = consumer.next().await {
+        let delivery = delivery?;
+
+        match serde_json::from_slice::<ReplicatedEvent>(&delivery.data) {
+            // Already applied when it was published
+            Ok(event) if event.replica == *REPLICA_ID => {}
+            Ok(event) => apply_event(store, event.event).await,
+            Err(e) => println!("Failed to deserialize idempotency event: {:?}", e),
+        }
+
+        delivery.ack(BasicAckOptions::default()).await?;
+    }
+
+    Ok(())
+}
+
+// Snapshots from other replicas only replace a reservation with its saved response
+pub fn prefer_completed(local: &IdempotencyRecord, other: &IdempotencyRecord) -> bool {
+    local.response.is_none() && other.response.is_some()
+}
+
+pub async fn prune_idempotency_keys(store: IdempotencyStore) {
+    let mut ticker = interval(Duration::from_secs(600));
+
+    loop {
+        ticker.tick().await;
+        let now = Utc::now();
+        let removed = store.retain(|record| !is_expired(record, now)).await;
+        if removed > 0 {
+            println!("Pruned {} expired idempotency keys", removed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration as ChronoDuration;
+
+    fn record(fingerprint: &str, response: Option<SavedResponse>) -> IdempotencyRecord {
+        IdempotencyRecord {
+            fingerprint: fingerprint.to_string(),
+            response,
+            created_at: Utc::now(),
+        }
+    }
+
+    fn saved() -> SavedResponse {
+        SavedResponse {
+            status: 202,
+            content_type: Some("application/json".to_string()),
+            body: "\"Message published\"".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn replays_the_saved_response() {
+        let response = reused(record("abc", Some(saved())), "abc");
+
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert_eq!(response.headers()["Idempotent-Replayed"], "true");
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+        let body = to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"\"Message published\"");
+    }
+
+    #[test]
+    fn turns_away_a_retry_while_the_first_request_runs() {
+        let response = reused(record("abc", None), "abc");
+
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert_eq!(response.headers()["Retry-After"], "1");
+    }
+
+    #[test]
+    fn rejects_a_key_reused_for_a_different_request() {
+        assert_eq!(
+            reused(record("abc", Some(saved())), "def").status(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+        assert_eq!(
+            reused(record("abc", None), "def").status(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+    }
+
+    #[test]
+    fn fingerprints_differ_by_body() {
+        assert_eq!(fingerprint(&"hello"), fingerprint(&"hello"));
+        assert_ne!(fingerprint(&"hello"), fingerprint(&"hello!"));
+    }
+
+    #[test]
+    fn reservations_expire_before_responses() {
+        let now = Utc::now();
+        let age = ChronoDuration::seconds(CONFIG.idempotency.reservation_secs as i64 + 1);
+
+        let mut pending = record("abc", None);
+        pending.created_at = now - age;
+        let mut completed = record("abc", Some(saved()));
+        completed.created_at = now - age;
+
+        assert!(is_expired(&pending, now));
+        assert!(!is_expired(&completed, now));
+    }
+
+    #[test]
+    fn keeps_the_same_message_id_for_a_key() {
+        assert_eq!(
+            message_id_for("publish|1|news", Some("key")),
+            message_id_for("publish|1|news", Some("key"))
+        );
+        assert_ne!(
+            message_id_for("publish|1|news", Some("key")),
+            message_id_for("publish|2|news", Some("key"))
+        );
+    }
+}
@@ -0,0 +1,307 @@
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use futures::StreamExt;
+use ginger_shared_rs::rocket_utils::Claims;
+use lapin::{
+    options::{
+        BasicAckOptions, BasicConsumeOptions, BasicPublishOptions, ExchangeDeclareOptions,
+        QueueBindOptions, QueueDeclareOptions,
+    },
+    types::FieldTable,
+    BasicProperties, Channel as RabbitChannel, Error as LapinError,
+};
+use serde::{Deserialize, Serialize};
+use tokio::time::{interval, sleep, Duration};
+use utoipa::ToSchema;
+use warp::http::StatusCode;
+
+use crate::{
+    auth_helpers::forbidden_unless_self,
+    config::CONFIG,
+    shared::{open_rabbitmq_channel, RABBIT_POOL},
+    store::Store,
+};
+
+// A notification kept for a user who wasn't online to receive it
+#[derive(Deserialize, Serialize, ToSchema, Clone)]
+pub struct InboxItem {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub message: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize, Serialize, ToSchema, Clone)]
+pub struct UserInbox {
+    pub user_id: String,
+    pub items: Vec<InboxItem>,
+    pub updated_at: DateTime<Utc>,
+}
+
+// Keyed by user id
+pub type InboxStore = Store<UserInbox>;
+
+// Of two copies of a user's inbox, keep the one changed last
+pub fn prefer_newer_inbox(local: &UserInbox, other: &UserInbox) -> bool {
+    local.updated_at < other.updated_at
+}
+
+// Inbox changes are replicated over `inbox.exchange`, so any replica can answer for any user
+#[derive(Deserialize, Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+enum InboxEvent {
+    Added { user_id: String, item: InboxItem },
+    Removed { user_id: String, id: String },
+}
+
+pub async fn declare_inbox_exchange(rabbit_channel: &RabbitChannel) -> Result<(), LapinError> {
+    rabbit_channel
+        .exchange_declare(
+            &CONFIG.inbox.exchange,
+            lapin::ExchangeKind::Fanout,
+            ExchangeDeclareOptions {
+                durable: true,
+                ..Default::default()
+            },
+            FieldTable::default(),
+        )
+        .await
+}
+
+async fn publish_event(event: &InboxEvent) -> Result<(), LapinError> {
+    let rabbit_channel = RABBIT_POOL.channel().await?;
+
+    rabbit_channel
+        .basic_publish(
+            &CONFIG.inbox.exchange,
+            "",
+            BasicPublishOptions::default(),
+            &serde_json::to_vec(event).unwrap(),
+            BasicProperties::default(),
+        )
+        .await?;
+    Ok(())
+}
+
+// Applying an event twice is harmless, as the replica that published it applies it right away
+async fn apply_event(inbox: &InboxStore, event: InboxEvent) {
+    match event {
+        InboxEvent::Added { user_id, item } => {
+            inbox
+                .upsert(
+                    &user_id,
+                    || UserInbox {
+                        user_id: user_id.clone(),
+                        items: vec![],
+                        updated_at: item.created_at,
+                    },
+                    |user_inbox| {
+                        if user_inbox
+                            .items
+                            .iter()
+                            .any(|existing| existing.id == item.id)
+                        {
+                            return;
+                        }
+                        user_inbox.updated_at = item.created_at;
+                        user_inbox.items.push(item.clone());
+                        // Newest last; the oldest go once the inbox is full
+                        let overflow = user_inbox
+                            .items
+                            .len()
+                            .saturating_sub(CONFIG.inbox.max_items);
+                        user_inbox.items.drain(..overflow);
+                    },
+                )
+                .await;
+        }
+        InboxEvent::Removed { user_id, id } => {
+            inbox
+                .update(&user_id, |user_inbox| {
+                    user_inbox.items.retain(|item| item.id != id);
+                    user_inbox.updated_at = Utc::now();
+                })
+                .await;
+        }
+    }
+}
+
+pub async fn add_to_inbox(inbox: &InboxStore, user_id: &str, item: InboxItem) {
+    let event = InboxEvent::Added {
+        user_id: user_id.to_string(),
+        item,
+    };
+    if let Err(e) = publish_event(&event).await {
+        eprintln!("Failed to replicate inbox item for {}: {:?}", user_id, e);
+    }
+    apply_event(inbox, event).await;
+}
+
+// Whether the user still has the item, i.e. hasn't dismissed it
+pub async fn inbox_contains(inbox: &InboxStore, user_id: &str, id: &str) -> bool {
+    inbox
+        .get(user_id)
+        .await
+        .is_some_and(|user_inbox| user_inbox.items.iter().any(|item| item.id == id))
+}
+
+pub async fn consume_inbox_events(inbox: InboxStore) {
+    loop {
+        match open_rabbitmq_channel().await {
+            Ok(rabbit_channel) => {
+                if let Err(e) = process_inbox_events(rabbit_channel, &inbox).await {
+                    eprintln!("Error processing inbox events: {:?}", e);
+                }
+            }
+            Err(e) => {
+                eprintln!("Error connecting to RabbitMQ: {:?}", e);
+            }
+        }
+
+        eprintln!("Reconnecting to inbox events in 5 seconds...");
+        sleep(Duration::from_secs(5)).await;
+    }
+}
+
+async fn process_inbox_events(
+    rabbit_channel: RabbitChannel,
+    inbox: &InboxStore,
+) -> Result<(), LapinError> {
+    declare_inbox_exchange(&rabbit_channel).await?;
+
+    let queue = rabbit_channel
+        .queue_declare(
+            "",
+            QueueDeclareOptions {
+                exclusive: true,
+                auto_delete: true,
+                ..Default::default()
+            },
+            FieldTable::default(),
+        )
+        .await?;
+
+    rabbit_channel
+        .queue_bind(
+            queue.name().as_str(),
+            &CONFIG.inbox.exchange,
+            "",
+            QueueBindOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    let mut consumer = rabbit_channel
+        .basic_consume(
+            queue.name().as_str(),
+            "inbox_consumer",
+            BasicConsumeOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    while let Some(delivery) = consumer.next().await {
+        let delivery = delivery?;
+
+        match serde_json::from_slice::<InboxEvent>(&delivery.data) {
+            Ok(event) => apply_event(inbox, event).await,
+            Err(e) => println!("Failed to deserialize inbox event: {:?}", e),
+        }
+
+        delivery.ack(BasicAckOptions::default()).await?;
+    }
+
+    Ok(())
+}
+
+pub async fn prune_inboxes(inbox: InboxStore) {
+    let mut ticker = interval(Duration::from_secs(3600));
+
+    loop {
+        ticker.tick().await;
+        let cutoff = Utc::now() - ChronoDuration::days(CONFIG.inbox.retention_days as i64);
+        let removed = inbox
+            .retain(|user_inbox| user_inbox.updated_at > cutoff)
+            .await;
+        if removed > 0 {
+            println!("Pruned {} inactive inboxes", removed);
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/notification/users/{user_id}/inbox",
+    params(
+        ("user_id" = String, Path, description = "The user whose inbox to list")
+    ),
+    responses(
+        (status = 200, description = "Notifications received while offline, oldest first", body = [InboxItem]),
+        (status = 403, description = "Not the caller's own inbox")
+    ),
+    security(("bearerAuth" = [])),
+    tag = "default"
+)]
+pub async fn list_inbox(
+    user_id: String,
+    claims: Claims,
+    inbox: InboxStore,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    if let Some(reply) = forbidden_unless_self(&claims, &user_id) {
+        return Ok(reply);
+    }
+
+    let items = inbox
+        .get(&user_id)
+        .await
+        .map(|user_inbox| user_inbox.items)
+        .unwrap_or_default();
+    Ok(Box::new(warp::reply::json(&items)))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/notification/users/{user_id}/inbox/{item_id}",
+    params(
+        ("user_id" = String, Path, description = "The user whose inbox holds the item"),
+        ("item_id" = String, Path, description = "The id of the notification to dismiss")
+    ),
+    responses(
+        (status = 204, description = "Notification dismissed"),
+        (status = 403, description = "Not the caller's own inbox"),
+        (status = 404, description = "Notification not found")
+    ),
+    security(("bearerAuth" = [])),
+    tag = "default"
+)]
+pub async fn dismiss_inbox_item(
+    user_id: String,
+    item_id: String,
+    claims: Claims,
+    inbox: InboxStore,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    if let Some(reply) = forbidden_unless_self(&claims, &user_id) {
+        return Ok(reply);
+    }
+
+    if !inbox_contains(&inbox, &user_id, &item_id).await {
+        return Ok(Box::new(warp::reply::with_status(
+            warp::reply::json(&"Notification not found"),
+            StatusCode::NOT_FOUND,
+        )));
+    }
+
+    let event = InboxEvent::Removed {
+        user_id: user_id.clone(),
+        id: item_id,
+    };
+    if let Err(e) = publish_event(&event).await {
+        eprintln!(
+            "Failed to replicate dismissing inbox item of {}: {:?}",
+            user_id, e
+        );
+    }
+    apply_event(&inbox, event).await;
+
+    Ok(Box::new(StatusCode::NO_CONTENT))
+}
@@ -0,0 +1,130 @@
+use std::future::Future;
+
+use lapin::{
+    options::{BasicAckOptions, BasicConsumeOptions, BasicPublishOptions, QueueDeclareOptions},
+    types::{AMQPValue, FieldTable},
+    BasicProperties, Channel as RabbitChannel, Error as LapinError,
+};
+use tokio::time::{interval, sleep, Duration};
+
+use crate::{
+    shared::{open_rabbitmq_channel, RABBIT_POOL},
+    shutdown::SHUTDOWN,
+};
+
+// Periodic work only one replica may do at a time, like flushing digests. Every replica
+// publishes a tick onto `queue` each `period`, and the queue has a single active consumer, so
+// RabbitMQ hands every tick to the same replica and moves on to another when that one goes.
+pub async fn run_on_one_replica<F, Fut>(queue: &'static str, period: Duration, work: F)
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = ()>,
+{
+    tokio::select! {
+        _ = publish_ticks(queue, period) => {}
+        _ = consume_ticks(queue, &work) => {}
+    }
+}
+
+async fn declare_tick_queue(rabbit_channel: &RabbitChannel, queue: &str) -> Result<(), LapinError> {
+    let mut args = FieldTable::default();
+    args.insert("x-single-active-consumer".into(), AMQPValue::Boolean(true));
+    // Ticks are interchangeable, so one waiting is enough
+    args.insert("x-max-length".into(), AMQPValue::LongInt(1));
+
+    rabbit_channel
+        .queue_declare(
+            queue,
+            QueueDeclareOptions {
+                durable: true,
+                ..Default::default()
+            },
+            args,
+        )
+        .await?;
+    Ok(())
+}
+
+async fn publish_ticks(queue: &str, period: Duration) {
+    let mut ticker = interval(period);
+
+    loop {
+        ticker.tick().await;
+        let published = match RABBIT_POOL.channel().await {
+            Ok(rabbit_channel) => {
+                rabbit_channel
+                    .basic_publish(
+                        "", // Default exchange, routed by queue name
+                        queue,
+                        BasicPublishOptions::default(),
+                        b"",
+                        BasicProperties::default(),
+                    )
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = published {
+            eprintln!("Failed to publish tick to {}: {:?}", queue, e);
+        }
+    }
+}
+
+async fn consume_ticks<F, Fut>(queue: &str, work: &F)
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = ()>,
+{
+    loop {
+        match open_rabbitmq_channel().await {
+            Ok(rabbit_channel) => {
+                if let Err(e) = process_ticks(rabbit_channel, queue, work).await {
+                    eprintln!("Error processing ticks from {}: {:?}", queue, e);
+                }
+            }
+            Err(e) => {
+                eprintln!("Error connecting to RabbitMQ: {:?}", e);
+            }
+        }
+
+        // A draining replica hands the work over to another one
+        if SHUTDOWN.is_draining() {
+            return;
+        }
+
+        eprintln!("Reconnecting to {} in 5 seconds...", queue);
+        tokio::select! {
+            _ = sleep(Duration::from_secs(5)) => {}
+            _ = SHUTDOWN.started() => return,
+        }
+    }
+}
+
+async fn process_ticks<F, Fut>(
+    rabbit_channel: RabbitChannel,
+    queue: &str,
+    work: &F,
+) -> Result<(), LapinError>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = ()>,
+{
+    declare_tick_queue(&rabbit_channel, queue).await?;
+
+    let mut consumer = rabbit_channel
+        .basic_consume(
+            queue,
+            &format!("{}_consumer", queue),
+            BasicConsumeOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    while let Some(delivery) = SHUTDOWN.next_delivery(&mut consumer).await {
+        let delivery = delivery?;
+        work().await;
+        delivery.ack(BasicAckOptions::default()).await?;
+    }
+
+    Ok(())
+}
@@ -1,32 +1,202 @@
+use crate::config::CONFIG;
+use crate::digest::{add_to_digest, DigestStore};
+use crate::email_queue::{queue_email, EmailStatus, EmailStore};
+use crate::prom_helpers::{EMAIL_RATE_LIMITED_COUNTER, EMAIL_USAGE_COUNTER};
+use crate::rate_limits::{domain_label, recipient_domain, SharedRateLimiter};
 use crate::requests::EmailRequest;
+use crate::responses::{DigestQueuedResponse, EmailQueuedResponse};
+use crate::senders::resolve_sender;
+use crate::suppression::{find_suppression, SuppressionStore};
 use aws_config::meta::region::RegionProviderChain;
-use aws_config::Region;
+use aws_config::{BehaviorVersion, Region};
 use aws_sdk_ses::types::Body as EmailBody;
 use aws_sdk_ses::types::Content as EmailContent;
 use aws_sdk_ses::types::Destination;
 use aws_sdk_ses::types::Message as EmailMessage;
 use aws_sdk_ses::Client;
 use ginger_shared_rs::ISCClaims;
+use warp::http::StatusCode;
 
 #[utoipa::path(
     post,
     path = "/notification/send-email",
     request_body = EmailRequest,
+    params(
+        ("Idempotency-Key" = Option<String>, Header, description = "Retries with the same key within the idempotency window replay the original response")
+    ),
     responses(
-        (status = 200, description = "Email sent"),
+        (status = 202, description = "Email queued, or added to a digest when `digest` is set", body = EmailQueuedResponse),
+        (status = 403, description = "Sender not permitted for the calling service"),
+        (status = 409, description = "A request with the same Idempotency-Key is still in progress, see Retry-After"),
+        (status = 422, description = "Recipient is on the suppression list, or Idempotency-Key already used for a different request"),
+        (status = 429, description = "Rate limit or daily quota exceeded, see Retry-After"),
+        (status = 503, description = "Unable to queue email"),
     ),
     security(("apiISCBearerAuth" = [])),  // Referencing the security scheme
     tag = "default"
 )]
 pub async fn send_email(
-    email_request: EmailRequest,
+    mut email_request: EmailRequest,
     claims: ISCClaims, // Add claims from JWT here
-) -> Result<impl warp::Reply, warp::Rejection> {
+    store: EmailStore,
+    suppressions: SuppressionStore,
+    rate_limiter: SharedRateLimiter,
+    digests: DigestStore,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
     println!("claims : {:?}", claims);
+
+    if let Some(suppression) = find_suppression(&suppressions, &email_request.to).await {
+        println!(
+            "Refusing to email suppressed recipient {}",
+            suppression.email
+        );
+        return Ok(Box::new(warp::reply::with_status(
+            warp::reply::json(&format!(
+                "Recipient {} is suppressed after a {:?}",
+                suppression.email, suppression.reason
+            )),
+            StatusCode::UNPROCESSABLE_ENTITY,
+        )));
+    }
+
+    let sender = match resolve_sender(
+        &claims.sub,
+        email_request.from.as_deref(),
+        email_request.reply_to.as_deref(),
+    ) {
+        Ok(sender) => sender,
+        Err(e) => {
+            println!("Rejected email from {}: {}", claims.sub, e);
+            return Ok(Box::new(warp::reply::with_status(
+                warp::reply::json(&e.to_string()),
+                StatusCode::FORBIDDEN,
+            )));
+        }
+    };
+
+    let domain = recipient_domain(&email_request.to);
+    let limits = [
+        (
+            "service",
+            claims.sub.clone(),
+            CONFIG.email_limits.for_service(&claims.sub),
+        ),
+        (
+            "domain",
+            domain.clone(),
+            CONFIG.email_limits.for_domain(&domain),
+        ),
+    ];
+    if let Err(exceeded) = rate_limiter.acquire(&limits).await {
+        println!(
+            "Rate limited email from {} ({} limit)",
+            claims.sub, exceeded.scope
+        );
+        EMAIL_RATE_LIMITED_COUNTER
+            .with_label_values(&[&claims.sub, exceeded.scope])
+            .inc();
+        return Ok(Box::new(warp::reply::with_header(
+            warp::reply::with_status(
+                warp::reply::json(&format!("Email {} limit exceeded", exceeded.scope)),
+                StatusCode::TOO_MANY_REQUESTS,
+            ),
+            "Retry-After",
+            exceeded.retry_after_secs().to_string(),
+        )));
+    }
+
+    email_request.from = Some(sender.from.clone());
+    email_request.reply_to = sender.reply_to;
+
+    if let Some(frequency) = email_request.digest.clone() {
+        let batch = add_to_digest(
+            &digests,
+            &claims.sub,
+            email_request,
+            sender.source,
+            frequency,
+        )
+        .await;
+        EMAIL_USAGE_COUNTER
+            .with_label_values(&[&claims.sub, domain_label(&domain)])
+            .inc();
+        return Ok(Box::new(warp::reply::with_status(
+            warp::reply::json(&DigestQueuedResponse {
+                digest_id: batch.id,
+                pending_items: batch.items.len(),
+                flush_at: batch.flush_at,
+            }),
+            StatusCode::ACCEPTED,
+        )));
+    }
+
+    match queue_email(&store, &claims.sub, email_request, sender.source).await {
+        Ok(id) => {
+            EMAIL_USAGE_COUNTER
+                .with_label_values(&[&claims.sub, domain_label(&domain)])
+                .inc();
+            Ok(Box::new(warp::reply::with_status(
+                warp::reply::json(&EmailQueuedResponse {
+                    id,
+                    status: EmailStatus::Queued,
+                }),
+                StatusCode::ACCEPTED,
+            )))
+        }
+        Err(e) => {
+            eprintln!("Failed to queue email: {:?}", e);
+            Ok(Box::new(warp::reply::with_status(
+                warp::reply::json(&"Unable to queue email"),
+                StatusCode::SERVICE_UNAVAILABLE,
+            )))
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/notification/emails/{email_id}",
+    params(
+        ("email_id" = String, Path, description = "The id returned when the email was queued")
+    ),
+    responses(
+        (status = 200, description = "Email status", body = EmailRecord),
+        (status = 404, description = "Email not found, or queued by another service")
+    ),
+    security(("apiISCBearerAuth" = [])),
+    tag = "default"
+)]
+pub async fn get_email_status(
+    email_id: String,
+    claims: ISCClaims,
+    store: EmailStore,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    // Other services' emails are reported as missing, so their ids can't be probed
+    match store
+        .get(&email_id)
+        .await
+        .filter(|record| record.service == claims.sub)
+    {
+        Some(record) => Ok(warp::reply::with_status(
+            warp::reply::json(&record),
+            StatusCode::OK,
+        )),
+        None => Ok(warp::reply::with_status(
+            warp::reply::json(&"Email not found"),
+            StatusCode::NOT_FOUND,
+        )),
+    }
+}
+
+// Send a single email through SES as `source`, returning the SES message id
+pub async fn deliver_email(email_request: &EmailRequest, source: &str) -> Result<String, String> {
     // Set up AWS SES client
     let region_provider =
         RegionProviderChain::default_provider().or_else(Region::new("ap-south-1"));
-    let config = aws_config::from_env().region(region_provider).load().await;
+    let config = aws_config::defaults(BehaviorVersion::latest())
+        .region(region_provider)
+        .load()
+        .await;
     let client = Client::new(&config);
 
     let destination = Destination::builder()
@@ -39,7 +209,7 @@ pub async fn send_email(
             EmailContent::builder()
                 .data(email_request.subject.clone())
                 .build()
-                .unwrap(),
+                .map_err(|e| e.to_string())?,
         )
         .body(
             EmailBody::builder()
@@ -47,7 +217,7 @@ pub async fn send_email(
                     EmailContent::builder()
                         .data(email_request.message.clone())
                         .build()
-                        .unwrap(),
+                        .map_err(|e| e.to_string())?,
                 )
                 .build(),
         )
@@ -56,18 +226,23 @@ pub async fn send_email(
     // Prepare the SES email request
     let email_result = client
         .send_email()
-        .source("no-reply@gingersociety.org") // Replace with SES verified email
+        .source(source) // Must be an SES verified identity
         .destination(destination)
+        .set_reply_to_addresses(
+            email_request
+                .reply_to
+                .clone()
+                .map(|reply_to| vec![reply_to]),
+        )
         .message(message)
         .send()
         .await;
 
-    // Check for errors and send response
     match email_result {
-        Ok(_) => Ok(warp::reply::json(&"Email sent")),
+        Ok(output) => Ok(output.message_id().to_string()),
         Err(err) => {
             eprintln!("Failed to send email: {:?}", err);
-            Ok(warp::reply::json(&"Failed to send email"))
+            Err(err.to_string())
         }
     }
 }
@@ -1,47 +1,251 @@
-use crate::mailer::__path_send_email;
-use crate::rest_bridge::{__path_publish_message, __path_publish_message_to_group};
+#![recursion_limit = "256"]
 
+use crate::admin::{
+    __path_broadcast, __path_close_channel, __path_disconnect_connection, __path_disconnect_user,
+    __path_list_channels, __path_list_connections,
+};
+use crate::inbox::{__path_dismiss_inbox_item, __path_list_inbox};
+use crate::mailer::{__path_get_email_status, __path_send_email};
+use crate::notify::__path_notify_user;
+use crate::preferences::{
+    __path_delete_preferences, __path_get_preferences, __path_put_preferences,
+};
+use crate::presence::__path_get_presence;
+use crate::receipts::__path_get_receipts;
+use crate::rest_bridge::{
+    __path_publish_batch, __path_publish_message, __path_publish_message_to_group,
+};
+use crate::schedule::{__path_cancel_scheduled, __path_list_scheduled};
+use crate::sms::__path_send_sms;
+use crate::suppression::{
+    __path_ingest_ses_event, __path_list_suppressions, __path_remove_suppression,
+};
+use crate::web_push::{
+    __path_delete_push_subscription, __path_get_vapid_public_key, __path_list_push_subscriptions,
+    __path_register_push_subscription,
+};
+use crate::webhooks::{
+    __path_delete_webhook, __path_list_webhook_deliveries, __path_list_webhooks,
+    __path_register_webhook,
+};
+
+use admin::{
+    broadcast, close_channel, consume_admin_commands, disconnect_connection, disconnect_user,
+    list_channels, list_connections,
+};
 use auth_helpers::{
-    handle_ws_upgrade, user_authenticated, with_api_auth, with_auth, with_get_api_auth_header,
-    with_get_auth_header, with_isc_api_auth,
+    handle_ws_upgrade, user_authenticated, with_admin_auth, with_api_auth, with_auth,
+    with_caller_identity, with_get_api_auth_header, with_get_auth_header, with_isc_api_auth,
 };
 
 use auth_schemas::SecurityAddon;
+use chatops::consume_chatops;
+use config::CONFIG;
 
+use digest::{
+    consume_digest_events, prefer_fuller_digest, run_digest_scheduler, DigestFrequency, DigestStore,
+};
+use email_queue::{
+    consume_email_events, consume_emails, prefer_newer_email, prune_emails, EmailRecord,
+    EmailStatus, EmailStore, SesMessageStore,
+};
+use ginger_shared_rs::rocket_utils::{APIClaims, Claims};
+use ginger_shared_rs::ISCClaims;
+use group_membership::{
+    consume_iam_events, service_auth_header, with_group_cache, GroupMembershipCache,
+};
+use idempotency::{
+    consume_idempotency_events, fingerprint, idempotent, message_id_for, prefer_completed,
+    prune_idempotency_keys, IdempotencyStore, IDEMPOTENCY_KEY_HEADER,
+};
+use inbox::{
+    consume_inbox_events, dismiss_inbox_item, list_inbox, prefer_newer_inbox, prune_inboxes,
+    InboxItem, InboxStore,
+};
 use message_queue_helpers::consume_messages;
-use prom_helpers::{metrics_handler, REGISTRY, REQUEST_COUNTER};
+use notify::{
+    notify_user, run_email_fallbacks, with_notify_context, NotifyContext, PendingEmailStore,
+};
+use outbound::Priority;
+use preferences::{
+    consume_preference_updates, delete_preferences, get_preferences, prefer_newer, put_preferences,
+    route_emails, DeliveryRoute, PreferenceStore, PreferencesRequest, QuietHours,
+    RoutedEmailStores, UserPreferences,
+};
+use presence::{get_presence, run_presence, with_presence, PresenceView};
+use prom_helpers::{
+    metrics_handler, CHATOPS_MESSAGES_COUNTER, EMAIL_RATE_LIMITED_COUNTER, EMAIL_USAGE_COUNTER,
+    GROUP_CACHE_HITS, GROUP_CACHE_MISSES, GROUP_FANOUT_SIZE, MESSAGES_EXPIRED, OUTBOUND_COALESCED,
+    OUTBOUND_DROPPED, REGISTRY, REQUEST_COUNTER, SMS_COUNTER, WEBHOOK_DELIVERIES_COUNTER,
+    WEB_PUSH_COUNTER,
+};
+use rate_limits::{prune_idle_usage, with_rate_limiter, RateLimiter};
+use receipts::{
+    consume_receipts, get_receipts, prune_receipts, MessageReceipts, Receipt, ReceiptStatus,
+    ReceiptStore,
+};
+use shutdown::{drain, wait_for_signal, SHUTDOWN};
 // Renaming lapin::Channel to RabbitChannel
 use requests::EmailRequest;
-use requests::PublishRequest;
+use requests::{BatchPublishItem, BroadcastRequest, NotifyRequest, PublishRequest, SmsRequest};
+use responses::{
+    AdminActionResponse, AdminChannelsResponse, BatchPublishResult, ChannelSummary,
+    ConnectionSummary, DigestQueuedResponse, EmailQueuedResponse, NotifyResponse, PresenceResponse,
+    SmsSentResponse, VapidPublicKeyResponse, WebhookResponse,
+};
+use rest_bridge::publish_batch;
 use rest_bridge::publish_message;
 use rest_bridge::publish_message_to_group;
+use schedule::{
+    cancel_scheduled, consume_schedule_events, list_scheduled, run_notification_scheduler,
+    ScheduleStore, ScheduledNotification,
+};
 use shared::with_channels;
-use shared::Channels;
+use shared::{ChannelRegistry, Channels};
+use sms::{send_sms, SMS_TRANSPORT};
 use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use store::{with_store, JsonStore};
+use store_sync::{answer_snapshot_requests, bootstrap, StoreSync};
+use suppression::{
+    consume_suppression_events, ingest_ses_event, list_suppressions, prefer_newer_suppression,
+    prune_suppressions, remove_suppression, Suppression, SuppressionReason, SuppressionStore,
+};
+use tokio::sync::{mpsc, Mutex};
+use web_push::{
+    consume_subscription_events, delete_push_subscription, get_vapid_public_key,
+    list_push_subscriptions, prefer_newer_subscriptions, register_push_subscription, PushKeys,
+    PushSubscription, PushSubscriptionRequest, PushSubscriptionStore,
+};
+use webhooks::{
+    consume_webhook_deliveries, consume_webhook_events, consume_webhook_messages, delete_webhook,
+    list_webhook_deliveries, list_webhooks, prefer_newer_delivery, prefer_removed_webhook,
+    prune_webhook_deliveries, register_webhook, WebhookDelivery, WebhookDeliveryStatus,
+    WebhookDeliveryStore, WebhookRequest, WebhookStore,
+};
 
 use utoipa::OpenApi;
 use utoipa_swagger_ui::Config;
 use warp::Filter;
 
+mod admin;
 mod auth_helpers;
 mod auth_schemas;
+mod channel_acl;
+mod channel_trie;
+mod chatops;
+mod config;
+mod digest;
+mod email_queue;
+mod group_membership;
+mod idempotency;
+mod inbox;
+mod leader;
 mod mailer;
 mod message_queue_helpers;
+mod notify;
+mod outbound;
+mod preferences;
+mod presence;
 mod prom_helpers;
+mod rate_limits;
+mod receipts;
 mod requests;
 mod responses;
 mod rest_bridge;
+mod schedule;
+mod senders;
 mod shared;
-use crate::mailer::send_email;
+mod shutdown;
+mod sms;
+mod store;
+mod store_sync;
+mod suppression;
+mod web_push;
+mod webhooks;
+use crate::mailer::{get_email_status, send_email};
 
 // Swagger configuration for the REST endpoints
 #[derive(OpenApi)]
 #[openapi(
-    paths(publish_message, publish_message_to_group, send_email),
+    paths(
+        publish_message,
+        publish_message_to_group,
+        publish_batch,
+        send_email,
+        get_email_status,
+        send_sms,
+        ingest_ses_event,
+        list_suppressions,
+        remove_suppression,
+        list_scheduled,
+        cancel_scheduled,
+        get_presence,
+        get_receipts,
+        get_preferences,
+        put_preferences,
+        delete_preferences,
+        notify_user,
+        list_inbox,
+        dismiss_inbox_item,
+        get_vapid_public_key,
+        register_push_subscription,
+        list_push_subscriptions,
+        delete_push_subscription,
+        register_webhook,
+        list_webhooks,
+        delete_webhook,
+        list_webhook_deliveries,
+        list_channels,
+        list_connections,
+        disconnect_connection,
+        disconnect_user,
+        close_channel,
+        broadcast
+    ),
     components(
-        schemas(PublishRequest, EmailRequest)
+        schemas(
+            PublishRequest,
+            BatchPublishItem,
+            BatchPublishResult,
+            EmailRequest,
+            EmailQueuedResponse,
+            SmsRequest,
+            SmsSentResponse,
+            DigestQueuedResponse,
+            DigestFrequency,
+            EmailRecord,
+            EmailStatus,
+            Suppression,
+            SuppressionReason,
+            ScheduledNotification,
+            Priority,
+            PresenceResponse,
+            MessageReceipts,
+            Receipt,
+            ReceiptStatus,
+            PreferencesRequest,
+            UserPreferences,
+            DeliveryRoute,
+            QuietHours,
+            NotifyRequest,
+            NotifyResponse,
+            InboxItem,
+            VapidPublicKeyResponse,
+            PushSubscriptionRequest,
+            PushSubscription,
+            PushKeys,
+            WebhookRequest,
+            WebhookResponse,
+            WebhookDelivery,
+            WebhookDeliveryStatus,
+            AdminChannelsResponse,
+            ChannelSummary,
+            ConnectionSummary,
+            AdminActionResponse,
+            BroadcastRequest
+        )
     ),
     modifiers(&SecurityAddon),
 )]
@@ -53,6 +257,40 @@ async fn main() {
     REGISTRY
         .register(Box::new(REQUEST_COUNTER.clone()))
         .unwrap();
+    REGISTRY
+        .register(Box::new(EMAIL_USAGE_COUNTER.clone()))
+        .unwrap();
+    REGISTRY
+        .register(Box::new(EMAIL_RATE_LIMITED_COUNTER.clone()))
+        .unwrap();
+    REGISTRY
+        .register(Box::new(GROUP_CACHE_HITS.clone()))
+        .unwrap();
+    REGISTRY
+        .register(Box::new(GROUP_CACHE_MISSES.clone()))
+        .unwrap();
+    REGISTRY
+        .register(Box::new(GROUP_FANOUT_SIZE.clone()))
+        .unwrap();
+    REGISTRY
+        .register(Box::new(MESSAGES_EXPIRED.clone()))
+        .unwrap();
+    REGISTRY
+        .register(Box::new(OUTBOUND_DROPPED.clone()))
+        .unwrap();
+    REGISTRY
+        .register(Box::new(OUTBOUND_COALESCED.clone()))
+        .unwrap();
+    REGISTRY
+        .register(Box::new(WEB_PUSH_COUNTER.clone()))
+        .unwrap();
+    REGISTRY
+        .register(Box::new(WEBHOOK_DELIVERIES_COUNTER.clone()))
+        .unwrap();
+    REGISTRY
+        .register(Box::new(CHATOPS_MESSAGES_COUNTER.clone()))
+        .unwrap();
+    REGISTRY.register(Box::new(SMS_COUNTER.clone())).unwrap();
 
     // Define the metrics route
     let metrics_route = warp::path("notification")
@@ -60,15 +298,225 @@ async fn main() {
         .and(warp::get())
         .and_then(metrics_handler);
 
-    let channels: Channels = Arc::new(Mutex::new(HashMap::new()));
+    // Local channels coming and going update the consumer's bindings and the cluster's presence
+    let (binding_tx, binding_rx) = mpsc::unbounded_channel();
+    let (presence_tx, presence_rx) = mpsc::unbounded_channel();
+    let channels: Channels = Arc::new(Mutex::new(ChannelRegistry::new(binding_tx, presence_tx)));
+
+    let presence = Arc::new(PresenceView::default());
+    let channels_presence = channels.clone();
+    let presence_events = presence.clone();
+    tokio::spawn(
+        async move { run_presence(channels_presence, presence_events, presence_rx).await },
+    );
+
+    // Admin actions on sockets, sent by whichever replica handled the request
+    let channels_admin = channels.clone();
+    tokio::spawn(async move { consume_admin_commands(channels_admin).await });
+
+    // Every replica resolves group members itself when delivering group messages, so without a
+    // token group publishes are refused rather than dropped
+    if service_auth_header().is_none() {
+        eprintln!("IAM_API_TOKEN is not set, group delivery is disabled");
+    }
+
+    // A half-configured SMS transport stops startup here rather than failing the first send
+    lazy_static::initialize(&SMS_TRANSPORT);
+
+    // Group memberships are cached and invalidated by IAM change events
+    let group_cache = Arc::new(GroupMembershipCache::default());
+    let group_cache_events = group_cache.clone();
+    tokio::spawn(async move { consume_iam_events(group_cache_events).await });
+
+    // Per-user routing of typed messages, replicated from whichever replica saved them
+    let preference_store: PreferenceStore = JsonStore::open("preferences.json");
+    let preference_store_updates = preference_store.clone();
+    tokio::spawn(async move { consume_preference_updates(preference_store_updates).await });
+
+    // Consumers of queues shared by every replica, which shutdown waits on
+    let mut workers = vec![];
+
+    // Start the email worker
+    let email_store: EmailStore = JsonStore::open("emails.json");
+    let ses_message_store: SesMessageStore = JsonStore::open("ses_messages.json");
+    let email_store_events = email_store.clone();
+    let ses_message_store_events = ses_message_store.clone();
+    tokio::spawn(async move {
+        consume_email_events(email_store_events, ses_message_store_events).await
+    });
+    let email_store_pruner = email_store.clone();
+    let ses_message_store_pruner = ses_message_store.clone();
+    tokio::spawn(async move { prune_emails(email_store_pruner, ses_message_store_pruner).await });
+    let suppression_store: SuppressionStore = JsonStore::open("suppressions.json");
+    let suppression_store_events = suppression_store.clone();
+    tokio::spawn(async move { consume_suppression_events(suppression_store_events).await });
+    let suppression_store_pruner = suppression_store.clone();
+    tokio::spawn(async move { prune_suppressions(suppression_store_pruner).await });
+    let email_store_worker = email_store.clone();
+    let suppression_store_worker = suppression_store.clone();
+    workers.push(tokio::spawn(async move {
+        consume_emails(email_store_worker, suppression_store_worker).await
+    }));
+
+    // Flush hourly and daily digests onto the email queue when they fall due
+    let digest_store: DigestStore = JsonStore::open("digests.json");
+    let digest_store_events = digest_store.clone();
+    tokio::spawn(async move { consume_digest_events(digest_store_events).await });
+    let digest_store_scheduler = digest_store.clone();
+    let email_store_scheduler = email_store.clone();
+    tokio::spawn(async move {
+        run_digest_scheduler(digest_store_scheduler, email_store_scheduler).await
+    });
+
+    let routed_email_stores = RoutedEmailStores {
+        emails: email_store.clone(),
+        digests: digest_store.clone(),
+        suppressions: suppression_store.clone(),
+    };
 
     // Start RabbitMQ consumer
     let channels_clone = channels.clone();
-    tokio::spawn(async move {
-        consume_messages(channels_clone).await
+    let group_cache_consumer = group_cache.clone();
+    let preference_store_consumer = preference_store.clone();
+    let routed_email_stores_consumer = routed_email_stores.clone();
+    let consumer = tokio::spawn(async move {
+        consume_messages(
+            channels_clone,
+            group_cache_consumer,
+            preference_store_consumer,
+            routed_email_stores_consumer,
+            binding_rx,
+        )
+        .await
         // Ensure the block returns `()`
     });
 
+    // Email group members who route a message's type to email
+    if CONFIG.preferences.email_service.is_some() {
+        let preference_store_router = preference_store.clone();
+        let group_cache_router = group_cache.clone();
+        workers.push(tokio::spawn(async move {
+            route_emails(
+                preference_store_router,
+                group_cache_router,
+                routed_email_stores,
+            )
+            .await
+        }));
+    }
+
+    // Notifications kept for offline users, replicated like preferences
+    let inbox_store: InboxStore = JsonStore::open("inbox.json");
+    let inbox_store_consumer = inbox_store.clone();
+    tokio::spawn(async move { consume_inbox_events(inbox_store_consumer).await });
+    let inbox_store_pruner = inbox_store.clone();
+    tokio::spawn(async move { prune_inboxes(inbox_store_pruner).await });
+
+    // Browsers subscribed to Web Push, replicated like inboxes
+    let push_subscription_store: PushSubscriptionStore = JsonStore::open("push_subscriptions.json");
+    let push_subscription_store_consumer = push_subscription_store.clone();
+    tokio::spawn(
+        async move { consume_subscription_events(push_subscription_store_consumer).await },
+    );
+
+    // Emails sent to users who don't come online soon enough after a notify
+    let pending_email_store: PendingEmailStore = JsonStore::open("pending_emails.json");
+    let notify_context = Arc::new(NotifyContext {
+        preferences: preference_store.clone(),
+        presence: presence.clone(),
+        inbox: inbox_store.clone(),
+        push_subscriptions: push_subscription_store.clone(),
+        pending: pending_email_store,
+        emails: email_store.clone(),
+        digests: digest_store.clone(),
+        suppressions: suppression_store.clone(),
+    });
+    let notify_context_fallbacks = notify_context.clone();
+    tokio::spawn(async move { run_email_fallbacks(notify_context_fallbacks).await });
+
+    // Webhooks get channel messages from a queue shared by every replica, so each message is
+    // dispatched once; registrations and the delivery log are replicated like inboxes
+    let webhook_store: WebhookStore = JsonStore::open("webhooks.json");
+    let webhook_delivery_store: WebhookDeliveryStore = JsonStore::open("webhook_deliveries.json");
+    let webhook_store_events = webhook_store.clone();
+    let webhook_delivery_store_events = webhook_delivery_store.clone();
+    tokio::spawn(async move {
+        consume_webhook_events(webhook_store_events, webhook_delivery_store_events).await
+    });
+    let webhook_store_dispatcher = webhook_store.clone();
+    let webhook_delivery_store_dispatcher = webhook_delivery_store.clone();
+    workers.push(tokio::spawn(async move {
+        consume_webhook_messages(webhook_store_dispatcher, webhook_delivery_store_dispatcher).await
+    }));
+    let webhook_store_worker = webhook_store.clone();
+    let webhook_delivery_store_worker = webhook_delivery_store.clone();
+    workers.push(tokio::spawn(async move {
+        consume_webhook_deliveries(webhook_store_worker, webhook_delivery_store_worker).await
+    }));
+    let webhook_store_pruner = webhook_store.clone();
+    let webhook_delivery_store_pruner = webhook_delivery_store.clone();
+    tokio::spawn(async move {
+        prune_webhook_deliveries(webhook_store_pruner, webhook_delivery_store_pruner).await
+    });
+
+    // Channels mirrored into chat rooms
+    if !CONFIG.chatops.mappings.is_empty() {
+        workers.push(tokio::spawn(async move { consume_chatops().await }));
+    }
+
+    // Responses replayed to retried requests carrying an Idempotency-Key
+    let idempotency_store: IdempotencyStore = JsonStore::open("idempotency.json");
+    let idempotency_store_events = idempotency_store.clone();
+    tokio::spawn(async move { consume_idempotency_events(idempotency_store_events).await });
+    let idempotency_store_pruner = idempotency_store.clone();
+    tokio::spawn(async move { prune_idempotency_keys(idempotency_store_pruner).await });
+
+    // Publishes held back with `deliver_at` / `delay_secs`, published when they fall due
+    let schedule_store: ScheduleStore = JsonStore::open("scheduled.json");
+    let schedule_store_events = schedule_store.clone();
+    tokio::spawn(async move { consume_schedule_events(schedule_store_events).await });
+    let schedule_store_scheduler = schedule_store.clone();
+    tokio::spawn(async move { run_notification_scheduler(schedule_store_scheduler).await });
+
+    // Delivery and read receipts sent by clients, recorded by every replica
+    let receipt_store: ReceiptStore = JsonStore::open("receipts.json");
+    let receipt_store_consumer = receipt_store.clone();
+    tokio::spawn(async move { consume_receipts(receipt_store_consumer).await });
+    let receipt_store_pruner = receipt_store.clone();
+    tokio::spawn(async move { prune_receipts(receipt_store_pruner).await });
+
+    // Replicated stores only hear of changes made while this replica runs, so it starts from a
+    // copy of the other replicas' before serving requests
+    let mut store_sync = StoreSync::default();
+    store_sync.register("idempotency", idempotency_store.clone(), prefer_completed);
+    store_sync.register("emails", email_store.clone(), prefer_newer_email);
+    store_sync.register("digests", digest_store.clone(), prefer_fuller_digest);
+    // An SES message id always maps to the same email, so either copy will do
+    store_sync.register("ses_messages", ses_message_store.clone(), |_, _| false);
+    // A scheduled notification never changes, so either copy will do
+    store_sync.register("scheduled", schedule_store.clone(), |_, _| false);
+    store_sync.register(
+        "suppressions",
+        suppression_store.clone(),
+        prefer_newer_suppression,
+    );
+    store_sync.register("preferences", preference_store.clone(), prefer_newer);
+    store_sync.register("inbox", inbox_store.clone(), prefer_newer_inbox);
+    store_sync.register(
+        "push_subscriptions",
+        push_subscription_store.clone(),
+        prefer_newer_subscriptions,
+    );
+    store_sync.register("webhooks", webhook_store.clone(), prefer_removed_webhook);
+    store_sync.register(
+        "webhook_deliveries",
+        webhook_delivery_store.clone(),
+        prefer_newer_delivery,
+    );
+    let store_sync = Arc::new(store_sync);
+    bootstrap(&store_sync).await;
+    tokio::spawn(answer_snapshot_requests(store_sync));
+
     // WebSocket endpoint to subscribe to channels
     let channels_ws = channels.clone();
     // Modify the websocket_route to extract token from query parameters
@@ -78,10 +526,27 @@ async fn main() {
         .and(warp::ws()) // WebSocket instance
         .and(warp::query::<HashMap<String, String>>()) // Extract query parameters
         .and(with_channels(channels_ws)) // Channels
+        .and(warp::addr::remote())
+        .and(warp::header::optional::<String>("x-forwarded-for"))
         .and_then(
-            |channel_name, ws, query_params: HashMap<String, String>, channels| {
-                let token = query_params.get("token").cloned(); // Get token from query params
-                user_authenticated(channel_name, ws, channels, token) // Pass the token
+            |channel_name,
+             ws,
+             query_params: HashMap<String, String>,
+             channels,
+             peer: Option<SocketAddr>,
+             forwarded_for: Option<String>| {
+                // Get token from query params
+                let token = query_params.get("token").cloned();
+                // Behind a trusted proxy the peer is the proxy, so prefer the client it forwarded
+                // for. Otherwise the header is whatever the client sent.
+                let remote_addr = forwarded_for
+                    .filter(|_| CONFIG.admin.trust_forwarded_for)
+                    .and_then(|addrs| addrs.split(',').next().map(|addr| addr.trim().to_string()))
+                    .or_else(|| peer.map(|peer| peer.to_string()));
+                // Clients opt into JSON envelopes; others keep receiving the bare message
+                let envelope = query_params.get("envelope").map(String::as_str) == Some("true");
+                // Pass the token
+                user_authenticated(channel_name, ws, channels, token, remote_addr, envelope)
             },
         )
         .and_then(handle_ws_upgrade); // Handle WebSocket upgrade
@@ -94,24 +559,365 @@ async fn main() {
         .and(with_auth()) // Add authentication here
         .and(with_get_auth_header())
         .and(with_channels(channels_rest))
-        .and_then(publish_message);
+        .and(with_store(schedule_store.clone()))
+        .and(warp::header::optional::<String>(IDEMPOTENCY_KEY_HEADER))
+        .and(with_store(idempotency_store.clone()))
+        .and_then(
+            |channel_name: String,
+             publish_request: PublishRequest,
+             claims: Claims,
+             auth_header,
+             channels,
+             schedules,
+             idempotency_key: Option<String>,
+             store| {
+                let scope = format!("publish|{}|{}", claims.user_id, channel_name);
+                let message_id = message_id_for(&scope, idempotency_key.as_deref());
+                let fingerprint = fingerprint(&publish_request);
+                idempotent(
+                    store,
+                    scope,
+                    idempotency_key,
+                    fingerprint,
+                    publish_message(
+                        channel_name,
+                        publish_request,
+                        claims,
+                        auth_header,
+                        channels,
+                        schedules,
+                        message_id,
+                    ),
+                )
+            },
+        );
 
-    let channels_rest = channels.clone();
     let group_publish_route = warp::path("notification")
         .and(warp::path!("groups" / String / "publish"))
         .and(warp::post())
         .and(warp::body::json())
         .and(with_api_auth()) // Add authentication here
         .and(with_get_api_auth_header())
-        .and(with_channels(channels_rest))
-        .and_then(publish_message_to_group);
+        .and(with_group_cache(group_cache.clone()))
+        .and(with_store(schedule_store.clone()))
+        .and(warp::header::optional::<String>(IDEMPOTENCY_KEY_HEADER))
+        .and(with_store(idempotency_store.clone()))
+        .and_then(
+            |group_id: String,
+             publish_request: PublishRequest,
+             claims: APIClaims,
+             auth_header,
+             group_cache,
+             schedules,
+             idempotency_key: Option<String>,
+             store| {
+                let scope = format!("group-publish|{}|{}", claims.sub, group_id);
+                let message_id = message_id_for(&scope, idempotency_key.as_deref());
+                let fingerprint = fingerprint(&publish_request);
+                idempotent(
+                    store,
+                    scope,
+                    idempotency_key,
+                    fingerprint,
+                    publish_message_to_group(
+                        group_id,
+                        publish_request,
+                        claims,
+                        auth_header,
+                        group_cache,
+                        schedules,
+                        message_id,
+                    ),
+                )
+            },
+        );
+
+    let batch_publish_route = warp::path("notification")
+        .and(warp::path!("publish" / "batch"))
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_api_auth())
+        .and_then(publish_batch);
+
+    let presence_route = warp::path("notification")
+        .and(warp::path!("channels" / String / "presence"))
+        .and(warp::get())
+        .and(with_auth())
+        .and(with_presence(presence.clone()))
+        .and_then(get_presence);
+
+    let receipts_route = warp::path("notification")
+        .and(warp::path!("messages" / String / "receipts"))
+        .and(warp::get())
+        .and(with_caller_identity())
+        .and(with_store(receipt_store.clone()))
+        .and_then(get_receipts);
+
+    let get_preferences_route = warp::path("notification")
+        .and(warp::path!("users" / String / "preferences"))
+        .and(warp::get())
+        .and(with_auth())
+        .and(with_store(preference_store.clone()))
+        .and_then(get_preferences);
+
+    let put_preferences_route = warp::path("notification")
+        .and(warp::path!("users" / String / "preferences"))
+        .and(warp::put())
+        .and(warp::body::json())
+        .and(with_auth())
+        .and(with_store(preference_store.clone()))
+        .and_then(put_preferences);
+
+    let delete_preferences_route = warp::path("notification")
+        .and(warp::path!("users" / String / "preferences"))
+        .and(warp::delete())
+        .and(with_auth())
+        .and(with_store(preference_store.clone()))
+        .and_then(delete_preferences);
+
+    let notify_route = warp::path("notification")
+        .and(warp::path!("users" / String / "notify"))
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_api_auth())
+        .and(with_get_api_auth_header())
+        .and(with_notify_context(notify_context.clone()))
+        .and_then(notify_user);
+
+    let list_inbox_route = warp::path("notification")
+        .and(warp::path!("users" / String / "inbox"))
+        .and(warp::get())
+        .and(with_auth())
+        .and(with_store(inbox_store.clone()))
+        .and_then(list_inbox);
+
+    let dismiss_inbox_item_route = warp::path("notification")
+        .and(warp::path!("users" / String / "inbox" / String))
+        .and(warp::delete())
+        .and(with_auth())
+        .and(with_store(inbox_store.clone()))
+        .and_then(dismiss_inbox_item);
+
+    let vapid_public_key_route = warp::path("notification")
+        .and(warp::path!("web-push" / "vapid-public-key"))
+        .and(warp::get())
+        .and_then(get_vapid_public_key);
+
+    let register_push_subscription_route = warp::path("notification")
+        .and(warp::path!("users" / String / "push-subscriptions"))
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_auth())
+        .and(with_store(push_subscription_store.clone()))
+        .and_then(register_push_subscription);
+
+    let list_push_subscriptions_route = warp::path("notification")
+        .and(warp::path!("users" / String / "push-subscriptions"))
+        .and(warp::get())
+        .and(with_auth())
+        .and(with_store(push_subscription_store.clone()))
+        .and_then(list_push_subscriptions);
 
+    let delete_push_subscription_route = warp::path("notification")
+        .and(warp::path!(
+            "users" / String / "push-subscriptions" / String
+        ))
+        .and(warp::delete())
+        .and(with_auth())
+        .and(with_store(push_subscription_store.clone()))
+        .and_then(delete_push_subscription);
+
+    let register_webhook_route = warp::path("notification")
+        .and(warp::path!("webhooks"))
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_api_auth())
+        .and(with_store(webhook_store.clone()))
+        .and(with_store(webhook_delivery_store.clone()))
+        .and_then(register_webhook);
+
+    let list_webhooks_route = warp::path("notification")
+        .and(warp::path!("webhooks"))
+        .and(warp::get())
+        .and(with_api_auth())
+        .and(with_store(webhook_store.clone()))
+        .and_then(list_webhooks);
+
+    let delete_webhook_route = warp::path("notification")
+        .and(warp::path!("webhooks" / String))
+        .and(warp::delete())
+        .and(with_api_auth())
+        .and(with_store(webhook_store.clone()))
+        .and(with_store(webhook_delivery_store.clone()))
+        .and_then(delete_webhook);
+
+    let list_webhook_deliveries_route = warp::path("notification")
+        .and(warp::path!("webhooks" / String / "deliveries"))
+        .and(warp::get())
+        .and(with_api_auth())
+        .and(with_store(webhook_store.clone()))
+        .and(with_store(webhook_delivery_store.clone()))
+        .and_then(list_webhook_deliveries);
+
+    let list_scheduled_route = warp::path("notification")
+        .and(warp::path!("scheduled"))
+        .and(warp::get())
+        .and(with_caller_identity())
+        .and(with_store(schedule_store.clone()))
+        .and_then(list_scheduled);
+
+    let cancel_scheduled_route = warp::path("notification")
+        .and(warp::path!("scheduled" / String))
+        .and(warp::delete())
+        .and(with_caller_identity())
+        .and(with_store(schedule_store.clone()))
+        .and_then(cancel_scheduled);
+
+    let email_rate_limiter = Arc::new(RateLimiter::default());
+    let email_rate_limiter_pruner = email_rate_limiter.clone();
+    tokio::spawn(async move { prune_idle_usage(email_rate_limiter_pruner).await });
     let send_email_route = warp::path("notification")
         .and(warp::path!("send-email"))
         .and(warp::post())
         .and(warp::body::json())
         .and(with_isc_api_auth()) // Add authentication here
-        .and_then(send_email);
+        .and(with_store(email_store.clone()))
+        .and(with_store(suppression_store.clone()))
+        .and(with_rate_limiter(email_rate_limiter.clone()))
+        .and(with_store(digest_store.clone()))
+        .and(warp::header::optional::<String>(IDEMPOTENCY_KEY_HEADER))
+        .and(with_store(idempotency_store.clone()))
+        .and_then(
+            |email_request: EmailRequest,
+             claims: ISCClaims,
+             emails,
+             suppressions,
+             rate_limiter,
+             digests,
+             idempotency_key: Option<String>,
+             store| {
+                let scope = format!("send-email|{}", claims.sub);
+                let fingerprint = fingerprint(&email_request);
+                idempotent(
+                    store,
+                    scope,
+                    idempotency_key,
+                    fingerprint,
+                    send_email(
+                        email_request,
+                        claims,
+                        emails,
+                        suppressions,
+                        rate_limiter,
+                        digests,
+                    ),
+                )
+            },
+        );
+
+    let email_status_route = warp::path("notification")
+        .and(warp::path!("emails" / String))
+        .and(warp::get())
+        .and(with_isc_api_auth())
+        .and(with_store(email_store.clone()))
+        .and_then(get_email_status);
+
+    let sms_rate_limiter = Arc::new(RateLimiter::default());
+    let sms_rate_limiter_pruner = sms_rate_limiter.clone();
+    tokio::spawn(async move { prune_idle_usage(sms_rate_limiter_pruner).await });
+    let send_sms_route = warp::path("notification")
+        .and(warp::path!("send-sms"))
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_isc_api_auth())
+        .and(with_rate_limiter(sms_rate_limiter))
+        .and(warp::header::optional::<String>(IDEMPOTENCY_KEY_HEADER))
+        .and(with_store(idempotency_store.clone()))
+        .and_then(
+            |sms_request: SmsRequest,
+             claims: ISCClaims,
+             rate_limiter,
+             idempotency_key: Option<String>,
+             store| {
+                let scope = format!("send-sms|{}", claims.sub);
+                let fingerprint = fingerprint(&sms_request);
+                idempotent(
+                    store,
+                    scope,
+                    idempotency_key,
+                    fingerprint,
+                    send_sms(sms_request, claims, rate_limiter),
+                )
+            },
+        );
+
+    // SES bounce and complaint notifications, delivered by SNS or posted directly
+    let ses_events_route = warp::path("notification")
+        .and(warp::path!("ses" / "events"))
+        .and(warp::post())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::body::bytes())
+        .and(with_store(suppression_store.clone()))
+        .and(with_store(email_store.clone()))
+        .and(with_store(ses_message_store.clone()))
+        .and_then(ingest_ses_event);
+
+    let list_suppressions_route = warp::path("notification")
+        .and(warp::path!("suppressions"))
+        .and(warp::get())
+        .and(with_admin_auth())
+        .and(with_store(suppression_store.clone()))
+        .and_then(list_suppressions);
+
+    let list_channels_route = warp::path("notification")
+        .and(warp::path!("admin" / "channels"))
+        .and(warp::get())
+        .and(with_admin_auth())
+        .and(with_channels(channels.clone()))
+        .and_then(list_channels);
+
+    let list_connections_route = warp::path("notification")
+        .and(warp::path!("admin" / "channels" / String / "connections"))
+        .and(warp::get())
+        .and(with_admin_auth())
+        .and(with_channels(channels.clone()))
+        .and_then(list_connections);
+
+    let close_channel_route = warp::path("notification")
+        .and(warp::path!("admin" / "channels" / String))
+        .and(warp::delete())
+        .and(with_admin_auth())
+        .and(with_channels(channels.clone()))
+        .and_then(close_channel);
+
+    let disconnect_connection_route = warp::path("notification")
+        .and(warp::path!("admin" / "connections" / String))
+        .and(warp::delete())
+        .and(with_admin_auth())
+        .and(with_channels(channels.clone()))
+        .and_then(disconnect_connection);
+
+    let disconnect_user_route = warp::path("notification")
+        .and(warp::path!("admin" / "users" / String / "connections"))
+        .and(warp::delete())
+        .and(with_admin_auth())
+        .and(with_channels(channels.clone()))
+        .and_then(disconnect_user);
+
+    let broadcast_route = warp::path("notification")
+        .and(warp::path!("admin" / "broadcast"))
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_admin_auth())
+        .and(with_channels(channels.clone()))
+        .and_then(broadcast);
+
+    let remove_suppression_route = warp::path("notification")
+        .and(warp::path!("suppressions" / String))
+        .and(warp::delete())
+        .and(with_admin_auth())
+        .and(with_store(suppression_store.clone()))
+        .and_then(remove_suppression);
 
     // Serve OpenAPI spec
     let api_doc = warp::path("notification")
@@ -133,12 +939,50 @@ async fn main() {
     let routes = websocket_route
         .or(publish_route)
         .or(group_publish_route)
+        .or(batch_publish_route)
+        .or(presence_route)
+        .or(receipts_route)
+        .or(get_preferences_route)
+        .or(put_preferences_route)
+        .or(delete_preferences_route)
+        .or(notify_route)
+        .or(list_inbox_route)
+        .or(dismiss_inbox_item_route)
+        .or(vapid_public_key_route)
+        .or(register_push_subscription_route)
+        .or(list_push_subscriptions_route)
+        .or(delete_push_subscription_route)
+        .or(register_webhook_route)
+        .or(list_webhooks_route)
+        .or(delete_webhook_route)
+        .or(list_webhook_deliveries_route)
+        .or(list_scheduled_route)
+        .or(cancel_scheduled_route)
         .or(api_doc)
         .or(send_email_route)
+        .or(email_status_route)
+        .or(send_sms_route)
+        .or(ses_events_route)
+        .or(list_suppressions_route)
+        .or(remove_suppression_route)
+        .or(list_channels_route)
+        .or(list_connections_route)
+        .or(close_channel_route)
+        .or(disconnect_connection_route)
+        .or(disconnect_user_route)
+        .or(broadcast_route)
         .or(swagger_ui)
         .or(metrics_route);
 
-    warp::serve(routes).run(([0, 0, 0, 0], 3030)).await;
+    // On SIGTERM the server stops accepting connections and finishes the requests it has;
+    // upgraded sockets are drained separately
+    tokio::spawn(wait_for_signal());
+    let (_, server) =
+        warp::serve(routes).bind_with_graceful_shutdown(([0, 0, 0, 0], 3030), SHUTDOWN.started());
+    let server = tokio::spawn(server);
+
+    SHUTDOWN.started().await;
+    drain(channels, consumer, workers, server).await;
 }
 
 // Serve Swagger UI assets
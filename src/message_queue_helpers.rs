@@ -1,19 +1,54 @@
+use chrono::Utc;
 use futures::StreamExt;
 use lapin::Error as LapinError;
 use lapin::{
-    options::{BasicAckOptions, BasicConsumeOptions},
+    options::{BasicAckOptions, BasicCancelOptions, BasicConsumeOptions},
     Channel as RabbitChannel,
 };
-use tokio::time::{sleep, Duration};
+use tokio::{
+    sync::mpsc::UnboundedReceiver,
+    time::{sleep, Duration},
+};
+
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use uuid::Uuid;
 
+use crate::config::CONFIG;
+use crate::group_membership::{service_auth_header, SharedGroupCache};
+use crate::outbound::OutboundMessage;
+use crate::preferences::{
+    email_routed_message, routed_to_websocket, DeliveryRoute, PreferenceStore, RoutedEmailStores,
+};
+use crate::prom_helpers::MESSAGES_EXPIRED;
 use crate::requests::RabbitMessage;
-use crate::shared::{connect_rabbitmq, Channels};
+use crate::responses::DeliveryEnvelope;
+use crate::shared::{
+    bind_channel, connect_rabbitmq, declare_replica_queue, unbind_channel, BindingChange, Channels,
+    CHANNEL_EXCHANGE, GROUP_CHANNEL_PREFIX, LEGACY_CHANNEL_EXCHANGE, USER_CHANNEL_PREFIX,
+};
+use crate::shutdown::SHUTDOWN;
 
-pub async fn consume_messages(channels: Channels) {
+pub async fn consume_messages(
+    channels: Channels,
+    group_cache: SharedGroupCache,
+    preferences: PreferenceStore,
+    email_stores: RoutedEmailStores,
+    mut bindings: UnboundedReceiver<BindingChange>,
+) {
     loop {
         match connect_rabbitmq().await {
             Ok(rabbit_channel) => {
-                if let Err(e) = process_rabbitmq_messages(rabbit_channel, channels.clone()).await {
+                if let Err(e) = process_rabbitmq_messages(
+                    rabbit_channel,
+                    channels.clone(),
+                    group_cache.clone(),
+                    &preferences,
+                    &email_stores,
+                    &mut bindings,
+                )
+                .await
+                {
                     eprintln!("Error processing messages: {:?}", e);
                 }
             }
@@ -22,53 +57,344 @@ pub async fn consume_messages(channels: Channels) {
             }
         }
 
+        if SHUTDOWN.is_draining() {
+            return;
+        }
+
         // Wait before retrying
         eprintln!("Reconnecting to RabbitMQ in 5 seconds...");
-        sleep(Duration::from_secs(5)).await;
+        tokio::select! {
+            _ = sleep(Duration::from_secs(5)) => {}
+            _ = SHUTDOWN.started() => return,
+        }
+    }
+}
+
+// Logged once per replica, as a producer still on the legacy exchange publishes there every time
+static LEGACY_PUBLISH_WARNED: AtomicBool = AtomicBool::new(false);
+
+fn warn_legacy_publish() {
+    if !LEGACY_PUBLISH_WARNED.swap(true, Ordering::Relaxed) {
+        eprintln!(
+            "Received a message on the deprecated {} exchange, producers should publish to {} with the channel name as routing key",
+            LEGACY_CHANNEL_EXCHANGE, CHANNEL_EXCHANGE
+        );
     }
 }
 
 pub async fn process_rabbitmq_messages(
     rabbit_channel: RabbitChannel,
     channels: Channels,
+    group_cache: SharedGroupCache,
+    preferences: &PreferenceStore,
+    email_stores: &RoutedEmailStores,
+    bindings: &mut UnboundedReceiver<BindingChange>,
 ) -> Result<(), LapinError> {
+    let queue_name = declare_replica_queue(&rabbit_channel).await?;
+
+    // The queue is new, so bind everything subscribed right now; changes queued up before
+    // this point are covered by that, later ones are applied as they arrive
+    while bindings.try_recv().is_ok() {}
+    let subscribed = channels.lock().await.names();
+    for name in &subscribed {
+        bind_channel(&rabbit_channel, &queue_name, name).await?;
+    }
+    println!(
+        "Bound replica queue {} to {} channel(s)",
+        queue_name,
+        subscribed.len()
+    );
+
     let mut consumer = rabbit_channel
         .basic_consume(
-            "real-time-updates-queue",
+            &queue_name,
             "consumer_tag",
             BasicConsumeOptions::default(),
             Default::default(),
         )
         .await?;
 
-    while let Some(delivery) = consumer.next().await {
-        match delivery {
-            Ok(delivery) => {
-                // Handle message processing and acknowledgment
-                let message = String::from_utf8_lossy(&delivery.data).to_string();
-                println!("Received message from RabbitMQ: {}", message);
-
-                if let Ok(rabbit_message) = serde_json::from_str::<RabbitMessage>(&message) {
-                    let channels_lock = channels.lock().await;
-                    if let Some(channel) = channels_lock.get(&rabbit_message.channel_id) {
-                        let _ = channel.tx.send(rabbit_message.message.clone());
-                        delivery.ack(BasicAckOptions::default()).await?;
-                    } else {
-                        println!(
-                            "Message for non-existent channel: {}",
-                            rabbit_message.channel_id
-                        );
+    loop {
+        tokio::select! {
+            delivery = consumer.next() => match delivery {
+                Some(Ok(delivery)) => {
+                    if delivery.exchange.as_str() == LEGACY_CHANNEL_EXCHANGE {
+                        warn_legacy_publish();
                     }
-                } else {
-                    println!("Failed to deserialize message from RabbitMQ");
+                    handle_delivery(
+                        &delivery.data,
+                        &channels,
+                        &group_cache,
+                        preferences,
+                        email_stores,
+                    )
+                    .await;
+                    delivery.ack(BasicAckOptions::default()).await?;
+                }
+                Some(Err(e)) => {
+                    eprintln!("Error receiving message: {:?}", e);
+                    return Err(e); // Return error to trigger reconnection
+                }
+                None => return Ok(()),
+            },
+            Some(change) = bindings.recv() => match change {
+                BindingChange::Bind(name) => {
+                    bind_channel(&rabbit_channel, &queue_name, &name).await?;
                 }
+                BindingChange::Unbind(name) => {
+                    unbind_channel(&rabbit_channel, &queue_name, &name).await?;
+                }
+            },
+            // Only checked between deliveries, so the one being handled is acked first
+            _ = SHUTDOWN.started() => {
+                rabbit_channel
+                    .basic_cancel("consumer_tag", BasicCancelOptions::default())
+                    .await?;
+                println!("Cancelled RabbitMQ consumer");
+                return Ok(());
             }
-            Err(e) => {
-                eprintln!("Error receiving message: {:?}", e);
-                return Err(e); // Return error to trigger reconnection
+        }
+    }
+}
+
+// Parse a message off the channel exchange, or None when it is malformed or has expired
+pub fn decode_delivery(data: &[u8]) -> Option<RabbitMessage> {
+    let message = String::from_utf8_lossy(data).to_string();
+    println!("Received message from RabbitMQ: {}", message);
+
+    let Ok(mut rabbit_message) = serde_json::from_str::<RabbitMessage>(&message) else {
+        println!("Failed to deserialize message from RabbitMQ");
+        return None;
+    };
+
+    // Stale messages are never delivered, even if RabbitMQ hasn't discarded them
+    if let Some(expires_at) = rabbit_message.expires_at {
+        if expires_at <= Utc::now() {
+            println!(
+                "Dropping expired message {} for {}",
+                rabbit_message.message_id, rabbit_message.channel_id
+            );
+            MESSAGES_EXPIRED.inc();
+            return None;
+        }
+    }
+
+    // Messages from older publishers carry no id
+    if rabbit_message.message_id.is_empty() {
+        rabbit_message.message_id = Uuid::new_v4().to_string();
+    }
+
+    Some(rabbit_message)
+}
+
+async fn handle_delivery(
+    data: &[u8],
+    channels: &Channels,
+    group_cache: &SharedGroupCache,
+    preferences: &PreferenceStore,
+    email_stores: &RoutedEmailStores,
+) {
+    let Some(rabbit_message) = decode_delivery(data) else {
+        return;
+    };
+
+    let envelope = DeliveryEnvelope {
+        id: &rabbit_message.message_id,
+        channel: &rabbit_message.channel_id,
+        message: &rabbit_message.message,
+        expires_at: rabbit_message.expires_at,
+        collapse_key: rabbit_message.collapse_key.as_deref(),
+    }
+    .encode();
+    let outbound = OutboundMessage {
+        priority: rabbit_message.priority,
+        expires_at: rabbit_message.expires_at,
+        collapse_key: rabbit_message
+            .collapse_key
+            .clone()
+            .map(|key| (rabbit_message.channel_id.clone(), key)),
+        payload: envelope,
+        plain: Some(rabbit_message.message.clone()),
+        id: Some(rabbit_message.message_id.clone()),
+        publisher: rabbit_message.publisher.clone(),
+    };
+
+    if let Some(group_id) = rabbit_message.channel_id.strip_prefix(GROUP_CHANNEL_PREFIX) {
+        deliver_to_group(
+            group_id,
+            &outbound,
+            rabbit_message.event_type.as_deref(),
+            channels,
+            group_cache,
+            preferences,
+        )
+        .await;
+        return;
+    }
+
+    if let Some(user_id) = rabbit_message.channel_id.strip_prefix(USER_CHANNEL_PREFIX) {
+        deliver_to_user(user_id, &outbound, channels).await;
+        return;
+    }
+
+    deliver_to_channel(
+        &rabbit_message,
+        &outbound,
+        channels,
+        preferences,
+        email_stores,
+    )
+    .await;
+}
+
+// Users with a socket on a local subscription matching the channel
+async fn subscribed_users(channels: &Channels, channel_id: &str) -> HashSet<String> {
+    let channels_lock = channels.lock().await;
+    channels_lock
+        .matching(channel_id)
+        .into_iter()
+        .flat_map(|channel| channel.connections.values())
+        .filter_map(|connection| connection.user_id.clone())
+        .collect()
+}
+
+// Deliver to the exact subscribers and every matching pattern subscription. Typed messages only
+// reach users who route their type to the WebSocket, and subscribers routing it to email are
+// emailed instead, once each. As for groups, their preferences are looked up without holding
+// the registry.
+async fn deliver_to_channel(
+    rabbit_message: &RabbitMessage,
+    message: &OutboundMessage,
+    channels: &Channels,
+    preferences: &PreferenceStore,
+    email_stores: &RoutedEmailStores,
+) {
+    let channel_id = rabbit_message.channel_id.as_str();
+    let event_type = rabbit_message.event_type.as_deref();
+
+    let mut websocket_users = HashSet::new();
+    let mut email_users = vec![];
+    if let Some(event_type) = event_type {
+        for user_id in subscribed_users(channels, channel_id).await {
+            // Users without preferences get every event type over the WebSocket
+            let Some(user) = preferences.get(&user_id).await else {
+                websocket_users.insert(user_id);
+                continue;
+            };
+            match user.route(event_type) {
+                DeliveryRoute::Websocket => {
+                    websocket_users.insert(user_id);
+                }
+                DeliveryRoute::Email => email_users.push(user),
+                DeliveryRoute::Drop => {}
+            }
+        }
+    }
+
+    let channels_lock = channels.lock().await;
+    let matching = channels_lock.matching(channel_id);
+    if matching.is_empty() {
+        // Possible while an unbind is in flight after the last socket left
+        println!("Message for non-existent channel: {}", channel_id);
+    }
+    for channel in matching {
+        for connection in channel.connections.values() {
+            // API clients' sockets are not subject to preferences
+            let wanted = match (event_type, &connection.user_id) {
+                (Some(_), Some(user_id)) => websocket_users.contains(user_id),
+                _ => true,
+            };
+            if wanted {
+                connection.queue.push(message.clone());
+            }
+        }
+    }
+    drop(channels_lock);
+
+    if let (Some(event_type), Some(_)) = (event_type, &CONFIG.preferences.email_service) {
+        for user in &email_users {
+            email_routed_message(user, event_type, rabbit_message, email_stores).await;
+        }
+    }
+}
+
+// Deliver a group message to each member's own channel, the one named by their user id, so a
+// member gets it once per socket rather than once per channel they are subscribed to
+async fn deliver_to_group(
+    group_id: &str,
+    message: &OutboundMessage,
+    event_type: Option<&str>,
+    channels: &Channels,
+    group_cache: &SharedGroupCache,
+    preferences: &PreferenceStore,
+) {
+    let Some(auth_header) = service_auth_header() else {
+        eprintln!(
+            "IAM_API_TOKEN is not set, unable to resolve group {}",
+            group_id
+        );
+        return;
+    };
+
+    let members = match group_cache.get_members(group_id, auth_header).await {
+        Ok(members) => members,
+        Err(e) => {
+            println!("Failed to get members of group {}: {}", group_id, e);
+            return;
+        }
+    };
+
+    // Preferences are looked up without holding the registry, and only for members with a
+    // channel on this replica
+    let local_members: Vec<String> = {
+        let channels_lock = channels.lock().await;
+        members
+            .iter()
+            .filter(|member| channels_lock.get(member).is_some())
+            .cloned()
+            .collect()
+    };
+    let mut recipients = Vec::with_capacity(local_members.len());
+    for member in local_members {
+        if routed_to_websocket(preferences, Some(&member), event_type).await {
+            recipients.push(member);
+        }
+    }
+
+    let channels_lock = channels.lock().await;
+    let mut delivered = 0;
+    for member in &recipients {
+        if let Some(channel) = channels_lock.get(member) {
+            delivered += channel
+                .connections
+                .values()
+                .filter(|connection| connection.queue.push(message.clone()))
+                .count();
+        }
+    }
+
+    println!(
+        "Delivered group {} message to {} local connection(s)",
+        group_id, delivered
+    );
+}
+
+// Deliver a message to every local socket of the user, whichever channel it subscribed to
+async fn deliver_to_user(user_id: &str, message: &OutboundMessage, channels: &Channels) {
+    let channels_lock = channels.lock().await;
+    let mut delivered = 0;
+    for channel in channels_lock.values() {
+        for connection in channel.connections.values() {
+            if connection.user_id.as_deref() == Some(user_id)
+                && connection.queue.push(message.clone())
+            {
+                delivered += 1;
             }
         }
     }
 
-    Ok(())
+    println!(
+        "Delivered message for user {} to {} local connection(s)",
+        user_id, delivered
+    );
 }
@@ -0,0 +1,383 @@
+use std::{convert::Infallible, sync::Arc};
+
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use ginger_shared_rs::rocket_utils::APIClaims;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::time::{interval, Duration};
+use uuid::Uuid;
+use warp::{http::StatusCode, Filter};
+use IAMService::{
+    apis::default_api::{identity_get_user, IdentityGetUserParams},
+    get_configuration,
+};
+
+use crate::{
+    channel_acl::validate_message,
+    config::CONFIG,
+    digest::DigestStore,
+    email_queue::EmailStore,
+    inbox::{add_to_inbox, inbox_contains, InboxItem, InboxStore},
+    outbound::Priority,
+    preferences::{send_routed_email, DeliveryRoute, PreferenceStore, UserPreferences},
+    presence::SharedPresence,
+    requests::{NotifyRequest, PublishRequest},
+    responses::NotifyResponse,
+    rest_bridge::publish_to_channel,
+    shared::{RABBIT_POOL, USER_CHANNEL_PREFIX},
+    store::Store,
+    suppression::SuppressionStore,
+    web_push::{push_to_user, PushSubscriptionStore},
+};
+
+// An email sent `notify.email_fallback_secs` after a notification, unless the user shows up
+#[derive(Deserialize, Serialize, Clone)]
+pub struct PendingEmail {
+    pub id: String,
+    pub user_id: String,
+    pub to: String,
+    pub subject: String,
+    pub message: String,
+    pub priority: Priority,
+    pub created_at: DateTime<Utc>,
+    pub due_at: DateTime<Utc>,
+}
+
+// Keyed by notification id
+pub type PendingEmailStore = Store<PendingEmail>;
+
+// Everything a notification may be delivered through
+#[derive(Clone)]
+pub struct NotifyContext {
+    pub preferences: PreferenceStore,
+    pub presence: SharedPresence,
+    pub inbox: InboxStore,
+    pub push_subscriptions: PushSubscriptionStore,
+    pub pending: PendingEmailStore,
+    pub emails: EmailStore,
+    pub digests: DigestStore,
+    pub suppressions: SuppressionStore,
+}
+
+pub type SharedNotifyContext = Arc<NotifyContext>;
+
+// Filter to inject the notify context into the route handlers
+pub fn with_notify_context(
+    context: SharedNotifyContext,
+) -> impl Filter<Extract = (SharedNotifyContext,), Error = Infallible> + Clone {
+    warp::any().map(move || context.clone())
+}
+
+// Replace `{field}` with the payload's top-level fields; strings are inserted unquoted
+fn render(template: &str, payload: &Value) -> String {
+    let mut rendered = template.to_string();
+    if let Some(fields) = payload.as_object() {
+        for (field, value) in fields {
+            let value = match value {
+                Value::String(value) => value.clone(),
+                other => other.to_string(),
+            };
+            rendered = rendered.replace(&format!("{{{}}}", field), &value);
+        }
+    }
+    rendered
+}
+
+// Subject and body of the email for a notification
+fn render_email(request: &NotifyRequest) -> (String, String) {
+    match request
+        .template
+        .as_ref()
+        .and_then(|name| CONFIG.templates.get(name))
+    {
+        Some(template) => (
+            render(&template.subject, &request.payload),
+            render(&template.message, &request.payload),
+        ),
+        None => (
+            CONFIG
+                .preferences
+                .email_subject
+                .replace("{type}", &request.event_type),
+            match &request.payload {
+                Value::String(message) => message.clone(),
+                payload => serde_json::to_string_pretty(payload).unwrap_or_default(),
+            },
+        ),
+    }
+}
+
+// The address from the user's preferences, falling back to their IAM profile
+async fn resolve_email(
+    user: Option<&UserPreferences>,
+    user_id: &str,
+    auth_header: String,
+) -> Option<String> {
+    if let Some(email) = user.and_then(|user| user.email.clone()) {
+        return Some(email);
+    }
+
+    let iam_config = get_configuration(Some(auth_header));
+    match identity_get_user(
+        &iam_config,
+        IdentityGetUserParams {
+            user_id: user_id.to_string(),
+        },
+    )
+    .await
+    {
+        Ok(profile) => Some(profile.email).filter(|email| !email.is_empty()),
+        Err(e) => {
+            println!("Failed to look up email of user {}: {:?}", user_id, e);
+            None
+        }
+    }
+}
+
+fn bad_request(message: String) -> Box<dyn warp::Reply> {
+    Box::new(warp::reply::with_status(
+        warp::reply::json(&message),
+        StatusCode::BAD_REQUEST,
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/notification/users/{user_id}/notify",
+    params(
+        ("user_id" = String, Path, description = "The user to notify")
+    ),
+    request_body = NotifyRequest,
+    responses(
+        (status = 200, description = "How the notification was delivered; empty when the user drops this type", body = NotifyResponse),
+        (status = 400, description = "Missing type, unknown template or payload too large"),
+        (status = 503, description = "Unable to connect to RabbitMQ")
+    ),
+    security(("apiBearerAuth" = [])),
+    tag = "default"
+)]
+pub async fn notify_user(
+    user_id: String,
+    request: NotifyRequest,
+    claims: APIClaims,
+    auth_header: String,
+    context: SharedNotifyContext,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    if request.event_type.is_empty() {
+        return Ok(bad_request("type is required".to_string()));
+    }
+    if let Some(template) = &request.template {
+        if !CONFIG.templates.contains_key(template) {
+            return Ok(bad_request(format!("Unknown template {}", template)));
+        }
+    }
+
+    // What clients receive, over the socket or from their inbox
+    let message = serde_json::json!({
+        "type": request.event_type,
+        "payload": request.payload,
+    })
+    .to_string();
+    if let Err(e) = validate_message(&message) {
+        return Ok(bad_request(e));
+    }
+
+    let id = Uuid::new_v4().to_string();
+    let now = Utc::now();
+    let priority = request.priority.unwrap_or_default();
+    let user = context.preferences.get(&user_id).await;
+    let route = user
+        .as_ref()
+        .map(|user| user.route(&request.event_type))
+        .unwrap_or_default();
+    let online = context.presence.is_online(&user_id).await;
+
+    let mut response = NotifyResponse {
+        id: id.clone(),
+        online,
+        delivered_via: vec![],
+        email_fallback_at: None,
+    };
+
+    if route == DeliveryRoute::Drop {
+        println!(
+            "User {} drops {} notifications, not notifying on behalf of {}",
+            user_id, request.event_type, claims.sub
+        );
+        return Ok(Box::new(warp::reply::json(&response)));
+    }
+
+    if route == DeliveryRoute::Websocket && online {
+        let publish_request = PublishRequest {
+            message,
+            priority: Some(priority),
+            event_type: Some(request.event_type.clone()),
+            ..Default::default()
+        };
+        let user_channel = format!("{}{}", USER_CHANNEL_PREFIX, user_id);
+        let published = match RABBIT_POOL.channel().await {
+            Ok(rabbit_channel) => {
+                let publisher = format!("api:{}", claims.sub);
+                publish_to_channel(
+                    &rabbit_channel,
+                    &user_channel,
+                    &publish_request,
+                    &id,
+                    &publisher,
+                )
+                .await
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = published {
+            println!("Failed to notify user {}: {:?}", user_id, e);
+            return Ok(Box::new(warp::reply::with_status(
+                warp::reply::json(&"Unable to connect to RabbitMQ"),
+                StatusCode::SERVICE_UNAVAILABLE,
+            )));
+        }
+        response.delivered_via.push("websocket".to_string());
+        return Ok(Box::new(warp::reply::json(&response)));
+    }
+
+    let (subject, email_message) = render_email(&request);
+    let to = resolve_email(user.as_ref(), &user_id, auth_header).await;
+    let hold_for_digest = user
+        .as_ref()
+        .is_some_and(|user| user.holds_email(priority, now));
+
+    // Users routing the type to email get it straight away, falling back to the inbox
+    if route == DeliveryRoute::Email {
+        if let Some(to) = &to {
+            match send_routed_email(
+                to,
+                subject,
+                email_message,
+                hold_for_digest,
+                &context.emails,
+                &context.digests,
+                &context.suppressions,
+            )
+            .await
+            {
+                Ok(()) => {
+                    response.delivered_via.push("email".to_string());
+                    return Ok(Box::new(warp::reply::json(&response)));
+                }
+                Err(e) => println!("Unable to email user {}: {}", user_id, e),
+            }
+        }
+
+        add_to_inbox(
+            &context.inbox,
+            &user_id,
+            InboxItem {
+                id,
+                event_type: request.event_type,
+                message,
+                created_at: now,
+            },
+        )
+        .await;
+        response.delivered_via.push("inbox".to_string());
+        return Ok(Box::new(warp::reply::json(&response)));
+    }
+
+    // Offline: keep it in the inbox and push it to their browsers, then email it unless the
+    // user shows up in the meantime
+    if push_to_user(&context.push_subscriptions, &user_id, &message, priority).await > 0 {
+        response.delivered_via.push("push".to_string());
+    }
+    add_to_inbox(
+        &context.inbox,
+        &user_id,
+        InboxItem {
+            id: id.clone(),
+            event_type: request.event_type.clone(),
+            message,
+            created_at: now,
+        },
+    )
+    .await;
+    response.delivered_via.push("inbox".to_string());
+
+    let fallback_enabled =
+        CONFIG.notify.email_fallback_secs > 0 && CONFIG.preferences.email_service.is_some();
+    if let (true, Some(to)) = (fallback_enabled, to) {
+        let due_at = now + ChronoDuration::seconds(CONFIG.notify.email_fallback_secs as i64);
+        context
+            .pending
+            .insert(
+                id.clone(),
+                PendingEmail {
+                    id,
+                    user_id,
+                    to,
+                    subject,
+                    message: email_message,
+                    priority,
+                    created_at: now,
+                    due_at,
+                },
+            )
+            .await;
+        response.email_fallback_at = Some(due_at);
+    }
+
+    Ok(Box::new(warp::reply::json(&response)))
+}
+
+// Email pending notifications once due, unless the user has been online since or already
+// dismissed the notification from their inbox
+pub async fn run_email_fallbacks(context: SharedNotifyContext) {
+    let mut ticker = interval(Duration::from_secs(
+        CONFIG.notify.fallback_poll_interval_secs.max(1),
+    ));
+
+    loop {
+        ticker.tick().await;
+
+        let now = Utc::now();
+        let due = context
+            .pending
+            .values()
+            .await
+            .into_iter()
+            .filter(|pending| pending.due_at <= now)
+            .collect::<Vec<_>>();
+
+        for pending in due {
+            context.pending.remove(&pending.id).await;
+
+            let seen = context
+                .presence
+                .seen_since(&pending.user_id, pending.created_at)
+                .await;
+            if seen || !inbox_contains(&context.inbox, &pending.user_id, &pending.id).await {
+                continue;
+            }
+
+            let hold_for_digest = context
+                .preferences
+                .get(&pending.user_id)
+                .await
+                .is_some_and(|user| user.holds_email(pending.priority, now));
+            if let Err(e) = send_routed_email(
+                &pending.to,
+                pending.subject,
+                pending.message,
+                hold_for_digest,
+                &context.emails,
+                &context.digests,
+                &context.suppressions,
+            )
+            .await
+            {
+                println!(
+                    "Unable to send fallback email to user {}: {}",
+                    pending.user_id, e
+                );
+            }
+        }
+    }
+}
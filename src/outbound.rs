@@ -0,0 +1,326 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use utoipa::ToSchema;
+
+use crate::prom_helpers::{MESSAGES_EXPIRED, OUTBOUND_COALESCED, OUTBOUND_DROPPED};
+
+// Lowest first, so comparisons read naturally
+#[derive(
+    Deserialize, Serialize, ToSchema, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Bulk,
+    #[default]
+    Normal,
+    Critical,
+}
+
+impl Priority {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Priority::Bulk => "bulk",
+            Priority::Normal => "normal",
+            Priority::Critical => "critical",
+        }
+    }
+}
+
+// An encoded envelope waiting to be written to a socket
+#[derive(Clone, Debug)]
+pub struct OutboundMessage {
+    pub priority: Priority,
+    pub expires_at: Option<DateTime<Utc>>,
+    // The channel and the publisher's collapse key, as the same key may be used on other channels
+    pub collapse_key: Option<(String, String)>,
+    pub payload: String,
+    // The bare message, written instead of `payload` to clients that didn't ask for envelopes.
+    // None for control frames, which only those clients understand.
+    pub plain: Option<String>,
+    // The envelope id and who published the message, so clients can send receipts for it
+    pub id: Option<String>,
+    pub publisher: Option<String>,
+}
+
+impl OutboundMessage {
+    fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+// Bounded per-connection queue. Higher priorities are written first; when a slow client
+// falls behind, the oldest message of the lowest queued priority is dropped to make room.
+// Critical messages are never dropped, so they may take the queue over capacity.
+#[derive(Debug)]
+pub struct OutboundQueue {
+    capacity: usize,
+    // Indexed by priority, lowest first
+    lanes: Mutex<[VecDeque<OutboundMessage>; 3]>,
+    notify: Notify,
+    closed: AtomicBool,
+    // Set by `finish`: nothing more is accepted, but what is queued is still written
+    finishing: AtomicBool,
+    // The frame `finish` was given, written once every lane is empty
+    last: Mutex<Option<OutboundMessage>>,
+}
+
+fn lane(priority: Priority) -> usize {
+    priority as usize
+}
+
+impl OutboundQueue {
+    pub fn new(capacity: usize) -> Arc<Self> {
+        Arc::new(OutboundQueue {
+            capacity: capacity.max(1),
+            lanes: Mutex::new(Default::default()),
+            notify: Notify::new(),
+            closed: AtomicBool::new(false),
+            finishing: AtomicBool::new(false),
+            last: Mutex::new(None),
+        })
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    // Messages waiting to be written
+    pub fn queued(&self) -> usize {
+        self.lanes.lock().unwrap().iter().map(VecDeque::len).sum()
+    }
+
+    // Queue a message, returning false when it was dropped instead
+    pub fn push(&self, message: OutboundMessage) -> bool {
+        if self.closed.load(Ordering::Relaxed) || self.finishing.load(Ordering::Relaxed) {
+            return false;
+        }
+
+        {
+            let mut lanes = self.lanes.lock().unwrap();
+
+            // A newer message replaces a queued one for the same channel and collapse key, keeping
+            // its place
+            if let Some(key) = message.collapse_key.clone() {
+                let same_key =
+                    |queued: &OutboundMessage| queued.collapse_key.as_ref() == Some(&key);
+
+                if let Some(queued) = lanes[lane(message.priority)]
+                    .iter_mut()
+                    .find(|queued| same_key(queued))
+                {
+                    *queued = message;
+                    OUTBOUND_COALESCED.inc();
+                    return true;
+                }
+
+                let before: usize = lanes.iter().map(VecDeque::len).sum();
+                for queue in lanes.iter_mut() {
+                    queue.retain(|queued| !same_key(queued));
+                }
+                let after: usize = lanes.iter().map(VecDeque::len).sum();
+                OUTBOUND_COALESCED.inc_by((before - after) as u64);
+            }
+
+            let queued: usize = lanes.iter().map(VecDeque::len).sum();
+
+            if queued >= self.capacity {
+                let evictable = [Priority::Bulk, Priority::Normal]
+                    .into_iter()
+                    .filter(|priority| *priority <= message.priority)
+                    .find(|priority| !lanes[lane(*priority)].is_empty());
+
+                match evictable {
+                    Some(priority) => {
+                        lanes[lane(priority)].pop_front();
+                        OUTBOUND_DROPPED
+                            .with_label_values(&[priority.as_str()])
+                            .inc();
+                    }
+                    None if message.priority == Priority::Critical => {}
+                    None => {
+                        OUTBOUND_DROPPED
+                            .with_label_values(&[message.priority.as_str()])
+                            .inc();
+                        return false;
+                    }
+                }
+            }
+
+            lanes[lane(message.priority)].push_back(message);
+        }
+
+        self.notify.notify_one();
+        true
+    }
+
+    // Wait for the next message to write, skipping any that expired while queued.
+    // Returns None once the queue is closed, or finished, empty and its last frame written.
+    pub async fn pop(&self) -> Option<OutboundMessage> {
+        loop {
+            if self.closed.load(Ordering::Relaxed) {
+                return None;
+            }
+
+            {
+                let mut lanes = self.lanes.lock().unwrap();
+                let now = Utc::now();
+                for queue in lanes.iter_mut().rev() {
+                    while let Some(message) = queue.pop_front() {
+                        if message.is_expired(now) {
+                            MESSAGES_EXPIRED.inc();
+                            continue;
+                        }
+                        return Some(message);
+                    }
+                }
+            }
+
+            if self.finishing.load(Ordering::Relaxed) {
+                return self.last.lock().unwrap().take();
+            }
+
+            self.notify.notified().await;
+        }
+    }
+
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        self.notify.notify_one();
+    }
+
+    // Stop accepting messages and close once everything already queued has been written,
+    // followed by `last`. Only the first call's frame is written.
+    pub fn finish(&self, last: OutboundMessage) {
+        {
+            let mut slot = self.last.lock().unwrap();
+            if self.finishing.load(Ordering::Relaxed) {
+                return;
+            }
+            *slot = Some(last);
+            self.finishing.store(true, Ordering::Relaxed);
+        }
+        self.notify.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(priority: Priority, payload: &str) -> OutboundMessage {
+        OutboundMessage {
+            priority,
+            expires_at: None,
+            collapse_key: None,
+            payload: payload.to_string(),
+            plain: Some(payload.to_string()),
+            id: None,
+            publisher: None,
+        }
+    }
+
+    fn collapsing(priority: Priority, payload: &str, channel: &str, key: &str) -> OutboundMessage {
+        OutboundMessage {
+            collapse_key: Some((channel.to_string(), key.to_string())),
+            ..message(priority, payload)
+        }
+    }
+
+    // Everything the socket would be sent, closing it after what is queued
+    async fn drain(queue: &OutboundQueue) -> Vec<String> {
+        queue.finish(message(Priority::Critical, "close"));
+        let mut payloads = vec![];
+        while let Some(message) = queue.pop().await {
+            payloads.push(message.payload);
+        }
+        payloads
+    }
+
+    #[tokio::test]
+    async fn writes_higher_priorities_first() {
+        let queue = OutboundQueue::new(10);
+        queue.push(message(Priority::Bulk, "bulk"));
+        queue.push(message(Priority::Normal, "normal 1"));
+        queue.push(message(Priority::Critical, "critical"));
+        queue.push(message(Priority::Normal, "normal 2"));
+
+        assert_eq!(
+            drain(&queue).await,
+            ["critical", "normal 1", "normal 2", "bulk", "close"]
+        );
+    }
+
+    #[tokio::test]
+    async fn writes_the_close_frame_after_everything_queued() {
+        let queue = OutboundQueue::new(10);
+        queue.push(message(Priority::Bulk, "bulk"));
+        queue.finish(message(Priority::Critical, "reconnect"));
+        queue.finish(message(Priority::Critical, "disconnect"));
+
+        assert!(!queue.push(message(Priority::Critical, "late")));
+        assert_eq!(queue.pop().await.unwrap().payload, "bulk");
+        assert_eq!(queue.pop().await.unwrap().payload, "reconnect");
+        assert!(queue.pop().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn collapses_messages_with_the_same_key() {
+        let queue = OutboundQueue::new(10);
+        queue.push(collapsing(Priority::Normal, "price 1", "stocks", "price"));
+        queue.push(message(Priority::Normal, "other"));
+        queue.push(collapsing(Priority::Normal, "price 2", "stocks", "price"));
+        // A different priority moves the message to its own lane
+        queue.push(collapsing(Priority::Critical, "price 3", "stocks", "price"));
+
+        assert_eq!(queue.queued(), 2);
+        assert_eq!(drain(&queue).await, ["price 3", "other", "close"]);
+    }
+
+    #[tokio::test]
+    async fn keeps_messages_with_the_same_key_on_other_channels() {
+        let queue = OutboundQueue::new(10);
+        queue.push(collapsing(Priority::Normal, "stocks", "stocks", "price"));
+        queue.push(collapsing(Priority::Normal, "crypto", "crypto", "price"));
+
+        assert_eq!(drain(&queue).await, ["stocks", "crypto", "close"]);
+    }
+
+    #[tokio::test]
+    async fn drops_the_oldest_lowest_priority_message_when_full() {
+        let queue = OutboundQueue::new(2);
+        assert!(queue.push(message(Priority::Bulk, "bulk")));
+        assert!(queue.push(message(Priority::Normal, "normal 1")));
+        assert!(queue.push(message(Priority::Normal, "normal 2")));
+        assert!(!queue.push(message(Priority::Bulk, "bulk 2")));
+        assert!(queue.push(message(Priority::Critical, "critical 1")));
+        assert!(queue.push(message(Priority::Critical, "critical 2")));
+        // Critical messages are kept even over capacity
+        assert!(queue.push(message(Priority::Critical, "critical 3")));
+
+        assert_eq!(
+            drain(&queue).await,
+            ["critical 1", "critical 2", "critical 3", "close"]
+        );
+    }
+
+    #[tokio::test]
+    async fn skips_expired_messages() {
+        let queue = OutboundQueue::new(10);
+        queue.push(OutboundMessage {
+            expires_at: Some(Utc::now() - chrono::Duration::seconds(1)),
+            ..message(Priority::Critical, "stale")
+        });
+        queue.push(message(Priority::Normal, "fresh"));
+
+        assert_eq!(drain(&queue).await, ["fresh", "close"]);
+    }
+}
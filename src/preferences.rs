@@ -0,0 +1,702 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Duration as ChronoDuration, Timelike, Utc};
+use futures::StreamExt;
+use ginger_shared_rs::rocket_utils::Claims;
+use lapin::{
+    options::{
+        BasicAckOptions, BasicConsumeOptions, BasicPublishOptions, ExchangeDeclareOptions,
+        QueueBindOptions, QueueDeclareOptions,
+    },
+    types::FieldTable,
+    BasicProperties, Channel as RabbitChannel, Error as LapinError,
+};
+use serde::{Deserialize, Serialize};
+use tokio::time::{sleep, Duration};
+use utoipa::ToSchema;
+use warp::http::StatusCode;
+
+use crate::{
+    auth_helpers::forbidden_unless_self,
+    config::CONFIG,
+    digest::{add_to_digest, DigestFrequency, DigestStore},
+    email_queue::{queue_email, EmailStore},
+    group_membership::{service_auth_header, SharedGroupCache},
+    outbound::Priority,
+    requests::{EmailRequest, RabbitMessage},
+    senders::resolve_sender,
+    shared::{
+        connect_rabbitmq, open_rabbitmq_channel, CHANNEL_EXCHANGE, GROUP_CHANNEL_PREFIX,
+        RABBIT_POOL,
+    },
+    shutdown::SHUTDOWN,
+    store::Store,
+    suppression::{find_suppression, SuppressionStore},
+};
+
+#[derive(Deserialize, Serialize, ToSchema, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryRoute {
+    #[default]
+    Websocket,
+    Email,
+    Drop,
+}
+
+// Hours in the user's local time, given as an offset from UTC. Windows may wrap past midnight,
+// e.g. 22 to 7.
+#[derive(Deserialize, Serialize, ToSchema, Clone, Debug)]
+pub struct QuietHours {
+    pub start_hour: u32,
+    pub end_hour: u32,
+    #[serde(default)]
+    pub utc_offset_minutes: i32,
+}
+
+impl QuietHours {
+    fn validate(&self) -> Result<(), String> {
+        if self.start_hour > 23 || self.end_hour > 23 {
+            return Err("Quiet hours must be between 0 and 23".to_string());
+        }
+        if self.utc_offset_minutes.abs() > 14 * 60 {
+            return Err("utc_offset_minutes must be within 14 hours of UTC".to_string());
+        }
+        Ok(())
+    }
+
+    fn contains(&self, now: DateTime<Utc>) -> bool {
+        let hour = (now + ChronoDuration::minutes(self.utc_offset_minutes as i64)).hour();
+        if self.start_hour <= self.end_hour {
+            self.start_hour <= hour && hour < self.end_hour
+        } else {
+            hour >= self.start_hour || hour < self.end_hour
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, ToSchema, Clone)]
+pub struct PreferencesRequest {
+    pub email: Option<String>, // Where notifications routed to email are sent
+    #[serde(default)]
+    pub default_route: DeliveryRoute, // For event types not listed in `types`
+    #[serde(default)]
+    pub types: HashMap<String, DeliveryRoute>,
+    pub quiet_hours: Option<QuietHours>, // Emails are held for the daily digest during these hours
+}
+
+#[derive(Deserialize, Serialize, ToSchema, Clone)]
+pub struct UserPreferences {
+    pub user_id: String,
+    pub email: Option<String>,
+    pub default_route: DeliveryRoute,
+    pub types: HashMap<String, DeliveryRoute>,
+    pub quiet_hours: Option<QuietHours>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl UserPreferences {
+    pub fn route(&self, event_type: &str) -> DeliveryRoute {
+        self.types
+            .get(event_type)
+            .copied()
+            .unwrap_or(self.default_route)
+    }
+
+    // Emails during quiet hours wait for the daily digest, unless they are critical
+    pub fn holds_email(&self, priority: Priority, now: DateTime<Utc>) -> bool {
+        priority != Priority::Critical
+            && self
+                .quiet_hours
+                .as_ref()
+                .is_some_and(|quiet_hours| quiet_hours.contains(now))
+    }
+}
+
+// Keyed by user id
+pub type PreferenceStore = Store<UserPreferences>;
+
+// Of two copies of a user's preferences, keep the latest write
+pub fn prefer_newer(local: &UserPreferences, other: &UserPreferences) -> bool {
+    local.updated_at < other.updated_at
+}
+
+// Users without preferences get every event type over the WebSocket
+pub async fn route_for(
+    preferences: &PreferenceStore,
+    user_id: &str,
+    event_type: &str,
+) -> DeliveryRoute {
+    preferences
+        .get(user_id)
+        .await
+        .map(|preferences| preferences.route(event_type))
+        .unwrap_or_default()
+}
+
+// Whether a typed message should be pushed to a socket. Untyped messages and API clients'
+// sockets are not subject to preferences.
+pub async fn routed_to_websocket(
+    preferences: &PreferenceStore,
+    user_id: Option<&str>,
+    event_type: Option<&str>,
+) -> bool {
+    match (user_id, event_type) {
+        (Some(user_id), Some(event_type)) => {
+            route_for(preferences, user_id, event_type).await == DeliveryRoute::Websocket
+        }
+        _ => true,
+    }
+}
+
+// Preferences are written on whichever replica handles the request and replicated to the
+// others, since every replica routes the messages it delivers
+#[derive(Deserialize, Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+enum PreferencesEvent {
+    Updated { preferences: UserPreferences },
+    Deleted { user_id: String },
+}
+
+pub async fn declare_preferences_exchange(
+    rabbit_channel: &RabbitChannel,
+) -> Result<(), LapinError> {
+    rabbit_channel
+        .exchange_declare(
+            &CONFIG.preferences.exchange,
+            lapin::ExchangeKind::Fanout,
+            ExchangeDeclareOptions {
+                durable: true,
+                ..Default::default()
+            },
+            FieldTable::default(),
+        )
+        .await
+}
+
+async fn publish_event(event: &PreferencesEvent) -> Result<(), LapinError> {
+    let rabbit_channel = RABBIT_POOL.channel().await?;
+
+    rabbit_channel
+        .basic_publish(
+            &CONFIG.preferences.exchange,
+            "",
+            BasicPublishOptions::default(),
+            &serde_json::to_vec(event).unwrap(),
+            BasicProperties::default(),
+        )
+        .await?;
+    Ok(())
+}
+
+pub async fn consume_preference_updates(preferences: PreferenceStore) {
+    loop {
+        match open_rabbitmq_channel().await {
+            Ok(rabbit_channel) => {
+                if let Err(e) = process_preference_updates(rabbit_channel, &preferences).await {
+                    eprintln!("Error processing preference updates: {:?}", e);
+                }
+            }
+            Err(e) => {
+                eprintln!("Error connecting to RabbitMQ: {:?}", e);
+            }
+        }
+
+        eprintln!("Reconnecting to preference updates in 5 seconds...");
+        sleep(Duration::from_secs(5)).await;
+    }
+}
+
+async fn process_preference_updates(
+    rabbit_channel: RabbitChannel,
+    preferences: &PreferenceStore,
+) -> Result<(), LapinError> {
+    declare_preferences_exchange(&rabbit_channel).await?;
+
+    let queue = rabbit_channel
+        .queue_declare(
+            "",
+            QueueDeclareOptions {
+                exclusive: true,
+                auto_delete: true,
+                ..Default::default()
+            },
+            FieldTable::default(),
+        )
+        .await?;
+
+    rabbit_channel
+        .queue_bind(
+            queue.name().as_str(),
+            &CONFIG.preferences.exchange,
+            "",
+            QueueBindOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    let mut consumer = rabbit_channel
+        .basic_consume(
+            queue.name().as_str(),
+            "preferences_consumer",
+            BasicConsumeOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    while let Some(delivery) = consumer.next().await {
+        let delivery = delivery?;
+
+        match serde_json::from_slice::<PreferencesEvent>(&delivery.data) {
+            Ok(PreferencesEvent::Updated { preferences: user }) => {
+                // Events may arrive out of order; keep the latest write
+                let newer = preferences
+                    .get(&user.user_id)
+                    .await
+                    .is_none_or(|existing| !prefer_newer(&user, &existing));
+                if newer {
+                    preferences.insert(user.user_id.clone(), user).await;
+                }
+            }
+            Ok(PreferencesEvent::Deleted { user_id }) => {
+                preferences.remove(&user_id).await;
+            }
+            Err(e) => println!("Failed to deserialize preference update: {:?}", e),
+        }
+
+        delivery.ack(BasicAckOptions::default()).await?;
+    }
+
+    Ok(())
+}
+
+// The stores sending an email routed by preference goes through
+#[derive(Clone)]
+pub struct RoutedEmailStores {
+    pub emails: EmailStore,
+    pub digests: DigestStore,
+    pub suppressions: SuppressionStore,
+}
+
+// Emails routed by preference. Group messages are consumed from a queue shared by every
+// replica, so each is routed exactly once however many replicas are running.
+pub async fn route_emails(
+    preferences: PreferenceStore,
+    group_cache: SharedGroupCache,
+    stores: RoutedEmailStores,
+) {
+    loop {
+        match connect_rabbitmq().await {
+            Ok(rabbit_channel) => {
+                if let Err(e) =
+                    process_routed_emails(rabbit_channel, &preferences, &group_cache, &stores).await
+                {
+                    eprintln!("Error routing emails: {:?}", e);
+                }
+            }
+            Err(e) => {
+                eprintln!("Error connecting to RabbitMQ: {:?}", e);
+            }
+        }
+
+        if SHUTDOWN.is_draining() {
+            return;
+        }
+
+        eprintln!("Reconnecting email routing in 5 seconds...");
+        tokio::select! {
+            _ = sleep(Duration::from_secs(5)) => {}
+            _ = SHUTDOWN.started() => return,
+        }
+    }
+}
+
+async fn process_routed_emails(
+    rabbit_channel: RabbitChannel,
+    preferences: &PreferenceStore,
+    group_cache: &SharedGroupCache,
+    stores: &RoutedEmailStores,
+) -> Result<(), LapinError> {
+    let queue_name = &CONFIG.preferences.routing_queue;
+
+    rabbit_channel
+        .queue_declare(
+            queue_name,
+            QueueDeclareOptions {
+                durable: true,
+                ..Default::default()
+            },
+            FieldTable::default(),
+        )
+        .await?;
+
+    rabbit_channel
+        .queue_bind(
+            queue_name,
+            CHANNEL_EXCHANGE,
+            GROUP_CHANNEL_PREFIX,
+            QueueBindOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    let mut consumer = rabbit_channel
+        .basic_consume(
+            queue_name,
+            "preference_routing_consumer",
+            BasicConsumeOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    while let Some(delivery) = SHUTDOWN.next_delivery(&mut consumer).await {
+        let delivery = delivery?;
+
+        match serde_json::from_slice::<RabbitMessage>(&delivery.data) {
+            Ok(message) => route_group_message(message, preferences, group_cache, stores).await,
+            Err(e) => println!("Failed to deserialize message for email routing: {:?}", e),
+        }
+
+        delivery.ack(BasicAckOptions::default()).await?;
+    }
+
+    Ok(())
+}
+
+async fn route_group_message(
+    message: RabbitMessage,
+    preferences: &PreferenceStore,
+    group_cache: &SharedGroupCache,
+    stores: &RoutedEmailStores,
+) {
+    let Some(event_type) = message.event_type.as_deref() else {
+        return;
+    };
+    let Some(group_id) = message.channel_id.strip_prefix(GROUP_CHANNEL_PREFIX) else {
+        return;
+    };
+    if message
+        .expires_at
+        .is_some_and(|expires_at| expires_at <= Utc::now())
+    {
+        return;
+    }
+
+    let Some(auth_header) = service_auth_header() else {
+        println!(
+            "IAM_API_TOKEN is not set, unable to route emails for group {}",
+            group_id
+        );
+        return;
+    };
+    let members = match group_cache.get_members(group_id, auth_header).await {
+        Ok(members) => members,
+        Err(e) => {
+            println!("Failed to get members of group {}: {}", group_id, e);
+            return;
+        }
+    };
+
+    for member in members.iter() {
+        let Some(user) = preferences.get(member).await else {
+            continue;
+        };
+        if user.route(event_type) == DeliveryRoute::Email {
+            email_routed_message(&user, event_type, &message, stores).await;
+        }
+    }
+}
+
+// Email a message to a user who routes its type to email
+pub async fn email_routed_message(
+    user: &UserPreferences,
+    event_type: &str,
+    message: &RabbitMessage,
+    stores: &RoutedEmailStores,
+) {
+    let Some(to) = user.email.as_deref() else {
+        println!(
+            "No email address for {}, dropping {} email",
+            user.user_id, event_type
+        );
+        return;
+    };
+    let subject = CONFIG
+        .preferences
+        .email_subject
+        .replace("{type}", event_type);
+    if let Err(e) = send_routed_email(
+        to,
+        subject,
+        message.message.clone(),
+        user.holds_email(message.priority, Utc::now()),
+        &stores.emails,
+        &stores.digests,
+        &stores.suppressions,
+    )
+    .await
+    {
+        println!(
+            "Unable to send {} email to {}: {}",
+            event_type, user.user_id, e
+        );
+    }
+}
+
+// Queue an email routed by preference, or add it to the recipient's daily digest when held
+// back for quiet hours
+pub async fn send_routed_email(
+    to: &str,
+    subject: String,
+    message: String,
+    hold_for_digest: bool,
+    emails: &EmailStore,
+    digests: &DigestStore,
+    suppressions: &SuppressionStore,
+) -> Result<(), String> {
+    let service = CONFIG
+        .preferences
+        .email_service
+        .as_deref()
+        .ok_or_else(|| "preferences.email_service is not set".to_string())?;
+    if find_suppression(suppressions, to).await.is_some() {
+        return Err(format!("Recipient {} is suppressed", to));
+    }
+
+    let sender = resolve_sender(service, None, None).map_err(|e| e.to_string())?;
+    let email_request = EmailRequest {
+        message,
+        to: to.to_string(),
+        reply_to: sender.reply_to,
+        subject,
+        from: Some(sender.from),
+        digest: None,
+    };
+
+    if hold_for_digest {
+        add_to_digest(
+            digests,
+            service,
+            email_request,
+            sender.source,
+            DigestFrequency::Daily,
+        )
+        .await;
+        println!("Held email to {} until after quiet hours", to);
+        return Ok(());
+    }
+
+    queue_email(emails, service, email_request, sender.source)
+        .await
+        .map(|_| ())
+        .map_err(|e| format!("Failed to queue email: {:?}", e))
+}
+
+#[utoipa::path(
+    get,
+    path = "/notification/users/{user_id}/preferences",
+    params(
+        ("user_id" = String, Path, description = "The user whose preferences to fetch")
+    ),
+    responses(
+        (status = 200, description = "The user's notification preferences", body = UserPreferences),
+        (status = 403, description = "Not the caller's own preferences"),
+        (status = 404, description = "No preferences set, every event type is delivered over the WebSocket")
+    ),
+    security(("bearerAuth" = [])),
+    tag = "default"
+)]
+pub async fn get_preferences(
+    user_id: String,
+    claims: Claims,
+    preferences: PreferenceStore,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    if let Some(reply) = forbidden_unless_self(&claims, &user_id) {
+        return Ok(reply);
+    }
+
+    match preferences.get(&user_id).await {
+        Some(user) => Ok(Box::new(warp::reply::json(&user))),
+        None => Ok(Box::new(warp::reply::with_status(
+            warp::reply::json(&"No preferences set"),
+            StatusCode::NOT_FOUND,
+        ))),
+    }
+}
+
+#[utoipa::path(
+    put,
+    path = "/notification/users/{user_id}/preferences",
+    params(
+        ("user_id" = String, Path, description = "The user whose preferences to replace")
+    ),
+    request_body = PreferencesRequest,
+    responses(
+        (status = 200, description = "Preferences saved", body = UserPreferences),
+        (status = 400, description = "Invalid quiet hours"),
+        (status = 403, description = "Not the caller's own preferences")
+    ),
+    security(("bearerAuth" = [])),
+    tag = "default"
+)]
+pub async fn put_preferences(
+    user_id: String,
+    request: PreferencesRequest,
+    claims: Claims,
+    preferences: PreferenceStore,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    if let Some(reply) = forbidden_unless_self(&claims, &user_id) {
+        return Ok(reply);
+    }
+
+    if let Some(Err(e)) = request.quiet_hours.as_ref().map(QuietHours::validate) {
+        return Ok(Box::new(warp::reply::with_status(
+            warp::reply::json(&e),
+            StatusCode::BAD_REQUEST,
+        )));
+    }
+
+    let user = UserPreferences {
+        user_id: user_id.clone(),
+        email: request.email,
+        default_route: request.default_route,
+        types: request.types,
+        quiet_hours: request.quiet_hours,
+        updated_at: Utc::now(),
+    };
+    preferences.insert(user_id, user.clone()).await;
+
+    if let Err(e) = publish_event(&PreferencesEvent::Updated {
+        preferences: user.clone(),
+    })
+    .await
+    {
+        eprintln!(
+            "Failed to replicate preferences of {}: {:?}",
+            user.user_id, e
+        );
+    }
+
+    Ok(Box::new(warp::reply::json(&user)))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/notification/users/{user_id}/preferences",
+    params(
+        ("user_id" = String, Path, description = "The user whose preferences to reset")
+    ),
+    responses(
+        (status = 204, description = "Preferences removed, every event type is delivered over the WebSocket again"),
+        (status = 403, description = "Not the caller's own preferences"),
+        (status = 404, description = "No preferences set")
+    ),
+    security(("bearerAuth" = [])),
+    tag = "default"
+)]
+pub async fn delete_preferences(
+    user_id: String,
+    claims: Claims,
+    preferences: PreferenceStore,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    if let Some(reply) = forbidden_unless_self(&claims, &user_id) {
+        return Ok(reply);
+    }
+
+    if preferences.remove(&user_id).await.is_none() {
+        return Ok(Box::new(warp::reply::with_status(
+            warp::reply::json(&"No preferences set"),
+            StatusCode::NOT_FOUND,
+        )));
+    }
+
+    if let Err(e) = publish_event(&PreferencesEvent::Deleted {
+        user_id: user_id.clone(),
+    })
+    .await
+    {
+        eprintln!(
+            "Failed to replicate deleting preferences of {}: {:?}",
+            user_id, e
+        );
+    }
+
+    Ok(Box::new(StatusCode::NO_CONTENT))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn quiet(start_hour: u32, end_hour: u32, utc_offset_minutes: i32) -> QuietHours {
+        QuietHours {
+            start_hour,
+            end_hour,
+            utc_offset_minutes,
+        }
+    }
+
+    fn at(hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 3, 1, hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn quiet_hours_within_a_day() {
+        let lunch = quiet(12, 14, 0);
+
+        assert!(!lunch.contains(at(11, 59)));
+        assert!(lunch.contains(at(12, 0)));
+        assert!(lunch.contains(at(13, 59)));
+        assert!(!lunch.contains(at(14, 0)));
+    }
+
+    #[test]
+    fn quiet_hours_across_midnight() {
+        let night = quiet(22, 7, 0);
+
+        assert!(night.contains(at(22, 0)));
+        assert!(night.contains(at(0, 30)));
+        assert!(night.contains(at(6, 59)));
+        assert!(!night.contains(at(7, 0)));
+        assert!(!night.contains(at(21, 59)));
+    }
+
+    #[test]
+    fn quiet_hours_in_local_time() {
+        // 22:00 to 07:00 in UTC+05:30 is 16:30 to 01:30 UTC
+        let night = quiet(22, 7, 330);
+
+        assert!(!night.contains(at(16, 29)));
+        assert!(night.contains(at(16, 30)));
+        assert!(night.contains(at(1, 29)));
+        assert!(!night.contains(at(1, 30)));
+
+        // And in UTC-08:00, 06:00 to 15:00 UTC
+        let night = quiet(22, 7, -480);
+        assert!(night.contains(at(6, 0)));
+        assert!(!night.contains(at(15, 0)));
+    }
+
+    #[test]
+    fn equal_start_and_end_is_never_quiet() {
+        let never = quiet(9, 9, 0);
+
+        assert!((0..24).all(|hour| !never.contains(at(hour, 0))));
+    }
+
+    #[test]
+    fn critical_emails_are_never_held() {
+        let preferences = UserPreferences {
+            user_id: "17".to_string(),
+            email: Some("user@example.com".to_string()),
+            default_route: DeliveryRoute::Email,
+            types: HashMap::new(),
+            quiet_hours: Some(quiet(22, 7, 0)),
+            updated_at: Utc::now(),
+        };
+
+        assert!(preferences.holds_email(Priority::Normal, at(23, 0)));
+        assert!(!preferences.holds_email(Priority::Critical, at(23, 0)));
+        assert!(!preferences.holds_email(Priority::Normal, at(12, 0)));
+    }
+}
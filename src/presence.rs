@@ -0,0 +1,472 @@
+use std::{collections::HashMap, convert::Infallible, sync::Arc};
+
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use futures::StreamExt;
+use ginger_shared_rs::rocket_utils::Claims;
+use lapin::{
+    options::{
+        BasicAckOptions, BasicConsumeOptions, BasicPublishOptions, QueueBindOptions,
+        QueueDeclareOptions,
+    },
+    types::FieldTable,
+    BasicProperties, Channel as RabbitChannel, Error as LapinError,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{mpsc::UnboundedReceiver, Mutex},
+    time::{interval, sleep, Duration, Instant},
+};
+use warp::{http::StatusCode, Filter};
+
+use crate::{
+    channel_acl::{authorize_user_subscribe, patterns_cover},
+    config::CONFIG,
+    outbound::{OutboundMessage, Priority},
+    responses::{ControlFrame, PresenceResponse},
+    shared::{decode_path_segment, open_rabbitmq_channel, Channels, REPLICA_ID},
+};
+
+// A socket subscribed to `channel` (the name or pattern it subscribed with)
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct PresenceConnection {
+    pub channel: String,
+    pub connection_id: String,
+    pub user_id: Option<String>,
+}
+
+// Local subscriptions coming and going, sent by the channel registry
+#[derive(Debug)]
+pub enum PresenceChange {
+    Joined(PresenceConnection),
+    Left {
+        channel: String,
+        connection_id: String,
+    },
+}
+
+// What replicas exchange over `presence.exchange`. Snapshots replace everything known about
+// the sending replica, correcting any events that went missing.
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(tag = "event", rename_all = "lowercase")]
+enum PresenceEvent {
+    Join {
+        replica: String,
+        connection: PresenceConnection,
+    },
+    Leave {
+        replica: String,
+        channel: String,
+        connection_id: String,
+    },
+    Snapshot {
+        replica: String,
+        connections: Vec<PresenceConnection>,
+    },
+}
+
+struct ReplicaPresence {
+    last_seen: Instant,
+    connections: HashMap<String, PresenceConnection>,
+}
+
+// Cluster-wide presence, built from the events of every replica including this one
+#[derive(Default)]
+struct PresenceState {
+    replicas: HashMap<String, ReplicaPresence>,
+    // channel -> user (None for API clients) -> connections across every replica
+    counts: HashMap<String, HashMap<Option<String>, usize>>,
+    // When each user last joined or left a channel anywhere in the cluster
+    last_seen: HashMap<String, DateTime<Utc>>,
+}
+
+impl PresenceState {
+    fn replica(&mut self, replica: &str) -> &mut ReplicaPresence {
+        let presence =
+            self.replicas
+                .entry(replica.to_string())
+                .or_insert_with(|| ReplicaPresence {
+                    last_seen: Instant::now(),
+                    connections: HashMap::new(),
+                });
+        presence.last_seen = Instant::now();
+        presence
+    }
+
+    // Returns the user's connection count on the channel afterwards
+    fn add(&mut self, replica: &str, connection: PresenceConnection) -> usize {
+        self.remove(replica, &connection.connection_id);
+        self.touch(connection.user_id.as_deref());
+
+        let users = self.counts.entry(connection.channel.clone()).or_default();
+        let count = users.entry(connection.user_id.clone()).or_default();
+        *count += 1;
+        let count = *count;
+
+        self.replica(replica)
+            .connections
+            .insert(connection.connection_id.clone(), connection);
+        count
+    }
+
+    // Returns the removed connection and the user's connection count on the channel afterwards
+    fn remove(
+        &mut self,
+        replica: &str,
+        connection_id: &str,
+    ) -> Option<(PresenceConnection, usize)> {
+        let connection = self.replica(replica).connections.remove(connection_id)?;
+        self.touch(connection.user_id.as_deref());
+
+        let users = self.counts.get_mut(&connection.channel)?;
+        let count = users.get_mut(&connection.user_id)?;
+        *count = count.saturating_sub(1);
+        let count = *count;
+
+        if count == 0 {
+            users.remove(&connection.user_id);
+        }
+        if users.is_empty() {
+            self.counts.remove(&connection.channel);
+        }
+        Some((connection, count))
+    }
+
+    fn touch(&mut self, user_id: Option<&str>) {
+        if let Some(user_id) = user_id {
+            self.last_seen.insert(user_id.to_string(), Utc::now());
+        }
+    }
+
+    fn is_online(&self, user_id: &str) -> bool {
+        let user_id = Some(user_id.to_string());
+        self.counts
+            .values()
+            .any(|users| users.contains_key(&user_id))
+    }
+
+    fn replace_replica(&mut self, replica: &str, connections: Vec<PresenceConnection>) {
+        self.drop_replica(replica);
+        for connection in connections {
+            self.add(replica, connection);
+        }
+    }
+
+    fn drop_replica(&mut self, replica: &str) {
+        let connection_ids = self
+            .replicas
+            .get(replica)
+            .map(|presence| presence.connections.keys().cloned().collect::<Vec<_>>())
+            .unwrap_or_default();
+        for connection_id in connection_ids {
+            self.remove(replica, &connection_id);
+        }
+        self.replicas.remove(replica);
+    }
+
+    // Forget replicas that stopped sending snapshots, e.g. because they crashed
+    fn prune(&mut self, ttl: Duration) {
+        let stale = self
+            .replicas
+            .iter()
+            .filter(|(_, presence)| presence.last_seen.elapsed() > ttl)
+            .map(|(replica, _)| replica.clone())
+            .collect::<Vec<_>>();
+        for replica in stale {
+            println!("Dropping presence of unresponsive replica {}", replica);
+            self.drop_replica(&replica);
+        }
+
+        // Callers only ask about the recent past
+        let cutoff = Utc::now() - ChronoDuration::days(1);
+        self.last_seen.retain(|_, seen_at| *seen_at > cutoff);
+    }
+}
+
+#[derive(Default)]
+pub struct PresenceView {
+    state: Mutex<PresenceState>,
+}
+
+pub type SharedPresence = Arc<PresenceView>;
+
+impl PresenceView {
+    pub async fn for_channel(&self, channel: &str) -> PresenceResponse {
+        let state = self.state.lock().await;
+        let users = state.counts.get(channel);
+
+        let mut user_ids = users
+            .map(|users| users.keys().flatten().cloned().collect::<Vec<_>>())
+            .unwrap_or_default();
+        user_ids.sort();
+
+        PresenceResponse {
+            channel: channel.to_string(),
+            connections: users.map_or(0, |users| users.values().sum()),
+            users: user_ids,
+        }
+    }
+
+    // Whether the user has a socket open on any replica
+    pub async fn is_online(&self, user_id: &str) -> bool {
+        self.state.lock().await.is_online(user_id)
+    }
+
+    // Whether the user is online, or has been at some point since `since`
+    pub async fn seen_since(&self, user_id: &str, since: DateTime<Utc>) -> bool {
+        let state = self.state.lock().await;
+        state.is_online(user_id)
+            || state
+                .last_seen
+                .get(user_id)
+                .is_some_and(|seen_at| *seen_at >= since)
+    }
+}
+
+// Filter to inject the presence view into the route handlers
+pub fn with_presence(
+    presence: SharedPresence,
+) -> impl Filter<Extract = (SharedPresence,), Error = Infallible> + Clone {
+    warp::any().map(move || presence.clone())
+}
+
+pub async fn run_presence(
+    channels: Channels,
+    presence: SharedPresence,
+    mut changes: UnboundedReceiver<PresenceChange>,
+) {
+    loop {
+        match open_rabbitmq_channel().await {
+            Ok(rabbit_channel) => {
+                if let Err(e) =
+                    process_presence(rabbit_channel, &channels, &presence, &mut changes).await
+                {
+                    eprintln!("Error processing presence events: {:?}", e);
+                }
+            }
+            Err(e) => {
+                eprintln!("Error connecting to RabbitMQ: {:?}", e);
+            }
+        }
+
+        eprintln!("Reconnecting to presence events in 5 seconds...");
+        sleep(Duration::from_secs(5)).await;
+    }
+}
+
+async fn process_presence(
+    rabbit_channel: RabbitChannel,
+    channels: &Channels,
+    presence: &SharedPresence,
+    changes: &mut UnboundedReceiver<PresenceChange>,
+) -> Result<(), LapinError> {
+    let exchange = &CONFIG.presence.exchange;
+
+    rabbit_channel
+        .exchange_declare(
+            exchange,
+            lapin::ExchangeKind::Fanout,
+            Default::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    // Every replica keeps the whole cluster's presence, so each gets a private queue
+    let queue = rabbit_channel
+        .queue_declare(
+            "",
+            QueueDeclareOptions {
+                exclusive: true,
+                auto_delete: true,
+                ..Default::default()
+            },
+            FieldTable::default(),
+        )
+        .await?;
+
+    rabbit_channel
+        .queue_bind(
+            queue.name().as_str(),
+            exchange,
+            "",
+            QueueBindOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    let mut consumer = rabbit_channel
+        .basic_consume(
+            queue.name().as_str(),
+            "presence_consumer",
+            BasicConsumeOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    let heartbeat = Duration::from_secs(CONFIG.presence.heartbeat_secs.max(1));
+    let mut ticker = interval(heartbeat);
+
+    loop {
+        tokio::select! {
+            delivery = consumer.next() => match delivery {
+                Some(delivery) => {
+                    let delivery = delivery?;
+                    match serde_json::from_slice::<PresenceEvent>(&delivery.data) {
+                        Ok(event) => apply_event(event, channels, presence).await,
+                        Err(e) => println!("Failed to deserialize presence event: {:?}", e),
+                    }
+                    delivery.ack(BasicAckOptions::default()).await?;
+                }
+                None => return Ok(()),
+            },
+            Some(change) = changes.recv() => {
+                let event = match change {
+                    PresenceChange::Joined(connection) => PresenceEvent::Join {
+                        replica: REPLICA_ID.clone(),
+                        connection,
+                    },
+                    PresenceChange::Left { channel, connection_id } => PresenceEvent::Leave {
+                        replica: REPLICA_ID.clone(),
+                        channel,
+                        connection_id,
+                    },
+                };
+                publish_event(&rabbit_channel, &event).await?;
+            },
+            _ = ticker.tick() => {
+                let event = PresenceEvent::Snapshot {
+                    replica: REPLICA_ID.clone(),
+                    connections: local_connections(channels).await,
+                };
+                publish_event(&rabbit_channel, &event).await?;
+                presence.state.lock().await.prune(heartbeat * 3);
+            },
+        }
+    }
+}
+
+async fn publish_event(
+    rabbit_channel: &RabbitChannel,
+    event: &PresenceEvent,
+) -> Result<(), LapinError> {
+    rabbit_channel
+        .basic_publish(
+            &CONFIG.presence.exchange,
+            "",
+            BasicPublishOptions::default(),
+            &serde_json::to_vec(event).unwrap(),
+            BasicProperties::default(),
+        )
+        .await?;
+    Ok(())
+}
+
+async fn local_connections(channels: &Channels) -> Vec<PresenceConnection> {
+    channels
+        .lock()
+        .await
+        .values()
+        .flat_map(|channel| {
+            channel
+                .connections
+                .iter()
+                .map(|(connection_id, connection)| PresenceConnection {
+                    channel: channel.name.clone(),
+                    connection_id: connection_id.clone(),
+                    user_id: connection.user_id.clone(),
+                })
+        })
+        .collect()
+}
+
+async fn apply_event(event: PresenceEvent, channels: &Channels, presence: &SharedPresence) {
+    let mut state = presence.state.lock().await;
+
+    match event {
+        PresenceEvent::Join {
+            replica,
+            connection,
+        } => {
+            let channel = connection.channel.clone();
+            let user_id = connection.user_id.clone();
+            // A user's first connection anywhere in the cluster
+            if state.add(&replica, connection) == 1 {
+                if let Some(user_id) = user_id {
+                    announce(channels, &channel, "join", &user_id).await;
+                }
+            }
+        }
+        PresenceEvent::Leave {
+            replica,
+            connection_id,
+            ..
+        } => {
+            // A user's last connection anywhere in the cluster
+            if let Some((connection, 0)) = state.remove(&replica, &connection_id) {
+                if let Some(user_id) = connection.user_id {
+                    announce(channels, &connection.channel, "leave", &user_id).await;
+                }
+            }
+        }
+        PresenceEvent::Snapshot {
+            replica,
+            connections,
+        } => state.replace_replica(&replica, connections),
+    }
+}
+
+// Push join / leave events to local subscribers of channels opted in with `presence.announce`
+async fn announce(channels: &Channels, channel: &str, event: &str, user_id: &str) {
+    if !patterns_cover(&CONFIG.presence.announce, channel) {
+        return;
+    }
+
+    if let Some(local) = channels.lock().await.get(channel) {
+        local.deliver(&OutboundMessage {
+            priority: Priority::Normal,
+            expires_at: None,
+            collapse_key: None,
+            payload: ControlFrame::Presence {
+                channel,
+                event,
+                user_id,
+            }
+            .encode(),
+            plain: None,
+            id: None,
+            publisher: None,
+        });
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/notification/channels/{channel_name}/presence",
+    params(
+        ("channel_name" = String, Path, description = "The channel, or subscription pattern, to list connected users of")
+    ),
+    responses(
+        (status = 200, description = "Users connected to the channel across every replica", body = PresenceResponse),
+        (status = 403, description = "Caller may not subscribe to the channel")
+    ),
+    security(("bearerAuth" = [])),
+    tag = "default"
+)]
+pub async fn get_presence(
+    channel_name: String,
+    claims: Claims,
+    presence: SharedPresence,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    let channel_name = decode_path_segment(&channel_name);
+
+    if let Err(e) = authorize_user_subscribe(&claims.user_id, &channel_name) {
+        println!("{}", e);
+        return Ok(Box::new(warp::reply::with_status(
+            warp::reply::json(&e),
+            StatusCode::FORBIDDEN,
+        )));
+    }
+
+    Ok(Box::new(warp::reply::json(
+        &presence.for_channel(&channel_name).await,
+    )))
+}
@@ -1,4 +1,6 @@
-use prometheus::{Encoder, IntCounter, Opts, Registry, TextEncoder};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, Opts, Registry, TextEncoder,
+};
 
 use crate::responses::EncodeError;
 
@@ -10,6 +12,54 @@ lazy_static::lazy_static! {
 lazy_static::lazy_static! {
     pub static ref REQUEST_COUNTER: IntCounter = IntCounter::with_opts(Opts::new("request_count", "Total number of requests"))
         .expect("Counter can be created");
+    pub static ref EMAIL_USAGE_COUNTER: IntCounterVec = IntCounterVec::new(
+        Opts::new("email_usage_total", "Emails accepted for sending per calling service and recipient domain (\"other\" unless it has its own limits)"),
+        &["service", "domain"]
+    )
+    .expect("Counter can be created");
+    pub static ref EMAIL_RATE_LIMITED_COUNTER: IntCounterVec = IntCounterVec::new(
+        Opts::new("email_rate_limited_total", "Emails rejected by a rate limit or quota"),
+        &["service", "scope"]
+    )
+    .expect("Counter can be created");
+    pub static ref GROUP_CACHE_HITS: IntCounter = IntCounter::with_opts(Opts::new("group_membership_cache_hits_total", "Group membership lookups served from cache"))
+        .expect("Counter can be created");
+    pub static ref GROUP_CACHE_MISSES: IntCounter = IntCounter::with_opts(Opts::new("group_membership_cache_misses_total", "Group membership lookups fetched from IAM"))
+        .expect("Counter can be created");
+    pub static ref MESSAGES_EXPIRED: IntCounter = IntCounter::with_opts(Opts::new("messages_expired_total", "Messages dropped because their expires_at / ttl passed before delivery"))
+        .expect("Counter can be created");
+    pub static ref OUTBOUND_DROPPED: IntCounterVec = IntCounterVec::new(
+        Opts::new("outbound_messages_dropped_total", "Messages dropped from a slow connection's outbound queue"),
+        &["priority"]
+    )
+    .expect("Counter can be created");
+    pub static ref OUTBOUND_COALESCED: IntCounter = IntCounter::with_opts(Opts::new("outbound_messages_coalesced_total", "Queued messages replaced by a newer one with the same collapse_key"))
+        .expect("Counter can be created");
+    pub static ref WEB_PUSH_COUNTER: IntCounterVec = IntCounterVec::new(
+        Opts::new("web_push_total", "Web Push deliveries by outcome"),
+        &["outcome"]
+    )
+    .expect("Counter can be created");
+    pub static ref WEBHOOK_DELIVERIES_COUNTER: IntCounterVec = IntCounterVec::new(
+        Opts::new("webhook_deliveries_total", "Webhook delivery attempts by outcome"),
+        &["outcome"]
+    )
+    .expect("Counter can be created");
+    pub static ref CHATOPS_MESSAGES_COUNTER: IntCounterVec = IntCounterVec::new(
+        Opts::new("chatops_messages_total", "Messages posted to chat rooms by outcome"),
+        &["outcome"]
+    )
+    .expect("Counter can be created");
+    pub static ref SMS_COUNTER: IntCounterVec = IntCounterVec::new(
+        Opts::new("sms_total", "SMS requests per calling service by outcome"),
+        &["service", "outcome"]
+    )
+    .expect("Counter can be created");
+    pub static ref GROUP_FANOUT_SIZE: Histogram = Histogram::with_opts(
+        HistogramOpts::new("group_publish_fanout_size", "Number of members a group publish fans out to")
+            .buckets(vec![1.0, 10.0, 50.0, 100.0, 500.0, 1000.0, 5000.0, 10000.0])
+    )
+    .expect("Histogram can be created");
 }
 
 pub async fn metrics_handler() -> Result<impl warp::Reply, warp::Rejection> {
@@ -22,7 +72,7 @@ pub async fn metrics_handler() -> Result<impl warp::Reply, warp::Rejection> {
     // Encode the metrics
     encoder
         .encode(&REGISTRY.gather(), &mut buffer)
-        .map_err(|e| warp::reject::custom(EncodeError))?;
+        .map_err(|_| warp::reject::custom(EncodeError))?;
 
     Ok(warp::reply::with_header(
         String::from_utf8(buffer).unwrap(),
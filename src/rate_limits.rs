@@ -0,0 +1,173 @@
+use std::{collections::HashMap, convert::Infallible, sync::Arc};
+
+use chrono::{Duration as ChronoDuration, NaiveDate, Utc};
+use tokio::{
+    sync::Mutex,
+    time::{interval, Duration, Instant},
+};
+use warp::Filter;
+
+use crate::config::{RateLimit, CONFIG};
+
+// A bucket left alone this long has refilled and its day has rolled over, so it is no different
+// from a new one
+const IDLE_EVICTION: Duration = Duration::from_secs(24 * 3600);
+
+struct Usage {
+    tokens: f64,
+    last_refill: Instant,
+    day: NaiveDate,
+    used_today: u64,
+}
+
+impl Usage {
+    fn new(limit: &RateLimit, now: Instant, today: NaiveDate) -> Self {
+        Usage {
+            tokens: bucket_capacity(limit),
+            last_refill: now,
+            day: today,
+            used_today: 0,
+        }
+    }
+
+    fn refill(&mut self, limit: &RateLimit, now: Instant, today: NaiveDate) {
+        if let Some(per_minute) = limit.per_minute {
+            let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * per_minute / 60.0).min(bucket_capacity(limit));
+        }
+        self.last_refill = now;
+
+        if self.day != today {
+            self.day = today;
+            self.used_today = 0;
+        }
+    }
+}
+
+fn bucket_capacity(limit: &RateLimit) -> f64 {
+    limit.burst.or(limit.per_minute).unwrap_or(f64::MAX)
+}
+
+fn until_next_utc_day() -> Duration {
+    let now = Utc::now();
+    let midnight = (now.date_naive() + ChronoDuration::days(1))
+        .and_hms_opt(0, 0, 0)
+        .unwrap()
+        .and_utc();
+    (midnight - now).to_std().unwrap_or(Duration::from_secs(1))
+}
+
+#[derive(Debug)]
+pub struct LimitExceeded {
+    pub scope: &'static str,
+    pub retry_after: Duration,
+}
+
+impl LimitExceeded {
+    // Whole seconds for the Retry-After header, never zero
+    pub fn retry_after_secs(&self) -> u64 {
+        self.retry_after.as_secs_f64().ceil().max(1.0) as u64
+    }
+}
+
+// In-memory token buckets and daily counters, keyed by scope and identity. Every replica keeps
+// its own, so each enforces the configured limits separately.
+#[derive(Default)]
+pub struct RateLimiter {
+    usage: Mutex<HashMap<String, Usage>>,
+}
+
+pub type SharedRateLimiter = Arc<RateLimiter>;
+
+impl RateLimiter {
+    // Take one unit from every `(scope, key, limit)` or, if any of them is exhausted, from none
+    pub async fn acquire(
+        &self,
+        limits: &[(&'static str, String, &RateLimit)],
+    ) -> Result<(), LimitExceeded> {
+        let now = Instant::now();
+        let today = Utc::now().date_naive();
+        let mut usage = self.usage.lock().await;
+
+        for (scope, key, limit) in limits {
+            let entry = usage
+                .entry(format!("{}:{}", scope, key))
+                .or_insert_with(|| Usage::new(limit, now, today));
+            entry.refill(limit, now, today);
+
+            if let Some(daily_quota) = limit.daily_quota {
+                if entry.used_today >= daily_quota {
+                    return Err(LimitExceeded {
+                        scope,
+                        retry_after: until_next_utc_day(),
+                    });
+                }
+            }
+
+            if let Some(per_minute) = limit.per_minute {
+                if entry.tokens < 1.0 {
+                    let wait_secs = (1.0 - entry.tokens) * 60.0 / per_minute.max(f64::EPSILON);
+                    return Err(LimitExceeded {
+                        scope,
+                        retry_after: Duration::from_secs_f64(wait_secs),
+                    });
+                }
+            }
+        }
+
+        for (scope, key, limit) in limits {
+            if let Some(entry) = usage.get_mut(&format!("{}:{}", scope, key)) {
+                if limit.per_minute.is_some() {
+                    entry.tokens -= 1.0;
+                }
+                entry.used_today += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn evict_idle(&self) -> usize {
+        let now = Instant::now();
+        let mut usage = self.usage.lock().await;
+        let before = usage.len();
+        usage.retain(|_, entry| now.duration_since(entry.last_refill) < IDLE_EVICTION);
+        before - usage.len()
+    }
+}
+
+// Keeps callers and recipient domains that stopped sending from piling up in memory
+pub async fn prune_idle_usage(rate_limiter: SharedRateLimiter) {
+    let mut ticker = interval(Duration::from_secs(3600));
+
+    loop {
+        ticker.tick().await;
+        let evicted = rate_limiter.evict_idle().await;
+        if evicted > 0 {
+            println!("Evicted {} idle rate limit buckets", evicted);
+        }
+    }
+}
+
+pub fn recipient_domain(email: &str) -> String {
+    email
+        .rsplit_once('@')
+        .map(|(_, domain)| domain.trim().to_lowercase())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+// Metrics only label the domains with their own limits, as every other domain would add a series
+pub fn domain_label(domain: &str) -> &str {
+    if CONFIG.email_limits.domains.contains_key(domain) {
+        domain
+    } else {
+        "other"
+    }
+}
+
+// Filter to inject the rate limiter into the route handlers
+pub fn with_rate_limiter(
+    rate_limiter: SharedRateLimiter,
+) -> impl Filter<Extract = (SharedRateLimiter,), Error = Infallible> + Clone {
+    warp::any().map(move || rate_limiter.clone())
+}
@@ -0,0 +1,369 @@
+use std::collections::{HashMap, VecDeque};
+
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use futures::StreamExt;
+use lapin::{
+    options::{
+        BasicAckOptions, BasicConsumeOptions, BasicPublishOptions, ExchangeDeclareOptions,
+        QueueBindOptions, QueueDeclareOptions,
+    },
+    types::FieldTable,
+    BasicProperties, Channel as RabbitChannel, Error as LapinError,
+};
+use serde::{Deserialize, Serialize};
+use tokio::time::{interval, sleep, Duration};
+use utoipa::ToSchema;
+use warp::http::StatusCode;
+
+use crate::{
+    config::CONFIG,
+    shared::{open_rabbitmq_channel, RABBIT_POOL},
+    store::Store,
+};
+
+#[derive(Deserialize, Serialize, ToSchema, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReceiptStatus {
+    Delivered,
+    Read,
+}
+
+// Published to `receipts.exchange` for every ack / read a client sends. Each replica records
+// them, and the originating service may bind its own queue to consume them too.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ReceiptEvent {
+    pub message_id: String,
+    pub channel: String,
+    pub user_id: String,
+    pub status: ReceiptStatus,
+    pub at: DateTime<Utc>,
+    #[serde(default)]
+    pub publisher: Option<String>, // Who published the message, e.g. user:17 or api:build-service
+}
+
+#[derive(Deserialize, Serialize, ToSchema, Clone)]
+pub struct Receipt {
+    pub user_id: String,
+    pub delivered_at: DateTime<Utc>,
+    pub read_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize, Serialize, ToSchema, Clone)]
+pub struct MessageReceipts {
+    pub message_id: String,
+    #[serde(default)]
+    pub publisher: Option<String>,
+    pub receipts: Vec<Receipt>,
+    pub updated_at: DateTime<Utc>,
+}
+
+// Keyed by message id
+pub type ReceiptStore = Store<MessageReceipts>;
+
+// Ids of the messages most recently written to one socket, along with who published each.
+// Clients may only send receipts for these, so they can't acknowledge other users' messages.
+#[derive(Debug)]
+pub struct DeliveredMessages {
+    capacity: usize,
+    order: VecDeque<String>,
+    publishers: HashMap<String, Option<String>>,
+}
+
+impl DeliveredMessages {
+    pub fn new(capacity: usize) -> Self {
+        DeliveredMessages {
+            capacity: capacity.max(1),
+            order: VecDeque::new(),
+            publishers: HashMap::new(),
+        }
+    }
+
+    // Remember a written message, forgetting the oldest one when full
+    pub fn record(&mut self, id: String, publisher: Option<String>) {
+        if self.publishers.insert(id.clone(), publisher).is_some() {
+            return;
+        }
+        self.order.push_back(id);
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.publishers.remove(&oldest);
+            }
+        }
+    }
+
+    // The publisher of a message written to the socket, or None if it never was
+    pub fn publisher(&self, id: &str) -> Option<Option<String>> {
+        self.publishers.get(id).cloned()
+    }
+}
+
+pub async fn declare_receipts_exchange(rabbit_channel: &RabbitChannel) -> Result<(), LapinError> {
+    rabbit_channel
+        .exchange_declare(
+            &CONFIG.receipts.exchange,
+            lapin::ExchangeKind::Fanout,
+            ExchangeDeclareOptions {
+                durable: true,
+                ..Default::default()
+            },
+            FieldTable::default(),
+        )
+        .await
+}
+
+pub async fn publish_receipt(event: &ReceiptEvent) -> Result<(), LapinError> {
+    let rabbit_channel = RABBIT_POOL.channel().await?;
+
+    rabbit_channel
+        .basic_publish(
+            &CONFIG.receipts.exchange,
+            "",
+            BasicPublishOptions::default(),
+            &serde_json::to_vec(event).unwrap(),
+            BasicProperties::default(),
+        )
+        .await?;
+    Ok(())
+}
+
+pub async fn record_receipt(receipts: &ReceiptStore, event: ReceiptEvent) {
+    receipts
+        .upsert(
+            &event.message_id,
+            || MessageReceipts {
+                message_id: event.message_id.clone(),
+                publisher: None,
+                receipts: vec![],
+                updated_at: event.at,
+            },
+            |message| {
+                message.updated_at = event.at;
+                if message.publisher.is_none() {
+                    message.publisher = event.publisher.clone();
+                }
+
+                let index = match message
+                    .receipts
+                    .iter()
+                    .position(|receipt| receipt.user_id == event.user_id)
+                {
+                    Some(index) => index,
+                    None => {
+                        // Reading a message implies it was delivered
+                        message.receipts.push(Receipt {
+                            user_id: event.user_id.clone(),
+                            delivered_at: event.at,
+                            read_at: None,
+                        });
+                        message.receipts.len() - 1
+                    }
+                };
+
+                let receipt = &mut message.receipts[index];
+                if event.status == ReceiptStatus::Read && receipt.read_at.is_none() {
+                    receipt.read_at = Some(event.at);
+                }
+            },
+        )
+        .await;
+}
+
+pub async fn consume_receipts(receipts: ReceiptStore) {
+    loop {
+        match open_rabbitmq_channel().await {
+            Ok(rabbit_channel) => {
+                if let Err(e) = process_receipts(rabbit_channel, &receipts).await {
+                    eprintln!("Error processing receipts: {:?}", e);
+                }
+            }
+            Err(e) => {
+                eprintln!("Error connecting to RabbitMQ: {:?}", e);
+            }
+        }
+
+        eprintln!("Reconnecting to receipts in 5 seconds...");
+        sleep(Duration::from_secs(5)).await;
+    }
+}
+
+async fn process_receipts(
+    rabbit_channel: RabbitChannel,
+    receipts: &ReceiptStore,
+) -> Result<(), LapinError> {
+    declare_receipts_exchange(&rabbit_channel).await?;
+
+    // Every replica answers receipt queries, so each records every receipt
+    let queue = rabbit_channel
+        .queue_declare(
+            "",
+            QueueDeclareOptions {
+                exclusive: true,
+                auto_delete: true,
+                ..Default::default()
+            },
+            FieldTable::default(),
+        )
+        .await?;
+
+    rabbit_channel
+        .queue_bind(
+            queue.name().as_str(),
+            &CONFIG.receipts.exchange,
+            "",
+            QueueBindOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    let mut consumer = rabbit_channel
+        .basic_consume(
+            queue.name().as_str(),
+            "receipts_consumer",
+            BasicConsumeOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    while let Some(delivery) = consumer.next().await {
+        let delivery = delivery?;
+
+        match serde_json::from_slice::<ReceiptEvent>(&delivery.data) {
+            Ok(event) => record_receipt(receipts, event).await,
+            Err(e) => println!("Failed to deserialize receipt: {:?}", e),
+        }
+
+        delivery.ack(BasicAckOptions::default()).await?;
+    }
+
+    Ok(())
+}
+
+pub async fn prune_receipts(receipts: ReceiptStore) {
+    let mut ticker = interval(Duration::from_secs(3600));
+
+    loop {
+        ticker.tick().await;
+        let cutoff = Utc::now() - ChronoDuration::days(CONFIG.receipts.retention_days as i64);
+        let removed = receipts.retain(|message| message.updated_at > cutoff).await;
+        if removed > 0 {
+            println!("Pruned receipts of {} messages", removed);
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/notification/messages/{message_id}/receipts",
+    params(
+        ("message_id" = String, Path, description = "The envelope id of the delivered message")
+    ),
+    responses(
+        (status = 200, description = "Delivery and read receipts per user; recipients only see their own", body = MessageReceipts),
+        (status = 404, description = "No receipts for this message that the caller may see")
+    ),
+    security(("bearerAuth" = []), ("apiBearerAuth" = [])),
+    tag = "default"
+)]
+pub async fn get_receipts(
+    message_id: String,
+    caller: String,
+    receipts: ReceiptStore,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let Some(message) = receipts.get(&message_id).await else {
+        return Ok(receipts_not_found());
+    };
+
+    match visible_receipts(message, &caller) {
+        Some(message) => Ok(warp::reply::with_status(
+            warp::reply::json(&message),
+            StatusCode::OK,
+        )),
+        None => Ok(receipts_not_found()),
+    }
+}
+
+fn receipts_not_found() -> warp::reply::WithStatus<warp::reply::Json> {
+    warp::reply::with_status(
+        warp::reply::json(&"No receipts for this message"),
+        StatusCode::NOT_FOUND,
+    )
+}
+
+// The publisher sees every receipt, a recipient only their own, and anyone else nothing, not
+// even whether the message exists
+fn visible_receipts(mut message: MessageReceipts, caller: &str) -> Option<MessageReceipts> {
+    if message.publisher.as_deref() == Some(caller) {
+        return Some(message);
+    }
+
+    message
+        .receipts
+        .retain(|receipt| caller.strip_prefix("user:") == Some(receipt.user_id.as_str()));
+    (!message.receipts.is_empty()).then_some(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn receipt(user_id: &str) -> Receipt {
+        Receipt {
+            user_id: user_id.to_string(),
+            delivered_at: Utc::now(),
+            read_at: None,
+        }
+    }
+
+    fn message() -> MessageReceipts {
+        MessageReceipts {
+            message_id: "m1".to_string(),
+            publisher: Some("api:build-service".to_string()),
+            receipts: vec![receipt("17"), receipt("23")],
+            updated_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn publisher_sees_every_receipt() {
+        let visible = visible_receipts(message(), "api:build-service").unwrap();
+        assert_eq!(visible.receipts.len(), 2);
+    }
+
+    #[test]
+    fn recipient_sees_only_their_own_receipt() {
+        let visible = visible_receipts(message(), "user:17").unwrap();
+        assert_eq!(visible.receipts.len(), 1);
+        assert_eq!(visible.receipts[0].user_id, "17");
+    }
+
+    #[test]
+    fn others_see_nothing() {
+        assert!(visible_receipts(message(), "user:31").is_none());
+        assert!(visible_receipts(message(), "api:other-service").is_none());
+        // An API client named like a user id isn't that user
+        assert!(visible_receipts(message(), "api:17").is_none());
+    }
+
+    #[test]
+    fn only_remembers_the_latest_deliveries() {
+        let mut delivered = DeliveredMessages::new(2);
+        delivered.record("a".to_string(), Some("api:x".to_string()));
+        delivered.record("b".to_string(), None);
+        delivered.record("c".to_string(), None);
+
+        assert_eq!(delivered.publisher("a"), None);
+        assert_eq!(delivered.publisher("b"), Some(None));
+        assert_eq!(delivered.publisher("c"), Some(None));
+        assert_eq!(delivered.publisher("never"), None);
+    }
+
+    #[test]
+    fn redelivery_keeps_one_entry() {
+        let mut delivered = DeliveredMessages::new(2);
+        delivered.record("a".to_string(), Some("api:x".to_string()));
+        delivered.record("a".to_string(), Some("api:x".to_string()));
+        delivered.record("b".to_string(), None);
+
+        assert_eq!(delivered.publisher("a"), Some(Some("api:x".to_string())));
+    }
+}
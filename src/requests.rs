@@ -1,21 +1,154 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use utoipa::ToSchema;
 
-#[derive(Deserialize, Serialize, ToSchema)]
+use crate::{digest::DigestFrequency, outbound::Priority};
+
+#[derive(Deserialize, Serialize, ToSchema, Clone, Default)]
 pub struct PublishRequest {
-    pub message: String, // Only the message is in the body
+    pub message: String,
+    pub deliver_at: Option<DateTime<Utc>>, // Hold the message back until this time
+    pub delay_secs: Option<u64>,           // Or for this many seconds
+    pub expires_at: Option<DateTime<Utc>>, // Never deliver the message after this time
+    pub ttl_secs: Option<u64>,             // Or later than this many seconds after publishing
+    pub priority: Option<Priority>,        // Defaults to normal
+    pub collapse_key: Option<String>, // Newer messages replace undelivered ones on the channel with the same key
+    #[serde(rename = "type")]
+    pub event_type: Option<String>, // Lets recipients' preferences route the message
+}
+
+impl PublishRequest {
+    // When a message published at `published_at` goes stale, if ever. A `ttl_secs` too large
+    // to represent gives None; `validate_expiry` rejects those.
+    pub fn expiry(&self, published_at: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.expires_at.or_else(|| {
+            self.ttl_secs
+                .and_then(|ttl_secs| i64::try_from(ttl_secs).ok())
+                .and_then(chrono::Duration::try_seconds)
+                .and_then(|ttl| published_at.checked_add_signed(ttl))
+        })
+    }
+}
+
+// A notification for one user, delivered however their preferences and presence dictate
+#[derive(Deserialize, Serialize, ToSchema, Clone)]
+pub struct NotifyRequest {
+    #[serde(rename = "type")]
+    pub event_type: String,
+    #[serde(default)]
+    #[schema(value_type = Object)]
+    pub payload: serde_json::Value,
+    pub template: Option<String>, // Name of a [templates] entry rendering the email
+    pub priority: Option<Priority>,
+}
+
+// Frames clients send over the WebSocket to acknowledge or mark a delivered message as read
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ClientFrame {
+    Ack { id: String },
+    Read { id: String },
 }
 
+// One entry of a batch publish: the same envelope sent to each of `channels`
 #[derive(Deserialize, Serialize, ToSchema)]
+pub struct BatchPublishItem {
+    pub channels: Vec<String>,
+    pub envelope: PublishRequest,
+}
+
+#[derive(Deserialize, Serialize, ToSchema, Clone)]
 pub struct EmailRequest {
     pub message: String,
     pub to: String,
     pub reply_to: Option<String>,
     pub subject: String,
+    pub from: Option<String>, // Must be one of the calling service's configured senders
+    pub digest: Option<DigestFrequency>, // Batch into an hourly / daily digest instead of sending now
+}
+
+// A system message for every connected client
+#[derive(Deserialize, Serialize, ToSchema, Clone)]
+pub struct BroadcastRequest {
+    pub message: String,
+}
+
+#[derive(Deserialize, Serialize, ToSchema, Clone)]
+pub struct SmsRequest {
+    pub to: String, // E.164, e.g. +14155550123
+    pub message: String,
 }
 
 #[derive(Deserialize, Serialize)]
 pub struct RabbitMessage {
     pub channel_id: String,
     pub message: String,
+    #[serde(default)]
+    pub message_id: String,
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub priority: Priority,
+    pub collapse_key: Option<String>,
+    #[serde(rename = "type")]
+    pub event_type: Option<String>,
+    #[serde(default)]
+    pub publisher: Option<String>, // Caller identity, e.g. user:17 or api:build-service
+}
+
+// Payload placed on the email queue and picked up by the email worker
+#[derive(Deserialize, Serialize, Clone)]
+pub struct EmailJob {
+    pub id: String,
+    pub request: EmailRequest,
+    // Jobs queued by earlier releases carry no source and go out as the default sender
+    #[serde(default)]
+    pub source: String,
+    pub attempts: u32,
+}
+
+// SNS wraps SES notifications in an envelope whose `Message` is the JSON encoded notification
+#[derive(Deserialize)]
+pub struct SnsEnvelope {
+    #[serde(rename = "Type")]
+    pub message_type: String,
+    #[serde(rename = "Message")]
+    pub message: Option<String>,
+    #[serde(rename = "SubscribeURL")]
+    pub subscribe_url: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SesNotification {
+    pub notification_type: String,
+    pub bounce: Option<SesBounce>,
+    pub complaint: Option<SesComplaint>,
+    pub mail: Option<SesMail>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SesBounce {
+    pub bounce_type: String,
+    pub bounced_recipients: Vec<SesRecipient>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SesComplaint {
+    pub complained_recipients: Vec<SesRecipient>,
+    pub complaint_feedback_type: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SesRecipient {
+    pub email_address: String,
+    pub diagnostic_code: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SesMail {
+    pub message_id: String,
 }
@@ -1,5 +1,10 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use utoipa::ToSchema;
 use warp::reject::Reject;
 
+use crate::email_queue::EmailStatus;
+
 // Custom JWT Error
 #[derive(Debug)]
 pub struct JWTError;
@@ -14,3 +19,156 @@ impl Reject for InvalidTokenError {}
 #[derive(Debug)]
 pub struct EncodeError;
 impl Reject for EncodeError {}
+
+// Channel name or subscription pattern that isn't valid
+#[derive(Debug)]
+pub struct InvalidChannelError;
+impl Reject for InvalidChannelError {}
+
+// Authenticated, but not allowed to do what was asked
+#[derive(Debug)]
+pub struct ForbiddenError;
+impl Reject for ForbiddenError {}
+
+// The replica is shutting down and takes no new sockets
+#[derive(Debug)]
+pub struct ShuttingDownError;
+impl Reject for ShuttingDownError {}
+
+#[derive(Serialize, ToSchema)]
+pub struct EmailQueuedResponse {
+    pub id: String,
+    pub status: EmailStatus,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct DigestQueuedResponse {
+    pub digest_id: String,
+    pub pending_items: usize,
+    pub flush_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct BatchPublishResult {
+    pub index: usize,
+    pub status: u16,
+    pub channels: Vec<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct PresenceResponse {
+    pub channel: String,
+    pub connections: usize,
+    pub users: Vec<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct NotifyResponse {
+    pub id: String,
+    pub online: bool,
+    pub delivered_via: Vec<String>, // Any of websocket, push, inbox and email
+    pub email_fallback_at: Option<DateTime<Utc>>, // When the user is emailed unless seen online
+}
+
+// The applicationServerKey browsers subscribe with
+#[derive(Serialize, ToSchema)]
+pub struct VapidPublicKeyResponse {
+    pub public_key: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct SmsSentResponse {
+    pub id: String, // The provider's message id
+    pub transport: String,
+}
+
+// A channel with subscribers on one replica. `buffered` counts messages waiting in its
+// connections' outbound queues, out of `buffer_capacity`.
+#[derive(Serialize, ToSchema)]
+pub struct ChannelSummary {
+    pub name: String,
+    pub subscribers: usize,
+    pub buffered: usize,
+    pub buffer_capacity: usize,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct AdminChannelsResponse {
+    pub replica_id: String,
+    pub channels: Vec<ChannelSummary>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ConnectionSummary {
+    pub connection_id: String,
+    pub user_id: Option<String>, // None for API clients
+    pub connected_at: DateTime<Utc>,
+    pub remote_addr: Option<String>,
+    pub buffered: usize,
+}
+
+// Other replicas apply the action as it reaches them, so only local connections are counted
+#[derive(Serialize, ToSchema)]
+pub struct AdminActionResponse {
+    pub local_connections: usize,
+}
+
+// A registered webhook, without its secret
+#[derive(Serialize, ToSchema)]
+pub struct WebhookResponse {
+    pub id: String,
+    pub channel: String,
+    pub url: String,
+    pub event_types: Vec<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+// Frames the service itself sends to WebSocket clients, told apart from deliveries by `type`
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ControlFrame<'a> {
+    Presence {
+        channel: &'a str,
+        event: &'a str,
+        user_id: &'a str,
+    },
+    // An announcement from an administrator, sent to every connection
+    System {
+        message: &'a str,
+    },
+    // Sent right before the service closes the socket
+    Disconnect {
+        reason: &'a str,
+    },
+    // Sent when the replica shuts down; clients should wait `delay_ms` and connect again,
+    // which lands them on another replica
+    Reconnect {
+        reason: &'a str,
+        delay_ms: u64,
+    },
+}
+
+impl ControlFrame<'_> {
+    pub fn encode(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+// What WebSocket clients receive; `id` is stable across retries of an idempotent publish
+#[derive(Serialize)]
+pub struct DeliveryEnvelope<'a> {
+    pub id: &'a str,
+    pub channel: &'a str,
+    pub message: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub collapse_key: Option<&'a str>,
+}
+
+impl DeliveryEnvelope<'_> {
+    pub fn encode(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
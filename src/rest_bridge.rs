@@ -1,28 +1,37 @@
 use ginger_shared_rs::rocket_utils::{APIClaims, Claims};
 
 use crate::{
-    requests::{PublishRequest, RabbitMessage},
-    shared::{connect_rabbitmq, Channels},
-};
-use lapin::{
-    options::{BasicAckOptions, BasicConsumeOptions, BasicPublishOptions},
-    BasicProperties, Channel as RabbitChannel, Connection, ConnectionProperties,
-}; // Renaming lapin::Channel to RabbitChannel
-use IAMService::{
-    apis::default_api::{identity_get_group_members_ids, IdentityGetGroupMembersIdsParams},
-    get_configuration,
+    channel_acl::{authorize_publish, validate_channel_name, validate_expiry, validate_message},
+    config::CONFIG,
+    group_membership::{service_auth_header, SharedGroupCache},
+    prom_helpers::GROUP_FANOUT_SIZE,
+    requests::{BatchPublishItem, PublishRequest, RabbitMessage},
+    responses::BatchPublishResult,
+    schedule::{defer_publish, ScheduleStore},
+    shared::{routing_key, Channels, CHANNEL_EXCHANGE, GROUP_CHANNEL_PREFIX, RABBIT_POOL},
+    shutdown::SHUTDOWN,
 };
+use chrono::Utc;
+use futures::{stream, StreamExt};
+use lapin::{options::BasicPublishOptions, BasicProperties, Channel as RabbitChannel}; // Renaming lapin::Channel to RabbitChannel
+use uuid::Uuid;
+use warp::http::StatusCode;
 
 #[utoipa::path(
     post,
     path = "/notification/channels/{channel_name}/publish",
     params(
-        ("channel_name" = String, Path, description = "The name of the channel to publish to")
+        ("channel_name" = String, Path, description = "The name of the channel to publish to"),
+        ("Idempotency-Key" = Option<String>, Header, description = "Retries with the same key within the idempotency window replay the original response")
     ),
     request_body = PublishRequest,
     responses(
         (status = 200, description = "Message sent"),
-        (status = 404, description = "Channel not found")
+        (status = 202, description = "Message scheduled for `deliver_at` / `delay_secs`", body = ScheduledNotification),
+        (status = 400, description = "Message too large, or invalid delivery or expiry time"),
+        (status = 404, description = "Channel not found"),
+        (status = 409, description = "A request with the same Idempotency-Key is still in progress, see Retry-After"),
+        (status = 422, description = "Idempotency-Key already used for a different request")
     ),
     security(("bearerAuth" = [])),  // Referencing the security scheme
     tag = "default"
@@ -31,52 +40,124 @@ pub async fn publish_message(
     channel_name: String,
     publish_request: PublishRequest,
     claims: Claims, // Add claims from JWT here
-    auth_header: String,
+    _auth_header: String,
     _channels: Channels,
-) -> Result<impl warp::Reply, warp::Rejection> {
-    if let Ok(rabbit_channel) = connect_rabbitmq().await {
-        // let payload = publish_request.message.clone().into_bytes();
+    schedules: ScheduleStore,
+    message_id: String,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    if let Err(e) = validate_channel_name(&channel_name)
+        .and_then(|_| validate_message(&publish_request.message))
+        .and_then(|_| validate_expiry(&publish_request))
+    {
+        return Ok(Box::new(warp::reply::with_status(
+            warp::reply::json(&e),
+            StatusCode::BAD_REQUEST,
+        )));
+    }
 
-        let rabbit_message = RabbitMessage {
-            channel_id: channel_name.clone(), // channel_id from the path
-            message: publish_request.message.clone(),
-        };
+    let owner = format!("user:{}", claims.user_id);
+    if let Some(reply) = defer_publish(
+        &schedules,
+        &channel_name,
+        &publish_request,
+        &message_id,
+        &owner,
+    )
+    .await
+    {
+        return Ok(reply);
+    }
 
-        match rabbit_channel
-            .basic_publish(
-                "real-time-updates", // Exchange name
-                "",                  // Routing key
-                BasicPublishOptions::default(),
-                &serde_json::to_string(&rabbit_message).unwrap().into_bytes(),
-                BasicProperties::default(),
-            )
-            .await
+    if let Ok(rabbit_channel) = RABBIT_POOL.channel().await {
+        match publish_to_channel(
+            &rabbit_channel,
+            &channel_name,
+            &publish_request,
+            &message_id,
+            &owner,
+        )
+        .await
         {
             Ok(_) => {
                 println!("Message successfully sent to RabbitMQ");
-                Ok(warp::reply::json(&"Message sent"))
+                Ok(Box::new(warp::reply::json(&"Message sent")))
             }
             Err(e) => {
                 println!("Failed to send message to RabbitMQ: {:?}", e);
-                Ok(warp::reply::json(&"Failed to send message to RabbitMQ"))
+                Ok(Box::new(warp::reply::json(
+                    &"Failed to send message to RabbitMQ",
+                )))
             }
         }
     } else {
         println!("Unable to connect to RabbitMQ");
-        Ok(warp::reply::json(&"Unable to connect to RabbitMQ"))
+        Ok(Box::new(warp::reply::json(
+            &"Unable to connect to RabbitMQ",
+        )))
     }
 }
 
+pub async fn publish_to_channel(
+    rabbit_channel: &RabbitChannel,
+    channel_id: &str,
+    publish_request: &PublishRequest,
+    message_id: &str,
+    publisher: &str,
+) -> Result<(), lapin::Error> {
+    // Shutdown waits for this publish to reach RabbitMQ
+    let _publishing = SHUTDOWN.track_publish();
+    let now = Utc::now();
+    let rabbit_message = RabbitMessage {
+        channel_id: channel_id.to_string(),
+        message: publish_request.message.clone(),
+        message_id: message_id.to_string(),
+        expires_at: publish_request.expiry(now),
+        priority: publish_request.priority.unwrap_or_default(),
+        collapse_key: publish_request.collapse_key.clone(),
+        event_type: publish_request.event_type.clone(),
+        publisher: Some(publisher.to_string()),
+    };
+
+    // Let RabbitMQ discard stale messages still queued; consumers check `expires_at` as well.
+    // RabbitMQ refuses expirations past u32::MAX milliseconds, so those are left to consumers.
+    let mut properties = BasicProperties::default();
+    if let Some(expires_at) = rabbit_message.expires_at {
+        let ttl_ms = (expires_at - now).num_milliseconds().max(0);
+        if let Ok(ttl_ms) = u32::try_from(ttl_ms) {
+            properties = properties.with_expiration(ttl_ms.to_string().into());
+        }
+    }
+
+    rabbit_channel
+        .basic_publish(
+            CHANNEL_EXCHANGE,
+            routing_key(channel_id),
+            BasicPublishOptions::default(),
+            &serde_json::to_string(&rabbit_message).unwrap().into_bytes(),
+            properties,
+        )
+        .await?;
+
+    Ok(())
+}
+
 #[utoipa::path(
     post,
     path = "/notification/groups/{group_id}/publish",
     params(
-        ("group_id" = String, Path, description = "The id of the group to publish to")
+        ("group_id" = String, Path, description = "The id of the group to publish to"),
+        ("Idempotency-Key" = Option<String>, Header, description = "Retries with the same key within the idempotency window replay the original response")
     ),
     request_body = PublishRequest,
     responses(
         (status = 200, description = "Message sent"),
-        (status = 404, description = "Channel not found")
+        (status = 202, description = "Message scheduled for `deliver_at` / `delay_secs`", body = ScheduledNotification),
+        (status = 400, description = "Message too large, or invalid delivery or expiry time"),
+        (status = 404, description = "Channel not found"),
+        (status = 409, description = "A request with the same Idempotency-Key is still in progress, see Retry-After"),
+        (status = 422, description = "Idempotency-Key already used for a different request"),
+        (status = 502, description = "The group's members couldn't be looked up in IAM"),
+        (status = 503, description = "Group delivery is disabled as IAM_API_TOKEN is not set")
     ),
     security(("apiBearerAuth" = [])),  // Referencing the security scheme
     tag = "default"
@@ -84,79 +165,243 @@ pub async fn publish_message(
 pub async fn publish_message_to_group(
     group_id: String,
     publish_request: PublishRequest,
-    _claims: APIClaims, // Add claims from JWT here
+    claims: APIClaims, // Add claims from JWT here
     auth_header: String,
-    _channels: Channels,
-) -> Result<impl warp::Reply, warp::Rejection> {
-    // Connect to RabbitMQ using the connection pool
-    let rabbit_channel_result = connect_rabbitmq().await;
+    group_cache: SharedGroupCache,
+    schedules: ScheduleStore,
+    message_id: String,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    if let Err(e) =
+        validate_message(&publish_request.message).and_then(|_| validate_expiry(&publish_request))
+    {
+        return Ok(Box::new(warp::reply::with_status(
+            warp::reply::json(&e),
+            StatusCode::BAD_REQUEST,
+        )));
+    }
 
-    match rabbit_channel_result {
-        Ok(rabbit_channel) => {
-            println!("{:?}", auth_header);
+    // Replicas couldn't resolve the members when delivering the message
+    if service_auth_header().is_none() {
+        return Ok(Box::new(warp::reply::with_status(
+            warp::reply::json(&"Group delivery is disabled"),
+            StatusCode::SERVICE_UNAVAILABLE,
+        )));
+    }
+
+    // Make sure the group resolves before publishing; this also warms the membership cache
+    let members = match group_cache.get_members(&group_id, auth_header).await {
+        Ok(members) => members,
+        Err(e) => {
+            println!("Failed to get members of group {}: {}", group_id, e);
+            return Ok(Box::new(warp::reply::with_status(
+                warp::reply::json(&"Failed to get group members"),
+                StatusCode::BAD_GATEWAY,
+            )));
+        }
+    };
+    GROUP_FANOUT_SIZE.observe(members.len() as f64);
+
+    // Published once; every replica delivers it to its own sockets of group members
+    let group_channel = format!("{}{}", GROUP_CHANNEL_PREFIX, group_id);
 
-            let iam_config = get_configuration(Some(auth_header));
+    let owner = format!("api:{}", claims.sub);
+    if let Some(reply) = defer_publish(
+        &schedules,
+        &group_channel,
+        &publish_request,
+        &message_id,
+        &owner,
+    )
+    .await
+    {
+        return Ok(reply);
+    }
 
-            // Fetch group member IDs
-            match identity_get_group_members_ids(
-                &iam_config,
-                IdentityGetGroupMembersIdsParams {
-                    group_identifier: group_id,
-                },
+    match RABBIT_POOL.channel().await {
+        Ok(rabbit_channel) => {
+            match publish_to_channel(
+                &rabbit_channel,
+                &group_channel,
+                &publish_request,
+                &message_id,
+                &owner,
             )
             .await
             {
-                Ok(ids) => {
-                    let mut publish_results = vec![]; // Collect publish results
-
-                    for id in ids {
-                        let rabbit_message = RabbitMessage {
-                            channel_id: id.clone().to_string(), // Use the ID as the channel ID
-                            message: publish_request.message.clone(),
-                        };
-
-                        // Publish the message to the corresponding channel
-                        let publish_result = rabbit_channel
-                            .basic_publish(
-                                "real-time-updates", // Exchange name
-                                "",                  // Routing key
-                                BasicPublishOptions::default(),
-                                &serde_json::to_string(&rabbit_message).unwrap().into_bytes(),
-                                BasicProperties::default(),
-                            )
-                            .await;
-
-                        // Store the result of the publish attempt
-                        match publish_result {
-                            Ok(_) => {
-                                println!("Message successfully sent to RabbitMQ for ID: {}", id);
-                                publish_results.push(format!("Message sent for ID: {}", id));
-                            }
-                            Err(e) => {
-                                println!(
-                                    "Failed to send message to RabbitMQ for ID: {}: {:?}",
-                                    id, e
-                                );
-                                publish_results.push(format!(
-                                    "Failed to send message for ID: {}: {:?}",
-                                    id, e
-                                ));
-                            }
-                        }
-                    }
-
-                    // Respond with the results of the publish attempts
-                    Ok(warp::reply::json(&publish_results))
+                Ok(_) => {
+                    println!(
+                        "Group message successfully sent to RabbitMQ for {}",
+                        group_id
+                    );
+                    Ok(Box::new(warp::reply::json(&"Message sent")))
                 }
                 Err(e) => {
-                    println!("{:?}", e);
-                    Ok(warp::reply::json(&"Failed to get group members"))
+                    println!("Failed to send group message to RabbitMQ: {:?}", e);
+                    Ok(Box::new(warp::reply::json(
+                        &"Failed to send message to RabbitMQ",
+                    )))
                 }
             }
         }
         Err(e) => {
             println!("Unable to connect to RabbitMQ: {:?}", e);
-            Ok(warp::reply::json(&"Unable to connect to RabbitMQ"))
+            Ok(Box::new(warp::reply::json(
+                &"Unable to connect to RabbitMQ",
+            )))
         }
     }
 }
+
+#[utoipa::path(
+    post,
+    path = "/notification/publish/batch",
+    request_body = [BatchPublishItem],
+    responses(
+        (status = 200, description = "Per-item publish results", body = [BatchPublishResult]),
+        (status = 413, description = "Too many items or channels in the batch"),
+        (status = 503, description = "Unable to connect to RabbitMQ")
+    ),
+    security(("apiBearerAuth" = [])),
+    tag = "default"
+)]
+pub async fn publish_batch(
+    items: Vec<BatchPublishItem>,
+    claims: APIClaims,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if items.len() > CONFIG.publish.max_batch_items {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&format!(
+                "A batch may contain at most {} items",
+                CONFIG.publish.max_batch_items
+            )),
+            StatusCode::PAYLOAD_TOO_LARGE,
+        ));
+    }
+
+    let channel_count: usize = items.iter().map(|item| item.channels.len()).sum();
+    if channel_count > CONFIG.publish.max_batch_channels {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&format!(
+                "A batch may publish to at most {} channels",
+                CONFIG.publish.max_batch_channels
+            )),
+            StatusCode::PAYLOAD_TOO_LARGE,
+        ));
+    }
+
+    let rabbit_channel = match RABBIT_POOL.channel().await {
+        Ok(rabbit_channel) => rabbit_channel,
+        Err(e) => {
+            println!("Unable to connect to RabbitMQ: {:?}", e);
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&"Unable to connect to RabbitMQ"),
+                StatusCode::SERVICE_UNAVAILABLE,
+            ));
+        }
+    };
+
+    let mut results = vec![];
+    let mut accepted = vec![];
+
+    for (index, item) in items.into_iter().enumerate() {
+        let validation = if item.channels.is_empty() {
+            Err("No channels given".to_string())
+        } else {
+            item.channels
+                .iter()
+                .try_for_each(|channel| validate_channel_name(channel))
+                .and_then(|_| validate_message(&item.envelope.message))
+                .and_then(|_| validate_expiry(&item.envelope))
+                .and_then(|_| reject_scheduling(&item.envelope))
+        };
+        if let Err(error) = validation {
+            results.push(BatchPublishResult {
+                index,
+                status: StatusCode::BAD_REQUEST.as_u16(),
+                channels: item.channels,
+                error: Some(error),
+            });
+            continue;
+        }
+
+        if let Err(error) = item
+            .channels
+            .iter()
+            .try_for_each(|channel| authorize_publish(&claims.sub, channel))
+        {
+            results.push(BatchPublishResult {
+                index,
+                status: StatusCode::FORBIDDEN.as_u16(),
+                channels: item.channels,
+                error: Some(error),
+            });
+            continue;
+        }
+
+        accepted.push((index, Uuid::new_v4().to_string(), item));
+    }
+
+    // Publish a few at a time so lapin pipelines the messages on the pooled channel
+    let publisher = format!("api:{}", claims.sub);
+    let publishes = accepted.iter().flat_map(|(index, message_id, item)| {
+        let rabbit_channel = &rabbit_channel;
+        let publisher = &publisher;
+        item.channels.iter().map(move |channel| async move {
+            let outcome = publish_to_channel(
+                rabbit_channel,
+                channel,
+                &item.envelope,
+                message_id,
+                publisher,
+            )
+            .await;
+            (*index, channel.clone(), outcome)
+        })
+    });
+    let outcomes = stream::iter(publishes.collect::<Vec<_>>())
+        .buffer_unordered(CONFIG.publish.batch_concurrency.max(1))
+        .collect::<Vec<_>>()
+        .await;
+
+    for (index, _, item) in accepted {
+        let failed = outcomes
+            .iter()
+            .filter(|(outcome_index, _, _)| *outcome_index == index)
+            .filter_map(|(_, channel, outcome)| {
+                outcome
+                    .as_ref()
+                    .err()
+                    .map(|e| format!("{}: {:?}", channel, e))
+            })
+            .collect::<Vec<_>>();
+
+        let (status, error) = if failed.is_empty() {
+            (StatusCode::OK, None)
+        } else {
+            println!("Batch item {} failed to publish: {:?}", index, failed);
+            (StatusCode::BAD_GATEWAY, Some(failed.join(", ")))
+        };
+
+        results.push(BatchPublishResult {
+            index,
+            status: status.as_u16(),
+            channels: item.channels,
+            error,
+        });
+    }
+
+    results.sort_by_key(|result| result.index);
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&results),
+        StatusCode::OK,
+    ))
+}
+
+// Batches are published immediately; schedule messages through the single publish endpoints
+fn reject_scheduling(envelope: &PublishRequest) -> Result<(), String> {
+    if envelope.deliver_at.is_some() || envelope.delay_secs.is_some() {
+        return Err("Scheduling is not supported in batch publish".to_string());
+    }
+    Ok(())
+}
@@ -0,0 +1,504 @@
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use futures::StreamExt;
+use lapin::{
+    options::{
+        BasicAckOptions, BasicConsumeOptions, BasicPublishOptions, ExchangeDeclareOptions,
+        QueueBindOptions, QueueDeclareOptions,
+    },
+    types::FieldTable,
+    BasicProperties, Channel as RabbitChannel, Error as LapinError,
+};
+use serde::{Deserialize, Serialize};
+use tokio::time::{sleep, Duration};
+use utoipa::ToSchema;
+use uuid::Uuid;
+use warp::http::StatusCode;
+
+use crate::{
+    config::CONFIG,
+    leader::run_on_one_replica,
+    prom_helpers::MESSAGES_EXPIRED,
+    requests::PublishRequest,
+    rest_bridge::publish_to_channel,
+    shared::{open_rabbitmq_channel, RABBIT_POOL},
+    store::Store,
+};
+
+// Ticks for publishing due notifications, handled by one replica at a time
+const SCHEDULE_PUBLISH_QUEUE: &str = "schedule-publish-ticks";
+
+// A publish held back until `deliver_at`. `channel` is the exchange-level channel id, so
+// group publishes keep their `group:` prefix.
+#[derive(Deserialize, Serialize, ToSchema, Clone)]
+pub struct ScheduledNotification {
+    pub id: String,
+    pub channel: String,
+    pub request: PublishRequest,
+    pub message_id: String,
+    pub owner: String,
+    pub deliver_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+}
+
+// Keyed by scheduled notification id
+pub type ScheduleStore = Store<ScheduledNotification>;
+
+// Scheduled notifications are replicated over `schedule.exchange`, so every replica can list and
+// cancel them and the one publishing them knows of all of them
+#[derive(Deserialize, Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+enum ScheduleEvent {
+    Scheduled {
+        notification: Box<ScheduledNotification>,
+    },
+    Removed {
+        id: String,
+    },
+}
+
+pub async fn declare_schedule_exchange(rabbit_channel: &RabbitChannel) -> Result<(), LapinError> {
+    rabbit_channel
+        .exchange_declare(
+            &CONFIG.schedule.exchange,
+            lapin::ExchangeKind::Fanout,
+            ExchangeDeclareOptions {
+                durable: true,
+                ..Default::default()
+            },
+            FieldTable::default(),
+        )
+        .await
+}
+
+async fn publish_event(event: &ScheduleEvent) -> Result<(), LapinError> {
+    let rabbit_channel = RABBIT_POOL.channel().await?;
+
+    rabbit_channel
+        .basic_publish(
+            &CONFIG.schedule.exchange,
+            "",
+            BasicPublishOptions::default(),
+            &serde_json::to_vec(event).unwrap(),
+            BasicProperties::default(),
+        )
+        .await?;
+    Ok(())
+}
+
+// Applying an event twice is harmless, as the replica that published it applies it right away.
+// Returns the notification a removal took out, if this replica still had it.
+async fn apply_event(
+    schedules: &ScheduleStore,
+    event: ScheduleEvent,
+) -> Option<ScheduledNotification> {
+    match event {
+        ScheduleEvent::Scheduled { notification } => {
+            schedules
+                .insert(notification.id.clone(), *notification)
+                .await;
+            None
+        }
+        ScheduleEvent::Removed { id } => schedules.remove(&id).await,
+    }
+}
+
+async fn replicate(
+    schedules: &ScheduleStore,
+    event: ScheduleEvent,
+) -> Option<ScheduledNotification> {
+    if let Err(e) = publish_event(&event).await {
+        eprintln!("Failed to replicate scheduled notification change: {:?}", e);
+    }
+    apply_event(schedules, event).await
+}
+
+pub async fn consume_schedule_events(schedules: ScheduleStore) {
+    loop {
+        match open_rabbitmq_channel().await {
+            Ok(rabbit_channel) => {
+                if let Err(e) = process_schedule_events(rabbit_channel, &schedules).await {
+                    eprintln!("Error processing schedule events: {:?}", e);
+                }
+            }
+            Err(e) => {
+                eprintln!("Error connecting to RabbitMQ: {:?}", e);
+            }
+        }
+
+        eprintln!("Reconnecting to schedule events in 5 seconds...");
+        sleep(Duration::from_secs(5)).await;
+    }
+}
+
+async fn process_schedule_events(
+    rabbit_channel: RabbitChannel,
+    schedules: &ScheduleStore,
+) -> Result<(), LapinError> {
+    declare_schedule_exchange(&rabbit_channel).await?;
+
+    let queue = rabbit_channel
+        .queue_declare(
+            "",
+            QueueDeclareOptions {
+                exclusive: true,
+                auto_delete: true,
+                ..Default::default()
+            },
+            FieldTable::default(),
+        )
+        .await?;
+
+    rabbit_channel
+        .queue_bind(
+            queue.name().as_str(),
+            &CONFIG.schedule.exchange,
+            "",
+            QueueBindOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    let mut consumer = rabbit_channel
+        .basic_consume(
+            queue.name().as_str(),
+            "schedule_consumer",
+            BasicConsumeOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    while let Some(delivery) = consumer.next().await {
+        let delivery = delivery?;
+
+        match serde_json::from_slice::<ScheduleEvent>(&delivery.data) {
+            Ok(event) => {
+                apply_event(schedules, event).await;
+            }
+            Err(e) => println!("Failed to deserialize schedule event: {:?}", e),
+        }
+
+        delivery.ack(BasicAckOptions::default()).await?;
+    }
+
+    Ok(())
+}
+
+// When the request asks to be delivered later, or None to publish straight away
+pub fn requested_delivery(
+    publish_request: &PublishRequest,
+) -> Result<Option<DateTime<Utc>>, String> {
+    let now = Utc::now();
+    let too_far = || {
+        format!(
+            "Notifications can be scheduled at most {} days ahead",
+            CONFIG.schedule.max_horizon_days
+        )
+    };
+
+    let deliver_at = match (publish_request.deliver_at, publish_request.delay_secs) {
+        (Some(_), Some(_)) => return Err("Set either deliver_at or delay_secs, not both".into()),
+        (Some(deliver_at), None) => deliver_at,
+        // Delays too large to represent are certainly past the horizon
+        (None, Some(delay_secs)) => i64::try_from(delay_secs)
+            .ok()
+            .and_then(ChronoDuration::try_seconds)
+            .and_then(|delay| now.checked_add_signed(delay))
+            .ok_or_else(too_far)?,
+        (None, None) => return Ok(None),
+    };
+
+    if publish_request
+        .expires_at
+        .is_some_and(|expires_at| expires_at <= deliver_at)
+    {
+        return Err("expires_at is before the delivery time".to_string());
+    }
+
+    let horizon = ChronoDuration::try_days(CONFIG.schedule.max_horizon_days.into())
+        .and_then(|horizon| now.checked_add_signed(horizon))
+        .unwrap_or(DateTime::<Utc>::MAX_UTC);
+    if deliver_at > horizon {
+        return Err(too_far());
+    }
+
+    // Anything due already is published right away
+    Ok((deliver_at > now).then_some(deliver_at))
+}
+
+// Store the publish for later when it asks for a future delivery time, returning the reply
+// for the caller. None means the message should be published now.
+pub async fn defer_publish(
+    schedules: &ScheduleStore,
+    channel: &str,
+    publish_request: &PublishRequest,
+    message_id: &str,
+    owner: &str,
+) -> Option<Box<dyn warp::Reply>> {
+    let deliver_at = match requested_delivery(publish_request) {
+        Ok(Some(deliver_at)) => deliver_at,
+        Ok(None) => return None,
+        Err(e) => {
+            return Some(Box::new(warp::reply::with_status(
+                warp::reply::json(&e),
+                StatusCode::BAD_REQUEST,
+            )))
+        }
+    };
+
+    let scheduled = ScheduledNotification {
+        id: Uuid::new_v4().to_string(),
+        channel: channel.to_string(),
+        request: publish_request.clone(),
+        message_id: message_id.to_string(),
+        owner: owner.to_string(),
+        deliver_at,
+        created_at: Utc::now(),
+    };
+    replicate(
+        schedules,
+        ScheduleEvent::Scheduled {
+            notification: Box::new(scheduled.clone()),
+        },
+    )
+    .await;
+    println!(
+        "Scheduled notification {} for {} at {}",
+        scheduled.id, channel, deliver_at
+    );
+
+    Some(Box::new(warp::reply::with_status(
+        warp::reply::json(&scheduled),
+        StatusCode::ACCEPTED,
+    )))
+}
+
+// Publish every scheduled notification whose delivery time has passed
+pub async fn publish_due_notifications(schedules: &ScheduleStore) {
+    let now = Utc::now();
+    let due = schedules
+        .values()
+        .await
+        .into_iter()
+        .filter(|scheduled| scheduled.deliver_at <= now)
+        .collect::<Vec<_>>();
+    if due.is_empty() {
+        return;
+    }
+
+    let rabbit_channel = match RABBIT_POOL.channel().await {
+        Ok(rabbit_channel) => rabbit_channel,
+        Err(e) => {
+            eprintln!(
+                "Unable to connect to RabbitMQ, retrying scheduled notifications: {:?}",
+                e
+            );
+            return;
+        }
+    };
+
+    for scheduled in due {
+        // Remove first, everywhere, so a notification cancelled in the meantime isn't published
+        let removal = ScheduleEvent::Removed {
+            id: scheduled.id.clone(),
+        };
+        let Some(scheduled) = replicate(schedules, removal).await else {
+            continue;
+        };
+        if scheduled
+            .request
+            .expires_at
+            .is_some_and(|expires_at| expires_at <= now)
+        {
+            println!(
+                "Dropping scheduled notification {}, it expired before it was published",
+                scheduled.id
+            );
+            MESSAGES_EXPIRED.inc();
+            continue;
+        }
+
+        match publish_to_channel(
+            &rabbit_channel,
+            &scheduled.channel,
+            &scheduled.request,
+            &scheduled.message_id,
+            &scheduled.owner,
+        )
+        .await
+        {
+            Ok(_) => println!(
+                "Published scheduled notification {} to {}",
+                scheduled.id, scheduled.channel
+            ),
+            Err(e) => {
+                eprintln!(
+                    "Failed to publish scheduled notification {}, retrying: {:?}",
+                    scheduled.id, e
+                );
+                let notification = Box::new(scheduled);
+                replicate(schedules, ScheduleEvent::Scheduled { notification }).await;
+            }
+        }
+    }
+}
+
+pub async fn run_notification_scheduler(schedules: ScheduleStore) {
+    let period = Duration::from_secs(CONFIG.schedule.poll_interval_secs.max(1));
+    run_on_one_replica(SCHEDULE_PUBLISH_QUEUE, period, || {
+        publish_due_notifications(&schedules)
+    })
+    .await;
+}
+
+#[utoipa::path(
+    get,
+    path = "/notification/scheduled",
+    responses(
+        (status = 200, description = "The caller's pending scheduled notifications", body = [ScheduledNotification]),
+    ),
+    security(("bearerAuth" = []), ("apiBearerAuth" = [])),
+    tag = "default"
+)]
+pub async fn list_scheduled(
+    owner: String,
+    schedules: ScheduleStore,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let mut items = schedules
+        .values()
+        .await
+        .into_iter()
+        .filter(|scheduled| scheduled.owner == owner)
+        .collect::<Vec<_>>();
+    items.sort_by_key(|scheduled| scheduled.deliver_at);
+    Ok(warp::reply::json(&items))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/notification/scheduled/{id}",
+    params(
+        ("id" = String, Path, description = "The id returned when the notification was scheduled")
+    ),
+    responses(
+        (status = 200, description = "Scheduled notification cancelled", body = ScheduledNotification),
+        (status = 404, description = "No pending scheduled notification with this id")
+    ),
+    security(("bearerAuth" = []), ("apiBearerAuth" = [])),
+    tag = "default"
+)]
+pub async fn cancel_scheduled(
+    id: String,
+    owner: String,
+    schedules: ScheduleStore,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let owned = schedules
+        .get(&id)
+        .await
+        .is_some_and(|scheduled| scheduled.owner == owner);
+
+    if !owned {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&"Scheduled notification not found"),
+            StatusCode::NOT_FOUND,
+        ));
+    }
+
+    match replicate(&schedules, ScheduleEvent::Removed { id: id.clone() }).await {
+        Some(scheduled) => {
+            println!("{} cancelled scheduled notification {}", owner, id);
+            Ok(warp::reply::with_status(
+                warp::reply::json(&scheduled),
+                StatusCode::OK,
+            ))
+        }
+        None => Ok(warp::reply::with_status(
+            warp::reply::json(&"Scheduled notification was already published"),
+            StatusCode::NOT_FOUND,
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn delayed(delay_secs: u64) -> PublishRequest {
+        PublishRequest {
+            delay_secs: Some(delay_secs),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn publishes_right_away_without_a_delivery_time() {
+        assert_eq!(requested_delivery(&PublishRequest::default()), Ok(None));
+        assert_eq!(requested_delivery(&delayed(0)), Ok(None));
+    }
+
+    #[test]
+    fn holds_back_delayed_publishes() {
+        let deliver_at = requested_delivery(&delayed(60)).unwrap().unwrap();
+
+        let delay = deliver_at - Utc::now();
+        assert!(delay > ChronoDuration::seconds(55) && delay <= ChronoDuration::seconds(60));
+    }
+
+    #[test]
+    fn rejects_delays_past_the_horizon() {
+        let horizon_secs = u64::from(CONFIG.schedule.max_horizon_days) * 24 * 60 * 60;
+
+        assert!(requested_delivery(&delayed(horizon_secs + 60)).is_err());
+        assert!(requested_delivery(&delayed(i64::MAX as u64)).is_err());
+        assert!(requested_delivery(&delayed(u64::MAX)).is_err());
+    }
+
+    #[test]
+    fn rejects_both_deliver_at_and_delay_secs() {
+        let request = PublishRequest {
+            deliver_at: Some(Utc::now()),
+            ..delayed(60)
+        };
+
+        assert!(requested_delivery(&request).is_err());
+    }
+
+    #[test]
+    fn rejects_expiry_before_delivery() {
+        let request = PublishRequest {
+            expires_at: Some(Utc::now() + ChronoDuration::seconds(30)),
+            ..delayed(60)
+        };
+
+        assert!(requested_delivery(&request).is_err());
+    }
+
+    #[tokio::test]
+    async fn removal_returns_the_notification_once() {
+        let file_name = format!("schedule-test-{}.json", Uuid::new_v4());
+        let schedules: ScheduleStore = crate::store::JsonStore::open(&file_name);
+        let notification = ScheduledNotification {
+            id: Uuid::new_v4().to_string(),
+            channel: "news".to_string(),
+            request: delayed(60),
+            message_id: Uuid::new_v4().to_string(),
+            owner: "billing-service".to_string(),
+            deliver_at: Utc::now() + ChronoDuration::seconds(60),
+            created_at: Utc::now(),
+        };
+        let id = notification.id.clone();
+
+        // Redelivered events leave a single copy behind
+        for _ in 0..2 {
+            let notification = Box::new(notification.clone());
+            apply_event(&schedules, ScheduleEvent::Scheduled { notification }).await;
+        }
+        assert_eq!(schedules.values().await.len(), 1);
+
+        // Only the first removal hands the notification over, so it's published or cancelled once
+        let removal = || ScheduleEvent::Removed { id: id.clone() };
+        assert!(apply_event(&schedules, removal()).await.is_some());
+        assert!(apply_event(&schedules, removal()).await.is_none());
+
+        let _ = std::fs::remove_file(crate::store::data_path(&file_name));
+    }
+}
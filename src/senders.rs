@@ -0,0 +1,125 @@
+use crate::config::{SenderIdentity, CONFIG};
+
+// Used by every service while no [[senders]] are configured
+pub const DEFAULT_SENDER: &str = "no-reply@gingersociety.org";
+
+// Sender details resolved for a single email
+pub struct ResolvedSender {
+    pub source: String,
+    pub from: String,
+    pub reply_to: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum SenderError {
+    UnknownService(String),
+    AddressNotAllowed(String),
+}
+
+impl std::fmt::Display for SenderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SenderError::UnknownService(service) => {
+                write!(f, "Service {} is not allowed to send email", service)
+            }
+            SenderError::AddressNotAllowed(address) => {
+                write!(f, "Sending as {} is not allowed for this service", address)
+            }
+        }
+    }
+}
+
+fn find_identity(service: &str) -> Option<&'static SenderIdentity> {
+    CONFIG
+        .senders
+        .iter()
+        .find(|identity| identity.service == service)
+}
+
+fn default_sender(
+    requested_from: Option<&str>,
+    requested_reply_to: Option<&str>,
+) -> Result<ResolvedSender, SenderError> {
+    match requested_from {
+        Some(from) if !from.eq_ignore_ascii_case(DEFAULT_SENDER) => {
+            Err(SenderError::AddressNotAllowed(from.to_string()))
+        }
+        _ => Ok(ResolvedSender {
+            source: DEFAULT_SENDER.to_string(),
+            from: DEFAULT_SENDER.to_string(),
+            reply_to: requested_reply_to.map(str::to_string),
+        }),
+    }
+}
+
+// Quotes, angle brackets and line breaks in the display name could end the header early or
+// inject another one, so they are left out
+fn format_source(display_name: &str, address: &str) -> String {
+    let display_name: String = display_name
+        .chars()
+        .filter(|c| !matches!(c, '"' | '<' | '>' | '\\') && !c.is_control())
+        .collect();
+    format!("\"{}\" <{}>", display_name, address)
+}
+
+// Pick the from-address for `service`, honouring `requested_from` if it is on the allow-list
+pub fn resolve_sender(
+    service: &str,
+    requested_from: Option<&str>,
+    requested_reply_to: Option<&str>,
+) -> Result<ResolvedSender, SenderError> {
+    if CONFIG.senders.is_empty() {
+        return default_sender(requested_from, requested_reply_to);
+    }
+
+    let identity =
+        find_identity(service).ok_or_else(|| SenderError::UnknownService(service.to_string()))?;
+
+    let sender = match requested_from {
+        Some(from) => identity
+            .addresses
+            .iter()
+            .find(|sender| sender.address.eq_ignore_ascii_case(from))
+            .ok_or_else(|| SenderError::AddressNotAllowed(from.to_string()))?,
+        None => identity
+            .addresses
+            .first()
+            .ok_or_else(|| SenderError::UnknownService(service.to_string()))?,
+    };
+
+    let source = match &sender.display_name {
+        Some(display_name) => format_source(display_name, &sender.address),
+        None => sender.address.clone(),
+    };
+
+    let reply_to = requested_reply_to
+        .map(str::to_string)
+        .or_else(|| sender.reply_to.clone())
+        .or_else(|| identity.default_reply_to.clone());
+
+    Ok(ResolvedSender {
+        source,
+        from: sender.address.clone(),
+        reply_to,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_header_syntax_from_display_names() {
+        assert_eq!(
+            format_source("Ginger Society", "no-reply@gingersociety.org"),
+            "\"Ginger Society\" <no-reply@gingersociety.org>"
+        );
+        assert_eq!(
+            format_source(
+                "Evil\" <evil@example.com>\r\nBcc: victim@example.com",
+                "no-reply@gingersociety.org"
+            ),
+            "\"Evil evil@example.comBcc: victim@example.com\" <no-reply@gingersociety.org>"
+        );
+    }
+}
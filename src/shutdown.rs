@@ -0,0 +1,165 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use futures::{future::join_all, StreamExt};
+use lapin::{message::Delivery, Consumer, Error as LapinError};
+use rand::Rng;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::{watch, Notify},
+    task::JoinHandle,
+    time::{sleep, timeout, Duration},
+};
+
+use crate::{config::CONFIG, responses::ControlFrame, shared::Channels};
+
+const RECONNECT_REASON: &str = "Server restarting, reconnect to another replica";
+
+// Set once SIGTERM or SIGINT arrives. New sockets are refused from then on, and the channel
+// consumer and HTTP server wind down.
+pub struct Shutdown {
+    draining: watch::Sender<bool>,
+    publishes: AtomicUsize,
+    publishes_done: Notify,
+}
+
+// Held for the duration of a publish to the channel exchange
+pub struct PublishGuard<'a>(&'a Shutdown);
+
+impl Drop for PublishGuard<'_> {
+    fn drop(&mut self) {
+        if self.0.publishes.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.publishes_done.notify_waiters();
+        }
+    }
+}
+
+impl Shutdown {
+    pub fn is_draining(&self) -> bool {
+        *self.draining.borrow()
+    }
+
+    pub fn begin(&self) {
+        self.draining.send_replace(true);
+    }
+
+    // Resolves once shutdown has begun
+    pub async fn started(&self) {
+        let mut draining = self.draining.subscribe();
+        let _ = draining.wait_for(|draining| *draining).await;
+    }
+
+    pub fn track_publish(&self) -> PublishGuard<'_> {
+        self.publishes.fetch_add(1, Ordering::SeqCst);
+        PublishGuard(self)
+    }
+
+    // The next delivery, or None once shutdown has begun. Work queue consumers stop here, between
+    // deliveries, so the one being handled is finished and acked first; anything prefetched
+    // goes back to the queue for the other replicas when the connection closes.
+    pub async fn next_delivery(
+        &self,
+        consumer: &mut Consumer,
+    ) -> Option<Result<Delivery, LapinError>> {
+        tokio::select! {
+            delivery = consumer.next() => delivery,
+            _ = self.started() => None,
+        }
+    }
+
+    async fn publishes_finished(&self) {
+        loop {
+            let done = self.publishes_done.notified();
+            if self.publishes.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            done.await;
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    pub static ref SHUTDOWN: Shutdown = Shutdown {
+        draining: watch::Sender::new(false),
+        publishes: AtomicUsize::new(0),
+        publishes_done: Notify::new(),
+    };
+}
+
+// Kubernetes sends SIGTERM on rollouts; SIGINT covers Ctrl-C when running locally
+pub async fn wait_for_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("Unable to listen for SIGTERM");
+
+    let name = tokio::select! {
+        _ = terminate.recv() => "SIGTERM",
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+    };
+    println!("Received {}, draining connections", name);
+    SHUTDOWN.begin();
+}
+
+// Ask every socket to reconnect elsewhere, each after its own random delay so the other
+// replicas aren't hit by every client at once, then close it once its queue is written
+async fn ask_to_reconnect(channels: &Channels) -> usize {
+    let registry = channels.lock().await;
+    let mut rng = rand::thread_rng();
+    let mut asked = 0;
+
+    for connection in registry
+        .values()
+        .flat_map(|channel| channel.connections.values())
+    {
+        let delay_ms = rng.gen_range(0..=CONFIG.shutdown.reconnect_jitter_ms);
+        connection.close_with(
+            ControlFrame::Reconnect {
+                reason: RECONNECT_REASON,
+                delay_ms,
+            }
+            .encode(),
+        );
+        asked += 1;
+    }
+    asked
+}
+
+// Sockets leave the registry as clients acknowledge the close
+async fn sockets_closed(channels: &Channels) {
+    while channels.lock().await.connection_count() > 0 {
+        sleep(Duration::from_millis(100)).await;
+    }
+}
+
+// Run once shutdown has begun. Returns when everything has wound down or `shutdown.deadline_secs`
+// has passed, whichever comes first. `workers` are the consumers of shared work queues: email,
+// email routing, webhooks and chat-ops.
+pub async fn drain(
+    channels: Channels,
+    consumer: JoinHandle<()>,
+    workers: Vec<JoinHandle<()>>,
+    server: JoinHandle<()>,
+) {
+    let deadline = Duration::from_secs(CONFIG.shutdown.deadline_secs);
+
+    let drained = timeout(deadline, async {
+        let asked = ask_to_reconnect(&channels).await;
+        println!("Asked {} connection(s) to reconnect elsewhere", asked);
+
+        // The consumers stop after acking the delivery they are handling, and the server after
+        // answering the requests it has accepted
+        let _ = tokio::join!(
+            consumer,
+            join_all(workers),
+            server,
+            SHUTDOWN.publishes_finished(),
+            sockets_closed(&channels)
+        );
+    })
+    .await;
+
+    match drained {
+        Ok(()) => println!("Drained, shutting down"),
+        Err(_) => eprintln!(
+            "Still draining after {} seconds, shutting down anyway",
+            CONFIG.shutdown.deadline_secs
+        ),
+    }
+}
@@ -0,0 +1,376 @@
+use std::io::Write;
+
+use aws_config::meta::region::RegionProviderChain;
+use aws_config::{BehaviorVersion, Region};
+use aws_sdk_sns::types::MessageAttributeValue;
+use aws_sdk_sns::Client as SnsClient;
+use chrono::Utc;
+use futures::future::{BoxFuture, FutureExt};
+use ginger_shared_rs::ISCClaims;
+use serde_json::Value;
+use tokio::sync::OnceCell;
+use uuid::Uuid;
+use warp::http::StatusCode;
+
+use crate::config::{SmsTransportKind, CONFIG};
+use crate::prom_helpers::SMS_COUNTER;
+use crate::rate_limits::SharedRateLimiter;
+use crate::requests::SmsRequest;
+use crate::responses::SmsSentResponse;
+use crate::shared::HTTP_CLIENT;
+
+// E.164 allows at most 15 digits after the `+`
+const MAX_E164_DIGITS: usize = 15;
+
+// Something that can get a text message to a phone number, returning the provider's message id
+pub trait SmsTransport: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn send<'a>(&'a self, to: &'a str, message: &'a str) -> BoxFuture<'a, Result<String, String>>;
+}
+
+// POSTs `{"to", "from", "message"}` as JSON to `sms.http.url`. The message id is taken from an
+// `id` or `message_id` field of the response, when there is one.
+pub struct HttpGatewayTransport {
+    url: String,
+    auth_token: Option<String>,
+    from: Option<String>,
+}
+
+impl SmsTransport for HttpGatewayTransport {
+    fn name(&self) -> &'static str {
+        "http"
+    }
+
+    fn send<'a>(&'a self, to: &'a str, message: &'a str) -> BoxFuture<'a, Result<String, String>> {
+        async move {
+            let mut request = HTTP_CLIENT.post(&self.url).json(&serde_json::json!({
+                "to": to,
+                "from": self.from,
+                "message": message,
+            }));
+            if let Some(auth_token) = &self.auth_token {
+                request = request.bearer_auth(auth_token);
+            }
+
+            let response = request
+                .send()
+                .await
+                .map_err(|e| format!("Request to SMS gateway failed: {}", e))?;
+            let status = response.status();
+            if !status.is_success() {
+                return Err(format!("SMS gateway responded with {}", status));
+            }
+
+            let body = response.json::<Value>().await.unwrap_or_default();
+            let id = body
+                .get("id")
+                .or_else(|| body.get("message_id"))
+                .and_then(|id| match id {
+                    Value::String(id) => Some(id.clone()),
+                    Value::Number(id) => Some(id.to_string()),
+                    _ => None,
+                })
+                .unwrap_or_else(|| Uuid::new_v4().to_string());
+            Ok(id)
+        }
+        .boxed()
+    }
+}
+
+// Publishes straight to the phone number through AWS SNS
+pub struct SnsTransport {
+    client: OnceCell<SnsClient>,
+}
+
+impl SnsTransport {
+    async fn client(&self) -> &SnsClient {
+        self.client
+            .get_or_init(|| async {
+                let region_provider =
+                    RegionProviderChain::default_provider().or_else(Region::new("ap-south-1"));
+                let config = aws_config::defaults(BehaviorVersion::latest())
+                    .region(region_provider)
+                    .load()
+                    .await;
+                SnsClient::new(&config)
+            })
+            .await
+    }
+}
+
+fn string_attribute(value: &str) -> Result<MessageAttributeValue, String> {
+    MessageAttributeValue::builder()
+        .data_type("String")
+        .string_value(value)
+        .build()
+        .map_err(|e| e.to_string())
+}
+
+impl SmsTransport for SnsTransport {
+    fn name(&self) -> &'static str {
+        "sns"
+    }
+
+    fn send<'a>(&'a self, to: &'a str, message: &'a str) -> BoxFuture<'a, Result<String, String>> {
+        async move {
+            let mut publish = self
+                .client()
+                .await
+                .publish()
+                .phone_number(to)
+                .message(message)
+                .message_attributes(
+                    "AWS.SNS.SMS.SMSType",
+                    string_attribute(&CONFIG.sms.sns.sms_type)?,
+                );
+            if let Some(sender_id) = &CONFIG.sms.sns.sender_id {
+                publish = publish
+                    .message_attributes("AWS.SNS.SMS.SenderID", string_attribute(sender_id)?);
+            }
+
+            match publish.send().await {
+                Ok(output) => Ok(output
+                    .message_id()
+                    .map(str::to_string)
+                    .unwrap_or_else(|| Uuid::new_v4().to_string())),
+                Err(err) => {
+                    eprintln!("Failed to send SMS: {:?}", err);
+                    Err(err.to_string())
+                }
+            }
+        }
+        .boxed()
+    }
+}
+
+// Sends nothing, appending each message as a JSON line to `sms.log_path`. Meant for local
+// testing.
+pub struct LogSinkTransport {
+    path: String,
+}
+
+impl SmsTransport for LogSinkTransport {
+    fn name(&self) -> &'static str {
+        "log"
+    }
+
+    fn send<'a>(&'a self, to: &'a str, message: &'a str) -> BoxFuture<'a, Result<String, String>> {
+        async move {
+            let id = Uuid::new_v4().to_string();
+            let line = serde_json::json!({
+                "id": id,
+                "to": to,
+                "message": message,
+                "sent_at": Utc::now(),
+            })
+            .to_string();
+
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)
+                .and_then(|mut file| writeln!(file, "{}", line))
+                .map_err(|e| format!("Unable to write SMS to {}: {}", self.path, e))?;
+            Ok(id)
+        }
+        .boxed()
+    }
+}
+
+// None when no transport is configured. A transport missing its settings is an error rather
+// than a silent fallback, as that could leave codes in a log instead of on a phone.
+fn build_transport() -> Result<Option<Box<dyn SmsTransport>>, String> {
+    let Some(kind) = CONFIG.sms.transport else {
+        return Ok(None);
+    };
+
+    let transport: Box<dyn SmsTransport> = match kind {
+        SmsTransportKind::Http => {
+            let url = CONFIG
+                .sms
+                .http
+                .url
+                .as_ref()
+                .ok_or("sms.http.url is not set")?;
+            Box::new(HttpGatewayTransport {
+                url: url.clone(),
+                auth_token: CONFIG.sms.http.auth_token.clone(),
+                from: CONFIG.sms.http.from.clone(),
+            })
+        }
+        SmsTransportKind::Sns => Box::new(SnsTransport {
+            client: OnceCell::new(),
+        }),
+        SmsTransportKind::Log => {
+            let path = CONFIG
+                .sms
+                .log_path
+                .as_ref()
+                .ok_or("sms.log_path is not set")?;
+            Box::new(LogSinkTransport { path: path.clone() })
+        }
+    };
+    Ok(Some(transport))
+}
+
+lazy_static::lazy_static! {
+    pub static ref SMS_TRANSPORT: Option<Box<dyn SmsTransport>> = build_transport()
+        .unwrap_or_else(|e| panic!("Invalid SMS transport config: {}", e));
+}
+
+// `+` followed by a country code and subscriber number, 15 digits at most, no leading zero
+pub fn validate_e164(number: &str) -> Result<(), String> {
+    let valid = number.strip_prefix('+').is_some_and(|digits| {
+        (2..=MAX_E164_DIGITS).contains(&digits.len())
+            && !digits.starts_with('0')
+            && digits.chars().all(|c| c.is_ascii_digit())
+    });
+
+    if valid {
+        Ok(())
+    } else {
+        Err(format!(
+            "{} is not an E.164 phone number, e.g. +14155550123",
+            number
+        ))
+    }
+}
+
+fn bad_request(message: String) -> Box<dyn warp::Reply> {
+    Box::new(warp::reply::with_status(
+        warp::reply::json(&message),
+        StatusCode::BAD_REQUEST,
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/notification/send-sms",
+    request_body = SmsRequest,
+    params(
+        ("Idempotency-Key" = Option<String>, Header, description = "Retries with the same key within the idempotency window replay the original response")
+    ),
+    responses(
+        (status = 200, description = "SMS handed to the provider", body = SmsSentResponse),
+        (status = 400, description = "Not an E.164 number, or an empty or overlong message"),
+        (status = 409, description = "A request with the same Idempotency-Key is still in progress, see Retry-After"),
+        (status = 422, description = "Idempotency-Key already used for a different request"),
+        (status = 429, description = "Rate limit or daily quota exceeded, see Retry-After"),
+        (status = 502, description = "The SMS provider rejected the message"),
+        (status = 503, description = "No SMS transport is configured"),
+    ),
+    security(("apiISCBearerAuth" = [])),
+    tag = "default"
+)]
+pub async fn send_sms(
+    sms_request: SmsRequest,
+    claims: ISCClaims,
+    rate_limiter: SharedRateLimiter,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    let Some(transport) = SMS_TRANSPORT.as_ref() else {
+        return Ok(Box::new(warp::reply::with_status(
+            warp::reply::json(&"SMS is not configured"),
+            StatusCode::SERVICE_UNAVAILABLE,
+        )));
+    };
+
+    if let Err(e) = validate_e164(&sms_request.to) {
+        return Ok(bad_request(e));
+    }
+    if sms_request.message.trim().is_empty() {
+        return Ok(bad_request("message is empty".to_string()));
+    }
+    if sms_request.message.chars().count() > CONFIG.sms.max_message_chars {
+        return Ok(bad_request(format!(
+            "message is longer than {} characters",
+            CONFIG.sms.max_message_chars
+        )));
+    }
+
+    let limits = [
+        ("service", claims.sub.clone(), &CONFIG.sms.per_service),
+        (
+            "recipient",
+            sms_request.to.clone(),
+            &CONFIG.sms.per_recipient,
+        ),
+    ];
+    if let Err(exceeded) = rate_limiter.acquire(&limits).await {
+        println!(
+            "Rate limited SMS from {} ({} limit)",
+            claims.sub, exceeded.scope
+        );
+        SMS_COUNTER
+            .with_label_values(&[&claims.sub, "rate_limited"])
+            .inc();
+        return Ok(Box::new(warp::reply::with_header(
+            warp::reply::with_status(
+                warp::reply::json(&format!("SMS {} limit exceeded", exceeded.scope)),
+                StatusCode::TOO_MANY_REQUESTS,
+            ),
+            "Retry-After",
+            exceeded.retry_after_secs().to_string(),
+        )));
+    }
+
+    match transport.send(&sms_request.to, &sms_request.message).await {
+        Ok(id) => {
+            println!(
+                "Sent SMS {} for {} through {}",
+                id,
+                claims.sub,
+                transport.name()
+            );
+            SMS_COUNTER.with_label_values(&[&claims.sub, "sent"]).inc();
+            Ok(Box::new(warp::reply::json(&SmsSentResponse {
+                id,
+                transport: transport.name().to_string(),
+            })))
+        }
+        Err(e) => {
+            eprintln!("Failed to send SMS for {}: {}", claims.sub, e);
+            SMS_COUNTER
+                .with_label_values(&[&claims.sub, "failed"])
+                .inc();
+            Ok(Box::new(warp::reply::with_status(
+                warp::reply::json(&"Unable to send SMS"),
+                StatusCode::BAD_GATEWAY,
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_e164_numbers() {
+        assert!(validate_e164("+14155550123").is_ok());
+        assert!(validate_e164("+919876543210").is_ok());
+        assert!(validate_e164("+12").is_ok());
+        assert!(validate_e164("+123456789012345").is_ok());
+    }
+
+    #[test]
+    fn rejects_other_numbers() {
+        for number in [
+            "",
+            "+",
+            "+1",
+            "14155550123",
+            "+04155550123",
+            "+1 415 555 0123",
+            "+1-415-555-0123",
+            "+1234567890123456",
+            "+１４１５５５５０１２３",
+        ] {
+            assert!(
+                validate_e164(number).is_err(),
+                "{} should be rejected",
+                number
+            );
+        }
+    }
+}
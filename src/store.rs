@@ -0,0 +1,196 @@
+use std::{collections::HashMap, convert::Infallible, path::PathBuf, sync::Arc};
+
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::sync::Mutex;
+use warp::Filter;
+
+// Small JSON file backed key/value store used for state that has to survive restarts
+pub struct JsonStore<T> {
+    path: PathBuf,
+    items: Mutex<HashMap<String, T>>,
+}
+
+pub type Store<T> = Arc<JsonStore<T>>;
+
+// Resolve a file name inside the data directory (DATA_DIR, defaults to ./data)
+pub fn data_path(file_name: &str) -> PathBuf {
+    let dir = std::env::var("DATA_DIR").unwrap_or_else(|_| "./data".to_string());
+    PathBuf::from(dir).join(file_name)
+}
+
+impl<T> JsonStore<T>
+where
+    T: Serialize + DeserializeOwned + Clone + Send,
+{
+    pub fn open(file_name: &str) -> Store<T> {
+        let path = data_path(file_name);
+
+        let items = match std::fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+                eprintln!("Failed to parse store {:?}, starting empty: {:?}", path, e);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+
+        Arc::new(JsonStore {
+            path,
+            items: Mutex::new(items),
+        })
+    }
+
+    pub async fn get(&self, key: &str) -> Option<T> {
+        self.items.lock().await.get(key).cloned()
+    }
+
+    pub async fn insert(&self, key: String, value: T) {
+        let mut items = self.items.lock().await;
+        items.insert(key, value);
+        self.persist(&items);
+    }
+
+    // Insert `value` unless the item already under `key` should be kept, as decided by `keep`,
+    // in which case that item is returned instead
+    pub async fn try_insert<F>(&self, key: String, value: T, keep: F) -> Result<(), T>
+    where
+        F: FnOnce(&T) -> bool,
+    {
+        let mut items = self.items.lock().await;
+        if let Some(existing) = items.get(&key).filter(|existing| keep(existing)) {
+            return Err(existing.clone());
+        }
+        items.insert(key, value);
+        self.persist(&items);
+        Ok(())
+    }
+
+    pub async fn remove(&self, key: &str) -> Option<T> {
+        let mut items = self.items.lock().await;
+        let removed = items.remove(key);
+        if removed.is_some() {
+            self.persist(&items);
+        }
+        removed
+    }
+
+    // Apply `f` to the stored value and persist the result
+    pub async fn update<F>(&self, key: &str, f: F) -> Option<T>
+    where
+        F: FnOnce(&mut T),
+    {
+        let mut items = self.items.lock().await;
+        let updated = items.get_mut(key).map(|item| {
+            f(item);
+            item.clone()
+        });
+        if updated.is_some() {
+            self.persist(&items);
+        }
+        updated
+    }
+
+    // Like `update`, inserting `default()` first when the key is missing
+    pub async fn upsert<D, F>(&self, key: &str, default: D, f: F) -> T
+    where
+        D: FnOnce() -> T,
+        F: FnOnce(&mut T),
+    {
+        let mut items = self.items.lock().await;
+        let item = items.entry(key.to_string()).or_insert_with(default);
+        f(item);
+        let updated = item.clone();
+        self.persist(&items);
+        updated
+    }
+
+    // Keep only the items `f` returns true for, returning how many were dropped
+    pub async fn retain<F>(&self, mut f: F) -> usize
+    where
+        F: FnMut(&T) -> bool,
+    {
+        let mut items = self.items.lock().await;
+        let before = items.len();
+        items.retain(|_, item| f(item));
+        let removed = before - items.len();
+        if removed > 0 {
+            self.persist(&items);
+        }
+        removed
+    }
+
+    pub async fn values(&self) -> Vec<T> {
+        self.items.lock().await.values().cloned().collect()
+    }
+
+    pub async fn entries(&self) -> HashMap<String, T> {
+        self.items.lock().await.clone()
+    }
+
+    // Add items copied from elsewhere, replacing a local item only when `prefer(local, other)`.
+    // Returns how many were taken.
+    pub async fn merge<F>(&self, others: HashMap<String, T>, prefer: F) -> usize
+    where
+        F: Fn(&T, &T) -> bool,
+    {
+        let mut items = self.items.lock().await;
+        let mut taken = 0;
+        for (key, other) in others {
+            let take = items.get(&key).is_none_or(|local| prefer(local, &other));
+            if take {
+                items.insert(key, other);
+                taken += 1;
+            }
+        }
+        if taken > 0 {
+            self.persist(&items);
+        }
+        taken
+    }
+
+    fn persist(&self, items: &HashMap<String, T>) {
+        if let Some(parent) = self.path.parent() {
+            let _ = std::fs::create_dir_all(parent);
+        }
+
+        // Write to a temporary file first so a crash never leaves a truncated store behind
+        let tmp_path = self.path.with_extension("tmp");
+        let result = serde_json::to_vec(items)
+            .map_err(std::io::Error::from)
+            .and_then(|contents| std::fs::write(&tmp_path, contents))
+            .and_then(|_| std::fs::rename(&tmp_path, &self.path));
+
+        if let Err(e) = result {
+            eprintln!("Failed to persist store {:?}: {:?}", self.path, e);
+        }
+    }
+}
+
+// Filter to inject a store into the route handlers
+pub fn with_store<T: Send + Sync>(
+    store: Store<T>,
+) -> impl Filter<Extract = (Store<T>,), Error = Infallible> + Clone {
+    warp::any().map(move || store.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn persists_updates_across_reopen() {
+        let file_name = format!("store-test-{}.json", uuid::Uuid::new_v4());
+
+        let store: Store<u32> = JsonStore::open(&file_name);
+        store.insert("a".to_string(), 1).await;
+        store.insert("b".to_string(), 2).await;
+        assert_eq!(store.update("a", |value| *value += 10).await, Some(11));
+        assert_eq!(store.update("missing", |value| *value += 10).await, None);
+        assert_eq!(store.remove("b").await, Some(2));
+
+        let reopened: Store<u32> = JsonStore::open(&file_name);
+        assert_eq!(reopened.get("a").await, Some(11));
+        assert_eq!(reopened.get("b").await, None);
+
+        let _ = std::fs::remove_file(data_path(&file_name));
+    }
+}
@@ -0,0 +1,292 @@
+use std::{collections::HashMap, sync::Arc};
+
+use futures::{future::BoxFuture, StreamExt};
+use lapin::{
+    options::{
+        BasicAckOptions, BasicCancelOptions, BasicConsumeOptions, BasicPublishOptions,
+        ExchangeDeclareOptions, QueueBindOptions, QueueDeclareOptions,
+    },
+    types::FieldTable,
+    BasicProperties, Channel as RabbitChannel, Error as LapinError,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use tokio::time::{sleep, Duration};
+
+use crate::{
+    config::CONFIG,
+    shared::{open_rabbitmq_channel, RABBIT_POOL, REPLICA_ID},
+    store::Store,
+};
+
+// Replicated stores are kept in step by events on their own exchanges, which only cover changes
+// made while a replica is running. A replica starting up asks the others for a copy of each
+// store over `sync.exchange` and merges the answers into its own.
+#[derive(Deserialize, Serialize)]
+struct SnapshotRequest {
+    replica: String,
+}
+
+// Sent straight back to the requesting replica's reply queue
+#[derive(Deserialize, Serialize)]
+struct Snapshot {
+    replica: String,
+    stores: HashMap<String, Value>,
+}
+
+// Lets stores of any item type be copied and merged through one registry
+trait SyncedStore: Send + Sync {
+    fn snapshot(&self) -> BoxFuture<'_, Value>;
+    fn merge(&self, snapshot: Value) -> BoxFuture<'_, usize>;
+}
+
+struct Synced<T> {
+    store: Store<T>,
+    // Whether another replica's copy of an item should replace the local one
+    prefer: fn(&T, &T) -> bool,
+}
+
+impl<T> SyncedStore for Synced<T>
+where
+    T: Serialize + DeserializeOwned + Clone + Send + Sync + 'static,
+{
+    fn snapshot(&self) -> BoxFuture<'_, Value> {
+        Box::pin(
+            async move { serde_json::to_value(self.store.entries().await).unwrap_or_default() },
+        )
+    }
+
+    fn merge(&self, snapshot: Value) -> BoxFuture<'_, usize> {
+        Box::pin(async move {
+            match serde_json::from_value::<HashMap<String, T>>(snapshot) {
+                Ok(items) => self.store.merge(items, self.prefer).await,
+                Err(e) => {
+                    println!("Failed to deserialize store snapshot: {:?}", e);
+                    0
+                }
+            }
+        })
+    }
+}
+
+#[derive(Default)]
+pub struct StoreSync {
+    stores: HashMap<&'static str, Box<dyn SyncedStore>>,
+}
+
+pub type SharedStoreSync = Arc<StoreSync>;
+
+impl StoreSync {
+    pub fn register<T>(&mut self, name: &'static str, store: Store<T>, prefer: fn(&T, &T) -> bool)
+    where
+        T: Serialize + DeserializeOwned + Clone + Send + Sync + 'static,
+    {
+        self.stores.insert(name, Box::new(Synced { store, prefer }));
+    }
+
+    async fn snapshot(&self) -> Snapshot {
+        let mut stores = HashMap::new();
+        for (name, store) in &self.stores {
+            stores.insert(name.to_string(), store.snapshot().await);
+        }
+        Snapshot {
+            replica: REPLICA_ID.clone(),
+            stores,
+        }
+    }
+
+    async fn merge(&self, snapshot: Snapshot) {
+        for (name, items) in snapshot.stores {
+            if let Some(store) = self.stores.get(name.as_str()) {
+                let taken = store.merge(items).await;
+                println!(
+                    "Merged {} {} item(s) from replica {}",
+                    taken, name, snapshot.replica
+                );
+            }
+        }
+    }
+}
+
+pub async fn declare_sync_exchange(rabbit_channel: &RabbitChannel) -> Result<(), LapinError> {
+    rabbit_channel
+        .exchange_declare(
+            &CONFIG.sync.exchange,
+            lapin::ExchangeKind::Fanout,
+            ExchangeDeclareOptions {
+                durable: true,
+                ..Default::default()
+            },
+            FieldTable::default(),
+        )
+        .await
+}
+
+// Merge in the copies the running replicas send back within `sync.bootstrap_secs`. The first
+// replica to start gets no answers and starts from what it has on disk.
+pub async fn bootstrap(sync: &StoreSync) {
+    match request_snapshots(sync).await {
+        Ok(answers) => println!("Bootstrapped stores from {} replica(s)", answers),
+        Err(e) => eprintln!("Failed to bootstrap stores from other replicas: {:?}", e),
+    }
+}
+
+async fn request_snapshots(sync: &StoreSync) -> Result<usize, LapinError> {
+    let rabbit_channel = RABBIT_POOL.channel().await?;
+
+    let reply_queue = rabbit_channel
+        .queue_declare(
+            "",
+            QueueDeclareOptions {
+                exclusive: true,
+                auto_delete: true,
+                ..Default::default()
+            },
+            FieldTable::default(),
+        )
+        .await?;
+
+    let mut consumer = rabbit_channel
+        .basic_consume(
+            reply_queue.name().as_str(),
+            "store_sync_bootstrap",
+            BasicConsumeOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    rabbit_channel
+        .basic_publish(
+            &CONFIG.sync.exchange,
+            "",
+            BasicPublishOptions::default(),
+            &serde_json::to_vec(&SnapshotRequest {
+                replica: REPLICA_ID.clone(),
+            })
+            .unwrap(),
+            BasicProperties::default().with_reply_to(reply_queue.name().clone()),
+        )
+        .await?;
+
+    let deadline = sleep(Duration::from_secs(CONFIG.sync.bootstrap_secs));
+    tokio::pin!(deadline);
+    let mut answers = 0;
+
+    loop {
+        tokio::select! {
+            _ = &mut deadline => break,
+            delivery = consumer.next() => match delivery {
+                Some(Ok(delivery)) => {
+                    match serde_json::from_slice::<Snapshot>(&delivery.data) {
+                        Ok(snapshot) => {
+                            sync.merge(snapshot).await;
+                            answers += 1;
+                        }
+                        Err(e) => println!("Failed to deserialize store snapshot: {:?}", e),
+                    }
+                    delivery.ack(BasicAckOptions::default()).await?;
+                }
+                Some(Err(e)) => return Err(e),
+                None => break,
+            },
+        }
+    }
+
+    // The reply queue is deleted with its consumer
+    rabbit_channel
+        .basic_cancel("store_sync_bootstrap", BasicCancelOptions::default())
+        .await?;
+    Ok(answers)
+}
+
+pub async fn answer_snapshot_requests(sync: SharedStoreSync) {
+    loop {
+        match open_rabbitmq_channel().await {
+            Ok(rabbit_channel) => {
+                if let Err(e) = process_snapshot_requests(rabbit_channel, &sync).await {
+                    eprintln!("Error processing snapshot requests: {:?}", e);
+                }
+            }
+            Err(e) => {
+                eprintln!("Error connecting to RabbitMQ: {:?}", e);
+            }
+        }
+
+        eprintln!("Reconnecting to snapshot requests in 5 seconds...");
+        sleep(Duration::from_secs(5)).await;
+    }
+}
+
+async fn process_snapshot_requests(
+    rabbit_channel: RabbitChannel,
+    sync: &StoreSync,
+) -> Result<(), LapinError> {
+    declare_sync_exchange(&rabbit_channel).await?;
+
+    let queue = rabbit_channel
+        .queue_declare(
+            "",
+            QueueDeclareOptions {
+                exclusive: true,
+                auto_delete: true,
+                ..Default::default()
+            },
+            FieldTable::default(),
+        )
+        .await?;
+
+    rabbit_channel
+        .queue_bind(
+            queue.name().as_str(),
+            &CONFIG.sync.exchange,
+            "",
+            QueueBindOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    let mut consumer = rabbit_channel
+        .basic_consume(
+            queue.name().as_str(),
+            "store_sync_consumer",
+            BasicConsumeOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    while let Some(delivery) = consumer.next().await {
+        let delivery = delivery?;
+
+        match (
+            serde_json::from_slice::<SnapshotRequest>(&delivery.data),
+            delivery.properties.reply_to(),
+        ) {
+            (Ok(request), _) if request.replica == *REPLICA_ID => {}
+            (Ok(request), Some(reply_to)) => {
+                let snapshot = serde_json::to_vec(&sync.snapshot().await).unwrap();
+                // Replies go through the default exchange, which routes by queue name
+                rabbit_channel
+                    .basic_publish(
+                        "",
+                        reply_to.as_str(),
+                        BasicPublishOptions::default(),
+                        &snapshot,
+                        BasicProperties::default(),
+                    )
+                    .await?;
+                println!("Sent store snapshot to replica {}", request.replica);
+            }
+            (Ok(request), None) => {
+                println!(
+                    "Snapshot request from {} has no reply queue",
+                    request.replica
+                )
+            }
+            (Err(e), _) => println!("Failed to deserialize snapshot request: {:?}", e),
+        }
+
+        delivery.ack(BasicAckOptions::default()).await?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,518 @@
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use futures::StreamExt;
+use lapin::{
+    options::{
+        BasicAckOptions, BasicConsumeOptions, BasicPublishOptions, ExchangeDeclareOptions,
+        QueueBindOptions, QueueDeclareOptions,
+    },
+    types::FieldTable,
+    BasicProperties, Channel as RabbitChannel, Error as LapinError,
+};
+use serde::{Deserialize, Serialize};
+use std::{cmp::Reverse, collections::HashMap};
+use subtle::ConstantTimeEq;
+use tokio::time::{interval, sleep, Duration};
+use utoipa::ToSchema;
+use warp::http::StatusCode;
+
+use crate::{
+    auth_helpers::AdminClaims,
+    config::CONFIG,
+    email_queue::{update_email, EmailStatus, EmailStore, SesMessageStore},
+    requests::{SesNotification, SnsEnvelope},
+    shared::{decode_path_segment, open_rabbitmq_channel, RABBIT_POOL},
+    store::Store,
+};
+
+#[derive(Deserialize, Serialize, ToSchema, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SuppressionReason {
+    Bounce,
+    Complaint,
+}
+
+#[derive(Deserialize, Serialize, ToSchema, Clone)]
+pub struct Suppression {
+    pub email: String,
+    pub reason: SuppressionReason,
+    pub detail: Option<String>,
+    pub ses_message_id: Option<String>,
+    pub created_at: DateTime<Utc>,
+    // Lifted suppressions are kept as tombstones for `suppressions.tombstone_days`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub removed_at: Option<DateTime<Utc>>,
+}
+
+impl Suppression {
+    pub fn is_active(&self) -> bool {
+        self.removed_at.is_none()
+    }
+
+    fn changed_at(&self) -> DateTime<Utc> {
+        self.removed_at.unwrap_or(self.created_at)
+    }
+}
+
+// Keyed by the normalised (trimmed, lowercase) email address
+pub type SuppressionStore = Store<Suppression>;
+
+// Whichever of suppressing and lifting happened last wins
+pub fn prefer_newer_suppression(local: &Suppression, other: &Suppression) -> bool {
+    local.changed_at() < other.changed_at()
+}
+
+// The suppression list is replicated over `suppressions.exchange`, so every replica refuses
+// the same recipients
+#[derive(Deserialize, Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+enum SuppressionEvent {
+    Added {
+        suppression: Suppression,
+    },
+    Removed {
+        email: String,
+        removed_at: DateTime<Utc>,
+    },
+}
+
+pub fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+pub async fn find_suppression(store: &SuppressionStore, email: &str) -> Option<Suppression> {
+    store
+        .get(&normalize_email(email))
+        .await
+        .filter(Suppression::is_active)
+}
+
+pub async fn declare_suppressions_exchange(
+    rabbit_channel: &RabbitChannel,
+) -> Result<(), LapinError> {
+    rabbit_channel
+        .exchange_declare(
+            &CONFIG.suppressions.exchange,
+            lapin::ExchangeKind::Fanout,
+            ExchangeDeclareOptions {
+                durable: true,
+                ..Default::default()
+            },
+            FieldTable::default(),
+        )
+        .await
+}
+
+async fn publish_event(event: &SuppressionEvent) -> Result<(), LapinError> {
+    let rabbit_channel = RABBIT_POOL.channel().await?;
+
+    rabbit_channel
+        .basic_publish(
+            &CONFIG.suppressions.exchange,
+            "",
+            BasicPublishOptions::default(),
+            &serde_json::to_vec(event).unwrap(),
+            BasicProperties::default(),
+        )
+        .await?;
+    Ok(())
+}
+
+// Applying an event twice is harmless, as the replica that published it applies it right away.
+// Events may arrive out of order, so an older change never replaces a newer one.
+async fn apply_event(suppressions: &SuppressionStore, event: SuppressionEvent) {
+    match event {
+        SuppressionEvent::Added { suppression } => {
+            let email = suppression.email.clone();
+            let initial = suppression.clone();
+            suppressions
+                .upsert(
+                    &email,
+                    || initial,
+                    |existing| {
+                        if !prefer_newer_suppression(&suppression, existing) {
+                            *existing = suppression;
+                        }
+                    },
+                )
+                .await;
+        }
+        SuppressionEvent::Removed { email, removed_at } => {
+            suppressions
+                .update(&email, |suppression| {
+                    if suppression.changed_at() <= removed_at {
+                        suppression.removed_at = Some(removed_at);
+                    }
+                })
+                .await;
+        }
+    }
+}
+
+async fn replicate(suppressions: &SuppressionStore, event: SuppressionEvent) {
+    if let Err(e) = publish_event(&event).await {
+        eprintln!("Failed to replicate suppression change: {:?}", e);
+    }
+    apply_event(suppressions, event).await;
+}
+
+pub async fn consume_suppression_events(suppressions: SuppressionStore) {
+    loop {
+        match open_rabbitmq_channel().await {
+            Ok(rabbit_channel) => {
+                if let Err(e) = process_suppression_events(rabbit_channel, &suppressions).await {
+                    eprintln!("Error processing suppression events: {:?}", e);
+                }
+            }
+            Err(e) => {
+                eprintln!("Error connecting to RabbitMQ: {:?}", e);
+            }
+        }
+
+        eprintln!("Reconnecting to suppression events in 5 seconds...");
+        sleep(Duration::from_secs(5)).await;
+    }
+}
+
+async fn process_suppression_events(
+    rabbit_channel: RabbitChannel,
+    suppressions: &SuppressionStore,
+) -> Result<(), LapinError> {
+    declare_suppressions_exchange(&rabbit_channel).await?;
+
+    let queue = rabbit_channel
+        .queue_declare(
+            "",
+            QueueDeclareOptions {
+                exclusive: true,
+                auto_delete: true,
+                ..Default::default()
+            },
+            FieldTable::default(),
+        )
+        .await?;
+
+    rabbit_channel
+        .queue_bind(
+            queue.name().as_str(),
+            &CONFIG.suppressions.exchange,
+            "",
+            QueueBindOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    let mut consumer = rabbit_channel
+        .basic_consume(
+            queue.name().as_str(),
+            "suppression_consumer",
+            BasicConsumeOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    while let Some(delivery) = consumer.next().await {
+        let delivery = delivery?;
+
+        match serde_json::from_slice::<SuppressionEvent>(&delivery.data) {
+            Ok(event) => apply_event(suppressions, event).await,
+            Err(e) => println!("Failed to deserialize suppression event: {:?}", e),
+        }
+
+        delivery.ack(BasicAckOptions::default()).await?;
+    }
+
+    Ok(())
+}
+
+pub async fn prune_suppressions(suppressions: SuppressionStore) {
+    let mut ticker = interval(Duration::from_secs(3600));
+
+    loop {
+        ticker.tick().await;
+        let cutoff = Utc::now() - ChronoDuration::days(CONFIG.suppressions.tombstone_days as i64);
+        let removed = suppressions
+            .retain(|suppression| {
+                suppression
+                    .removed_at
+                    .is_none_or(|removed_at| removed_at > cutoff)
+            })
+            .await;
+        if removed > 0 {
+            println!("Pruned {} lifted suppressions", removed);
+        }
+    }
+}
+
+// Compared in constant time so the token can't be guessed a byte at a time
+fn token_matches(expected: &str, given: Option<&String>) -> bool {
+    given.is_some_and(|given| bool::from(expected.as_bytes().ct_eq(given.as_bytes())))
+}
+
+// Accept either the raw SES notification or the SNS envelope wrapping it
+fn parse_ses_event(body: &[u8]) -> Result<Option<SesNotification>, String> {
+    if let Ok(envelope) = serde_json::from_slice::<SnsEnvelope>(body) {
+        return match envelope.message_type.as_str() {
+            "Notification" => {
+                let message = envelope.message.unwrap_or_default();
+                serde_json::from_str(&message)
+                    .map(Some)
+                    .map_err(|e| e.to_string())
+            }
+            "SubscriptionConfirmation" => {
+                println!(
+                    "SNS subscription confirmation received, confirm via: {:?}",
+                    envelope.subscribe_url
+                );
+                Ok(None)
+            }
+            other => {
+                println!("Ignoring SNS message of type {}", other);
+                Ok(None)
+            }
+        };
+    }
+
+    serde_json::from_slice(body)
+        .map(Some)
+        .map_err(|e| e.to_string())
+}
+
+#[utoipa::path(
+    post,
+    path = "/notification/ses/events",
+    params(
+        ("token" = Option<String>, Query, description = "Shared secret configured in ses_events.token")
+    ),
+    responses(
+        (status = 200, description = "Event processed, returns the newly suppressed addresses", body = [String]),
+        (status = 400, description = "Malformed notification"),
+        (status = 401, description = "Invalid token"),
+        (status = 503, description = "ses_events.token is not configured")
+    ),
+    tag = "default"
+)]
+pub async fn ingest_ses_event(
+    query_params: HashMap<String, String>,
+    body: warp::hyper::body::Bytes,
+    suppressions: SuppressionStore,
+    emails: EmailStore,
+    ses_messages: SesMessageStore,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    // Anyone could lift or add suppressions without a token, so events are refused until one is set
+    let Some(expected) = &CONFIG.ses_events.token else {
+        println!("Rejected SES event, ses_events.token is not configured");
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&"SES events are not configured"),
+            StatusCode::SERVICE_UNAVAILABLE,
+        ));
+    };
+    if !token_matches(expected, query_params.get("token")) {
+        println!("Rejected SES event with invalid token");
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&"Invalid token"),
+            StatusCode::UNAUTHORIZED,
+        ));
+    }
+
+    let notification = match parse_ses_event(&body) {
+        Ok(Some(notification)) => notification,
+        Ok(None) => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&Vec::<String>::new()),
+                StatusCode::OK,
+            ))
+        }
+        Err(e) => {
+            println!("Failed to parse SES event: {}", e);
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&"Malformed notification"),
+                StatusCode::BAD_REQUEST,
+            ));
+        }
+    };
+
+    let ses_message_id = notification
+        .mail
+        .as_ref()
+        .map(|mail| mail.message_id.clone());
+
+    let (reason, recipients, detail) = match notification.notification_type.as_str() {
+        // Transient bounces (mailbox full, etc.) are worth retrying later
+        "Bounce" => match notification.bounce {
+            Some(bounce) if bounce.bounce_type == "Permanent" => {
+                (SuppressionReason::Bounce, bounce.bounced_recipients, None)
+            }
+            _ => {
+                println!("Ignoring non-permanent bounce");
+                (SuppressionReason::Bounce, vec![], None)
+            }
+        },
+        "Complaint" => match notification.complaint {
+            Some(complaint) => (
+                SuppressionReason::Complaint,
+                complaint.complained_recipients,
+                complaint.complaint_feedback_type,
+            ),
+            None => (SuppressionReason::Complaint, vec![], None),
+        },
+        other => {
+            println!("Ignoring SES notification of type {}", other);
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&Vec::<String>::new()),
+                StatusCode::OK,
+            ));
+        }
+    };
+
+    let mut suppressed = vec![];
+    for recipient in recipients {
+        let email = normalize_email(&recipient.email_address);
+        println!("Suppressing {} ({:?})", email, reason);
+
+        let suppression = Suppression {
+            email: email.clone(),
+            reason: reason.clone(),
+            detail: recipient.diagnostic_code.or_else(|| detail.clone()),
+            ses_message_id: ses_message_id.clone(),
+            created_at: Utc::now(),
+            removed_at: None,
+        };
+        replicate(&suppressions, SuppressionEvent::Added { suppression }).await;
+        suppressed.push(email);
+    }
+
+    // Flag the originating email as bounced so its status reflects what happened
+    if reason == SuppressionReason::Bounce && !suppressed.is_empty() {
+        if let Some(ses_message_id) = &ses_message_id {
+            if let Some(email_id) = ses_messages.get(ses_message_id).await {
+                update_email(&emails, &email_id, |record| {
+                    record.status = EmailStatus::Bounced;
+                    record.updated_at = Utc::now();
+                })
+                .await;
+            }
+        }
+    }
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&suppressed),
+        StatusCode::OK,
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/notification/suppressions",
+    responses(
+        (status = 200, description = "Suppressed addresses", body = [Suppression]),
+    ),
+    security(("bearerAuth" = [])),
+    tag = "default"
+)]
+pub async fn list_suppressions(
+    _claims: AdminClaims,
+    suppressions: SuppressionStore,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let mut items: Vec<Suppression> = suppressions
+        .values()
+        .await
+        .into_iter()
+        .filter(Suppression::is_active)
+        .collect();
+    items.sort_by_key(|suppression| Reverse(suppression.created_at));
+    Ok(warp::reply::json(&items))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/notification/suppressions/{email}",
+    params(
+        ("email" = String, Path, description = "The suppressed email address")
+    ),
+    responses(
+        (status = 200, description = "Suppression removed"),
+        (status = 404, description = "Address is not suppressed")
+    ),
+    security(("bearerAuth" = [])),
+    tag = "default"
+)]
+pub async fn remove_suppression(
+    email: String,
+    claims: AdminClaims,
+    suppressions: SuppressionStore,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    // Path segments arrive percent-encoded, e.g. someone%40example.com
+    let email = decode_path_segment(&email);
+
+    match find_suppression(&suppressions, &email).await {
+        Some(suppression) => {
+            println!("{} removed suppression for {}", claims.user_id, email);
+            let event = SuppressionEvent::Removed {
+                email: suppression.email,
+                removed_at: Utc::now(),
+            };
+            replicate(&suppressions, event).await;
+            Ok(warp::reply::with_status(
+                warp::reply::json(&"Suppression removed"),
+                StatusCode::OK,
+            ))
+        }
+        None => Ok(warp::reply::with_status(
+            warp::reply::json(&"Address is not suppressed"),
+            StatusCode::NOT_FOUND,
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn lifting_wins_over_an_older_suppression_arriving_late() {
+        let file_name = format!("suppressions-test-{}.json", uuid::Uuid::new_v4());
+        let suppressions: SuppressionStore = crate::store::JsonStore::open(&file_name);
+
+        let suppression = Suppression {
+            email: "gone@example.com".to_string(),
+            reason: SuppressionReason::Bounce,
+            detail: None,
+            ses_message_id: None,
+            created_at: Utc::now() - ChronoDuration::minutes(1),
+            removed_at: None,
+        };
+        let removed = SuppressionEvent::Removed {
+            email: suppression.email.clone(),
+            removed_at: Utc::now(),
+        };
+
+        apply_event(
+            &suppressions,
+            SuppressionEvent::Added {
+                suppression: suppression.clone(),
+            },
+        )
+        .await;
+        apply_event(&suppressions, removed).await;
+        apply_event(&suppressions, SuppressionEvent::Added { suppression }).await;
+        assert!(find_suppression(&suppressions, "Gone@example.com")
+            .await
+            .is_none());
+
+        // Suppressing the address again later puts it back
+        let suppression = Suppression {
+            email: "gone@example.com".to_string(),
+            reason: SuppressionReason::Complaint,
+            detail: None,
+            ses_message_id: None,
+            created_at: Utc::now() + ChronoDuration::minutes(1),
+            removed_at: None,
+        };
+        apply_event(&suppressions, SuppressionEvent::Added { suppression }).await;
+        assert!(find_suppression(&suppressions, "gone@example.com")
+            .await
+            .is_some());
+
+        let _ = std::fs::remove_file(crate::store::data_path(&file_name));
+    }
+}
@@ -0,0 +1,646 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use ginger_shared_rs::rocket_utils::Claims;
+use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use lapin::{
+    options::{
+        BasicAckOptions, BasicConsumeOptions, BasicPublishOptions, ExchangeDeclareOptions,
+        QueueBindOptions, QueueDeclareOptions,
+    },
+    types::FieldTable,
+    BasicProperties, Channel as RabbitChannel, Error as LapinError,
+};
+
+use reqwest::{StatusCode as HttpStatus, Url};
+use serde::{Deserialize, Serialize};
+use tokio::time::{sleep, Duration};
+use utoipa::ToSchema;
+use uuid::Uuid;
+use warp::http::StatusCode;
+
+use crate::{
+    auth_helpers::forbidden_unless_self,
+    config::CONFIG,
+    outbound::Priority,
+    prom_helpers::WEB_PUSH_COUNTER,
+    responses::VapidPublicKeyResponse,
+    shared::{check_resolves_publicly, open_rabbitmq_channel, HTTP_CLIENT, RABBIT_POOL},
+    store::Store,
+};
+
+// VAPID tokens are valid for at most 24 hours
+const VAPID_TOKEN_TTL_SECS: i64 = 12 * 60 * 60;
+
+// The keys from the browser's PushSubscription, base64url encoded
+#[derive(Deserialize, Serialize, ToSchema, Clone)]
+pub struct PushKeys {
+    pub p256dh: String,
+    pub auth: String,
+}
+
+#[derive(Deserialize, Serialize, ToSchema, Clone)]
+pub struct PushSubscriptionRequest {
+    pub endpoint: String,
+    pub keys: PushKeys,
+}
+
+#[derive(Deserialize, Serialize, ToSchema, Clone)]
+pub struct PushSubscription {
+    pub id: String, // Derived from the endpoint, so registering again is harmless
+    pub endpoint: String,
+    pub keys: PushKeys,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct UserPushSubscriptions {
+    pub user_id: String,
+    pub subscriptions: Vec<PushSubscription>,
+    #[serde(default)]
+    pub updated_at: DateTime<Utc>,
+}
+
+// Keyed by user id
+pub type PushSubscriptionStore = Store<UserPushSubscriptions>;
+
+// Of two copies of a user's subscriptions, keep the one changed last
+pub fn prefer_newer_subscriptions(
+    local: &UserPushSubscriptions,
+    other: &UserPushSubscriptions,
+) -> bool {
+    local.updated_at < other.updated_at
+}
+
+// Subscriptions are replicated over `web_push.exchange`, so any replica can push to any user
+#[derive(Deserialize, Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+enum SubscriptionEvent {
+    Added {
+        user_id: String,
+        subscription: PushSubscription,
+    },
+    Removed {
+        user_id: String,
+        id: String,
+    },
+}
+
+#[derive(Debug)]
+pub enum PushError {
+    NotConfigured,
+    // The push service no longer knows the subscription, e.g. the user unsubscribed
+    Gone,
+    Failed(String),
+}
+
+impl std::fmt::Display for PushError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PushError::NotConfigured => write!(f, "VAPID keys are not configured"),
+            PushError::Gone => write!(f, "Subscription has expired"),
+            PushError::Failed(reason) => write!(f, "Push failed: {}", reason),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct VapidClaims<'a> {
+    aud: &'a str,
+    exp: i64,
+    sub: &'a str,
+}
+
+lazy_static::lazy_static! {
+    static ref VAPID_KEY: Option<EncodingKey> = CONFIG
+        .web_push
+        .vapid_private_key_path
+        .as_ref()
+        .and_then(|path| match std::fs::read(path) {
+            Ok(pem) => EncodingKey::from_ec_pem(&pem)
+                .map_err(|e| eprintln!("Invalid VAPID private key {}: {:?}", path, e))
+                .ok(),
+            Err(e) => {
+                eprintln!("Unable to read VAPID private key {}: {:?}", path, e);
+                None
+            }
+        });
+}
+
+pub fn web_push_enabled() -> bool {
+    VAPID_KEY.is_some() && CONFIG.web_push.vapid_public_key.is_some()
+}
+
+fn subscription_id(endpoint: &str) -> String {
+    Uuid::new_v5(&Uuid::NAMESPACE_URL, endpoint.as_bytes()).to_string()
+}
+
+// Where the push is actually sent, honouring `web_push.push_service_url`. Endpoints come from
+// browsers, that is from users, so anything but a known push service is refused rather than
+// letting the service be pointed at internal addresses.
+fn push_url(endpoint: &str) -> Result<Url, String> {
+    let url = Url::parse(endpoint).map_err(|e| format!("Invalid endpoint {}: {}", endpoint, e))?;
+
+    match &CONFIG.web_push.push_service_url {
+        Some(push_service_url) => {
+            let mut base = Url::parse(push_service_url)
+                .map_err(|e| format!("Invalid push_service_url {}: {}", push_service_url, e))?;
+            base.set_path(url.path());
+            base.set_query(url.query());
+            Ok(base)
+        }
+        None => {
+            check_endpoint(&url, &CONFIG.web_push.allowed_hosts)?;
+            Ok(url)
+        }
+    }
+}
+
+fn check_endpoint(url: &Url, allowed_hosts: &[String]) -> Result<(), String> {
+    if url.scheme() != "https" {
+        return Err(format!("Endpoint scheme {} is not allowed", url.scheme()));
+    }
+    if url.port().is_some() {
+        return Err("Endpoint must not name a port".to_string());
+    }
+
+    // None for IP addresses
+    match url.domain() {
+        Some(host) if host_allowed(allowed_hosts, host) => Ok(()),
+        Some(host) => Err(format!("Push service {} is not allowed", host)),
+        None => Err("Endpoint must name a push service, not an address".to_string()),
+    }
+}
+
+fn host_allowed(allowed_hosts: &[String], host: &str) -> bool {
+    let host = host.trim_end_matches('.');
+    allowed_hosts
+        .iter()
+        .any(|allowed| match allowed.strip_prefix("*.") {
+            Some(domain) => host
+                .strip_suffix(domain)
+                .is_some_and(|subdomain| subdomain.len() > 1 && subdomain.ends_with('.')),
+            None => host.eq_ignore_ascii_case(allowed),
+        })
+}
+
+fn decode_key(key: &str) -> Result<Vec<u8>, String> {
+    URL_SAFE_NO_PAD
+        .decode(key.trim_end_matches('='))
+        .map_err(|e| format!("Invalid subscription key: {}", e))
+}
+
+// Push services rank deliveries by urgency, e.g. to spare a phone's battery
+fn urgency(priority: Priority) -> &'static str {
+    match priority {
+        Priority::Bulk => "low",
+        Priority::Normal => "normal",
+        Priority::Critical => "high",
+    }
+}
+
+// Encrypt `payload` for the subscription (RFC 8291, aes128gcm) and post it to the push service
+// with a VAPID (RFC 8292) authorization
+pub async fn send_web_push(
+    subscription: &PushSubscription,
+    payload: &[u8],
+    priority: Priority,
+) -> Result<(), PushError> {
+    let (Some(key), Some(public_key)) = (VAPID_KEY.as_ref(), &CONFIG.web_push.vapid_public_key)
+    else {
+        return Err(PushError::NotConfigured);
+    };
+
+    let url = push_url(&subscription.endpoint).map_err(PushError::Failed)?;
+    let audience = url.origin().ascii_serialization();
+    let token = encode(
+        &Header::new(Algorithm::ES256),
+        &VapidClaims {
+            aud: &audience,
+            exp: Utc::now().timestamp() + VAPID_TOKEN_TTL_SECS,
+            sub: &CONFIG.web_push.subject,
+        },
+        key,
+    )
+    .map_err(|e| PushError::Failed(format!("Unable to sign VAPID token: {:?}", e)))?;
+
+    let p256dh = decode_key(&subscription.keys.p256dh).map_err(PushError::Failed)?;
+    let auth = decode_key(&subscription.keys.auth).map_err(PushError::Failed)?;
+    let body = ece::encrypt(&p256dh, &auth, payload)
+        .map_err(|e| PushError::Failed(format!("Unable to encrypt payload: {:?}", e)))?;
+
+    let response = HTTP_CLIENT
+        .post(url)
+        .header("TTL", CONFIG.web_push.ttl_secs.to_string())
+        .header("Urgency", urgency(priority))
+        .header("Content-Encoding", "aes128gcm")
+        .header("Content-Type", "application/octet-stream")
+        .header(
+            "Authorization",
+            format!("vapid t={}, k={}", token, public_key),
+        )
+        .body(body)
+        .send()
+        .await
+        .map_err(|e| PushError::Failed(e.to_string()))?;
+
+    match response.status() {
+        status if status.is_success() => Ok(()),
+        HttpStatus::NOT_FOUND | HttpStatus::GONE => Err(PushError::Gone),
+        status => {
+            let reason = response.text().await.unwrap_or_default();
+            Err(PushError::Failed(format!("{} {}", status, reason)))
+        }
+    }
+}
+
+// Push to every browser the user subscribed, forgetting expired subscriptions. Returns how
+// many pushes were accepted.
+pub async fn push_to_user(
+    subscriptions: &PushSubscriptionStore,
+    user_id: &str,
+    payload: &str,
+    priority: Priority,
+) -> usize {
+    if !web_push_enabled() {
+        return 0;
+    }
+    let Some(user) = subscriptions.get(user_id).await else {
+        return 0;
+    };
+
+    let mut pushed = 0;
+    for subscription in &user.subscriptions {
+        match send_web_push(subscription, payload.as_bytes(), priority).await {
+            Ok(()) => {
+                WEB_PUSH_COUNTER.with_label_values(&["sent"]).inc();
+                pushed += 1;
+            }
+            Err(PushError::Gone) => {
+                WEB_PUSH_COUNTER.with_label_values(&["gone"]).inc();
+                println!(
+                    "Removing expired push subscription {} of {}",
+                    subscription.id, user_id
+                );
+                remove_subscription(subscriptions, user_id, &subscription.id).await;
+            }
+            Err(e) => {
+                WEB_PUSH_COUNTER.with_label_values(&["failed"]).inc();
+                println!("Failed to push to {}: {}", user_id, e);
+            }
+        }
+    }
+    pushed
+}
+
+pub async fn declare_push_exchange(rabbit_channel: &RabbitChannel) -> Result<(), LapinError> {
+    rabbit_channel
+        .exchange_declare(
+            &CONFIG.web_push.exchange,
+            lapin::ExchangeKind::Fanout,
+            ExchangeDeclareOptions {
+                durable: true,
+                ..Default::default()
+            },
+            FieldTable::default(),
+        )
+        .await
+}
+
+async fn publish_event(event: &SubscriptionEvent) -> Result<(), LapinError> {
+    let rabbit_channel = RABBIT_POOL.channel().await?;
+
+    rabbit_channel
+        .basic_publish(
+            &CONFIG.web_push.exchange,
+            "",
+            BasicPublishOptions::default(),
+            &serde_json::to_vec(event).unwrap(),
+            BasicProperties::default(),
+        )
+        .await?;
+    Ok(())
+}
+
+// Applying an event twice is harmless, as the replica that published it applies it right away
+async fn apply_event(subscriptions: &PushSubscriptionStore, event: SubscriptionEvent) {
+    match event {
+        SubscriptionEvent::Added {
+            user_id,
+            subscription,
+        } => {
+            subscriptions
+                .upsert(
+                    &user_id,
+                    || UserPushSubscriptions {
+                        user_id: user_id.clone(),
+                        subscriptions: vec![],
+                        updated_at: subscription.created_at,
+                    },
+                    |user| {
+                        user.updated_at = user.updated_at.max(subscription.created_at);
+                        user.subscriptions
+                            .retain(|existing| existing.id != subscription.id);
+                        user.subscriptions.push(subscription.clone());
+                        // Browsers re-subscribe now and then; the oldest go first
+                        let overflow = user
+                            .subscriptions
+                            .len()
+                            .saturating_sub(CONFIG.web_push.max_subscriptions_per_user);
+                        user.subscriptions.drain(..overflow);
+                    },
+                )
+                .await;
+        }
+        SubscriptionEvent::Removed { user_id, id } => {
+            subscriptions
+                .update(&user_id, |user| {
+                    user.subscriptions
+                        .retain(|subscription| subscription.id != id);
+                    user.updated_at = Utc::now();
+                })
+                .await;
+        }
+    }
+}
+
+async fn remove_subscription(subscriptions: &PushSubscriptionStore, user_id: &str, id: &str) {
+    let event = SubscriptionEvent::Removed {
+        user_id: user_id.to_string(),
+        id: id.to_string(),
+    };
+    if let Err(e) = publish_event(&event).await {
+        eprintln!(
+            "Failed to replicate removing push subscription of {}: {:?}",
+            user_id, e
+        );
+    }
+    apply_event(subscriptions, event).await;
+}
+
+pub async fn consume_subscription_events(subscriptions: PushSubscriptionStore) {
+    loop {
+        match open_rabbitmq_channel().await {
+            Ok(rabbit_channel) => {
+                if let Err(e) = process_subscription_events(rabbit_channel, &subscriptions).await {
+                    eprintln!("Error processing push subscription events: {:?}", e);
+                }
+            }
+            Err(e) => {
+                eprintln!("Error connecting to RabbitMQ: {:?}", e);
+            }
+        }
+
+        eprintln!("Reconnecting to push subscription events in 5 seconds...");
+        sleep(Duration::from_secs(5)).await;
+    }
+}
+
+async fn process_subscription_events(
+    rabbit_channel: RabbitChannel,
+    subscriptions: &PushSubscriptionStore,
+) -> Result<(), LapinError> {
+    declare_push_exchange(&rabbit_channel).await?;
+
+    let queue = rabbit_channel
+        .queue_declare(
+            "",
+            QueueDeclareOptions {
+                exclusive: true,
+                auto_delete: true,
+                ..Default::default()
+            },
+            FieldTable::default(),
+        )
+        .await?;
+
+    rabbit_channel
+        .queue_bind(
+            queue.name().as_str(),
+            &CONFIG.web_push.exchange,
+            "",
+            QueueBindOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    let mut consumer = rabbit_channel
+        .basic_consume(
+            queue.name().as_str(),
+            "push_subscriptions_consumer",
+            BasicConsumeOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    while let Some(delivery) = consumer.next().await {
+        let delivery = delivery?;
+
+        match serde_json::from_slice::<SubscriptionEvent>(&delivery.data) {
+            Ok(event) => apply_event(subscriptions, event).await,
+            Err(e) => println!("Failed to deserialize push subscription event: {:?}", e),
+        }
+
+        delivery.ack(BasicAckOptions::default()).await?;
+    }
+
+    Ok(())
+}
+
+#[utoipa::path(
+    get,
+    path = "/notification/web-push/vapid-public-key",
+    responses(
+        (status = 200, description = "The applicationServerKey to subscribe browsers with", body = VapidPublicKeyResponse),
+        (status = 404, description = "Web Push is not configured")
+    ),
+    tag = "default"
+)]
+pub async fn get_vapid_public_key() -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    match (&CONFIG.web_push.vapid_public_key, web_push_enabled()) {
+        (Some(public_key), true) => Ok(Box::new(warp::reply::json(&VapidPublicKeyResponse {
+            public_key: public_key.clone(),
+        }))),
+        _ => Ok(Box::new(warp::reply::with_status(
+            warp::reply::json(&"Web Push is not configured"),
+            StatusCode::NOT_FOUND,
+        ))),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/notification/users/{user_id}/push-subscriptions",
+    params(
+        ("user_id" = String, Path, description = "The user the browser belongs to")
+    ),
+    request_body = PushSubscriptionRequest,
+    responses(
+        (status = 201, description = "Subscription registered", body = PushSubscription),
+        (status = 400, description = "Invalid endpoint or keys, or the endpoint isn't an allowed push service"),
+        (status = 403, description = "Not the caller's own subscriptions")
+    ),
+    security(("bearerAuth" = [])),
+    tag = "default"
+)]
+pub async fn register_push_subscription(
+    user_id: String,
+    request: PushSubscriptionRequest,
+    claims: Claims,
+    subscriptions: PushSubscriptionStore,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    if let Some(reply) = forbidden_unless_self(&claims, &user_id) {
+        return Ok(reply);
+    }
+
+    // Local stand-ins set with `push_service_url` may use plain HTTP and any address
+    let endpoint_check = match push_url(&request.endpoint) {
+        Ok(url) if CONFIG.web_push.push_service_url.is_none() => {
+            check_resolves_publicly(&url).await
+        }
+        Ok(_) => Ok(()),
+        Err(e) => Err(e),
+    };
+    let validation = endpoint_check
+        .and_then(|_| decode_key(&request.keys.p256dh))
+        .and_then(|_| decode_key(&request.keys.auth));
+    if let Err(e) = validation {
+        return Ok(Box::new(warp::reply::with_status(
+            warp::reply::json(&e),
+            StatusCode::BAD_REQUEST,
+        )));
+    }
+
+    let subscription = PushSubscription {
+        id: subscription_id(&request.endpoint),
+        endpoint: request.endpoint,
+        keys: request.keys,
+        created_at: Utc::now(),
+    };
+    let event = SubscriptionEvent::Added {
+        user_id: user_id.clone(),
+        subscription: subscription.clone(),
+    };
+    if let Err(e) = publish_event(&event).await {
+        eprintln!(
+            "Failed to replicate push subscription of {}: {:?}",
+            user_id, e
+        );
+    }
+    apply_event(&subscriptions, event).await;
+
+    Ok(Box::new(warp::reply::with_status(
+        warp::reply::json(&subscription),
+        StatusCode::CREATED,
+    )))
+}
+
+#[utoipa::path(
+    get,
+    path = "/notification/users/{user_id}/push-subscriptions",
+    params(
+        ("user_id" = String, Path, description = "The user whose subscriptions to list")
+    ),
+    responses(
+        (status = 200, description = "The user's browser push subscriptions", body = [PushSubscription]),
+        (status = 403, description = "Not the caller's own subscriptions")
+    ),
+    security(("bearerAuth" = [])),
+    tag = "default"
+)]
+pub async fn list_push_subscriptions(
+    user_id: String,
+    claims: Claims,
+    subscriptions: PushSubscriptionStore,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    if let Some(reply) = forbidden_unless_self(&claims, &user_id) {
+        return Ok(reply);
+    }
+
+    let user_subscriptions = subscriptions
+        .get(&user_id)
+        .await
+        .map(|user| user.subscriptions)
+        .unwrap_or_default();
+    Ok(Box::new(warp::reply::json(&user_subscriptions)))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/notification/users/{user_id}/push-subscriptions/{subscription_id}",
+    params(
+        ("user_id" = String, Path, description = "The user the subscription belongs to"),
+        ("subscription_id" = String, Path, description = "The id returned on registration")
+    ),
+    responses(
+        (status = 204, description = "Subscription removed"),
+        (status = 403, description = "Not the caller's own subscriptions"),
+        (status = 404, description = "Subscription not found")
+    ),
+    security(("bearerAuth" = [])),
+    tag = "default"
+)]
+pub async fn delete_push_subscription(
+    user_id: String,
+    subscription_id: String,
+    claims: Claims,
+    subscriptions: PushSubscriptionStore,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    if let Some(reply) = forbidden_unless_self(&claims, &user_id) {
+        return Ok(reply);
+    }
+
+    let exists = subscriptions.get(&user_id).await.is_some_and(|user| {
+        user.subscriptions
+            .iter()
+            .any(|subscription| subscription.id == subscription_id)
+    });
+    if !exists {
+        return Ok(Box::new(warp::reply::with_status(
+            warp::reply::json(&"Subscription not found"),
+            StatusCode::NOT_FOUND,
+        )));
+    }
+
+    remove_subscription(&subscriptions, &user_id, &subscription_id).await;
+    Ok(Box::new(StatusCode::NO_CONTENT))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowed() -> Vec<String> {
+        vec![
+            "fcm.googleapis.com".to_string(),
+            "*.push.apple.com".to_string(),
+        ]
+    }
+
+    fn check(endpoint: &str) -> Result<(), String> {
+        check_endpoint(&Url::parse(endpoint).unwrap(), &allowed())
+    }
+
+    #[test]
+    fn accepts_allowed_push_services() {
+        assert!(check("https://fcm.googleapis.com/fcm/send/abc").is_ok());
+        assert!(check("https://web.push.apple.com/QGuQ").is_ok());
+        assert!(check("https://FCM.googleapis.com/fcm/send/abc").is_ok());
+    }
+
+    #[test]
+    fn refuses_other_hosts() {
+        assert!(check("https://push.apple.com/x").is_err());
+        assert!(check("https://evilpush.apple.com.example.org/x").is_err());
+        assert!(check("https://fcm.googleapis.com.example.org/x").is_err());
+        assert!(check("https://metadata.google.internal/x").is_err());
+    }
+
+    #[test]
+    fn refuses_addresses_ports_and_plain_http() {
+        assert!(check("https://127.0.0.1/x").is_err());
+        assert!(check("https://[::1]/x").is_err());
+        assert!(check("https://fcm.googleapis.com:8443/x").is_err());
+        assert!(check("http://fcm.googleapis.com/x").is_err());
+    }
+}
@@ -0,0 +1,1143 @@
+use std::cmp::Reverse;
+
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use futures::StreamExt;
+use ginger_shared_rs::rocket_utils::APIClaims;
+use hmac::{Hmac, Mac};
+use lapin::{
+    options::{
+        BasicAckOptions, BasicConsumeOptions, BasicNackOptions, BasicPublishOptions,
+        BasicQosOptions, ExchangeDeclareOptions, QueueBindOptions, QueueDeclareOptions,
+    },
+    types::{AMQPValue, FieldTable},
+    BasicProperties, Channel as RabbitChannel, Error as LapinError,
+};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::{
+    task::JoinSet,
+    time::{interval, sleep, Duration},
+};
+use utoipa::ToSchema;
+use uuid::Uuid;
+use warp::http::StatusCode;
+
+use crate::{
+    channel_acl::{authorize_api_subscribe, patterns_cover, validate_channel_pattern},
+    config::CONFIG,
+    message_queue_helpers::decode_delivery,
+    prom_helpers::WEBHOOK_DELIVERIES_COUNTER,
+    requests::RabbitMessage,
+    responses::WebhookResponse,
+    shared::{
+        bind_channel, check_resolves_publicly, connect_rabbitmq, open_rabbitmq_channel,
+        unbind_channel, GROUP_CHANNEL_PREFIX, RABBIT_POOL, USER_CHANNEL_PREFIX,
+    },
+    shutdown::SHUTDOWN,
+    store::Store,
+};
+
+// Bound to the channel exchange for every webhook's channel. Shared by all replicas, so each
+// message is dispatched to its webhooks once.
+pub const WEBHOOK_MESSAGE_QUEUE: &str = "webhook-message-queue";
+pub const WEBHOOK_QUEUE: &str = "webhook-delivery-queue";
+pub const WEBHOOK_RETRY_QUEUE: &str = "webhook-retry-queue";
+pub const WEBHOOK_DEAD_LETTER_QUEUE: &str = "webhook-dead-letter-queue";
+
+pub const WEBHOOK_ID_HEADER: &str = "X-Webhook-Id";
+pub const DELIVERY_ID_HEADER: &str = "X-Webhook-Delivery";
+pub const TIMESTAMP_HEADER: &str = "X-Webhook-Timestamp";
+pub const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+
+const MIN_SECRET_LENGTH: usize = 16;
+
+lazy_static::lazy_static! {
+    // Not the shared client, as following a redirect would let an endpoint send deliveries on
+    // to an internal address
+    static ref WEBHOOK_CLIENT: reqwest::Client = reqwest::Client::builder()
+        .timeout(std::time::Duration::from_secs(10))
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap();
+}
+
+#[derive(Deserialize, Serialize, ToSchema, Clone)]
+pub struct WebhookRequest {
+    pub channel: String, // A channel name or subscription pattern
+    pub url: String,
+    pub secret: String,
+    #[serde(default)]
+    pub event_types: Vec<String>, // Only messages of these types; every message when empty
+}
+
+// Stored along with its secret, which is never handed back out. Removed webhooks are kept as
+// tombstones until the delivery log would have been pruned, so jobs still queued for them are
+// dropped rather than delivered with the copy they carry.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Webhook {
+    pub id: String,
+    pub owner: String,
+    pub channel: String,
+    pub url: String,
+    pub secret: String,
+    pub event_types: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    #[serde(default)]
+    pub removed_at: Option<DateTime<Utc>>,
+}
+
+impl Webhook {
+    fn is_active(&self) -> bool {
+        self.removed_at.is_none()
+    }
+
+    // Untyped messages only reach webhooks without a type filter. Group and user messages are
+    // addressed to people rather than channels, even when a `#` binding picks them up.
+    fn accepts(&self, message: &RabbitMessage) -> bool {
+        let channel = &message.channel_id;
+        if !self.is_active()
+            || channel.starts_with(GROUP_CHANNEL_PREFIX)
+            || channel.starts_with(USER_CHANNEL_PREFIX)
+        {
+            return false;
+        }
+
+        let type_matches = self.event_types.is_empty()
+            || message
+                .event_type
+                .as_ref()
+                .is_some_and(|event_type| self.event_types.contains(event_type));
+        type_matches && patterns_cover(std::slice::from_ref(&self.channel), channel)
+    }
+}
+
+impl From<&Webhook> for WebhookResponse {
+    fn from(webhook: &Webhook) -> Self {
+        WebhookResponse {
+            id: webhook.id.clone(),
+            channel: webhook.channel.clone(),
+            url: webhook.url.clone(),
+            event_types: webhook.event_types.clone(),
+            created_at: webhook.created_at,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, ToSchema, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookDeliveryStatus {
+    Pending,
+    Delivered,
+    DeadLettered,
+    Expired,
+}
+
+// One message POSTed to one webhook, as kept in the delivery log
+#[derive(Deserialize, Serialize, ToSchema, Clone)]
+pub struct WebhookDelivery {
+    pub id: String,
+    pub webhook_id: String,
+    pub message_id: String,
+    pub channel: String,
+    #[serde(rename = "type")]
+    pub event_type: Option<String>,
+    pub status: WebhookDeliveryStatus,
+    pub attempts: u32,
+    pub response_status: Option<u16>,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+// Keyed by webhook id
+pub type WebhookStore = Store<Webhook>;
+// Keyed by delivery id
+pub type WebhookDeliveryStore = Store<WebhookDelivery>;
+
+// A removal always wins over the registration it tombstones
+pub fn prefer_removed_webhook(local: &Webhook, other: &Webhook) -> bool {
+    local.is_active() && !other.is_active()
+}
+
+pub fn prefer_newer_delivery(local: &WebhookDelivery, other: &WebhookDelivery) -> bool {
+    local.updated_at < other.updated_at
+}
+
+// Placed on the delivery queue. Carries its log entry and the webhook itself, as the replica
+// picking it up may not have seen either replicated yet. Jobs queued by older versions carry no
+// webhook.
+#[derive(Deserialize, Serialize, Clone)]
+struct WebhookJob {
+    delivery: WebhookDelivery,
+    #[serde(default)]
+    webhook: Option<Webhook>,
+    payload: String,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+// The body POSTed to webhooks
+#[derive(Serialize)]
+struct WebhookPayload<'a> {
+    id: &'a str,
+    channel: &'a str,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    event_type: Option<&'a str>,
+    message: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<DateTime<Utc>>,
+}
+
+// Webhooks and their delivery log are replicated over `webhooks.exchange`, so any replica can
+// dispatch to and answer for any webhook
+#[derive(Deserialize, Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+enum WebhookEvent {
+    Registered { webhook: Webhook },
+    Removed { id: String },
+    Delivery { delivery: WebhookDelivery },
+}
+
+// Declared on the pooled channel, so handlers can bind the message queue right away
+pub async fn declare_webhooks(rabbit_channel: &RabbitChannel) -> Result<(), LapinError> {
+    rabbit_channel
+        .exchange_declare(
+            &CONFIG.webhooks.exchange,
+            lapin::ExchangeKind::Fanout,
+            ExchangeDeclareOptions {
+                durable: true,
+                ..Default::default()
+            },
+            FieldTable::default(),
+        )
+        .await?;
+
+    let durable = QueueDeclareOptions {
+        durable: true,
+        ..Default::default()
+    };
+
+    for queue in [
+        WEBHOOK_MESSAGE_QUEUE,
+        WEBHOOK_QUEUE,
+        WEBHOOK_DEAD_LETTER_QUEUE,
+    ] {
+        rabbit_channel
+            .queue_declare(queue, durable, FieldTable::default())
+            .await?;
+    }
+
+    // Deliveries waiting out their backoff are dead-lettered back onto the delivery queue once
+    // their expiration elapses, as for emails
+    let mut retry_args = FieldTable::default();
+    retry_args.insert(
+        "x-dead-letter-exchange".into(),
+        AMQPValue::LongString("".into()),
+    );
+    retry_args.insert(
+        "x-dead-letter-routing-key".into(),
+        AMQPValue::LongString(WEBHOOK_QUEUE.into()),
+    );
+    rabbit_channel
+        .queue_declare(WEBHOOK_RETRY_QUEUE, durable, retry_args)
+        .await?;
+
+    Ok(())
+}
+
+async fn publish_event(event: &WebhookEvent) -> Result<(), LapinError> {
+    let rabbit_channel = RABBIT_POOL.channel().await?;
+
+    rabbit_channel
+        .basic_publish(
+            &CONFIG.webhooks.exchange,
+            "",
+            BasicPublishOptions::default(),
+            &serde_json::to_vec(event).unwrap(),
+            BasicProperties::default(),
+        )
+        .await?;
+    Ok(())
+}
+
+// Applying an event twice is harmless, as the replica that published it applies it right away.
+// Delivery updates may arrive out of order, so an older one never replaces a newer one.
+async fn apply_event(
+    webhooks: &WebhookStore,
+    deliveries: &WebhookDeliveryStore,
+    event: WebhookEvent,
+) {
+    match event {
+        WebhookEvent::Registered { webhook } => {
+            webhooks.insert(webhook.id.clone(), webhook).await;
+        }
+        WebhookEvent::Removed { id } => {
+            webhooks
+                .update(&id, |webhook| {
+                    webhook.removed_at.get_or_insert_with(Utc::now);
+                })
+                .await;
+            deliveries
+                .retain(|delivery| delivery.webhook_id != id)
+                .await;
+        }
+        WebhookEvent::Delivery { delivery } => {
+            let id = delivery.id.clone();
+            let initial = delivery.clone();
+            deliveries
+                .upsert(
+                    &id,
+                    || initial,
+                    |existing| {
+                        if existing.updated_at <= delivery.updated_at {
+                            *existing = delivery;
+                        }
+                    },
+                )
+                .await;
+        }
+    }
+}
+
+async fn replicate(
+    webhooks: &WebhookStore,
+    deliveries: &WebhookDeliveryStore,
+    event: WebhookEvent,
+) {
+    if let Err(e) = publish_event(&event).await {
+        eprintln!("Failed to replicate webhook change: {:?}", e);
+    }
+    apply_event(webhooks, deliveries, event).await;
+}
+
+pub async fn consume_webhook_events(webhooks: WebhookStore, deliveries: WebhookDeliveryStore) {
+    loop {
+        match open_rabbitmq_channel().await {
+            Ok(rabbit_channel) => {
+                if let Err(e) = process_webhook_events(rabbit_channel, &webhooks, &deliveries).await
+                {
+                    eprintln!("Error processing webhook events: {:?}", e);
+                }
+            }
+            Err(e) => {
+                eprintln!("Error connecting to RabbitMQ: {:?}", e);
+            }
+        }
+
+        eprintln!("Reconnecting to webhook events in 5 seconds...");
+        sleep(Duration::from_secs(5)).await;
+    }
+}
+
+async fn process_webhook_events(
+    rabbit_channel: RabbitChannel,
+    webhooks: &WebhookStore,
+    deliveries: &WebhookDeliveryStore,
+) -> Result<(), LapinError> {
+    declare_webhooks(&rabbit_channel).await?;
+
+    let queue = rabbit_channel
+        .queue_declare(
+            "",
+            QueueDeclareOptions {
+                exclusive: true,
+                auto_delete: true,
+                ..Default::default()
+            },
+            FieldTable::default(),
+        )
+        .await?;
+
+    rabbit_channel
+        .queue_bind(
+            queue.name().as_str(),
+            &CONFIG.webhooks.exchange,
+            "",
+            QueueBindOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    let mut consumer = rabbit_channel
+        .basic_consume(
+            queue.name().as_str(),
+            "webhook_event_consumer",
+            BasicConsumeOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    while let Some(delivery) = consumer.next().await {
+        let delivery = delivery?;
+
+        match serde_json::from_slice::<WebhookEvent>(&delivery.data) {
+            Ok(event) => apply_event(webhooks, deliveries, event).await,
+            Err(e) => println!("Failed to deserialize webhook event: {:?}", e),
+        }
+
+        delivery.ack(BasicAckOptions::default()).await?;
+    }
+
+    Ok(())
+}
+
+async fn publish_job(
+    rabbit_channel: &RabbitChannel,
+    queue: &str,
+    job: &WebhookJob,
+    expiration_ms: Option<u64>,
+) -> Result<(), LapinError> {
+    let mut properties = BasicProperties::default()
+        .with_delivery_mode(2) // Persistent
+        .with_message_id(job.delivery.id.clone().into());
+
+    if let Some(expiration_ms) = expiration_ms {
+        properties = properties.with_expiration(expiration_ms.to_string().into());
+    }
+
+    rabbit_channel
+        .basic_publish(
+            "", // Default exchange, routed by queue name
+            queue,
+            BasicPublishOptions::default(),
+            &serde_json::to_vec(job).unwrap(),
+            properties,
+        )
+        .await?;
+
+    Ok(())
+}
+
+// Exponential backoff for the given (1-based) attempt, capped at `max_backoff_ms`
+fn retry_backoff_ms(attempt: u32) -> u64 {
+    let exponent = attempt.saturating_sub(1).min(31);
+    CONFIG
+        .webhooks
+        .initial_backoff_ms
+        .saturating_mul(1u64 << exponent)
+        .min(CONFIG.webhooks.max_backoff_ms)
+}
+
+// Consumes channel messages the way each replica's own consumer does, turning them into one
+// delivery per matching webhook
+pub async fn consume_webhook_messages(webhooks: WebhookStore, deliveries: WebhookDeliveryStore) {
+    loop {
+        match connect_rabbitmq().await {
+            Ok(rabbit_channel) => {
+                if let Err(e) =
+                    process_webhook_messages(rabbit_channel, &webhooks, &deliveries).await
+                {
+                    eprintln!("Error dispatching webhook messages: {:?}", e);
+                }
+            }
+            Err(e) => {
+                eprintln!("Error connecting to RabbitMQ: {:?}", e);
+            }
+        }
+
+        if SHUTDOWN.is_draining() {
+            return;
+        }
+
+        eprintln!("Reconnecting webhook dispatcher to RabbitMQ in 5 seconds...");
+        tokio::select! {
+            _ = sleep(Duration::from_secs(5)) => {}
+            _ = SHUTDOWN.started() => return,
+        }
+    }
+}
+
+async fn process_webhook_messages(
+    rabbit_channel: RabbitChannel,
+    webhooks: &WebhookStore,
+    deliveries: &WebhookDeliveryStore,
+) -> Result<(), LapinError> {
+    // Bindings are normally added on registration; rebinding is harmless and covers webhooks
+    // registered while the queue was being recreated
+    for webhook in webhooks.values().await {
+        if webhook.is_active() {
+            bind_channel(&rabbit_channel, WEBHOOK_MESSAGE_QUEUE, &webhook.channel).await?;
+        }
+    }
+
+    let mut consumer = rabbit_channel
+        .basic_consume(
+            WEBHOOK_MESSAGE_QUEUE,
+            "webhook_dispatcher",
+            BasicConsumeOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    while let Some(delivery) = SHUTDOWN.next_delivery(&mut consumer).await {
+        let delivery = delivery?;
+
+        if let Some(rabbit_message) = decode_delivery(&delivery.data) {
+            dispatch_message(&rabbit_channel, webhooks, deliveries, &rabbit_message).await?;
+        }
+
+        delivery.ack(BasicAckOptions::default()).await?;
+    }
+
+    Ok(())
+}
+
+async fn dispatch_message(
+    rabbit_channel: &RabbitChannel,
+    webhooks: &WebhookStore,
+    deliveries: &WebhookDeliveryStore,
+    message: &RabbitMessage,
+) -> Result<(), LapinError> {
+    let payload = serde_json::to_string(&WebhookPayload {
+        id: &message.message_id,
+        channel: &message.channel_id,
+        event_type: message.event_type.as_deref(),
+        message: &message.message,
+        expires_at: message.expires_at,
+    })
+    .unwrap();
+    let now = Utc::now();
+
+    for webhook in webhooks.values().await {
+        if !webhook.accepts(message) {
+            continue;
+        }
+
+        let delivery = WebhookDelivery {
+            id: Uuid::new_v4().to_string(),
+            webhook_id: webhook.id.clone(),
+            message_id: message.message_id.clone(),
+            channel: message.channel_id.clone(),
+            event_type: message.event_type.clone(),
+            status: WebhookDeliveryStatus::Pending,
+            attempts: 0,
+            response_status: None,
+            last_error: None,
+            created_at: now,
+            updated_at: now,
+        };
+        replicate(
+            webhooks,
+            deliveries,
+            WebhookEvent::Delivery {
+                delivery: delivery.clone(),
+            },
+        )
+        .await;
+
+        let job = WebhookJob {
+            delivery,
+            webhook: Some(webhook),
+            payload: payload.clone(),
+            expires_at: message.expires_at,
+        };
+        publish_job(rabbit_channel, WEBHOOK_QUEUE, &job, None).await?;
+    }
+
+    Ok(())
+}
+
+pub async fn consume_webhook_deliveries(webhooks: WebhookStore, deliveries: WebhookDeliveryStore) {
+    loop {
+        match connect_rabbitmq().await {
+            Ok(rabbit_channel) => {
+                if let Err(e) =
+                    process_webhook_jobs(rabbit_channel, webhooks.clone(), deliveries.clone()).await
+                {
+                    eprintln!("Error processing webhook deliveries: {:?}", e);
+                }
+            }
+            Err(e) => {
+                eprintln!("Error connecting to RabbitMQ: {:?}", e);
+            }
+        }
+
+        if SHUTDOWN.is_draining() {
+            return;
+        }
+
+        eprintln!("Reconnecting webhook worker to RabbitMQ in 5 seconds...");
+        tokio::select! {
+            _ = sleep(Duration::from_secs(5)) => {}
+            _ = SHUTDOWN.started() => return,
+        }
+    }
+}
+
+async fn process_webhook_jobs(
+    rabbit_channel: RabbitChannel,
+    webhooks: WebhookStore,
+    deliveries: WebhookDeliveryStore,
+) -> Result<(), LapinError> {
+    // Several deliveries are in flight at once, so one slow endpoint doesn't hold up the rest
+    rabbit_channel
+        .basic_qos(
+            CONFIG.webhooks.concurrency.max(1),
+            BasicQosOptions::default(),
+        )
+        .await?;
+
+    let mut consumer = rabbit_channel
+        .basic_consume(
+            WEBHOOK_QUEUE,
+            "webhook_worker",
+            BasicConsumeOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    let mut in_flight = JoinSet::new();
+    while let Some(delivery) = SHUTDOWN.next_delivery(&mut consumer).await {
+        let delivery = delivery?;
+        let rabbit_channel = rabbit_channel.clone();
+        let webhooks = webhooks.clone();
+        let deliveries = deliveries.clone();
+        while in_flight.try_join_next().is_some() {}
+
+        in_flight.spawn(async move {
+            match serde_json::from_slice::<WebhookJob>(&delivery.data) {
+                Ok(job) => {
+                    match handle_webhook_job(&rabbit_channel, &webhooks, &deliveries, job).await {
+                        Ok(true) => {}
+                        // Requeued after a pause, as the registration may still be on its way
+                        Ok(false) => {
+                            sleep(Duration::from_secs(5)).await;
+                            let requeue = BasicNackOptions {
+                                requeue: true,
+                                ..Default::default()
+                            };
+                            if let Err(e) = delivery.nack(requeue).await {
+                                eprintln!("Failed to requeue webhook delivery: {:?}", e);
+                            }
+                            return;
+                        }
+                        // Left unacked, so the job is redelivered once the channel is reopened
+                        Err(e) => {
+                            eprintln!("Error handling webhook delivery: {:?}", e);
+                            return;
+                        }
+                    }
+                }
+                Err(e) => println!("Failed to deserialize webhook job: {:?}", e),
+            }
+
+            if let Err(e) = delivery.ack(BasicAckOptions::default()).await {
+                eprintln!("Failed to acknowledge webhook delivery: {:?}", e);
+            }
+        });
+    }
+
+    // On shutdown, deliveries already being POSTed are finished and acked first
+    while in_flight.join_next().await.is_some() {}
+
+    Ok(())
+}
+
+// Returns whether the job is done with, or false when neither this replica nor the job knows
+// the webhook, in which case it must be requeued rather than acked
+async fn handle_webhook_job(
+    rabbit_channel: &RabbitChannel,
+    webhooks: &WebhookStore,
+    deliveries: &WebhookDeliveryStore,
+    mut job: WebhookJob,
+) -> Result<bool, LapinError> {
+    // The registry's copy is preferred, as it knows about removals the job can't
+    let known = webhooks.get(&job.delivery.webhook_id).await;
+    let Some(webhook) = known.or_else(|| job.webhook.clone()) else {
+        println!(
+            "Requeueing delivery {} for unknown webhook {}",
+            job.delivery.id, job.delivery.webhook_id
+        );
+        return Ok(false);
+    };
+    if !webhook.is_active() {
+        println!(
+            "Dropping delivery {} for removed webhook {}",
+            job.delivery.id, webhook.id
+        );
+        return Ok(true);
+    }
+
+    if job
+        .expires_at
+        .is_some_and(|expires_at| expires_at <= Utc::now())
+    {
+        println!(
+            "Delivery {} to webhook {} expired before it succeeded",
+            job.delivery.id, webhook.id
+        );
+        WEBHOOK_DELIVERIES_COUNTER
+            .with_label_values(&["expired"])
+            .inc();
+        job.delivery.status = WebhookDeliveryStatus::Expired;
+        job.delivery.updated_at = Utc::now();
+        replicate(
+            webhooks,
+            deliveries,
+            WebhookEvent::Delivery {
+                delivery: job.delivery,
+            },
+        )
+        .await;
+        return Ok(true);
+    }
+
+    job.delivery.attempts += 1;
+    let attempts = job.delivery.attempts;
+
+    match post_webhook(&webhook, &job).await {
+        Ok(response_status) => {
+            println!(
+                "Delivered {} to webhook {} after {} attempt(s)",
+                job.delivery.id, webhook.id, attempts
+            );
+            WEBHOOK_DELIVERIES_COUNTER
+                .with_label_values(&["delivered"])
+                .inc();
+            job.delivery.status = WebhookDeliveryStatus::Delivered;
+            job.delivery.response_status = Some(response_status);
+            job.delivery.last_error = None;
+        }
+        Err((response_status, error)) => {
+            job.delivery.response_status = response_status;
+            job.delivery.last_error = Some(error.clone());
+
+            if attempts >= CONFIG.webhooks.max_attempts {
+                eprintln!(
+                    "Delivery {} to webhook {} failed permanently: {}",
+                    job.delivery.id, webhook.id, error
+                );
+                WEBHOOK_DELIVERIES_COUNTER
+                    .with_label_values(&["dead_lettered"])
+                    .inc();
+                job.delivery.status = WebhookDeliveryStatus::DeadLettered;
+                // The dead letter copy is for inspection, so it doesn't carry the secret
+                let dead_letter = WebhookJob {
+                    webhook: None,
+                    ..job.clone()
+                };
+                publish_job(
+                    rabbit_channel,
+                    WEBHOOK_DEAD_LETTER_QUEUE,
+                    &dead_letter,
+                    None,
+                )
+                .await?;
+            } else {
+                let backoff_ms = retry_backoff_ms(attempts);
+                eprintln!(
+                    "Delivery {} to webhook {} failed (attempt {}), retrying in {}ms: {}",
+                    job.delivery.id, webhook.id, attempts, backoff_ms, error
+                );
+                WEBHOOK_DELIVERIES_COUNTER
+                    .with_label_values(&["retried"])
+                    .inc();
+                job.delivery.status = WebhookDeliveryStatus::Pending;
+                publish_job(rabbit_channel, WEBHOOK_RETRY_QUEUE, &job, Some(backoff_ms)).await?;
+            }
+        }
+    }
+
+    job.delivery.updated_at = Utc::now();
+    replicate(
+        webhooks,
+        deliveries,
+        WebhookEvent::Delivery {
+            delivery: job.delivery,
+        },
+    )
+    .await;
+
+    Ok(true)
+}
+
+// Hex encoded HMAC-SHA256 of `{timestamp}.{body}`. Signing the timestamp lets receivers reject
+// replayed deliveries.
+fn sign(secret: &str, timestamp: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+// POST the payload, returning the response status, plus a reason when it wasn't a 2xx
+async fn post_webhook(webhook: &Webhook, job: &WebhookJob) -> Result<u16, (Option<u16>, String)> {
+    // The host may have been pointed at an internal address since the webhook was registered
+    let url = check_url(&webhook.url).await.map_err(|e| (None, e))?;
+
+    let timestamp = Utc::now().timestamp().to_string();
+    let signature = sign(&webhook.secret, &timestamp, &job.payload);
+
+    let response = WEBHOOK_CLIENT
+        .post(url)
+        .header("Content-Type", "application/json")
+        .header(WEBHOOK_ID_HEADER, &webhook.id)
+        .header(DELIVERY_ID_HEADER, &job.delivery.id)
+        .header(TIMESTAMP_HEADER, &timestamp)
+        .header(SIGNATURE_HEADER, format!("sha256={}", signature))
+        .body(job.payload.clone())
+        .send()
+        .await
+        .map_err(|e| (None, format!("Request failed: {}", e)))?;
+
+    let status = response.status();
+    if status.is_success() {
+        Ok(status.as_u16())
+    } else {
+        Err((
+            Some(status.as_u16()),
+            format!("Endpoint responded with {}", status),
+        ))
+    }
+}
+
+pub async fn prune_webhook_deliveries(webhooks: WebhookStore, deliveries: WebhookDeliveryStore) {
+    let mut ticker = interval(Duration::from_secs(3600));
+
+    loop {
+        ticker.tick().await;
+        let cutoff = Utc::now() - ChronoDuration::days(CONFIG.webhooks.log_retention_days as i64);
+        let removed = deliveries
+            .retain(|delivery| delivery.updated_at > cutoff)
+            .await;
+        if removed > 0 {
+            println!("Pruned {} webhook deliveries", removed);
+        }
+
+        // By now no job for a removed webhook can still be queued
+        let tombstones = webhooks
+            .retain(|webhook| {
+                webhook
+                    .removed_at
+                    .is_none_or(|removed_at| removed_at > cutoff)
+            })
+            .await;
+        if tombstones > 0 {
+            println!("Pruned {} removed webhooks", tombstones);
+        }
+    }
+}
+
+async fn check_url(url: &str) -> Result<Url, String> {
+    let url = Url::parse(url).map_err(|e| format!("Invalid url {}: {}", url, e))?;
+    match url.scheme() {
+        "https" => {}
+        "http" if CONFIG.webhooks.allow_http => {}
+        scheme => return Err(format!("Url scheme {} is not allowed", scheme)),
+    }
+
+    if !CONFIG.webhooks.allow_internal {
+        check_resolves_publicly(&url).await?;
+    }
+    Ok(url)
+}
+
+async fn validate_webhook(request: &WebhookRequest) -> Result<(), String> {
+    validate_channel_pattern(&request.channel)?;
+    check_url(&request.url).await?;
+
+    if request.secret.len() < MIN_SECRET_LENGTH {
+        return Err(format!(
+            "secret must be at least {} characters",
+            MIN_SECRET_LENGTH
+        ));
+    }
+    Ok(())
+}
+
+fn reply_with_status(message: String, status: StatusCode) -> Box<dyn warp::Reply> {
+    Box::new(warp::reply::with_status(
+        warp::reply::json(&message),
+        status,
+    ))
+}
+
+// The caller's webhook with this id, if any
+async fn owned_webhook(webhooks: &WebhookStore, id: &str, claims: &APIClaims) -> Option<Webhook> {
+    webhooks
+        .get(id)
+        .await
+        .filter(|webhook| webhook.is_active() && webhook.owner == claims.sub)
+}
+
+#[utoipa::path(
+    post,
+    path = "/notification/webhooks",
+    request_body = WebhookRequest,
+    responses(
+        (status = 201, description = "Webhook registered", body = WebhookResponse),
+        (status = 400, description = "Invalid channel, url or secret, or too many webhooks"),
+        (status = 403, description = "The caller may not subscribe to the channel"),
+        (status = 503, description = "Unable to connect to RabbitMQ")
+    ),
+    security(("apiBearerAuth" = [])),
+    tag = "default"
+)]
+pub async fn register_webhook(
+    request: WebhookRequest,
+    claims: APIClaims,
+    webhooks: WebhookStore,
+    deliveries: WebhookDeliveryStore,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    if let Err(e) = validate_webhook(&request).await {
+        return Ok(reply_with_status(e, StatusCode::BAD_REQUEST));
+    }
+    if let Err(e) = authorize_api_subscribe(&claims.sub, &request.channel) {
+        return Ok(reply_with_status(e, StatusCode::FORBIDDEN));
+    }
+
+    let registered = webhooks
+        .values()
+        .await
+        .iter()
+        .filter(|webhook| webhook.is_active() && webhook.owner == claims.sub)
+        .count();
+    if registered >= CONFIG.webhooks.max_per_client {
+        return Ok(reply_with_status(
+            format!(
+                "At most {} webhooks may be registered per client",
+                CONFIG.webhooks.max_per_client
+            ),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+
+    let webhook = Webhook {
+        id: Uuid::new_v4().to_string(),
+        owner: claims.sub,
+        channel: request.channel,
+        url: request.url,
+        secret: request.secret,
+        event_types: request.event_types,
+        created_at: Utc::now(),
+        removed_at: None,
+    };
+
+    let bound = match RABBIT_POOL.channel().await {
+        Ok(rabbit_channel) => {
+            bind_channel(&rabbit_channel, WEBHOOK_MESSAGE_QUEUE, &webhook.channel).await
+        }
+        Err(e) => Err(e),
+    };
+    if let Err(e) = bound {
+        println!("Failed to bind webhook {}: {:?}", webhook.channel, e);
+        return Ok(reply_with_status(
+            "Unable to connect to RabbitMQ".to_string(),
+            StatusCode::SERVICE_UNAVAILABLE,
+        ));
+    }
+
+    let response = WebhookResponse::from(&webhook);
+    println!(
+        "Registered webhook {} for {} on {}",
+        webhook.id, webhook.owner, webhook.channel
+    );
+    replicate(&webhooks, &deliveries, WebhookEvent::Registered { webhook }).await;
+
+    Ok(Box::new(warp::reply::with_status(
+        warp::reply::json(&response),
+        StatusCode::CREATED,
+    )))
+}
+
+#[utoipa::path(
+    get,
+    path = "/notification/webhooks",
+    responses(
+        (status = 200, description = "The caller's webhooks", body = [WebhookResponse])
+    ),
+    security(("apiBearerAuth" = [])),
+    tag = "default"
+)]
+pub async fn list_webhooks(
+    claims: APIClaims,
+    webhooks: WebhookStore,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let mut owned = webhooks
+        .values()
+        .await
+        .into_iter()
+        .filter(|webhook| webhook.is_active() && webhook.owner == claims.sub)
+        .collect::<Vec<_>>();
+    owned.sort_by_key(|webhook| webhook.created_at);
+
+    let response = owned.iter().map(WebhookResponse::from).collect::<Vec<_>>();
+    Ok(warp::reply::json(&response))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/notification/webhooks/{webhook_id}",
+    params(
+        ("webhook_id" = String, Path, description = "The id returned on registration")
+    ),
+    responses(
+        (status = 204, description = "Webhook removed along with its delivery log"),
+        (status = 404, description = "Webhook not found")
+    ),
+    security(("apiBearerAuth" = [])),
+    tag = "default"
+)]
+pub async fn delete_webhook(
+    webhook_id: String,
+    claims: APIClaims,
+    webhooks: WebhookStore,
+    deliveries: WebhookDeliveryStore,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    let Some(webhook) = owned_webhook(&webhooks, &webhook_id, &claims).await else {
+        return Ok(reply_with_status(
+            "Webhook not found".to_string(),
+            StatusCode::NOT_FOUND,
+        ));
+    };
+
+    replicate(
+        &webhooks,
+        &deliveries,
+        WebhookEvent::Removed {
+            id: webhook_id.clone(),
+        },
+    )
+    .await;
+
+    // The binding is shared by every webhook on the same channel
+    let still_bound = webhooks
+        .values()
+        .await
+        .iter()
+        .any(|other| other.is_active() && other.channel == webhook.channel);
+    if !still_bound {
+        let unbound = match RABBIT_POOL.channel().await {
+            Ok(rabbit_channel) => {
+                unbind_channel(&rabbit_channel, WEBHOOK_MESSAGE_QUEUE, &webhook.channel).await
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = unbound {
+            println!("Failed to unbind webhook {}: {:?}", webhook.channel, e);
+        }
+    }
+
+    println!("Removed webhook {} of {}", webhook_id, claims.sub);
+    Ok(Box::new(StatusCode::NO_CONTENT))
+}
+
+#[utoipa::path(
+    get,
+    path = "/notification/webhooks/{webhook_id}/deliveries",
+    params(
+        ("webhook_id" = String, Path, description = "The id returned on registration")
+    ),
+    responses(
+        (status = 200, description = "Recent deliveries to the webhook, newest first", body = [WebhookDelivery]),
+        (status = 404, description = "Webhook not found")
+    ),
+    security(("apiBearerAuth" = [])),
+    tag = "default"
+)]
+pub async fn list_webhook_deliveries(
+    webhook_id: String,
+    claims: APIClaims,
+    webhooks: WebhookStore,
+    deliveries: WebhookDeliveryStore,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    if owned_webhook(&webhooks, &webhook_id, &claims)
+        .await
+        .is_none()
+    {
+        return Ok(reply_with_status(
+            "Webhook not found".to_string(),
+            StatusCode::NOT_FOUND,
+        ));
+    }
+
+    let mut log = deliveries
+        .values()
+        .await
+        .into_iter()
+        .filter(|delivery| delivery.webhook_id == webhook_id)
+        .collect::<Vec<_>>();
+    log.sort_by_key(|delivery| Reverse(delivery.created_at));
+    Ok(Box::new(warp::reply::json(&log)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn webhook(channel: &str, event_types: &[&str]) -> Webhook {
+        Webhook {
+            id: "webhook".to_string(),
+            owner: "client".to_string(),
+            channel: channel.to_string(),
+            url: "https://example.com/hook".to_string(),
+            secret: "whsec_0123456789abcdef".to_string(),
+            event_types: event_types.iter().map(|t| t.to_string()).collect(),
+            created_at: Utc::now(),
+            removed_at: None,
+        }
+    }
+
+    fn message(channel: &str, event_type: Option<&str>) -> RabbitMessage {
+        serde_json::from_value(serde_json::json!({
+            "channel_id": channel,
+            "message": "hello",
+            "type": event_type,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn signs_timestamp_and_body() {
+        assert_eq!(
+            sign("whsec_0123456789abcdef", "1700000000", r#"{"id":"1"}"#),
+            "a9dde36b92a3804d07aae8c5c12974ebd0f1a5107fb4823bca502467943477ca"
+        );
+        assert_ne!(
+            sign("whsec_0123456789abcdef", "1700000001", r#"{"id":"1"}"#),
+            sign("whsec_0123456789abcdef", "1700000000", r#"{"id":"1"}"#)
+        );
+    }
+
+    #[test]
+    fn backs_off_exponentially_up_to_the_cap() {
+        let initial = CONFIG.webhooks.initial_backoff_ms;
+
+        assert_eq!(
+            retry_backoff_ms(1),
+            initial.min(CONFIG.webhooks.max_backoff_ms)
+        );
+        assert_eq!(
+            retry_backoff_ms(2),
+            (initial * 2).min(CONFIG.webhooks.max_backoff_ms)
+        );
+        assert_eq!(retry_backoff_ms(u32::MAX), CONFIG.webhooks.max_backoff_ms);
+    }
+
+    #[test]
+    fn accepts_matching_channels_and_types() {
+        let typed = webhook("orders.*", &["created"]);
+
+        assert!(typed.accepts(&message("orders.eu", Some("created"))));
+        assert!(!typed.accepts(&message("orders.eu", Some("deleted"))));
+        assert!(!typed.accepts(&message("orders.eu", None)));
+        assert!(!typed.accepts(&message("invoices.eu", Some("created"))));
+        assert!(webhook("#", &[]).accepts(&message("orders.eu", None)));
+        assert!(!webhook("#", &[]).accepts(&message("group:staff", None)));
+    }
+
+    #[test]
+    fn removed_webhooks_accept_nothing_and_win_merges() {
+        let active = webhook("orders.*", &[]);
+        let removed = Webhook {
+            removed_at: Some(Utc::now()),
+            ..active.clone()
+        };
+
+        assert!(!removed.accepts(&message("orders.eu", None)));
+        assert!(prefer_removed_webhook(&active, &removed));
+        assert!(!prefer_removed_webhook(&removed, &active));
+    }
+}